@@ -0,0 +1,132 @@
+//! Announces to and scrapes every tracker listed by a torrent and prints how each one answered.
+//!
+//! Usage: tracker-probe TORRENT [--port PORT] [--timeout SECONDS]
+
+use std::env;
+use std::process::ExitCode;
+use std::time::Duration;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use tokio::time::Instant;
+
+use engine::model::Torrent;
+use engine::peer::peer::PeerId;
+use engine::tracker::{
+    AnyTrackerConnector, HttpTrackerConnector, TrackerManager, TrackerScheme, TrackerStats, TrackerUrl,
+    UdpTrackerConnector, WsTrackerConnector,
+};
+
+const USAGE: &str = "Usage: tracker-probe TORRENT [--port PORT] [--timeout SECONDS]";
+
+struct Args {
+    torrent: String,
+    port: u16,
+    timeout: Duration,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut torrent = None;
+    let mut port = 6881;
+    let mut timeout = Duration::from_secs(10);
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("Missing value for {arg}"));
+        match arg.as_str() {
+            "--port" => port = value()?.parse().map_err(|err| format!("Invalid port: {err}"))?,
+            "--timeout" => {
+                let seconds = value()?.parse().map_err(|err| format!("Invalid timeout: {err}"))?;
+                timeout = Duration::from_secs(seconds);
+            },
+            _ if arg.starts_with("--") || torrent.is_some() => return Err(format!("Unknown argument '{arg}'")),
+            _ => torrent = Some(arg),
+        }
+    }
+    let torrent = torrent.ok_or_else(|| String::from("Missing torrent file"))?;
+    Ok(Args { torrent, port, timeout })
+}
+
+fn peer_id() -> PeerId {
+    let mut peer_id = *b"-ST0001-000000000000";
+    for (byte, random) in peer_id[8..].iter_mut().zip(rand::thread_rng().sample_iter(Alphanumeric)) {
+        *byte = random;
+    }
+    peer_id
+}
+
+/// A connector giving up after `timeout`, so a dead tracker doesn't hold up the others.
+fn connector(url: &TrackerUrl, timeout: Duration) -> AnyTrackerConnector {
+    match url.scheme() {
+        TrackerScheme::Http | TrackerScheme::Https => AnyTrackerConnector::Http(HttpTrackerConnector::with_timeout(timeout)),
+        TrackerScheme::Udp => AnyTrackerConnector::Udp(UdpTrackerConnector::with_timeout(timeout, 0)),
+        TrackerScheme::Ws | TrackerScheme::Wss => AnyTrackerConnector::Ws(WsTrackerConnector::with_timeout(timeout)),
+    }
+}
+
+fn count<T: ToString>(value: Option<T>) -> String {
+    value.map_or_else(|| String::from("-"), |value| value.to_string())
+}
+
+fn print_stats(stats: &TrackerStats) {
+    let status = match &stats.last_error {
+        Some(err) => format!("FAILED  {err}"),
+        None => String::from("OK"),
+    };
+    let next_announce = match stats.next_announce {
+        Some(at) => format!("in {}s", at.saturating_duration_since(Instant::now()).as_secs()),
+        None => String::from("never"),
+    };
+    println!("[tier {}] {}", stats.tier, stats.url);
+    println!("  announce: {status}");
+    println!("  latency: {}ms, peers: {}, seeders: {}, leechers: {}, completed: {}",
+        stats.latency.map_or(0, |latency| latency.as_millis()),
+        count(stats.peers_returned),
+        count(stats.seeders),
+        count(stats.leechers),
+        count(stats.completed),
+    );
+    if let Some(err) = &stats.last_scrape_error {
+        println!("  scrape: {err}");
+    }
+    println!("  next announce: {next_announce}");
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{err}");
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        },
+    };
+    let torrent = match Torrent::from_file(&args.torrent) {
+        Ok(torrent) => torrent,
+        Err(err) => {
+            eprintln!("Couldn't read torrent '{}': {err}", args.torrent);
+            return ExitCode::FAILURE;
+        },
+    };
+    let mut tiers = vec![];
+    for tier in &torrent.announce_tiers {
+        let mut trackers = vec![];
+        for url in tier {
+            match TrackerUrl::parse(url) {
+                Ok(tracker_url) => trackers.push((url.clone(), connector(&tracker_url, args.timeout))),
+                Err(err) => println!("Skipping {url}: {err}"),
+            }
+        }
+        tiers.push(trackers);
+    }
+    println!("Probing trackers of {} ({})", torrent.root_name, hex::encode(torrent.info_hash));
+    let mut manager = TrackerManager::new(torrent.info_hash, peer_id(), args.port, tiers);
+    let stats = manager.probe().await;
+    for stats in &stats {
+        print_stats(stats);
+    }
+    if stats.iter().any(|stats| stats.last_error.is_none()) {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
@@ -0,0 +1,87 @@
+//! Standalone tracker serving HTTP and UDP announces from memory.
+//!
+//! Usage: tracker-server [--http ADDR|off] [--udp ADDR|off] [--interval SECONDS] [--whitelist FILE]
+//!
+//! The whitelist file holds one hex encoded info hash per line.
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::process::ExitCode;
+use std::time::Duration;
+use std::{env, fs};
+
+use engine::model::Sha1Hash;
+use engine::tracker::server::{TrackerServer, TrackerServerConfig};
+
+fn parse_addr(value: &str) -> Result<Option<SocketAddr>, String> {
+    if value == "off" {
+        return Ok(None);
+    }
+    value.parse().map(Some).map_err(|err| format!("Invalid address '{value}': {err}"))
+}
+
+fn parse_whitelist(path: &str) -> Result<HashSet<Sha1Hash>, String> {
+    let content = fs::read_to_string(path).map_err(|err| format!("Couldn't read whitelist '{path}': {err}"))?;
+    content.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let mut info_hash = [0u8; 20];
+            hex::decode_to_slice(line, &mut info_hash)
+                .map(|_| info_hash)
+                .map_err(|err| format!("Invalid info hash '{line}': {err}"))
+        })
+        .collect()
+}
+
+fn parse_args() -> Result<TrackerServerConfig, String> {
+    let mut config = TrackerServerConfig::default();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("Missing value for {arg}"));
+        match arg.as_str() {
+            "--http" => config.http_addr = parse_addr(&value()?)?,
+            "--udp" => config.udp_addr = parse_addr(&value()?)?,
+            "--interval" => {
+                let seconds: u64 = value()?.parse().map_err(|err| format!("Invalid interval: {err}"))?;
+                config.announce_interval = Duration::from_secs(seconds);
+                config.peer_ttl = Duration::from_secs(seconds * 2);
+            },
+            "--whitelist" => config.whitelist = Some(parse_whitelist(&value()?)?),
+            _ => return Err(format!("Unknown argument '{arg}'")),
+        }
+    }
+    Ok(config)
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let config = match parse_args() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{err}");
+            eprintln!("Usage: tracker-server [--http ADDR|off] [--udp ADDR|off] [--interval SECONDS] [--whitelist FILE]");
+            return ExitCode::FAILURE;
+        },
+    };
+    let server = match TrackerServer::bind(config).await {
+        Ok(server) => server,
+        Err(err) => {
+            eprintln!("Couldn't bind tracker sockets: {err}");
+            return ExitCode::FAILURE;
+        },
+    };
+    if let Some(addr) = server.http_addr() {
+        println!("HTTP tracker listening on http://{addr}/announce");
+    }
+    if let Some(addr) = server.udp_addr() {
+        println!("UDP tracker listening on udp://{addr}");
+    }
+    match server.run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Tracker stopped: {err}");
+            ExitCode::FAILURE
+        },
+    }
+}
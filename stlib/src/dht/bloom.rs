@@ -0,0 +1,90 @@
+use std::fmt;
+use std::net::IpAddr;
+
+use crate::util::common::sha1_hash;
+
+pub const BLOOM_FILTER_LEN: usize = 256;
+const BLOOM_FILTER_BITS: usize = BLOOM_FILTER_LEN * 8;
+
+/// Bloom filter of peer addresses, the `BFsd` and `BFpe` keys of a DHT scrape (BEP 33).
+/// Filters of several nodes are merged with [`BloomFilter::union`] before estimating the
+/// size of the swarm.
+#[derive(Clone, PartialEq, Eq)]
+pub struct BloomFilter(Box<[u8; BLOOM_FILTER_LEN]>);
+
+impl BloomFilter {
+    pub fn from_slice(bytes: &[u8]) -> Option<Self> {
+        Some(Self(Box::new(bytes.try_into().ok()?)))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; BLOOM_FILTER_LEN] {
+        &self.0
+    }
+
+    pub fn insert(&mut self, ip: IpAddr) {
+        let hash = match ip {
+            IpAddr::V4(ip) => sha1_hash(ip.octets()),
+            IpAddr::V6(ip) => sha1_hash(ip.octets()),
+        };
+        for index in [u16::from_le_bytes([hash[0], hash[1]]), u16::from_le_bytes([hash[2], hash[3]])] {
+            let index = index as usize % BLOOM_FILTER_BITS;
+            self.0[index / 8] |= 1 << (index % 8);
+        }
+    }
+
+    pub fn union(&mut self, other: &BloomFilter) {
+        for (byte, other) in self.0.iter_mut().zip(other.0.iter()) {
+            *byte |= other;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|byte| *byte == 0)
+    }
+
+    /// Estimated number of addresses inserted.
+    pub fn estimate(&self) -> f64 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let m = BLOOM_FILTER_BITS as f64;
+        let zeros = self.0.iter().map(|byte| byte.count_zeros()).sum::<u32>() as f64;
+        let zeros = zeros.min(m - 1.0);
+        (zeros / m).ln() / (2.0 * (1.0 - 1.0 / m).ln())
+    }
+}
+
+impl Default for BloomFilter {
+    fn default() -> Self {
+        Self(Box::new([0; BLOOM_FILTER_LEN]))
+    }
+}
+
+impl fmt::Debug for BloomFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "BloomFilter(~{:.0})", self.estimate())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use super::*;
+
+    #[test]
+    fn test_spec_example() {
+        // Example of BEP 33: 192.0.2.0 to 192.0.2.255 and 2001:db8:: to 2001:db8::3e7.
+        let mut filter = BloomFilter::default();
+        assert!(filter.is_empty());
+        assert_eq!(filter.estimate(), 0.0);
+        for i in 0..=255 {
+            filter.insert(IpAddr::V4(Ipv4Addr::new(192, 0, 2, i)));
+        }
+        let mut filter6 = BloomFilter::default();
+        for i in 0..0x3e8 {
+            filter6.insert(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, i)));
+        }
+        filter.union(&filter6);
+        assert!((filter.estimate() - 1224.9308).abs() < 0.001, "{}", filter.estimate());
+    }
+}
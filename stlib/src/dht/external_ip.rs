@@ -0,0 +1,80 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+
+use crate::util::common::is_local_ip;
+
+/// Distinct nodes that must report the same address before it is believed.
+const MIN_VOTES: usize = 3;
+/// Voters remembered before starting over, so that a stale majority doesn't stick forever.
+const MAX_VOTERS: usize = 1000;
+
+/// Our external address, as set from trackers or a peer's `yourip`, or as reported by the `ip`
+/// key of DHT responses (BEP 42).
+#[derive(Debug, Default)]
+pub struct ExternalIp {
+    ip: Option<IpAddr>,
+    votes: HashMap<IpAddr, HashSet<IpAddr>>,
+}
+
+impl ExternalIp {
+    pub fn get(&self) -> Option<IpAddr> {
+        self.ip
+    }
+
+    /// Takes an address learned from a source we trust, which the votes then start from.
+    pub fn set(&mut self, ip: IpAddr) {
+        self.ip = Some(ip);
+        self.votes.clear();
+    }
+
+    /// Records that `voter` saw us at `ip`. Returns the new external address when it changes,
+    /// that is when more nodes saw us at `ip` than at the current one.
+    pub fn vote(&mut self, voter: IpAddr, ip: IpAddr) -> Option<IpAddr> {
+        if is_local_ip(ip) {
+            return None;
+        }
+        if self.votes.values().map(HashSet::len).sum::<usize>() >= MAX_VOTERS {
+            self.votes.clear();
+        }
+        for voters in self.votes.values_mut() {
+            voters.remove(&voter);
+        }
+        self.votes.entry(ip).or_default().insert(voter);
+
+        let count = |ip: &IpAddr| self.votes.get(ip).map_or(0, HashSet::len);
+        let current = self.ip.as_ref().map_or(0, count);
+        if Some(ip) == self.ip || count(&ip) < MIN_VOTES || count(&ip) <= current {
+            return None;
+        }
+        self.ip = Some(ip);
+        self.ip
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_majority_of_voters_wins() {
+        let (ip, other) = (IpAddr::from([81, 2, 3, 4]), IpAddr::from([81, 2, 3, 5]));
+        let voter = |i: u8| IpAddr::from([10, 0, 0, i]);
+        let mut external = ExternalIp::default();
+        assert_eq!(external.vote(voter(1), IpAddr::from([192, 168, 1, 2])), None);
+        assert_eq!(external.vote(voter(1), ip), None);
+        // The same voter counts once.
+        assert_eq!(external.vote(voter(1), ip), None);
+        assert_eq!(external.vote(voter(2), ip), None);
+        assert_eq!(external.vote(voter(3), ip), Some(ip));
+        assert_eq!(external.get(), Some(ip));
+
+        for i in 4..=6 {
+            assert_eq!(external.vote(voter(i), other), None);
+        }
+        assert_eq!(external.vote(voter(1), other), Some(other));
+
+        external.set(ip);
+        assert_eq!(external.get(), Some(ip));
+        assert_eq!(external.vote(voter(1), other), None);
+    }
+}
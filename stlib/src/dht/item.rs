@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+use ed25519_dalek::{Signature, Signer, Verifier};
+use serde_bencode::value::Value;
+use tokio::time::Instant;
+
+use crate::model::Sha1Hash;
+use crate::util::common::sha1_hash;
+
+use super::krpc::ERROR_PROTOCOL;
+
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+
+/// Upper bound on the bencoded value of an item, to fit in a UDP packet.
+pub const MAX_VALUE_SIZE: usize = 1000;
+pub const MAX_SALT_SIZE: usize = 64;
+/// Items are forgotten after this long without being put again.
+pub const ITEM_TTL: Duration = Duration::from_secs(2 * 60 * 60);
+const MAX_ITEMS: usize = 10_000;
+
+pub const ERROR_VALUE_TOO_BIG: i64 = 205;
+pub const ERROR_INVALID_SIGNATURE: i64 = 206;
+pub const ERROR_SALT_TOO_BIG: i64 = 207;
+pub const ERROR_CAS_MISMATCH: i64 = 301;
+pub const ERROR_SEQ_TOO_LOW: i64 = 302;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ItemError {
+    /// The value isn't bencoded.
+    InvalidValue,
+    ValueTooBig(usize),
+    SaltTooBig(usize),
+    InvalidSignature,
+    /// The sequence number expected by a compare-and-swap isn't the stored one.
+    CasMismatch,
+    /// A newer version of the mutable item is stored.
+    SeqTooLow,
+}
+
+impl ItemError {
+    /// KRPC error code of the error, for `put` queries that fail with it.
+    pub fn code(&self) -> i64 {
+        match self {
+            Self::InvalidValue => ERROR_PROTOCOL,
+            Self::ValueTooBig(_) => ERROR_VALUE_TOO_BIG,
+            Self::SaltTooBig(_) => ERROR_SALT_TOO_BIG,
+            Self::InvalidSignature => ERROR_INVALID_SIGNATURE,
+            Self::CasMismatch => ERROR_CAS_MISMATCH,
+            Self::SeqTooLow => ERROR_SEQ_TOO_LOW,
+        }
+    }
+}
+
+impl fmt::Display for ItemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidValue => write!(f, "Item value isn't bencoded"),
+            Self::ValueTooBig(size) => write!(f, "Item value of {size} bytes exceeds {MAX_VALUE_SIZE}"),
+            Self::SaltTooBig(size) => write!(f, "Item salt of {size} bytes exceeds {MAX_SALT_SIZE}"),
+            Self::InvalidSignature => write!(f, "Invalid item signature"),
+            Self::CasMismatch => write!(f, "CAS mismatch"),
+            Self::SeqTooLow => write!(f, "Sequence number less than current"),
+        }
+    }
+}
+
+impl std::error::Error for ItemError {}
+
+/// Arbitrary data stored in the DHT (BEP 44), as a bencoded value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Item {
+    /// Stored under the SHA-1 of its value, which therefore can't change.
+    Immutable { value: Vec<u8> },
+    /// Stored under the SHA-1 of the public key and salt, and replaced by signing a value with
+    /// a higher sequence number.
+    Mutable {
+        value: Vec<u8>,
+        key: [u8; 32],
+        salt: Vec<u8>,
+        seq: i64,
+        signature: [u8; 64],
+    },
+}
+
+impl Item {
+    pub fn immutable(value: Vec<u8>) -> Result<Self, ItemError> {
+        let item = Self::Immutable { value };
+        item.validate()?;
+        Ok(item)
+    }
+
+    /// Signs `value` as version `seq` of the item of `key` and `salt`.
+    pub fn mutable(key: &SigningKey, salt: Vec<u8>, seq: i64, value: Vec<u8>) -> Result<Self, ItemError> {
+        let signature = key.sign(&signable(&salt, seq, &value)).to_bytes();
+        let item = Self::Mutable { value, key: key.verifying_key().to_bytes(), salt, seq, signature };
+        item.validate()?;
+        Ok(item)
+    }
+
+    pub fn value(&self) -> &[u8] {
+        match self {
+            Self::Immutable { value } | Self::Mutable { value, .. } => value,
+        }
+    }
+
+    /// Sequence number of a mutable item.
+    pub fn seq(&self) -> Option<i64> {
+        match self {
+            Self::Immutable { .. } => None,
+            Self::Mutable { seq, .. } => Some(*seq),
+        }
+    }
+
+    /// Key the item is stored under.
+    pub fn target(&self) -> Sha1Hash {
+        match self {
+            Self::Immutable { value } => immutable_target(value),
+            Self::Mutable { key, salt, .. } => mutable_target(key, salt),
+        }
+    }
+
+    /// Checks the value, the salt and the signature, as items from other nodes must be.
+    pub fn validate(&self) -> Result<(), ItemError> {
+        let value = self.value();
+        if value.len() > MAX_VALUE_SIZE {
+            return Err(ItemError::ValueTooBig(value.len()));
+        }
+        if serde_bencode::from_bytes::<Value>(value).is_err() {
+            return Err(ItemError::InvalidValue);
+        }
+        let Self::Mutable { key, salt, seq, signature, .. } = self else { return Ok(()) };
+        if salt.len() > MAX_SALT_SIZE {
+            return Err(ItemError::SaltTooBig(salt.len()));
+        }
+        let key = VerifyingKey::from_bytes(key).map_err(|_| ItemError::InvalidSignature)?;
+        key.verify(&signable(salt, *seq, value), &Signature::from_bytes(signature))
+            .map_err(|_| ItemError::InvalidSignature)
+    }
+}
+
+pub fn immutable_target(value: &[u8]) -> Sha1Hash {
+    sha1_hash(value)
+}
+
+pub fn mutable_target(key: &[u8; 32], salt: &[u8]) -> Sha1Hash {
+    sha1_hash([&key[..], salt].concat())
+}
+
+/// What the signature of a mutable item covers: its salt, sequence number and value, as they
+/// appear in a bencoded dictionary.
+fn signable(salt: &[u8], seq: i64, value: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![];
+    if !salt.is_empty() {
+        bytes.extend(format!("4:salt{}:", salt.len()).into_bytes());
+        bytes.extend(salt);
+    }
+    bytes.extend(format!("3:seqi{seq}e1:v").into_bytes());
+    bytes.extend(value);
+    bytes
+}
+
+/// Items put to us with `put`.
+#[derive(Default)]
+pub struct ItemStore {
+    items: HashMap<Sha1Hash, (Item, Instant)>,
+}
+
+impl ItemStore {
+    pub fn get(&self, target: &Sha1Hash, now: Instant) -> Option<&Item> {
+        self.items.get(target)
+            .filter(|(_, put_at)| now.duration_since(*put_at) < ITEM_TTL)
+            .map(|(item, _)| item)
+    }
+
+    /// Stores a valid item, unless a newer version of it is stored or, when `cas` is set, the
+    /// stored version isn't `cas`.
+    pub fn put(&mut self, item: Item, cas: Option<i64>, now: Instant) -> Result<(), ItemError> {
+        item.validate()?;
+        let target = item.target();
+        if let Some(current) = self.get(&target, now).and_then(Item::seq) {
+            if cas.is_some_and(|cas| cas != current) {
+                return Err(ItemError::CasMismatch);
+            }
+            if item.seq().is_some_and(|seq| seq < current) {
+                return Err(ItemError::SeqTooLow);
+            }
+        }
+        if self.items.len() >= MAX_ITEMS && !self.items.contains_key(&target) {
+            self.expire(now);
+            if self.items.len() >= MAX_ITEMS {
+                return Ok(());
+            }
+        }
+        self.items.insert(target, (item, now));
+        Ok(())
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        self.items.retain(|_, (_, put_at)| now.duration_since(*put_at) < ITEM_TTL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spec_examples() {
+        // Test vectors of BEP 44.
+        let value = b"12:Hello World!".to_vec();
+        assert_eq!(hex::encode(Item::immutable(value.clone()).unwrap().target()), "e5f96f6f38320f0f33959cb4d3d656452117aadb");
+        assert_eq!(signable(b"", 1, &value), b"3:seqi1e1:v12:Hello World!");
+        assert_eq!(signable(b"foobar", 1, &value), b"4:salt6:foobar3:seqi1e1:v12:Hello World!");
+
+        let key = hex::decode("77ff84905a91936367c01360803104f92432fcd904a43511876df5cdf3e7e548").unwrap();
+        let signature = hex::decode("6834284b6b24c3204eb2fea824d82f88883a3d95e8b4a21b8c0ded553d17d17ddf9a8a7104b1258f30bed3787e6cb896fca78c58f8e03b5f18f14951a87d9a08").unwrap();
+        let item = Item::Mutable {
+            value,
+            key: key.try_into().unwrap(),
+            salt: b"foobar".to_vec(),
+            seq: 1,
+            signature: signature.try_into().unwrap(),
+        };
+        assert_eq!(item.validate(), Ok(()));
+        assert_eq!(hex::encode(item.target()), "411eba73b6f087ca51a3795d9c8c938d365e32c1");
+    }
+
+    #[test]
+    fn test_invalid_items_are_refused() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        assert_eq!(Item::immutable(b"not bencode".to_vec()), Err(ItemError::InvalidValue));
+        let big = format!("{}:{}", MAX_VALUE_SIZE, "a".repeat(MAX_VALUE_SIZE)).into_bytes();
+        assert!(matches!(Item::immutable(big), Err(ItemError::ValueTooBig(_))));
+        assert_eq!(Item::mutable(&key, vec![0; 65], 1, b"i1e".to_vec()), Err(ItemError::SaltTooBig(65)));
+
+        let Ok(Item::Mutable { value, key, salt, seq, signature }) = Item::mutable(&key, vec![], 1, b"i1e".to_vec()) else { panic!() };
+        let forged = Item::Mutable { value, key, salt, seq: seq + 1, signature };
+        assert_eq!(forged.validate(), Err(ItemError::InvalidSignature));
+    }
+
+    #[test]
+    fn test_store_keeps_latest_version() {
+        let now = Instant::now();
+        let key = SigningKey::from_bytes(&[2; 32]);
+        let version = |seq: i64| Item::mutable(&key, b"salt".to_vec(), seq, format!("i{seq}e").into_bytes()).unwrap();
+        let mut store = ItemStore::default();
+        let target = version(1).target();
+        store.put(version(2), None, now).unwrap();
+        assert_eq!(store.put(version(1), None, now), Err(ItemError::SeqTooLow));
+        assert_eq!(store.put(version(3), Some(1), now), Err(ItemError::CasMismatch));
+        store.put(version(3), Some(2), now).unwrap();
+        assert_eq!(store.get(&target, now), Some(&version(3)));
+
+        let immutable = Item::immutable(b"i7e".to_vec()).unwrap();
+        store.put(immutable.clone(), None, now).unwrap();
+        assert_eq!(store.get(&immutable.target(), now), Some(&immutable));
+        assert_eq!(store.get(&target, now + ITEM_TTL), None);
+        store.expire(now + ITEM_TTL);
+        assert!(store.items.is_empty());
+    }
+}
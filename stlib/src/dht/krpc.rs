@@ -0,0 +1,580 @@
+//! KRPC, the bencoded query/response protocol DHT nodes speak over UDP (BEP 5).
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use serde_bencode::value::Value;
+
+use crate::model::{compact_peer, Sha1Hash, SHA1_HASH_LEN};
+use crate::util::bencode;
+
+use super::{BloomFilter, DhtError, Item, NodeId, NodeInfo};
+
+/// Length of a node in a compact IPv4 `nodes` string: id, address and port.
+pub const COMPACT_NODE_LEN: usize = 20 + 4 + 2;
+/// Length of a node in a compact `nodes6` string (BEP 32).
+pub const COMPACT_NODE6_LEN: usize = 20 + 16 + 2;
+
+pub const ERROR_GENERIC: i64 = 201;
+pub const ERROR_SERVER: i64 = 202;
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+/// Address families of the nodes asked for by `find_node`, `get_peers` and
+/// `sample_infohashes` (BEP 32). When
+/// neither is set, nodes of the family the query was sent over are returned.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Want {
+    pub n4: bool,
+    pub n6: bool,
+}
+
+impl Want {
+    pub fn is_empty(&self) -> bool {
+        !self.n4 && !self.n6
+    }
+
+    fn from_value(value: Option<&Value>) -> Self {
+        let mut want = Self::default();
+        if let Some(Value::List(families)) = value {
+            for family in families {
+                match family {
+                    Value::Bytes(family) if family == b"n4" => want.n4 = true,
+                    Value::Bytes(family) if family == b"n6" => want.n6 = true,
+                    _ => {}
+                }
+            }
+        }
+        want
+    }
+
+    fn to_value(self) -> Option<Value> {
+        if self.is_empty() {
+            return None;
+        }
+        let families = [(self.n4, "n4"), (self.n6, "n6")].into_iter()
+            .filter(|(wanted, _)| *wanted)
+            .map(|(_, family)| bytes(family))
+            .collect();
+        Some(Value::List(families))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    Ping,
+    FindNode { target: NodeId, want: Want },
+    GetPeers {
+        info_hash: Sha1Hash,
+        want: Want,
+        /// Asks for bloom filters of the seeds and peers as well (BEP 33).
+        scrape: bool,
+    },
+    AnnouncePeer {
+        info_hash: Sha1Hash,
+        port: u16,
+        /// The port to store is the one the query came from, for peers behind NAT.
+        implied_port: bool,
+        token: Vec<u8>,
+        /// The peer has the whole torrent (BEP 33).
+        seed: bool,
+    },
+    /// Asks for a sample of the info hashes the node stores peers of (BEP 51).
+    SampleInfohashes { target: NodeId, want: Want },
+    /// Asks for the item stored under `target` (BEP 44); a mutable item's value is only
+    /// returned when its sequence number is higher than `seq`.
+    Get { target: Sha1Hash, seq: Option<i64> },
+    /// Stores an item, replacing a mutable one only if its sequence number is `cas`, when set.
+    Put { token: Vec<u8>, item: Item, cas: Option<i64> },
+    /// A method we don't implement, answered with error 204.
+    Unknown(String),
+}
+
+impl Query {
+    pub fn method(&self) -> &str {
+        match self {
+            Self::Ping => "ping",
+            Self::FindNode { .. } => "find_node",
+            Self::GetPeers { .. } => "get_peers",
+            Self::AnnouncePeer { .. } => "announce_peer",
+            Self::SampleInfohashes { .. } => "sample_infohashes",
+            Self::Get { .. } => "get",
+            Self::Put { .. } => "put",
+            Self::Unknown(method) => method,
+        }
+    }
+}
+
+/// The union of the return values of every query; each query fills the keys it needs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Response {
+    /// Nodes of both families, sent as `nodes` and `nodes6`.
+    pub nodes: Vec<NodeInfo>,
+    pub values: Vec<SocketAddr>,
+    pub token: Option<Vec<u8>>,
+    /// Bencoded value of an item, the `v` key.
+    pub value: Option<Vec<u8>>,
+    /// Public key, signature and sequence number of a mutable item, the `k`, `sig` and `seq` keys.
+    pub key: Option<[u8; 32]>,
+    pub signature: Option<[u8; 64]>,
+    pub seq: Option<i64>,
+    /// Bloom filters of the seeds and of the other peers of a scrape, the `BFsd` and `BFpe` keys.
+    pub seeds_filter: Option<BloomFilter>,
+    pub peers_filter: Option<BloomFilter>,
+    /// Seconds before the node returns another sample of info hashes.
+    pub interval: Option<i64>,
+    /// Number of info hashes the node stores peers of, the `num` key.
+    pub num: Option<i64>,
+    pub samples: Vec<Sha1Hash>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KrpcBody {
+    Query { id: NodeId, query: Query },
+    Response { id: NodeId, response: Response },
+    Error { code: i64, message: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KrpcMessage {
+    pub transaction_id: Vec<u8>,
+    /// Client version of the sender, the `v` key.
+    pub version: Option<Vec<u8>>,
+    /// Address the sender saw the receiver's packet come from, the `ip` key of BEP 42.
+    pub ip: Option<SocketAddr>,
+    /// Set by read-only nodes on their queries, the `ro` key of BEP 43: they don't answer
+    /// queries and don't belong in routing tables.
+    pub read_only: bool,
+    pub body: KrpcBody,
+}
+
+type Dict = HashMap<Vec<u8>, Value>;
+
+fn bytes(value: impl Into<Vec<u8>>) -> Value {
+    Value::Bytes(value.into())
+}
+
+fn invalid(reason: &str) -> DhtError {
+    DhtError::InvalidMessage(reason.to_string())
+}
+
+fn get_bytes<'a>(dict: &'a Dict, key: &str) -> Option<&'a [u8]> {
+    match dict.get(key.as_bytes()) {
+        Some(Value::Bytes(bytes)) => Some(bytes),
+        _ => None,
+    }
+}
+
+fn get_int(dict: &Dict, key: &str) -> Option<i64> {
+    match dict.get(key.as_bytes()) {
+        Some(Value::Int(value)) => Some(*value),
+        _ => None,
+    }
+}
+
+fn get_hash(dict: &Dict, key: &str) -> Result<Sha1Hash, DhtError> {
+    get_bytes(dict, key)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| invalid(&format!("missing or malformed '{key}'")))
+}
+
+impl KrpcMessage {
+    pub fn from_bencode(packet: &[u8]) -> Result<Self, DhtError> {
+        let dict = match bencode::from_bytes(packet) {
+            Ok(Value::Dict(dict)) => dict,
+            _ => return Err(invalid("not a dictionary")),
+        };
+        let transaction_id = get_bytes(&dict, "t").ok_or_else(|| invalid("missing transaction id"))?.to_vec();
+        let version = get_bytes(&dict, "v").map(<[u8]>::to_vec);
+        let ip = get_bytes(&dict, "ip").and_then(parse_compact_addr);
+        let read_only = get_int(&dict, "ro").is_some_and(|read_only| read_only != 0);
+        let body = match get_bytes(&dict, "y") {
+            Some(b"q") => {
+                let method = get_bytes(&dict, "q").ok_or_else(|| invalid("missing method"))?;
+                let Some(Value::Dict(args)) = dict.get(&b"a"[..]) else { return Err(invalid("missing arguments")) };
+                let id = NodeId(get_hash(args, "id")?);
+                KrpcBody::Query { id, query: parse_query(method, args)? }
+            }
+            Some(b"r") => {
+                let Some(Value::Dict(values)) = dict.get(&b"r"[..]) else { return Err(invalid("missing return values")) };
+                let id = NodeId(get_hash(values, "id")?);
+                KrpcBody::Response { id, response: parse_response(values)? }
+            }
+            Some(b"e") => match dict.get(&b"e"[..]) {
+                Some(Value::List(error)) => match error.as_slice() {
+                    [Value::Int(code), Value::Bytes(message), ..] => KrpcBody::Error {
+                        code: *code,
+                        message: String::from_utf8_lossy(message).into_owned(),
+                    },
+                    _ => return Err(invalid("malformed error")),
+                },
+                _ => return Err(invalid("missing error")),
+            },
+            _ => return Err(invalid("unknown message type")),
+        };
+        Ok(Self { transaction_id, version, ip, read_only, body })
+    }
+
+    pub fn to_bencode(&self) -> Vec<u8> {
+        let mut dict = Dict::from([(b"t".to_vec(), bytes(self.transaction_id.clone()))]);
+        if let Some(version) = &self.version {
+            dict.insert(b"v".to_vec(), bytes(version.clone()));
+        }
+        if let Some(ip) = &self.ip {
+            dict.insert(b"ip".to_vec(), bytes(compact_peer(ip)));
+        }
+        if self.read_only {
+            dict.insert(b"ro".to_vec(), Value::Int(1));
+        }
+        match &self.body {
+            KrpcBody::Query { id, query } => {
+                dict.insert(b"y".to_vec(), bytes("q"));
+                dict.insert(b"q".to_vec(), bytes(query.method()));
+                dict.insert(b"a".to_vec(), Value::Dict(query_args(id, query)));
+            }
+            KrpcBody::Response { id, response } => {
+                dict.insert(b"y".to_vec(), bytes("r"));
+                dict.insert(b"r".to_vec(), Value::Dict(response_values(id, response)));
+            }
+            KrpcBody::Error { code, message } => {
+                dict.insert(b"y".to_vec(), bytes("e"));
+                dict.insert(b"e".to_vec(), Value::List(vec![Value::Int(*code), bytes(message.as_bytes())]));
+            }
+        }
+        serde_bencode::to_bytes(&Value::Dict(dict)).expect("KRPC message is always serializable")
+    }
+}
+
+fn parse_query(method: &[u8], args: &Dict) -> Result<Query, DhtError> {
+    Ok(match method {
+        b"ping" => Query::Ping,
+        b"find_node" => Query::FindNode {
+            target: NodeId(get_hash(args, "target")?),
+            want: Want::from_value(args.get(&b"want"[..])),
+        },
+        b"get_peers" => Query::GetPeers {
+            info_hash: get_hash(args, "info_hash")?,
+            want: Want::from_value(args.get(&b"want"[..])),
+            scrape: get_int(args, "scrape").is_some_and(|scrape| scrape != 0),
+        },
+        b"announce_peer" => Query::AnnouncePeer {
+            info_hash: get_hash(args, "info_hash")?,
+            port: get_int(args, "port")
+                .and_then(|port| u16::try_from(port).ok())
+                .ok_or_else(|| invalid("missing port"))?,
+            implied_port: get_int(args, "implied_port").is_some_and(|implied| implied != 0),
+            token: get_bytes(args, "token").ok_or_else(|| invalid("missing token"))?.to_vec(),
+            seed: get_int(args, "seed").is_some_and(|seed| seed != 0),
+        },
+        b"sample_infohashes" => Query::SampleInfohashes {
+            target: NodeId(get_hash(args, "target")?),
+            want: Want::from_value(args.get(&b"want"[..])),
+        },
+        b"get" => Query::Get { target: get_hash(args, "target")?, seq: get_int(args, "seq") },
+        b"put" => {
+            let value = get_value(args).ok_or_else(|| invalid("missing value"))?;
+            let item = match get_bytes(args, "k") {
+                Some(key) => Item::Mutable {
+                    value,
+                    key: key.try_into().map_err(|_| invalid("malformed key"))?,
+                    salt: get_bytes(args, "salt").unwrap_or_default().to_vec(),
+                    seq: get_int(args, "seq").ok_or_else(|| invalid("missing seq"))?,
+                    signature: get_bytes(args, "sig")
+                        .and_then(|signature| signature.try_into().ok())
+                        .ok_or_else(|| invalid("missing or malformed signature"))?,
+                },
+                None => Item::Immutable { value },
+            };
+            Query::Put {
+                token: get_bytes(args, "token").ok_or_else(|| invalid("missing token"))?.to_vec(),
+                item,
+                cas: get_int(args, "cas"),
+            }
+        }
+        method => Query::Unknown(String::from_utf8_lossy(method).into_owned()),
+    })
+}
+
+/// The `v` key re-encoded, which is as it was sent provided it was bencoded canonically.
+fn get_value(dict: &Dict) -> Option<Vec<u8>> {
+    dict.get(&b"v"[..]).and_then(|value| serde_bencode::to_bytes(value).ok())
+}
+
+/// A bencoded value to send as the `v` key; anything else is sent as a string.
+fn value(bencoded: &[u8]) -> Value {
+    serde_bencode::from_bytes(bencoded).unwrap_or_else(|_| bytes(bencoded))
+}
+
+fn query_args(id: &NodeId, query: &Query) -> Dict {
+    let mut args = Dict::from([(b"id".to_vec(), bytes(id.0))]);
+    match query {
+        Query::Ping | Query::Unknown(_) => {}
+        Query::FindNode { target, want } | Query::SampleInfohashes { target, want } => {
+            args.insert(b"target".to_vec(), bytes(target.0));
+            if let Some(want) = want.to_value() {
+                args.insert(b"want".to_vec(), want);
+            }
+        }
+        Query::GetPeers { info_hash, want, scrape } => {
+            args.insert(b"info_hash".to_vec(), bytes(*info_hash));
+            if let Some(want) = want.to_value() {
+                args.insert(b"want".to_vec(), want);
+            }
+            if *scrape {
+                args.insert(b"scrape".to_vec(), Value::Int(1));
+            }
+        }
+        Query::AnnouncePeer { info_hash, port, implied_port, token, seed } => {
+            args.insert(b"info_hash".to_vec(), bytes(*info_hash));
+            args.insert(b"port".to_vec(), Value::Int(*port as i64));
+            args.insert(b"implied_port".to_vec(), Value::Int(*implied_port as i64));
+            args.insert(b"token".to_vec(), bytes(token.clone()));
+            if *seed {
+                args.insert(b"seed".to_vec(), Value::Int(1));
+            }
+        }
+        Query::Get { target, seq } => {
+            args.insert(b"target".to_vec(), bytes(*target));
+            if let Some(seq) = seq {
+                args.insert(b"seq".to_vec(), Value::Int(*seq));
+            }
+        }
+        Query::Put { token, item, cas } => {
+            args.insert(b"token".to_vec(), bytes(token.clone()));
+            args.insert(b"v".to_vec(), value(item.value()));
+            if let Item::Mutable { key, salt, seq, signature, .. } = item {
+                args.insert(b"k".to_vec(), bytes(*key));
+                args.insert(b"seq".to_vec(), Value::Int(*seq));
+                args.insert(b"sig".to_vec(), bytes(*signature));
+                if !salt.is_empty() {
+                    args.insert(b"salt".to_vec(), bytes(salt.clone()));
+                }
+            }
+            if let Some(cas) = cas {
+                args.insert(b"cas".to_vec(), Value::Int(*cas));
+            }
+        }
+    }
+    args
+}
+
+fn parse_response(values: &Dict) -> Result<Response, DhtError> {
+    let mut nodes = match get_bytes(values, "nodes") {
+        Some(nodes) => parse_compact_nodes(nodes)?,
+        None => vec![],
+    };
+    if let Some(nodes6) = get_bytes(values, "nodes6") {
+        nodes.extend(parse_compact_nodes6(nodes6)?);
+    }
+    let peers = match values.get(&b"values"[..]) {
+        Some(Value::List(peers)) => peers.iter()
+            .filter_map(|peer| match peer {
+                Value::Bytes(peer) => parse_compact_addr(peer),
+                _ => None,
+            })
+            .collect(),
+        _ => vec![],
+    };
+    Ok(Response {
+        nodes,
+        values: peers,
+        token: get_bytes(values, "token").map(<[u8]>::to_vec),
+        value: get_value(values),
+        key: get_bytes(values, "k").and_then(|key| key.try_into().ok()),
+        signature: get_bytes(values, "sig").and_then(|signature| signature.try_into().ok()),
+        seq: get_int(values, "seq"),
+        seeds_filter: get_bytes(values, "BFsd").and_then(BloomFilter::from_slice),
+        peers_filter: get_bytes(values, "BFpe").and_then(BloomFilter::from_slice),
+        interval: get_int(values, "interval"),
+        num: get_int(values, "num"),
+        samples: match get_bytes(values, "samples") {
+            Some(samples) if samples.len().is_multiple_of(SHA1_HASH_LEN) => {
+                samples.chunks_exact(SHA1_HASH_LEN).map(|hash| hash.try_into().unwrap()).collect()
+            }
+            Some(_) => return Err(invalid("samples length isn't a multiple of 20")),
+            None => vec![],
+        },
+    })
+}
+
+fn response_values(id: &NodeId, response: &Response) -> Dict {
+    let mut values = Dict::from([(b"id".to_vec(), bytes(id.0))]);
+    if response.nodes.iter().any(|node| node.addr.is_ipv4()) {
+        values.insert(b"nodes".to_vec(), bytes(compact_nodes(&response.nodes)));
+    }
+    if response.nodes.iter().any(|node| node.addr.is_ipv6()) {
+        values.insert(b"nodes6".to_vec(), bytes(compact_nodes6(&response.nodes)));
+    }
+    if !response.values.is_empty() {
+        values.insert(b"values".to_vec(), Value::List(response.values.iter().map(|addr| bytes(compact_peer(addr))).collect()));
+    }
+    if let Some(token) = &response.token {
+        values.insert(b"token".to_vec(), bytes(token.clone()));
+    }
+    if let Some(bencoded) = &response.value {
+        values.insert(b"v".to_vec(), value(bencoded));
+    }
+    if let Some(key) = &response.key {
+        values.insert(b"k".to_vec(), bytes(*key));
+    }
+    if let Some(signature) = &response.signature {
+        values.insert(b"sig".to_vec(), bytes(*signature));
+    }
+    if let Some(seq) = response.seq {
+        values.insert(b"seq".to_vec(), Value::Int(seq));
+    }
+    if let Some(filter) = &response.seeds_filter {
+        values.insert(b"BFsd".to_vec(), bytes(*filter.as_bytes()));
+    }
+    if let Some(filter) = &response.peers_filter {
+        values.insert(b"BFpe".to_vec(), bytes(*filter.as_bytes()));
+    }
+    if let Some(interval) = response.interval {
+        values.insert(b"interval".to_vec(), Value::Int(interval));
+    }
+    if let Some(num) = response.num {
+        values.insert(b"num".to_vec(), Value::Int(num));
+    }
+    if !response.samples.is_empty() {
+        values.insert(b"samples".to_vec(), bytes(response.samples.concat()));
+    }
+    values
+}
+
+/// Compact `nodes` string; nodes without an IPv4 address are left out.
+pub fn compact_nodes(nodes: &[NodeInfo]) -> Vec<u8> {
+    compact_nodes_of_family(nodes, true)
+}
+
+/// Compact `nodes6` string; nodes without an IPv6 address are left out.
+pub fn compact_nodes6(nodes: &[NodeInfo]) -> Vec<u8> {
+    compact_nodes_of_family(nodes, false)
+}
+
+fn compact_nodes_of_family(nodes: &[NodeInfo], ipv4: bool) -> Vec<u8> {
+    nodes.iter()
+        .filter(|node| node.addr.is_ipv4() == ipv4)
+        .flat_map(|node| [&node.id.0[..], &compact_peer(&node.addr)].concat())
+        .collect()
+}
+
+pub fn parse_compact_nodes(bytes: &[u8]) -> Result<Vec<NodeInfo>, DhtError> {
+    parse_compact_nodes_of_len(bytes, COMPACT_NODE_LEN)
+}
+
+pub fn parse_compact_nodes6(bytes: &[u8]) -> Result<Vec<NodeInfo>, DhtError> {
+    parse_compact_nodes_of_len(bytes, COMPACT_NODE6_LEN)
+}
+
+fn parse_compact_nodes_of_len(bytes: &[u8], len: usize) -> Result<Vec<NodeInfo>, DhtError> {
+    if !bytes.len().is_multiple_of(len) {
+        return Err(invalid(&format!("compact nodes length isn't a multiple of {len}")));
+    }
+    Ok(bytes.chunks_exact(len)
+        .filter_map(|chunk| Some(NodeInfo {
+            id: NodeId::from_slice(&chunk[..20])?,
+            addr: parse_compact_addr(&chunk[20..])?,
+        }))
+        .collect())
+}
+
+/// Parses a compact address and port, 6 bytes long for IPv4 and 18 for IPv6.
+pub fn parse_compact_addr(bytes: &[u8]) -> Option<SocketAddr> {
+    let (ip, port) = bytes.split_at_checked(bytes.len().checked_sub(2)?)?;
+    let ip = match ip.len() {
+        4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(ip).ok()?)),
+        16 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(ip).ok()?)),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]])))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(body: KrpcBody) {
+        let ip = Some("[2001:db8::1]:6881".parse().unwrap());
+        let message = KrpcMessage { transaction_id: b"aa".to_vec(), version: Some(b"ST01".to_vec()), ip, read_only: true, body };
+        assert_eq!(KrpcMessage::from_bencode(&message.to_bencode()).unwrap(), message);
+    }
+
+    #[test]
+    fn test_messages_round_trip() {
+        let id = NodeId([1; 20]);
+        for query in [
+            Query::Ping,
+            Query::FindNode { target: NodeId([2; 20]), want: Want::default() },
+            Query::GetPeers { info_hash: [3; 20], want: Want { n4: true, n6: true }, scrape: true },
+            Query::AnnouncePeer { info_hash: [3; 20], port: 6881, implied_port: true, token: b"tok".to_vec(), seed: true },
+            Query::SampleInfohashes { target: NodeId([2; 20]), want: Want::default() },
+            Query::Get { target: [4; 20], seq: Some(3) },
+            Query::Put { token: b"tok".to_vec(), item: Item::Immutable { value: b"d1:ai1ee".to_vec() }, cas: None },
+            Query::Put {
+                token: b"tok".to_vec(),
+                item: Item::Mutable { value: b"4:spam".to_vec(), key: [5; 32], salt: b"salt".to_vec(), seq: 4, signature: [6; 64] },
+                cas: Some(3),
+            },
+            Query::Unknown(String::from("vote")),
+        ] {
+            round_trip(KrpcBody::Query { id, query });
+        }
+        round_trip(KrpcBody::Response {
+            id,
+            response: Response {
+                nodes: vec![
+                    NodeInfo { id: NodeId([4; 20]), addr: "10.0.0.1:6881".parse().unwrap() },
+                    NodeInfo { id: NodeId([5; 20]), addr: "[2001:db8::1]:6881".parse().unwrap() },
+                ],
+                values: vec!["10.0.0.2:51413".parse().unwrap(), "[2001:db8::2]:51413".parse().unwrap()],
+                token: Some(b"tok".to_vec()),
+                ..Response::default()
+            },
+        });
+        round_trip(KrpcBody::Response {
+            id,
+            response: Response {
+                value: Some(b"li1ei2ee".to_vec()),
+                key: Some([5; 32]),
+                signature: Some([6; 64]),
+                seq: Some(4),
+                ..Response::default()
+            },
+        });
+        round_trip(KrpcBody::Response {
+            id,
+            response: Response {
+                seeds_filter: BloomFilter::from_slice(&[7; 256]),
+                peers_filter: Some(BloomFilter::default()),
+                interval: Some(21600),
+                num: Some(2),
+                samples: vec![[8; 20], [9; 20]],
+                ..Response::default()
+            },
+        });
+        round_trip(KrpcBody::Error { code: ERROR_PROTOCOL, message: String::from("bad token") });
+    }
+
+    #[test]
+    fn test_parses_spec_examples() {
+        let ping = KrpcMessage::from_bencode(b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe").unwrap();
+        assert_eq!(ping.body, KrpcBody::Query { id: NodeId(*b"abcdefghij0123456789"), query: Query::Ping });
+        assert_eq!(ping.transaction_id, b"aa");
+
+        let error = KrpcMessage::from_bencode(b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee").unwrap();
+        assert_eq!(error.body, KrpcBody::Error { code: ERROR_GENERIC, message: String::from("A Generic Error Ocurred") });
+
+        let response = KrpcMessage::from_bencode(b"d1:rd2:id20:mnopqrstuvwxyz1234565:token8:aoeusnth6:valuesl6:axje.u6:idhtnmee1:t2:aa1:y1:re").unwrap();
+        let KrpcBody::Response { response, .. } = response.body else { panic!("not a response") };
+        assert_eq!(response.values.len(), 2);
+        assert_eq!(response.token.as_deref(), Some(&b"aoeusnth"[..]));
+
+        let find_node = KrpcMessage::from_bencode(b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz1234564:wantl2:n42:n6ee1:q9:find_node1:t2:aa1:y1:qe").unwrap();
+        let KrpcBody::Query { query: Query::FindNode { want, .. }, .. } = find_node.body else { panic!("not a find_node") };
+        assert_eq!(want, Want { n4: true, n6: true });
+
+        let deep = [&b"d1:a"[..], &[b'l'; 4090]].concat();
+        for packet in [&b"de"[..], b"d1:t2:aa1:y1:qe", b"d1:ad2:id3:abce1:q4:ping1:t2:aa1:y1:qe", b"d1:rd2:id20:abcdefghij01234567895:nodes3:abce1:t2:aa1:y1:re", &deep] {
+            assert!(KrpcMessage::from_bencode(packet).is_err());
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! Mainline DHT (BEP 5): a Kademlia network where peers of a torrent are found by info hash,
+//! without any tracker.
+
+mod node_id;
+pub mod krpc;
+mod routing;
+mod token;
+mod storage;
+mod bloom;
+mod item;
+mod state;
+mod external_ip;
+mod node;
+mod mutable_torrent;
+
+pub use node_id::*;
+pub use routing::*;
+pub use token::*;
+pub use storage::*;
+pub use bloom::*;
+pub use item::*;
+pub use state::*;
+pub use external_ip::*;
+pub use node::*;
+pub use mutable_torrent::*;
+
+use std::{fmt, io};
+
+#[derive(Debug)]
+pub enum DhtError {
+    /// A packet that isn't a valid KRPC message.
+    InvalidMessage(String),
+    /// The queried node answered with a KRPC error.
+    Remote { code: i64, message: String },
+    /// The queried node didn't answer in time.
+    Timeout,
+    /// No node could be reached to start from.
+    NoNodes,
+    Io(io::Error),
+}
+
+impl fmt::Display for DhtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidMessage(reason) => write!(f, "Invalid KRPC message: {reason}"),
+            Self::Remote { code, message } => write!(f, "DHT node error {code}: {message}"),
+            Self::Timeout => write!(f, "DHT query timed out"),
+            Self::NoNodes => write!(f, "No DHT node reachable"),
+            Self::Io(err) => write!(f, "DHT I/O error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for DhtError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DhtError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::TimedOut => Self::Timeout,
+            _ => Self::Io(err),
+        }
+    }
+}
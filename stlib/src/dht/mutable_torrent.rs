@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use serde_bencode::value::Value;
+use tokio::sync::mpsc;
+use tokio::time::interval;
+
+use crate::model::{MagnetLink, Sha1Hash, Torrent};
+use crate::peer::metadata::fetch_metadata;
+use crate::peer::peer::PeerId;
+
+use super::{Dht, Item, ItemError, SigningKey, VerifyingKey};
+
+/// How often the DHT is asked for a newer version of a followed torrent.
+pub const MUTABLE_TORRENT_POLL_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The mutable item publishing version `seq` of the torrent of `key` and `salt`: a dictionary
+/// whose `ih` key is the info hash (BEP 46).
+pub fn torrent_item(key: &SigningKey, salt: Vec<u8>, seq: i64, info_hash: Sha1Hash) -> Result<Item, ItemError> {
+    let value = Value::Dict(HashMap::from([(b"ih".to_vec(), Value::Bytes(info_hash.to_vec()))]));
+    let value = serde_bencode::to_bytes(&value).expect("Torrent item is always serializable");
+    Item::mutable(key, salt, seq, value)
+}
+
+/// The info hash a mutable torrent item points at.
+pub fn torrent_item_info_hash(item: &Item) -> Option<Sha1Hash> {
+    match serde_bencode::from_bytes(item.value()) {
+        Ok(Value::Dict(dict)) => match dict.get(&b"ih"[..]) {
+            Some(Value::Bytes(info_hash)) => info_hash.as_slice().try_into().ok(),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// A version of a followed torrent the follower switched to.
+#[derive(Debug, Clone)]
+pub struct TorrentUpdate {
+    pub torrent: Torrent,
+    /// Files of the previous version the new one can take over, see
+    /// [`Torrent::identical_files`].
+    pub identical_files: Vec<PathBuf>,
+}
+
+/// A torrent followed through the DHT (BEP 46): its publisher signs the info hash of each
+/// new version with the same key.
+#[derive(Debug, Clone)]
+pub struct MutableTorrent {
+    key: VerifyingKey,
+    salt: Vec<u8>,
+    seq: Option<i64>,
+    info_hash: Option<Sha1Hash>,
+    /// Trackers of every version, as the info dictionary doesn't name any.
+    announce_tiers: Vec<Vec<String>>,
+    /// The version switched to last.
+    torrent: Option<Torrent>,
+}
+
+impl MutableTorrent {
+    pub fn new(key: VerifyingKey, salt: Vec<u8>, announce_tiers: Vec<Vec<String>>) -> Self {
+        Self { key, salt, seq: None, info_hash: None, announce_tiers, torrent: None }
+    }
+
+    /// Follows the torrent of a `magnet:?xs=urn:btpk:` link, announcing to its trackers. `None`
+    /// for links that don't name a mutable torrent or whose key isn't a valid public key.
+    pub fn from_magnet(magnet: &MagnetLink) -> Option<Self> {
+        let key = VerifyingKey::from_bytes(magnet.public_key.as_ref()?).ok()?;
+        Some(Self::new(key, magnet.salt.clone(), magnet.announce_tiers()))
+    }
+
+    /// The version switched to last.
+    pub fn torrent(&self) -> Option<&Torrent> {
+        self.torrent.as_ref()
+    }
+
+    /// Info hash of the latest version found.
+    pub fn info_hash(&self) -> Option<Sha1Hash> {
+        self.info_hash
+    }
+
+    pub fn seq(&self) -> Option<i64> {
+        self.seq
+    }
+
+    /// Asks the DHT for a version newer than the latest found. Returns its info hash when there
+    /// is one.
+    pub async fn update(&mut self, dht: &Dht) -> Option<Sha1Hash> {
+        let item = dht.get_mutable(&self.key, &self.salt, self.seq).await?;
+        let info_hash = torrent_item_info_hash(&item)?;
+        self.seq = item.seq();
+        if self.info_hash == Some(info_hash) {
+            return None;
+        }
+        self.info_hash = Some(info_hash);
+        self.info_hash
+    }
+
+    /// Switches to the latest version found unless it's the current one: fetches its info
+    /// dictionary from the peers the DHT knows for it (BEP 9), and lists the files of the
+    /// previous version it takes over. `None` as well while no peer delivered it.
+    pub async fn switch(&mut self, dht: &Dht, peer_id: PeerId) -> Option<TorrentUpdate> {
+        let info_hash = self.info_hash?;
+        if self.torrent.as_ref().is_some_and(|torrent| torrent.info_hash == info_hash) {
+            return None;
+        }
+        let peers = dht.get_peers(info_hash).await;
+        let metadata = fetch_metadata(&peers, info_hash, peer_id, None).await?;
+        let torrent = Torrent::from_info(&metadata, self.announce_tiers.clone()).ok()?;
+        let identical_files = self.torrent.as_ref()
+            .map(|previous| torrent.identical_files(previous))
+            .unwrap_or_default();
+        self.torrent = Some(torrent.clone());
+        Some(TorrentUpdate { torrent, identical_files })
+    }
+
+    /// Switches to the current version, then to every new version found polling every
+    /// `poll_interval`, sending each to `updates` until it is closed. Versions no peer
+    /// delivered yet are tried again on the next poll.
+    pub async fn run(mut self, dht: Arc<Dht>, poll_interval: Duration, peer_id: PeerId, updates: mpsc::Sender<TorrentUpdate>) {
+        let mut ticks = interval(poll_interval);
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => {}
+                _ = updates.closed() => return,
+            }
+            self.update(&dht).await;
+            if let Some(update) = self.switch(&dht, peer_id).await {
+                if updates.send(update).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+    use tokio::net::TcpListener;
+    use super::*;
+    use crate::dht::DhtConfig;
+    use crate::model::SHA1_HASH_LEN;
+    use crate::peer::handshake::{handshake_incoming, PeerCapabilities};
+    use crate::peer::metadata::{exchange_metadata, MetadataDownload};
+
+    async fn node(routers: &[SocketAddr]) -> Arc<Dht> {
+        let config = DhtConfig {
+            bind_addrs: vec![SocketAddr::from((Ipv4Addr::LOCALHOST, 0))],
+            routers: routers.iter().map(|addr| (addr.ip().to_string(), addr.port())).collect(),
+            query_timeout: Duration::from_millis(500),
+            ..DhtConfig::default()
+        };
+        let node = Dht::bind(config).await.unwrap();
+        if !routers.is_empty() {
+            node.bootstrap(&[]).await.unwrap();
+        }
+        Arc::new(node)
+    }
+
+    /// A torrent of 4 byte pieces, hashed as 20 times the given letter.
+    fn torrent(files: &[(&str, u64)], pieces: &str) -> Torrent {
+        let files: String = files.iter().map(|(path, length)| format!("d6:lengthi{length}e4:pathl{}:{path}ee", path.len())).collect();
+        let pieces: String = pieces.chars().map(|piece| piece.to_string().repeat(SHA1_HASH_LEN)).collect();
+        let info = format!("d5:filesl{files}e4:name4:root12:piece lengthi4e6:pieces{}:{pieces}e", pieces.len());
+        Torrent::from_info(info.as_bytes(), vec![]).unwrap()
+    }
+
+    /// Serves the metadata of `torrents` to every peer connecting, returning the port.
+    async fn seed(torrents: &[&Torrent]) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let downloads: HashMap<Sha1Hash, Arc<std::sync::Mutex<MetadataDownload>>> = torrents.iter()
+            .map(|torrent| (torrent.info_hash, MetadataDownload::from_torrent(torrent).into_shared()))
+            .collect();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let downloads = downloads.clone();
+                tokio::spawn(async move {
+                    let capabilities = PeerCapabilities { extension_protocol: true, ..Default::default() };
+                    let Ok(outcome) = handshake_incoming(&mut stream, |info_hash| downloads.contains_key(info_hash), [9; 20], &capabilities).await else {
+                        return;
+                    };
+                    let _ = exchange_metadata(&mut stream, downloads[&outcome.info_hash].clone()).await;
+                });
+            }
+        });
+        port
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_switches_to_new_versions() {
+        let router = node(&[]).await;
+        let router_addr = router.local_addr().unwrap();
+        let (publisher, follower) = (node(&[router_addr]).await, node(&[router_addr]).await);
+        let first = torrent(&[("a", 8), ("b", 4)], "xyz");
+        let second = torrent(&[("a", 8), ("b", 4)], "xyw");
+        let port = seed(&[&first, &second]).await;
+        for version in [&first, &second] {
+            publisher.announce(version.info_hash, Some(port), true).await.unwrap();
+        }
+
+        let key = SigningKey::from_bytes(&[3; 32]);
+        let magnet = format!("magnet:?xs=urn:btpk:{}&s={}&tr=udp%3A%2F%2Ft.example%3A1", hex::encode(key.verifying_key().as_bytes()), hex::encode("nightly"));
+        let followed = MutableTorrent::from_magnet(&MagnetLink::parse(&magnet).unwrap()).unwrap();
+        assert_eq!(followed.info_hash(), None);
+
+        publisher.put(torrent_item(&key, b"nightly".to_vec(), 1, first.info_hash).unwrap(), None).await.unwrap();
+        let (updates_tx, mut updates) = mpsc::channel(1);
+        tokio::spawn(followed.run(follower, Duration::from_millis(100), [1; 20], updates_tx));
+        let update = updates.recv().await.unwrap();
+        assert_eq!(update.torrent.info_hash, first.info_hash);
+        assert_eq!(update.torrent.announce, "udp://t.example:1");
+        assert!(update.identical_files.is_empty());
+
+        publisher.put(torrent_item(&key, b"nightly".to_vec(), 2, second.info_hash).unwrap(), Some(1)).await.unwrap();
+        let update = updates.recv().await.unwrap();
+        assert_eq!(update.torrent.info_hash, second.info_hash);
+        assert_eq!(update.identical_files, vec![PathBuf::from("root/a")]);
+    }
+
+    #[test]
+    fn test_torrent_items() {
+        let item = torrent_item(&SigningKey::from_bytes(&[3; 32]), vec![], 1, [9; 20]).unwrap();
+        assert_eq!(torrent_item_info_hash(&item), Some([9; 20]));
+        assert_eq!(torrent_item_info_hash(&Item::immutable(b"d2:ih1:xe".to_vec()).unwrap()), None);
+        assert!(MutableTorrent::from_magnet(&MagnetLink { info_hash: Some([1; 20]), ..MagnetLink::default() }).is_none());
+    }
+}
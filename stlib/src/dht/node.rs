@@ -0,0 +1,1015 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::sync::oneshot;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{interval, timeout, Instant};
+
+use crate::model::Sha1Hash;
+use crate::util::common::is_local_ip;
+
+use super::krpc::{KrpcBody, KrpcMessage, Query, Response, Want, ERROR_METHOD_UNKNOWN, ERROR_PROTOCOL};
+use super::{mutable_target, BloomFilter, DhtError, DhtState, ExternalIp, Item, ItemStore, NodeId, NodeInfo, PeerStore, RoutingTable, TokenManager, VerifyingKey, K};
+
+/// Well known nodes to join the network through.
+pub const DEFAULT_ROUTERS: [(&str, u16); 3] = [
+    ("router.bittorrent.com", 6881),
+    ("dht.transmissionbt.com", 6881),
+    ("router.utorrent.com", 6881),
+];
+/// Queries of a lookup in flight at once, Kademlia's alpha.
+const ALPHA: usize = 3;
+const MAX_PACKET_SIZE: usize = 4096;
+/// How often questionable nodes are pinged and stale buckets refreshed.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+const VERSION: &[u8] = b"ST\x00\x01";
+/// How long the same sample of info hashes is returned to `sample_infohashes` (BEP 51).
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+/// Info hashes per sample, leaving room for nodes in the packet.
+const MAX_SAMPLES: usize = 20;
+
+#[derive(Debug, Clone)]
+pub struct DhtConfig {
+    /// One node is run per address, with its own routing table (BEP 32, BEP 45). Multi-homed
+    /// hosts should list each of their addresses rather than a wildcard, so that answers leave
+    /// from the address that was queried.
+    pub bind_addrs: Vec<SocketAddr>,
+    /// Nodes to bootstrap from, as host and port.
+    pub routers: Vec<(String, u16)>,
+    pub query_timeout: Duration,
+    /// The id and routing tables of a previous run, see [`Dht::state`].
+    pub state: Option<DhtState>,
+    /// Keeps nodes whose id isn't secure for their address (BEP 42) out of the routing tables
+    /// and lookups, rather than only preferring secure ones.
+    pub enforce_node_ids: bool,
+    /// Runs the nodes read-only (BEP 43): they look up and announce but never answer queries,
+    /// and tell other nodes to leave them out of their routing tables. Suits hosts that can't
+    /// be reached or shouldn't spend bandwidth on the network.
+    pub read_only: bool,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self {
+            bind_addrs: vec![SocketAddr::from(([0, 0, 0, 0], 6881)), SocketAddr::from(([0u16; 8], 6881))],
+            routers: DEFAULT_ROUTERS.iter().map(|(host, port)| (host.to_string(), *port)).collect(),
+            query_timeout: Duration::from_secs(5),
+            state: None,
+            enforce_node_ids: false,
+            read_only: false,
+        }
+    }
+}
+
+type QueryResult = Result<(NodeId, Response), DhtError>;
+
+struct PendingQuery {
+    addr: SocketAddr,
+    sender: oneshot::Sender<QueryResult>,
+}
+
+/// Outcome of an iterative lookup.
+struct Lookup {
+    /// The [`K`] closest nodes that answered, with the token they gave.
+    closest: Vec<(NodeInfo, Option<Vec<u8>>)>,
+    peers: Vec<SocketAddr>,
+    /// Nodes of the other address family heard of, which the lookup's socket can't reach.
+    foreign: Vec<NodeInfo>,
+    /// Responses that carried an item (BEP 44), unverified.
+    items: Vec<Response>,
+    /// Union of the bloom filters of the seeds and peers of a scrape (BEP 33).
+    seeds_filter: BloomFilter,
+    peers_filter: BloomFilter,
+}
+
+/// Size of a swarm as estimated by a DHT scrape (BEP 33).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DhtScrape {
+    pub seeds: usize,
+    pub peers: usize,
+}
+
+/// Answer of a node to `sample_infohashes` (BEP 51).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InfohashSample {
+    /// How long before the node returns another sample.
+    pub interval: Duration,
+    /// Number of info hashes the node stores peers of.
+    pub num: usize,
+    pub samples: Vec<Sha1Hash>,
+    /// Nodes close to the target, to crawl next.
+    pub nodes: Vec<NodeInfo>,
+}
+
+/// A DHT node per bound address: each answers other nodes' queries in the background, and
+/// lookups run over every address family.
+pub struct Dht {
+    nodes: Vec<Arc<Inner>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+/// What the nodes of a [`Dht`] share: queries over one family may ask for nodes of the other
+/// (BEP 32), and announced peers and put items are stored once.
+struct Shared {
+    /// Ids of the nodes, in their order.
+    ids: Mutex<Vec<NodeId>>,
+    tables: Vec<(bool, Arc<Mutex<RoutingTable>>)>,
+    peers: Mutex<PeerStore>,
+    items: Mutex<ItemStore>,
+    /// The sample of info hashes currently returned, and when it was taken.
+    samples: Mutex<Option<(Instant, Vec<Sha1Hash>)>>,
+}
+
+struct Inner {
+    socket: UdpSocket,
+    local_addr: SocketAddr,
+    index: usize,
+    /// Changes to a secure id once our external address is known.
+    id: Mutex<NodeId>,
+    external_ip: Mutex<ExternalIp>,
+    config: DhtConfig,
+    routing: Arc<Mutex<RoutingTable>>,
+    shared: Arc<Shared>,
+    tokens: Mutex<TokenManager>,
+    pending: Mutex<HashMap<Vec<u8>, PendingQuery>>,
+    next_transaction: AtomicU16,
+}
+
+/// IPv6 sockets are IPv6 only, so that wildcard sockets of both families can share a port.
+fn bind_socket(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+impl Dht {
+    /// Addresses that can't be bound, e.g. IPv6 ones on a host without IPv6, are skipped as long
+    /// as one of them can.
+    pub async fn bind(config: DhtConfig) -> io::Result<Self> {
+        let mut sockets = vec![];
+        let mut first_error = None;
+        for addr in &config.bind_addrs {
+            match bind_socket(*addr).and_then(|socket| Ok((socket.local_addr()?, socket))) {
+                Ok(socket) => sockets.push(socket),
+                Err(err) => first_error = first_error.or(Some(err)),
+            }
+        }
+        if sockets.is_empty() {
+            return Err(first_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No address to bind")));
+        }
+
+        // One id serves both families (BEP 32), but further addresses of a family get their own
+        // since other nodes know each address as a distinct node (BEP 45). Addresses on the
+        // internet are external ones, that ids must be secure for (BEP 42).
+        let primary_id = config.state.as_ref().map_or_else(NodeId::random, |state| state.id);
+        let mut families = vec![];
+        let ids: Vec<NodeId> = sockets.iter()
+            .map(|(local_addr, _)| {
+                let first_of_family = !families.contains(&local_addr.is_ipv4());
+                families.push(local_addr.is_ipv4());
+                let id = if first_of_family { primary_id } else { NodeId::random() };
+                if id.is_secure_for(local_addr.ip()) { id } else { NodeId::secure(local_addr.ip()) }
+            })
+            .collect();
+        let now = Instant::now();
+        let tables: Vec<_> = sockets.iter().zip(&ids)
+            .map(|((local_addr, _), id)| (local_addr.is_ipv4(), Arc::new(Mutex::new(RoutingTable::new(*id, now)))))
+            .collect();
+        let shared = Arc::new(Shared { ids: Mutex::new(ids.clone()), tables: tables.clone(),
+            peers: Mutex::new(PeerStore::default()),
+            items: Mutex::new(ItemStore::default()),
+            samples: Mutex::new(None),
+        });
+
+        let mut nodes = vec![];
+        let mut tasks = vec![];
+        for (index, (((local_addr, socket), id), (_, routing))) in sockets.into_iter().zip(ids).zip(tables).enumerate() {
+            let inner = Arc::new(Inner {
+                socket,
+                local_addr,
+                index,
+                id: Mutex::new(id),
+                external_ip: Mutex::new(ExternalIp::default()),
+                config: config.clone(),
+                routing,
+                shared: shared.clone(),
+                tokens: Mutex::new(TokenManager::new(now)),
+                pending: Mutex::new(HashMap::new()),
+                next_transaction: AtomicU16::new(0),
+            });
+            tasks.push(tokio::spawn({
+                let inner = inner.clone();
+                async move {
+                    tokio::join!(inner.clone().serve(), inner.maintain());
+                }
+            }));
+            nodes.push(inner);
+        }
+        Ok(Self { nodes, tasks })
+    }
+
+    /// Id of the first node, shared by the first node of the other family.
+    pub fn id(&self) -> NodeId {
+        self.nodes[0].id()
+    }
+
+    pub fn ids(&self) -> Vec<NodeId> {
+        self.nodes.iter().map(|node| node.id()).collect()
+    }
+
+    /// Our external address as learned from trackers or a peer's `yourip`, which nodes of its
+    /// family behind it take a secure id for (BEP 42). Nodes also learn it from the `ip` key of
+    /// responses.
+    pub fn set_external_ip(&self, ip: IpAddr) {
+        for node in &self.nodes {
+            node.external_ip.lock().unwrap().set(ip);
+            node.use_external_ip(ip);
+        }
+    }
+
+    /// External addresses of the nodes, in their order.
+    pub fn external_ips(&self) -> Vec<Option<IpAddr>> {
+        self.nodes.iter().map(|node| node.external_ip.lock().unwrap().get()).collect()
+    }
+
+    /// Address of the first node.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.nodes[0].local_addr)
+    }
+
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.nodes.iter().map(|node| node.local_addr).collect()
+    }
+
+    /// The nodes of every routing table.
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.nodes.iter().flat_map(|node| node.routing.lock().unwrap().nodes()).collect()
+    }
+
+    /// What to pass as [`DhtConfig::state`] on the next run.
+    pub fn state(&self) -> DhtState {
+        DhtState { id: self.id(), nodes: self.nodes() }
+    }
+
+    pub async fn ping(&self, addr: SocketAddr) -> Result<NodeId, DhtError> {
+        self.node_for(&addr).query(addr, Query::Ping).await.map(|(id, _)| id)
+    }
+
+    /// Joins the network through the configured routers, the nodes of the restored state and
+    /// `nodes`, e.g. the `nodes` key of a trackerless torrent. IPv4 nodes bootstrap first and
+    /// ask for IPv6 nodes as well, which seeds the IPv6 routing table when only IPv4 routers
+    /// answer. Returns the total size of the routing tables.
+    pub async fn bootstrap(&self, nodes: &[(String, u16)]) -> Result<usize, DhtError> {
+        let mut routers = vec![];
+        for (host, port) in self.nodes[0].config.routers.iter().chain(nodes) {
+            if let Ok(resolved) = lookup_host((host.as_str(), *port)).await {
+                routers.extend(resolved);
+            }
+        }
+        let want = Want {
+            n4: self.nodes.iter().any(|node| node.is_ipv4()),
+            n6: self.nodes.iter().any(|node| !node.is_ipv4()),
+        };
+        let mut seeds = self.nodes[0].config.state.as_ref().map_or_else(Vec::new, |state| state.nodes.clone());
+        let ipv4_first = self.nodes.iter().filter(|node| node.is_ipv4()).chain(self.nodes.iter().filter(|node| !node.is_ipv4()));
+        for node in ipv4_first {
+            let foreign = node.bootstrap(&routers, seeds.clone(), want).await;
+            seeds.extend(foreign);
+        }
+        match self.nodes.iter().map(|node| node.routing.lock().unwrap().len()).sum() {
+            0 => Err(DhtError::NoNodes),
+            len => Ok(len),
+        }
+    }
+
+    /// The [`K`] closest nodes to `target` that answered, for each node, closest first.
+    pub async fn find_node(&self, target: NodeId) -> Vec<NodeInfo> {
+        let lookups = self.lookups(|_| Query::FindNode { target, want: Want::default() }, target).await;
+        let mut nodes: Vec<NodeInfo> = lookups.into_iter()
+            .flat_map(|lookup| lookup.closest.into_iter().map(|(node, _)| node))
+            .collect();
+        nodes.sort_by_key(|node| node.id.distance(&target));
+        nodes
+    }
+
+    /// Peers of the torrent of both families.
+    pub async fn get_peers(&self, info_hash: Sha1Hash) -> Vec<SocketAddr> {
+        let lookups = self.lookups(|_| Query::GetPeers { info_hash, want: Want::default(), scrape: false }, NodeId(info_hash)).await;
+        merge_peers(&lookups)
+    }
+
+    /// Estimates the number of seeds and other peers of the torrent from the bloom filters of
+    /// the nodes closest to it (BEP 33).
+    pub async fn scrape(&self, info_hash: Sha1Hash) -> DhtScrape {
+        let lookups = self.lookups(|_| Query::GetPeers { info_hash, want: Want::default(), scrape: true }, NodeId(info_hash)).await;
+        let (mut seeds, mut peers) = (BloomFilter::default(), BloomFilter::default());
+        for lookup in &lookups {
+            seeds.union(&lookup.seeds_filter);
+            peers.union(&lookup.peers_filter);
+        }
+        DhtScrape { seeds: seeds.estimate().round() as usize, peers: peers.estimate().round() as usize }
+    }
+
+    /// Looks up the peers of the torrent and announces us to the closest nodes of each family,
+    /// on `port` or on the port of the announcing node's socket when `None`, and as a seed when
+    /// `seed` is set (BEP 33). Returns the peers found.
+    pub async fn announce(&self, info_hash: Sha1Hash, port: Option<u16>, seed: bool) -> Result<Vec<SocketAddr>, DhtError> {
+        let lookups = self.lookups(|_| Query::GetPeers { info_hash, want: Want::default(), scrape: false }, NodeId(info_hash)).await;
+        let peers = merge_peers(&lookups);
+        self.store(lookups, |token| Query::AnnouncePeer {
+            info_hash,
+            port: port.unwrap_or(0),
+            implied_port: port.is_none(),
+            token,
+            seed,
+        }).await?;
+        Ok(peers)
+    }
+
+    /// Asks the node at `addr` for a sample of the info hashes it stores peers of (BEP 51),
+    /// and for its nodes closest to `target`.
+    pub async fn sample_infohashes(&self, addr: SocketAddr, target: NodeId) -> Result<InfohashSample, DhtError> {
+        let (_, response) = self.node_for(&addr).query(addr, Query::SampleInfohashes { target, want: Want::default() }).await?;
+        Ok(InfohashSample {
+            interval: Duration::from_secs(response.interval.unwrap_or(0).max(0) as u64),
+            num: response.num.unwrap_or(0).max(0) as usize,
+            samples: response.samples,
+            nodes: response.nodes,
+        })
+    }
+
+    /// The immutable item whose value hashes to `target` (BEP 44).
+    pub async fn get_immutable(&self, target: Sha1Hash) -> Option<Item> {
+        let lookups = self.lookups(|_| Query::Get { target, seq: None }, NodeId(target)).await;
+        lookups.into_iter()
+            .flat_map(|lookup| lookup.items)
+            .filter_map(|response| Some(Item::Immutable { value: response.value? }))
+            .find(|item| item.target() == target && item.validate().is_ok())
+    }
+
+    /// The latest version of the mutable item of `key` and `salt` (BEP 44), provided it is newer
+    /// than `seq`.
+    pub async fn get_mutable(&self, key: &VerifyingKey, salt: &[u8], seq: Option<i64>) -> Option<Item> {
+        let key = key.to_bytes();
+        let target = mutable_target(&key, salt);
+        let lookups = self.lookups(|_| Query::Get { target, seq }, NodeId(target)).await;
+        lookups.into_iter()
+            .flat_map(|lookup| lookup.items)
+            .filter(|response| response.key == Some(key))
+            .filter_map(|response| Some(Item::Mutable {
+                value: response.value?,
+                key,
+                salt: salt.to_vec(),
+                seq: response.seq?,
+                signature: response.signature?,
+            }))
+            .filter(|item| item.validate().is_ok() && item.seq() > seq)
+            .max_by_key(Item::seq)
+    }
+
+    /// Stores `item` on the nodes closest to it (BEP 44) and returns the target it is stored
+    /// under. With `cas`, a mutable item only replaces version `cas`, and nodes that store
+    /// another answer with error 301.
+    pub async fn put(&self, item: Item, cas: Option<i64>) -> Result<Sha1Hash, DhtError> {
+        let target = item.target();
+        let lookups = self.lookups(|_| Query::Get { target, seq: None }, NodeId(target)).await;
+        self.store(lookups, |token| Query::Put { token, item: item.clone(), cas }).await?;
+        Ok(target)
+    }
+
+    /// Sends the query made from their token to the closest nodes of the lookups. Fails with the
+    /// first error when no node accepted it.
+    async fn store(&self, lookups: Vec<Lookup>, query: impl Fn(Vec<u8>) -> Query) -> Result<(), DhtError> {
+        let mut queries = JoinSet::new();
+        for (inner, lookup) in self.nodes.iter().zip(lookups) {
+            for (node, token) in lookup.closest {
+                let Some(token) = token else { continue };
+                let (inner, query) = (inner.clone(), query(token));
+                queries.spawn(async move { inner.query_node(&node, query).await });
+            }
+        }
+        let mut error = None;
+        while let Some(result) = queries.join_next().await {
+            match result {
+                Ok(Ok(_)) => error = Some(Ok(())),
+                Ok(Err(err)) if error.is_none() => error = Some(Err(err)),
+                _ => {}
+            }
+        }
+        error.unwrap_or(Err(DhtError::NoNodes))
+    }
+
+    /// Crawls the network for info hashes (BEP 51): asks up to `max_nodes` nodes, starting from
+    /// the routing tables, for samples and for their nodes close to random targets.
+    pub async fn crawl(&self, max_nodes: usize) -> HashSet<Sha1Hash> {
+        let ids = self.ids();
+        let mut pending = self.nodes();
+        let mut queried = HashSet::new();
+        let mut info_hashes = HashSet::new();
+        let mut queries = JoinSet::new();
+        loop {
+            while queries.len() < ALPHA && queried.len() < max_nodes {
+                let Some(node) = pending.pop() else { break };
+                let Some(inner) = self.nodes.iter().find(|inner| inner.is_ipv4() == node.addr.is_ipv4()) else { continue };
+                if ids.contains(&node.id) || !queried.insert(node.addr) {
+                    continue;
+                }
+                let inner = inner.clone();
+                let query = Query::SampleInfohashes { target: NodeId::random(), want: Want::default() };
+                queries.spawn(async move { inner.query(node.addr, query).await });
+            }
+            let Some(result) = queries.join_next().await else { break };
+            let Ok(Ok((_, response))) = result else { continue };
+            info_hashes.extend(response.samples);
+            pending.extend(response.nodes);
+        }
+        info_hashes
+    }
+
+    fn node_for(&self, addr: &SocketAddr) -> &Arc<Inner> {
+        self.nodes.iter().find(|node| node.is_ipv4() == addr.is_ipv4()).unwrap_or(&self.nodes[0])
+    }
+
+    /// Runs the same lookup from every node at once; results are in the order of the nodes.
+    async fn lookups(&self, query: impl Fn(&Inner) -> Query, target: NodeId) -> Vec<Lookup> {
+        let mut lookups = JoinSet::new();
+        for (index, node) in self.nodes.iter().enumerate() {
+            let (node, query) = (node.clone(), query(node));
+            lookups.spawn(async move { (index, node.lookup(target, query, vec![]).await) });
+        }
+        let mut results: Vec<(usize, Lookup)> = vec![];
+        while let Some(result) = lookups.join_next().await {
+            results.extend(result.ok());
+        }
+        results.sort_by_key(|(index, _)| *index);
+        results.into_iter().map(|(_, lookup)| lookup).collect()
+    }
+}
+
+fn merge_peers(lookups: &[Lookup]) -> Vec<SocketAddr> {
+    let mut peers = vec![];
+    for peer in lookups.iter().flat_map(|lookup| &lookup.peers) {
+        if !peers.contains(peer) {
+            peers.push(*peer);
+        }
+    }
+    peers
+}
+
+impl Drop for Dht {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+impl Inner {
+    fn is_ipv4(&self) -> bool {
+        self.local_addr.is_ipv4()
+    }
+
+    fn id(&self) -> NodeId {
+        *self.id.lock().unwrap()
+    }
+
+    /// Takes a secure id for our external address `ip`, unless ours already is or `ip` isn't
+    /// what this node is reached at.
+    fn use_external_ip(&self, ip: IpAddr) {
+        let bound_ip = self.local_addr.ip();
+        if ip.is_ipv4() != self.is_ipv4() || (!is_local_ip(bound_ip) && bound_ip != ip) || self.id().is_secure_for(ip) {
+            return;
+        }
+        let id = NodeId::secure(ip);
+        *self.id.lock().unwrap() = id;
+        self.shared.ids.lock().unwrap()[self.index] = id;
+        self.routing.lock().unwrap().set_id(id, Instant::now());
+    }
+
+    /// Whether a node may be used, see [`DhtConfig::enforce_node_ids`].
+    fn is_allowed(&self, node: &NodeInfo) -> bool {
+        !self.config.enforce_node_ids || node.id.is_secure_for(node.addr.ip())
+    }
+
+    async fn serve(self: Arc<Self>) {
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        loop {
+            // Errors reported for earlier packets, e.g. ICMP port unreachable, concern a single node.
+            let Ok((n, from)) = self.socket.recv_from(&mut buf).await else { continue };
+            let Ok(message) = KrpcMessage::from_bencode(&buf[..n]) else { continue };
+            match message.body {
+                KrpcBody::Query { .. } if self.config.read_only => {}
+                KrpcBody::Query { id, query } => {
+                    let reply = KrpcMessage {
+                        transaction_id: message.transaction_id,
+                        version: Some(VERSION.to_vec()),
+                        ip: Some(from),
+                        read_only: false,
+                        body: self.answer(id, query, from, message.read_only),
+                    };
+                    let _ = self.socket.send_to(&reply.to_bencode(), from).await;
+                }
+                KrpcBody::Response { id, response } => {
+                    self.complete(&message.transaction_id, from, message.ip, Ok((id, response)));
+                }
+                KrpcBody::Error { code, message: reason } => {
+                    self.complete(&message.transaction_id, from, message.ip, Err(DhtError::Remote { code, message: reason }));
+                }
+            }
+        }
+    }
+
+    async fn maintain(self: Arc<Self>) {
+        let mut ticks = interval(MAINTENANCE_INTERVAL);
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            let now = Instant::now();
+            self.shared.peers.lock().unwrap().expire(now);
+            self.shared.items.lock().unwrap().expire(now);
+            let (questionable, targets) = {
+                let routing = self.routing.lock().unwrap();
+                (routing.questionable(now), routing.refresh_targets(now))
+            };
+            for node in questionable {
+                let _ = self.query_node(&node, Query::Ping).await;
+            }
+            for target in targets {
+                self.lookup(target, Query::FindNode { target, want: Want::default() }, vec![]).await;
+            }
+        }
+    }
+
+    /// Records a node we heard from, unless it is one of our own.
+    fn seen(&self, node: NodeInfo, now: Instant) {
+        if !self.shared.ids.lock().unwrap().contains(&node.id) && self.is_allowed(&node) {
+            self.routing.lock().unwrap().insert(node, now);
+        }
+    }
+
+    /// Our closest nodes to `target` of the families in `want`, those of our own family when
+    /// `want` is empty.
+    fn closest_nodes(&self, target: &NodeId, want: Want) -> Vec<NodeInfo> {
+        let (n4, n6) = if want.is_empty() { (self.is_ipv4(), !self.is_ipv4()) } else { (want.n4, want.n6) };
+        let mut nodes = vec![];
+        for ipv4 in [true, false] {
+            if (ipv4 && !n4) || (!ipv4 && !n6) {
+                continue;
+            }
+            let table = if ipv4 == self.is_ipv4() {
+                Some(&self.routing)
+            } else {
+                self.shared.tables.iter().find(|(table_ipv4, _)| *table_ipv4 == ipv4).map(|(_, table)| table)
+            };
+            if let Some(table) = table {
+                nodes.extend(table.lock().unwrap().closest(target, K));
+            }
+        }
+        nodes
+    }
+
+    /// Answers a query of `from`, which goes in the routing table unless it is `read_only`.
+    fn answer(&self, id: NodeId, query: Query, from: SocketAddr, read_only: bool) -> KrpcBody {
+        let now = Instant::now();
+        if !read_only && !matches!(query, Query::Unknown(_)) {
+            self.seen(NodeInfo { id, addr: from }, now);
+        }
+        let mut response = Response::default();
+        match query {
+            Query::Ping => {}
+            Query::FindNode { target, want } => {
+                response.nodes = self.closest_nodes(&target, want);
+            }
+            Query::GetPeers { info_hash, want, scrape } => {
+                response.token = Some(self.tokens.lock().unwrap().token(from.ip(), now));
+                let peers = self.shared.peers.lock().unwrap();
+                response.values = peers.peers(&info_hash, self.is_ipv4(), now);
+                if scrape {
+                    let (seeds_filter, peers_filter) = peers.scrape(&info_hash, now);
+                    response.seeds_filter = Some(seeds_filter);
+                    response.peers_filter = Some(peers_filter);
+                }
+                drop(peers);
+                response.nodes = self.closest_nodes(&NodeId(info_hash), want);
+            }
+            Query::AnnouncePeer { info_hash, port, implied_port, token, seed } => {
+                if !self.tokens.lock().unwrap().is_valid(&token, from.ip(), now) {
+                    return KrpcBody::Error { code: ERROR_PROTOCOL, message: String::from("Bad token") };
+                }
+                let port = if implied_port { from.port() } else { port };
+                self.shared.peers.lock().unwrap().announce(info_hash, SocketAddr::new(from.ip(), port), seed, now);
+            }
+            Query::SampleInfohashes { target, want } => {
+                let peers = self.shared.peers.lock().unwrap();
+                let mut samples = self.shared.samples.lock().unwrap();
+                let (taken_at, sample) = match &*samples {
+                    Some((taken_at, sample)) if now.duration_since(*taken_at) < SAMPLE_INTERVAL => (*taken_at, sample.clone()),
+                    _ => samples.insert((now, peers.sample(MAX_SAMPLES))).clone(),
+                };
+                response.interval = Some((SAMPLE_INTERVAL - now.duration_since(taken_at)).as_secs() as i64);
+                response.num = Some(peers.num_torrents() as i64);
+                response.samples = sample;
+                drop((peers, samples));
+                response.nodes = self.closest_nodes(&target, want);
+            }
+            Query::Get { target, seq } => {
+                response.token = Some(self.tokens.lock().unwrap().token(from.ip(), now));
+                response.nodes = self.closest_nodes(&NodeId(target), Want::default());
+                if let Some(item) = self.shared.items.lock().unwrap().get(&target, now) {
+                    if let Item::Mutable { key, seq: stored, signature, .. } = item {
+                        response.key = Some(*key);
+                        response.seq = Some(*stored);
+                        // The asking node already has this version.
+                        if seq.is_some_and(|seq| seq >= *stored) {
+                            return KrpcBody::Response { id: self.id(), response };
+                        }
+                        response.signature = Some(*signature);
+                    }
+                    response.value = Some(item.value().to_vec());
+                }
+            }
+            Query::Put { token, item, cas } => {
+                if !self.tokens.lock().unwrap().is_valid(&token, from.ip(), now) {
+                    return KrpcBody::Error { code: ERROR_PROTOCOL, message: String::from("Bad token") };
+                }
+                if let Err(err) = self.shared.items.lock().unwrap().put(item, cas, now) {
+                    return KrpcBody::Error { code: err.code(), message: err.to_string() };
+                }
+            }
+            Query::Unknown(method) => {
+                return KrpcBody::Error { code: ERROR_METHOD_UNKNOWN, message: format!("Method Unknown: {method}") };
+            }
+        }
+        KrpcBody::Response { id: self.id(), response }
+    }
+
+    /// Hands a response over to the query waiting for it, provided it comes from the queried
+    /// address, and counts the address it says we have.
+    fn complete(&self, transaction_id: &[u8], from: SocketAddr, ip: Option<SocketAddr>, result: QueryResult) {
+        let pending = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.get(transaction_id) {
+                Some(query) if query.addr == from => pending.remove(transaction_id),
+                _ => None,
+            }
+        };
+        let Some(pending) = pending else { return };
+        let external_ip = ip.and_then(|ip| self.external_ip.lock().unwrap().vote(from.ip(), ip.ip()));
+        if let Some(ip) = external_ip {
+            self.use_external_ip(ip);
+        }
+        if let Ok((id, _)) = &result {
+            self.seen(NodeInfo { id: *id, addr: from }, Instant::now());
+        }
+        let _ = pending.sender.send(result);
+    }
+
+    async fn query(&self, addr: SocketAddr, query: Query) -> QueryResult {
+        let transaction_id = self.next_transaction.fetch_add(1, Ordering::Relaxed).to_be_bytes().to_vec();
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert(transaction_id.clone(), PendingQuery { addr, sender });
+        let message = KrpcMessage {
+            transaction_id: transaction_id.clone(),
+            version: Some(VERSION.to_vec()),
+            ip: None,
+            read_only: self.config.read_only,
+            body: KrpcBody::Query { id: self.id(), query },
+        };
+        let result = match self.socket.send_to(&message.to_bencode(), addr).await {
+            Ok(_) => match timeout(self.config.query_timeout, receiver).await {
+                Ok(Ok(result)) => result,
+                _ => Err(DhtError::Timeout),
+            },
+            Err(err) => Err(err.into()),
+        };
+        self.pending.lock().unwrap().remove(&transaction_id);
+        result
+    }
+
+    /// A query to a node of the routing table, whose failures count against it.
+    async fn query_node(&self, node: &NodeInfo, query: Query) -> QueryResult {
+        let result = self.query(node.addr, query).await;
+        if matches!(result, Err(DhtError::Timeout)) {
+            self.routing.lock().unwrap().failed(&node.id);
+        }
+        result
+    }
+
+    /// Finds nodes from `routers` and the `seeds` to fill the routing table with. Returns the
+    /// nodes of the other family heard of.
+    async fn bootstrap(self: &Arc<Self>, routers: &[SocketAddr], mut seeds: Vec<NodeInfo>, want: Want) -> Vec<NodeInfo> {
+        let target = self.id();
+        let mut queries = JoinSet::new();
+        for addr in routers.iter().filter(|addr| addr.is_ipv4() == self.is_ipv4()).copied() {
+            let inner = self.clone();
+            queries.spawn(async move { inner.query(addr, Query::FindNode { target, want }).await });
+        }
+        while let Some(result) = queries.join_next().await {
+            if let Ok(Ok((_, response))) = result {
+                seeds.extend(response.nodes);
+            }
+        }
+        self.lookup(target, Query::FindNode { target, want }, seeds).await.foreign
+    }
+
+    /// Iterative lookup: queries the closest known nodes to `target`, [`ALPHA`] at a time, until
+    /// the [`K`] closest nodes heard of all answered or failed.
+    async fn lookup(self: &Arc<Self>, target: NodeId, query: Query, seeds: Vec<NodeInfo>) -> Lookup {
+        let mut candidates = self.routing.lock().unwrap().closest(&target, K);
+        candidates.extend(seeds);
+        let mut queried = HashSet::new();
+        let mut closest: Vec<(NodeInfo, Option<Vec<u8>>)> = vec![];
+        let mut peers = vec![];
+        let mut foreign = vec![];
+        let mut items = vec![];
+        let (mut seeds_filter, mut peers_filter) = (BloomFilter::default(), BloomFilter::default());
+        let mut queries = JoinSet::new();
+        loop {
+            let ids = self.shared.ids.lock().unwrap().clone();
+            candidates.retain(|node| {
+                if node.addr.is_ipv4() != self.is_ipv4() {
+                    if !foreign.contains(node) {
+                        foreign.push(*node);
+                    }
+                    return false;
+                }
+                !ids.contains(&node.id) && !queried.contains(&node.id) && self.is_allowed(node)
+            });
+            candidates.sort_unstable_by_key(|node| node.id.distance(&target));
+            candidates.dedup_by_key(|node| node.id);
+            let farthest = (closest.len() >= K).then(|| closest[K - 1].0.id.distance(&target));
+            let mut pending = candidates.iter()
+                .filter(|node| farthest.is_none_or(|farthest| node.id.distance(&target) < farthest));
+            while queries.len() < ALPHA {
+                let Some(node) = pending.next().copied() else { break };
+                queried.insert(node.id);
+                let (inner, query) = (self.clone(), query.clone());
+                queries.spawn(async move { (node, inner.query_node(&node, query).await) });
+            }
+            let Some(result) = queries.join_next().await else { break };
+            let Ok((node, Ok((_, mut response)))) = result else { continue };
+            candidates.append(&mut response.nodes);
+            for peer in response.values.drain(..) {
+                if !peers.contains(&peer) {
+                    peers.push(peer);
+                }
+            }
+            closest.push((node, response.token.take()));
+            if let (Some(seeds), Some(peers)) = (response.seeds_filter.take(), response.peers_filter.take()) {
+                seeds_filter.union(&seeds);
+                peers_filter.union(&peers);
+            }
+            if response.value.is_some() {
+                items.push(response);
+            }
+            closest.sort_unstable_by_key(|(node, _)| node.id.distance(&target));
+            closest.truncate(K);
+        }
+        Lookup { closest, peers, foreign, items, seeds_filter, peers_filter }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use crate::dht::{SigningKey, ERROR_CAS_MISMATCH, ERROR_SEQ_TOO_LOW};
+    use super::*;
+
+    fn config(routers: &[SocketAddr]) -> DhtConfig {
+        config_on(&[IpAddr::V4(Ipv4Addr::LOCALHOST)], routers)
+    }
+
+    fn config_on(ips: &[IpAddr], routers: &[SocketAddr]) -> DhtConfig {
+        DhtConfig {
+            bind_addrs: ips.iter().map(|ip| SocketAddr::new(*ip, 0)).collect(),
+            routers: routers.iter().map(|addr| (addr.ip().to_string(), addr.port())).collect(),
+            query_timeout: Duration::from_millis(500),
+            state: None,
+            enforce_node_ids: false,
+            read_only: false,
+        }
+    }
+
+    async fn swarm_on(ips: &[IpAddr], size: usize) -> Vec<Dht> {
+        let first = Dht::bind(config_on(ips, &[])).await.unwrap();
+        let router = first.local_addr().unwrap();
+        // Only the first joiner is told the router's other addresses; later ones rely on `want`.
+        let others: Vec<(String, u16)> = first.local_addrs()[1..].iter()
+            .map(|addr| (addr.ip().to_string(), addr.port()))
+            .collect();
+        let mut nodes = vec![first];
+        for i in 1..size {
+            let node = Dht::bind(config_on(ips, &[router])).await.unwrap();
+            node.bootstrap(if i == 1 { &others } else { &[] }).await.unwrap();
+            nodes.push(node);
+        }
+        // Early joiners only met the router; a second round lets them see the whole swarm.
+        for node in &nodes[1..] {
+            node.bootstrap(&[]).await.unwrap();
+        }
+        nodes
+    }
+
+    async fn swarm(size: usize) -> Vec<Dht> {
+        swarm_on(&[IpAddr::V4(Ipv4Addr::LOCALHOST)], size).await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_swarm_finds_announced_peers() {
+        let nodes = swarm(20).await;
+        let info_hash = [0x5a; 20];
+        assert!(nodes[3].announce(info_hash, Some(4000), false).await.unwrap().is_empty());
+        nodes[8].announce(info_hash, None, false).await.unwrap();
+
+        let peers = nodes[17].get_peers(info_hash).await;
+        assert!(peers.contains(&SocketAddr::from((Ipv4Addr::LOCALHOST, 4000))), "{peers:?}");
+        assert!(peers.contains(&nodes[8].local_addr().unwrap()), "{peers:?}");
+        assert!(nodes[12].get_peers([0xa5; 20]).await.is_empty());
+
+        // Lookups converge on the nodes actually closest to the target.
+        let target = nodes[5].id();
+        let found = nodes[14].find_node(target).await;
+        assert_eq!(found.first().map(|node| node.id), Some(target));
+        let mut expected: Vec<NodeId> = nodes.iter().map(Dht::id).filter(|id| *id != nodes[14].id()).collect();
+        expected.sort_unstable_by_key(|id| id.distance(&target));
+        assert_eq!(found.iter().map(|node| node.id).collect::<Vec<_>>(), expected[..K]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_queries_and_errors() {
+        let node = Dht::bind(config(&[])).await.unwrap();
+        let other = Dht::bind(config(&[])).await.unwrap();
+        let addr = node.local_addr().unwrap();
+        assert_eq!(other.ping(addr).await.unwrap(), node.id());
+        assert_eq!(node.nodes(), vec![NodeInfo { id: other.id(), addr: other.local_addr().unwrap() }]);
+        assert_eq!(other.nodes(), vec![NodeInfo { id: node.id(), addr }]);
+
+        let announce = Query::AnnouncePeer { info_hash: [1; 20], port: 1, implied_port: false, token: b"forged".to_vec(), seed: false };
+        assert!(matches!(other.nodes[0].query(addr, announce).await, Err(DhtError::Remote { code: ERROR_PROTOCOL, .. })));
+        let unknown = Query::Unknown(String::from("vote"));
+        assert!(matches!(other.nodes[0].query(addr, unknown).await, Err(DhtError::Remote { code: ERROR_METHOD_UNKNOWN, .. })));
+
+        drop(node);
+        assert!(matches!(other.ping(addr).await, Err(DhtError::Timeout)));
+        assert!(matches!(Dht::bind(config(&[])).await.unwrap().bootstrap(&[]).await, Err(DhtError::NoNodes)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_bootstraps_from_restored_state_and_torrent_nodes() {
+        let nodes = swarm(6).await;
+        let state = nodes[2].state();
+        assert!(!state.nodes.is_empty());
+        let restored = Dht::bind(DhtConfig { state: Some(state.clone()), ..config(&[]) }).await.unwrap();
+        assert_eq!(restored.id(), state.id);
+        assert!(restored.bootstrap(&[]).await.unwrap() >= state.nodes.len());
+
+        let router = nodes[0].local_addr().unwrap();
+        let joined = Dht::bind(config(&[])).await.unwrap();
+        assert!(joined.bootstrap(&[(router.ip().to_string(), router.port())]).await.unwrap() > 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_puts_and_gets_items() {
+        let nodes = swarm(10).await;
+        let immutable = Item::immutable(b"d4:name5:build7:versioni42ee".to_vec()).unwrap();
+        let target = nodes[2].put(immutable.clone(), None).await.unwrap();
+        assert_eq!(nodes[7].get_immutable(target).await, Some(immutable));
+        assert_eq!(nodes[7].get_immutable([0x42; 20]).await, None);
+
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let version = |seq: i64| Item::mutable(&key, b"latest".to_vec(), seq, format!("i{seq}e").into_bytes()).unwrap();
+        nodes[3].put(version(1), None).await.unwrap();
+        nodes[3].put(version(2), Some(1)).await.unwrap();
+        let public = key.verifying_key();
+        assert_eq!(nodes[8].get_mutable(&public, b"latest", None).await, Some(version(2)));
+        assert_eq!(nodes[8].get_mutable(&public, b"latest", Some(2)).await, None);
+        assert_eq!(nodes[8].get_mutable(&public, b"other", None).await, None);
+
+        // The nodes that store version 2 refuse older ones and other bases for a swap.
+        let outdated = nodes[3].put(version(1), None).await;
+        assert!(matches!(outdated, Err(DhtError::Remote { code: ERROR_SEQ_TOO_LOW, .. })), "{outdated:?}");
+        let conflict = nodes[3].put(version(3), Some(1)).await;
+        assert!(matches!(conflict, Err(DhtError::Remote { code: ERROR_CAS_MISMATCH, .. })), "{conflict:?}");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_scrapes_swarm_size() {
+        let nodes = swarm(8).await;
+        let router = nodes[0].local_addr().unwrap();
+        let info_hash = [0x33; 20];
+        // Peers are counted by address, so each announces from its own.
+        let mut peers = vec![];
+        for i in 0..5 {
+            let peer = Dht::bind(config_on(&[IpAddr::V4(Ipv4Addr::new(127, 0, 0, 10 + i))], &[router])).await.unwrap();
+            peer.bootstrap(&[]).await.unwrap();
+            peer.announce(info_hash, None, i < 2).await.unwrap();
+            peers.push(peer);
+        }
+        assert_eq!(nodes[5].scrape(info_hash).await, DhtScrape { seeds: 2, peers: 3 });
+        assert_eq!(nodes[5].scrape([0x44; 20]).await, DhtScrape { seeds: 0, peers: 0 });
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_samples_and_crawls_info_hashes() {
+        let nodes = swarm(8).await;
+        let info_hashes: HashSet<Sha1Hash> = (1..=4).map(|i| [i; 20]).collect();
+        for info_hash in &info_hashes {
+            nodes[1].announce(*info_hash, Some(5000), false).await.unwrap();
+        }
+
+        let sample = nodes[2].sample_infohashes(nodes[3].local_addr().unwrap(), NodeId::random()).await.unwrap();
+        assert_eq!(sample.num, sample.samples.len());
+        assert!(sample.interval > Duration::ZERO && sample.interval <= SAMPLE_INTERVAL);
+        assert!(!sample.nodes.is_empty());
+        // The same sample is returned until the interval is over.
+        let again = nodes[2].sample_infohashes(nodes[3].local_addr().unwrap(), NodeId::random()).await.unwrap();
+        assert_eq!(again.samples, sample.samples);
+
+        let crawler = Dht::bind(config(&[nodes[0].local_addr().unwrap()])).await.unwrap();
+        crawler.bootstrap(&[]).await.unwrap();
+        assert_eq!(crawler.crawl(100).await, info_hashes);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_read_only_nodes_stay_out_of_routing_tables() {
+        let nodes = swarm(6).await;
+        let router = nodes[0].local_addr().unwrap();
+        let read_only = Dht::bind(DhtConfig { read_only: true, ..config(&[router]) }).await.unwrap();
+        assert!(read_only.bootstrap(&[]).await.unwrap() > 1);
+        let info_hash = [0x43; 20];
+        read_only.announce(info_hash, Some(7000), false).await.unwrap();
+        nodes[2].announce(info_hash, None, false).await.unwrap();
+        assert!(read_only.get_peers(info_hash).await.contains(&nodes[2].local_addr().unwrap()));
+        assert!(nodes[3].get_peers(info_hash).await.contains(&SocketAddr::from((Ipv4Addr::LOCALHOST, 7000))));
+
+        for node in &nodes {
+            assert!(node.nodes().iter().all(|info| info.id != read_only.id()));
+        }
+        assert!(matches!(nodes[1].ping(read_only.local_addr().unwrap()).await, Err(DhtError::Timeout)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_takes_secure_id_for_external_ip() {
+        let nodes = swarm(4).await;
+        let node = &nodes[1];
+        let known = node.nodes().len();
+        let ip = IpAddr::from([81, 2, 3, 4]);
+        node.set_external_ip(ip);
+        assert!(node.id().is_secure_for(ip));
+        assert_eq!(node.external_ips(), vec![Some(ip)]);
+        assert_eq!(node.nodes().len(), known);
+        assert_eq!(nodes[2].ping(node.local_addr().unwrap()).await.unwrap(), node.id());
+
+        // A secure id is kept, and addresses of the other family don't concern the node.
+        let id = node.id();
+        node.set_external_ip(ip);
+        node.set_external_ip(IpAddr::from([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1]));
+        assert_eq!(node.id(), id);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_dual_stack_swarm_finds_ipv6_peers() {
+        // Routers are reached over IPv4; IPv6 tables fill up through `want`.
+        let nodes = swarm_on(&[IpAddr::V4(Ipv4Addr::LOCALHOST), IpAddr::V6(Ipv6Addr::LOCALHOST)], 12).await;
+        for node in &nodes[1..] {
+            assert!(node.nodes().iter().any(|info| info.addr.is_ipv6()), "{:?}", node.nodes());
+        }
+        assert_eq!(nodes[1].ids(), vec![nodes[1].id(); 2]);
+
+        let info_hash = [0x66; 20];
+        nodes[4].announce(info_hash, None, false).await.unwrap();
+        let peers = nodes[9].get_peers(info_hash).await;
+        for addr in nodes[4].local_addrs() {
+            assert!(peers.contains(&addr), "{addr} not in {peers:?}");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_multi_homed_nodes_answer_from_each_address() {
+        let ips = [IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2))];
+        let multi_homed = Dht::bind(config_on(&ips, &[])).await.unwrap();
+        let [first, second] = multi_homed.local_addrs()[..] else { panic!("two addresses expected") };
+        let ids = multi_homed.ids();
+        assert_ne!(ids[0], ids[1]);
+
+        let other = Dht::bind(config(&[])).await.unwrap();
+        assert_eq!(other.ping(first).await.unwrap(), ids[0]);
+        assert_eq!(other.ping(second).await.unwrap(), ids[1]);
+        let mut known = other.nodes();
+        known.sort_by_key(|node| node.addr);
+        assert_eq!(known, vec![NodeInfo { id: ids[0], addr: first }, NodeInfo { id: ids[1], addr: second }]);
+
+        // The addresses don't take each other for peers in the network.
+        multi_homed.bootstrap(&[(String::from("127.0.0.1"), other.local_addr().unwrap().port())]).await.unwrap();
+        assert!(multi_homed.nodes().iter().all(|node| !ids.contains(&node.id)));
+    }
+}
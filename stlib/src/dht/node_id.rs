@@ -0,0 +1,171 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use rand::random;
+
+use crate::model::{Sha1Hash, SHA1_HASH_LEN};
+use crate::util::common::is_local_ip;
+
+pub const NODE_ID_BITS: usize = SHA1_HASH_LEN * 8;
+const IPV4_MASK: [u8; 4] = [0x03, 0x0f, 0x3f, 0xff];
+const IPV6_MASK: [u8; 8] = [0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff];
+
+/// 160 bit identifier of a DHT node, in the same space as info hashes.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct NodeId(pub Sha1Hash);
+
+impl NodeId {
+    pub fn random() -> Self {
+        Self(random())
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Option<Self> {
+        Some(Self(bytes.try_into().ok()?))
+    }
+
+    /// A random id whose first 21 bits derive from our external `ip` (BEP 42), so that nodes
+    /// can't pick their place in the key space.
+    pub fn secure(ip: IpAddr) -> Self {
+        let mut id = Self::random().0;
+        let r = id[19] & 0x07;
+        let prefix = secure_prefix(ip, r);
+        id[0] = prefix[0];
+        id[1] = prefix[1];
+        id[2] = (prefix[2] & 0xf8) | (id[2] & 0x07);
+        Self(id)
+    }
+
+    /// Whether the id complies with BEP 42 for a node at `ip`. Nodes on local networks are
+    /// exempt.
+    pub fn is_secure_for(&self, ip: IpAddr) -> bool {
+        if is_local_ip(ip) {
+            return true;
+        }
+        let prefix = secure_prefix(ip, self.0[19] & 0x07);
+        self.0[..2] == prefix[..2] && self.0[2] & 0xf8 == prefix[2] & 0xf8
+    }
+
+    /// XOR metric of Kademlia, comparable as big endian bytes.
+    pub fn distance(&self, other: &NodeId) -> NodeId {
+        Self(std::array::from_fn(|i| self.0[i] ^ other.0[i]))
+    }
+
+    /// Index of the bucket `other` belongs to in our routing table: the number of leading bits
+    /// both ids share. `None` for our own id.
+    pub fn bucket_index(&self, other: &NodeId) -> Option<usize> {
+        let distance = self.distance(other);
+        let zeros = distance.0.iter()
+            .position(|byte| *byte != 0)
+            .map(|i| i * 8 + distance.0[i].leading_zeros() as usize)?;
+        Some(zeros)
+    }
+
+    /// A random id whose bucket index relative to ours is `index`, to refresh that bucket.
+    pub fn random_in_bucket(&self, index: usize) -> NodeId {
+        let mut id = Self::random().0;
+        for bit in 0..=index.min(NODE_ID_BITS - 1) {
+            let (byte, mask) = (bit / 8, 0x80 >> (bit % 8));
+            let ours = self.0[byte] & mask;
+            // Bits before `index` are shared, the one at `index` differs.
+            let wanted = if bit == index { ours ^ mask } else { ours };
+            id[byte] = (id[byte] & !mask) | wanted;
+        }
+        Self(id)
+    }
+}
+
+impl From<Sha1Hash> for NodeId {
+    fn from(hash: Sha1Hash) -> Self {
+        Self(hash)
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NodeId({self})")
+    }
+}
+
+/// CRC32-C of the masked ip with `r` in its top bits, the first 21 bits of which start a
+/// secure id.
+fn secure_prefix(ip: IpAddr, r: u8) -> [u8; 3] {
+    let crc = match ip {
+        IpAddr::V4(ip) => {
+            let mut bytes: [u8; 4] = std::array::from_fn(|i| ip.octets()[i] & IPV4_MASK[i]);
+            bytes[0] |= r << 5;
+            crc32c::crc32c(&bytes)
+        }
+        IpAddr::V6(ip) => {
+            let mut bytes: [u8; 8] = std::array::from_fn(|i| ip.octets()[i] & IPV6_MASK[i]);
+            bytes[0] |= r << 5;
+            crc32c::crc32c(&bytes)
+        }
+    };
+    let crc = crc.to_be_bytes();
+    [crc[0], crc[1], crc[2]]
+}
+
+/// A node as found in routing tables and `nodes` lists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_index() {
+        let id = NodeId([0; 20]);
+        assert_eq!(id.bucket_index(&id), None);
+        let mut other = [0; 20];
+        other[0] = 0x80;
+        assert_eq!(id.bucket_index(&NodeId(other)), Some(0));
+        other = [0; 20];
+        other[2] = 0x10;
+        assert_eq!(id.bucket_index(&NodeId(other)), Some(19));
+        other[19] = 1;
+        assert_eq!(id.bucket_index(&NodeId(other)), Some(19));
+    }
+
+    #[test]
+    fn test_secure_ids() {
+        // Examples of BEP 42, with the random bytes of the ids zeroed.
+        let examples = [
+            ([124, 31, 75, 21], 1, "5fbfb8"),
+            ([21, 75, 31, 124], 86, "5a3ce8"),
+            ([65, 23, 51, 170], 22, "a5d430"),
+            ([84, 124, 73, 14], 65, "1b0320"),
+            ([43, 213, 53, 83], 90, "e56f68"),
+        ];
+        for (ip, last_byte, prefix) in examples {
+            let ip = IpAddr::from(ip);
+            let mut id = [0; 20];
+            hex::decode_to_slice(prefix, &mut id[..3]).unwrap();
+            id[19] = last_byte;
+            assert!(NodeId(id).is_secure_for(ip), "{ip}");
+            id[1] ^= 1;
+            assert!(!NodeId(id).is_secure_for(ip), "{ip}");
+        }
+
+        let ip = IpAddr::from([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1]);
+        assert!(NodeId::secure(ip).is_secure_for(ip));
+        assert!(!NodeId::secure(ip).is_secure_for(IpAddr::from([0x2001, 0xdb9, 0, 0, 0, 0, 0, 1])));
+        assert!(NodeId([0; 20]).is_secure_for(IpAddr::from([192, 168, 1, 1])));
+    }
+
+    #[test]
+    fn test_random_in_bucket() {
+        let id = NodeId::random();
+        for index in [0, 7, 8, 100, NODE_ID_BITS - 1] {
+            assert_eq!(id.bucket_index(&id.random_in_bucket(index)), Some(index));
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use std::time::Duration;
+use tokio::time::Instant;
+
+use super::{NodeId, NodeInfo, NODE_ID_BITS};
+
+/// Nodes per bucket.
+pub const K: usize = 8;
+/// Nodes unheard of for this long are questionable and get pinged, and buckets unchanged for
+/// this long get refreshed.
+pub const NODE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+/// Failed queries after which a node is bad and may be replaced.
+const MAX_FAILURES: u32 = 2;
+
+#[derive(Debug, Clone)]
+struct Node {
+    info: NodeInfo,
+    last_seen: Instant,
+    failures: u32,
+    /// Whether the id complies with BEP 42 for the node's address.
+    secure: bool,
+}
+
+impl Node {
+    fn is_bad(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Bucket {
+    nodes: Vec<Node>,
+    last_changed: Instant,
+}
+
+/// Kademlia routing table: one bucket of up to [`K`] nodes per shared prefix length with our id,
+/// where long lived nodes are kept over new ones, and nodes with secure ids (BEP 42) over those
+/// without.
+#[derive(Debug, Clone)]
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Bucket>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId, now: Instant) -> Self {
+        Self {
+            id,
+            buckets: vec![Bucket { nodes: vec![], last_changed: now }; NODE_ID_BITS],
+        }
+    }
+
+    pub fn id(&self) -> &NodeId {
+        &self.id
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.nodes.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.buckets.iter().flat_map(|bucket| bucket.nodes.iter().map(|node| node.info)).collect()
+    }
+
+    /// Records that `info` answered or queried us. New nodes take the place of a bad node when
+    /// their bucket is full, or of a node with an insecure id if theirs is secure, and are
+    /// dropped otherwise. Returns whether the node is in the table.
+    pub fn insert(&mut self, info: NodeInfo, now: Instant) -> bool {
+        let Some(index) = self.id.bucket_index(&info.id) else { return false };
+        let bucket = &mut self.buckets[index];
+        if let Some(node) = bucket.nodes.iter_mut().find(|node| node.info.id == info.id) {
+            // A known id moving to another address could be an impostor; keep the address we trust.
+            if node.info.addr == info.addr {
+                node.last_seen = now;
+                node.failures = 0;
+                bucket.last_changed = now;
+            }
+            return node.info.addr == info.addr;
+        }
+        let secure = info.id.is_secure_for(info.addr.ip());
+        let new_node = Node { info, last_seen: now, failures: 0, secure };
+        if bucket.nodes.len() < K {
+            bucket.nodes.push(new_node);
+        } else if let Some(bad) = bucket.nodes.iter_mut().find(|node| node.is_bad()) {
+            *bad = new_node;
+        } else if let Some(insecure) = bucket.nodes.iter_mut().find(|node| secure && !node.secure) {
+            *insecure = new_node;
+        } else {
+            return false;
+        }
+        bucket.last_changed = now;
+        true
+    }
+
+    /// Changes our id, e.g. for a secure one once our external address is known. Nodes keep
+    /// their state but move to the buckets of the new id, as long as those have room.
+    pub fn set_id(&mut self, id: NodeId, now: Instant) {
+        let old = std::mem::replace(self, Self::new(id, now));
+        for node in old.buckets.into_iter().flat_map(|bucket| bucket.nodes) {
+            let Some(index) = id.bucket_index(&node.info.id) else { continue };
+            if self.buckets[index].nodes.len() < K {
+                self.buckets[index].nodes.push(node);
+            }
+        }
+    }
+
+    /// Records a query to `id` that went unanswered.
+    pub fn failed(&mut self, id: &NodeId) {
+        if let Some(node) = self.node_mut(id) {
+            node.failures += 1;
+        }
+    }
+
+    pub fn remove(&mut self, id: &NodeId) {
+        if let Some(index) = self.id.bucket_index(id) {
+            self.buckets[index].nodes.retain(|node| node.info.id != *id);
+        }
+    }
+
+    /// Up to `count` nodes closest to `target`, bad ones excepted.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self.buckets.iter()
+            .flat_map(|bucket| bucket.nodes.iter())
+            .filter(|node| !node.is_bad())
+            .map(|node| node.info)
+            .collect();
+        nodes.sort_unstable_by_key(|node| node.id.distance(target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    /// Nodes that weren't heard of for [`NODE_TIMEOUT`] and should be pinged.
+    pub fn questionable(&self, now: Instant) -> Vec<NodeInfo> {
+        self.buckets.iter()
+            .flat_map(|bucket| bucket.nodes.iter())
+            .filter(|node| now.duration_since(node.last_seen) >= NODE_TIMEOUT)
+            .map(|node| node.info)
+            .collect()
+    }
+
+    /// Random targets in the non-empty buckets that didn't change for [`NODE_TIMEOUT`].
+    pub fn refresh_targets(&self, now: Instant) -> Vec<NodeId> {
+        self.buckets.iter()
+            .enumerate()
+            .filter(|(_, bucket)| !bucket.nodes.is_empty() && now.duration_since(bucket.last_changed) >= NODE_TIMEOUT)
+            .map(|(index, _)| self.id.random_in_bucket(index))
+            .collect()
+    }
+
+    fn node_mut(&mut self, id: &NodeId) -> Option<&mut Node> {
+        let index = self.id.bucket_index(id)?;
+        self.buckets[index].nodes.iter_mut().find(|node| node.info.id == *id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use super::*;
+
+    fn node(first_byte: u8, last_byte: u8) -> NodeInfo {
+        let mut id = [0; 20];
+        id[0] = first_byte;
+        id[19] = last_byte;
+        NodeInfo { id: NodeId(id), addr: SocketAddr::from(([10, 0, first_byte, last_byte], 6881)) }
+    }
+
+    #[test]
+    fn test_full_buckets_keep_old_nodes_but_replace_bad_ones() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new(NodeId([0; 20]), now);
+        for i in 0..K as u8 {
+            assert!(table.insert(node(0x80, i), now));
+        }
+        assert!(!table.insert(node(0x80, 100), now));
+        assert!(table.insert(node(0x40, 1), now));
+        assert_eq!(table.len(), K + 1);
+
+        table.failed(&node(0x80, 3).id);
+        assert!(!table.insert(node(0x80, 100), now));
+        table.failed(&node(0x80, 3).id);
+        assert!(table.insert(node(0x80, 100), now));
+        assert!(!table.nodes().contains(&node(0x80, 3)));
+
+        // Our own id and known ids on another address are refused.
+        assert!(!table.insert(NodeInfo { id: NodeId([0; 20]), addr: node(1, 1).addr }, now));
+        assert!(!table.insert(NodeInfo { id: node(0x40, 1).id, addr: node(1, 1).addr }, now));
+    }
+
+    #[test]
+    fn test_secure_ids_are_preferred() {
+        // Ids of the BEP 42 example for 124.31.75.21, which are secure at that address only.
+        let id = |i: u8| NodeId([0x5f, 0xbf, 0xb8, i, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01]);
+        let secure = NodeInfo { id: id(100), addr: SocketAddr::from(([124, 31, 75, 21], 6881)) };
+        let insecure = |i: u8| NodeInfo { id: id(i), addr: SocketAddr::from(([81, 2, 3, i], 6881)) };
+        let now = Instant::now();
+        let mut table = RoutingTable::new(NodeId([0xff; 20]), now);
+        for i in 0..K as u8 {
+            assert!(table.insert(insecure(i), now));
+        }
+        assert!(!table.insert(insecure(101), now));
+        assert!(table.insert(secure, now));
+        assert_eq!(table.len(), K);
+        assert!(!table.insert(insecure(101), now));
+
+        table.set_id(NodeId([0; 20]), now);
+        assert_eq!(table.id(), &NodeId([0; 20]));
+        assert_eq!(table.len(), K);
+        assert_eq!(table.closest(&id(100), 1), vec![secure]);
+    }
+
+    #[test]
+    fn test_closest_orders_by_distance_and_skips_bad_nodes() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new(NodeId([0; 20]), now);
+        for info in [node(0x80, 1), node(0x40, 1), node(0x20, 1), node(0x81, 1)] {
+            table.insert(info, now);
+        }
+        assert_eq!(table.closest(&node(0x80, 0).id, 2), vec![node(0x80, 1), node(0x81, 1)]);
+        table.failed(&node(0x80, 1).id);
+        table.failed(&node(0x80, 1).id);
+        assert_eq!(table.closest(&node(0x80, 0).id, 2), vec![node(0x81, 1), node(0x20, 1)]);
+    }
+
+    #[test]
+    fn test_maintenance_targets() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new(NodeId([0; 20]), now);
+        table.insert(node(0x80, 1), now);
+        table.insert(node(0x01, 1), now + NODE_TIMEOUT);
+        let later = now + NODE_TIMEOUT;
+        assert_eq!(table.questionable(later), vec![node(0x80, 1)]);
+        let targets = table.refresh_targets(later);
+        assert_eq!(targets.len(), 1);
+        assert_eq!(table.id().bucket_index(&targets[0]), Some(0));
+        table.remove(&node(0x80, 1).id);
+        assert!(table.refresh_targets(later).is_empty());
+    }
+}
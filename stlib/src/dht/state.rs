@@ -0,0 +1,76 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use super::krpc::{compact_nodes, compact_nodes6, parse_compact_nodes, parse_compact_nodes6};
+use super::{NodeId, NodeInfo};
+
+/// What a node keeps across restarts: its id, so that it stays responsible for the same part
+/// of the key space, and its routing tables to bootstrap from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DhtState {
+    pub id: NodeId,
+    pub nodes: Vec<NodeInfo>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DhtStateBencode {
+    id: ByteBuf,
+    #[serde(default)]
+    nodes: ByteBuf,
+    #[serde(default)]
+    nodes6: ByteBuf,
+}
+
+impl DhtState {
+    pub fn from_bencode(bytes: &[u8]) -> io::Result<Self> {
+        let invalid = |reason: String| io::Error::new(io::ErrorKind::InvalidData, reason);
+        let state: DhtStateBencode = serde_bencode::from_bytes(bytes).map_err(|err| invalid(err.to_string()))?;
+        let mut nodes = parse_compact_nodes(&state.nodes).map_err(|err| invalid(err.to_string()))?;
+        nodes.extend(parse_compact_nodes6(&state.nodes6).map_err(|err| invalid(err.to_string()))?);
+        Ok(Self {
+            id: NodeId::from_slice(&state.id).ok_or_else(|| invalid(String::from("Node id isn't 20 bytes long")))?,
+            nodes,
+        })
+    }
+
+    pub fn to_bencode(&self) -> Vec<u8> {
+        let state = DhtStateBencode {
+            id: ByteBuf::from(self.id.0),
+            nodes: ByteBuf::from(compact_nodes(&self.nodes)),
+            nodes6: ByteBuf::from(compact_nodes6(&self.nodes)),
+        };
+        serde_bencode::to_bytes(&state).expect("DHT state is always serializable")
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_bencode(&fs::read(path)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, self.to_bencode())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_round_trip() {
+        let state = DhtState {
+            id: NodeId([7; 20]),
+            nodes: vec![
+                NodeInfo { id: NodeId([1; 20]), addr: "10.0.0.1:6881".parse().unwrap() },
+                NodeInfo { id: NodeId([2; 20]), addr: "[2001:db8::1]:6881".parse().unwrap() },
+            ],
+        };
+        let path = std::env::temp_dir().join(format!("dht-state-{}.dat", std::process::id()));
+        state.save(&path).unwrap();
+        assert_eq!(DhtState::load(&path).unwrap(), state);
+        fs::remove_file(&path).unwrap();
+        assert!(DhtState::from_bencode(b"d2:id3:abce").is_err());
+    }
+}
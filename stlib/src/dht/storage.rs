@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+use rand::seq::IteratorRandom;
+use tokio::time::Instant;
+
+use crate::model::Sha1Hash;
+
+use super::BloomFilter;
+
+/// Announced peers are forgotten after this long without a new announce.
+pub const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+/// Upper bound on the peers returned by a single `get_peers`, to fit in a UDP packet.
+pub const MAX_VALUES: usize = 50;
+const MAX_PEERS_PER_TORRENT: usize = 1000;
+const MAX_TORRENTS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy)]
+struct Announce {
+    at: Instant,
+    /// Set by the `seed` key (BEP 33).
+    seed: bool,
+}
+
+/// Peers announced to us with `announce_peer`.
+#[derive(Default)]
+pub struct PeerStore {
+    torrents: HashMap<Sha1Hash, HashMap<SocketAddr, Announce>>,
+}
+
+impl PeerStore {
+    pub fn announce(&mut self, info_hash: Sha1Hash, addr: SocketAddr, seed: bool, now: Instant) {
+        if self.torrents.len() >= MAX_TORRENTS && !self.torrents.contains_key(&info_hash) {
+            self.expire(now);
+            if self.torrents.len() >= MAX_TORRENTS {
+                return;
+            }
+        }
+        let peers = self.torrents.entry(info_hash).or_default();
+        if peers.len() >= MAX_PEERS_PER_TORRENT && !peers.contains_key(&addr) {
+            peers.retain(|_, announce| now.duration_since(announce.at) < PEER_TTL);
+            if peers.len() >= MAX_PEERS_PER_TORRENT {
+                return;
+            }
+        }
+        peers.insert(addr, Announce { at: now, seed });
+    }
+
+    /// Up to [`MAX_VALUES`] random live peers of the torrent, of the IPv4 or the IPv6 family
+    /// since a `get_peers` only returns peers of the family it was sent over (BEP 32).
+    pub fn peers(&self, info_hash: &Sha1Hash, ipv4: bool, now: Instant) -> Vec<SocketAddr> {
+        let Some(peers) = self.torrents.get(info_hash) else { return vec![] };
+        peers.iter()
+            .filter(|(addr, announce)| addr.is_ipv4() == ipv4 && now.duration_since(announce.at) < PEER_TTL)
+            .map(|(addr, _)| *addr)
+            .choose_multiple(&mut rand::thread_rng(), MAX_VALUES)
+    }
+
+    /// Bloom filters of the live seeds and other peers of the torrent, for a scrape (BEP 33).
+    pub fn scrape(&self, info_hash: &Sha1Hash, now: Instant) -> (BloomFilter, BloomFilter) {
+        let (mut seeds, mut peers) = (BloomFilter::default(), BloomFilter::default());
+        for (addr, announce) in self.torrents.get(info_hash).into_iter().flatten() {
+            if now.duration_since(announce.at) < PEER_TTL {
+                let filter = if announce.seed { &mut seeds } else { &mut peers };
+                filter.insert(addr.ip());
+            }
+        }
+        (seeds, peers)
+    }
+
+    /// Number of torrents peers were announced for.
+    pub fn num_torrents(&self) -> usize {
+        self.torrents.len()
+    }
+
+    /// Up to `count` random info hashes of torrents peers were announced for (BEP 51).
+    pub fn sample(&self, count: usize) -> Vec<Sha1Hash> {
+        self.torrents.keys().copied().choose_multiple(&mut rand::thread_rng(), count)
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        for peers in self.torrents.values_mut() {
+            peers.retain(|_, announce| now.duration_since(announce.at) < PEER_TTL);
+        }
+        self.torrents.retain(|_, peers| !peers.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peers_expire() {
+        let now = Instant::now();
+        let mut store = PeerStore::default();
+        let addr = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let addr6 = SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1], 6881));
+        store.announce([1; 20], addr, false, now);
+        store.announce([1; 20], addr6, true, now);
+        assert_eq!(store.peers(&[1; 20], true, now), vec![addr]);
+        assert_eq!(store.peers(&[1; 20], false, now), vec![addr6]);
+        assert!(store.peers(&[2; 20], true, now).is_empty());
+        assert_eq!(store.sample(5), vec![[1; 20]]);
+
+        let (seeds, peers) = store.scrape(&[1; 20], now);
+        let (mut expected_seeds, mut expected_peers) = (BloomFilter::default(), BloomFilter::default());
+        expected_seeds.insert(addr6.ip());
+        expected_peers.insert(addr.ip());
+        assert_eq!((seeds, peers), (expected_seeds, expected_peers));
+        assert!(store.scrape(&[1; 20], now + PEER_TTL).0.is_empty());
+        assert!(store.peers(&[1; 20], true, now + PEER_TTL).is_empty());
+        store.expire(now + PEER_TTL);
+        assert!(store.torrents.is_empty());
+    }
+}
@@ -0,0 +1,70 @@
+use std::net::IpAddr;
+use std::time::Duration;
+use rand::random;
+use tokio::time::Instant;
+
+use crate::util::common::sha1_hash;
+
+/// How often the secret tokens are derived from changes; a token stays valid for two periods.
+pub const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+const TOKEN_LEN: usize = 8;
+
+/// Tokens handed out by `get_peers` and required by `announce_peer`, proving the announcing
+/// node owns its address. They are derived from the address and a rotating secret.
+pub struct TokenManager {
+    secret: [u8; 16],
+    previous_secret: [u8; 16],
+    rotated_at: Instant,
+}
+
+impl TokenManager {
+    pub fn new(now: Instant) -> Self {
+        Self { secret: random(), previous_secret: random(), rotated_at: now }
+    }
+
+    pub fn token(&mut self, ip: IpAddr, now: Instant) -> Vec<u8> {
+        self.rotate(now);
+        Self::derive(&self.secret, ip)
+    }
+
+    pub fn is_valid(&mut self, token: &[u8], ip: IpAddr, now: Instant) -> bool {
+        self.rotate(now);
+        token == Self::derive(&self.secret, ip) || token == Self::derive(&self.previous_secret, ip)
+    }
+
+    fn rotate(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.rotated_at);
+        if elapsed >= TOKEN_ROTATION {
+            // After two periods without a rotation, the previous secret is stale too.
+            self.previous_secret = if elapsed >= TOKEN_ROTATION * 2 { random() } else { self.secret };
+            self.secret = random();
+            self.rotated_at = now;
+        }
+    }
+
+    fn derive(secret: &[u8], ip: IpAddr) -> Vec<u8> {
+        let ip = match ip {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        sha1_hash([&ip[..], secret].concat())[..TOKEN_LEN].to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use super::*;
+
+    #[test]
+    fn test_tokens_expire_after_two_rotations() {
+        let now = Instant::now();
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let mut tokens = TokenManager::new(now);
+        let token = tokens.token(ip, now);
+        assert!(tokens.is_valid(&token, ip, now));
+        assert!(!tokens.is_valid(&token, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), now));
+        assert!(tokens.is_valid(&token, ip, now + TOKEN_ROTATION));
+        assert!(!tokens.is_valid(&token, ip, now + TOKEN_ROTATION * 2));
+    }
+}
@@ -3,4 +3,6 @@ pub mod client;
 pub mod util;
 pub mod peer;
 pub mod tracker;
+pub mod dht;
 
+pub mod lsd;
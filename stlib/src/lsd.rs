@@ -0,0 +1,342 @@
+//! Local Service Discovery (BEP 14): peers on the same network announce the torrents they
+//! have to a multicast group, and connect to each other without any tracker.
+//!
+//! Private torrents (BEP 27) are refused.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::{interval, Instant};
+
+use crate::model::{PeerInfo, Sha1Hash, Torrent, SHA1_HASH_LEN};
+
+pub const LSD_PORT: u16 = 6771;
+pub const LSD_GROUP_V4: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+pub const LSD_GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f);
+/// How often every torrent is announced.
+pub const LSD_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Minimum delay between two announces of a torrent, both sent and accepted from a host.
+pub const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+/// Info hashes per announce, to keep it in a single unfragmented packet.
+const MAX_INFO_HASHES: usize = 20;
+const MAX_MESSAGE_SIZE: usize = 1400;
+const SEARCH_LINE: &str = "BT-SEARCH * HTTP/1.1";
+
+/// A `BT-SEARCH` announce.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LsdMessage {
+    /// The multicast group and port the announce is sent to.
+    pub host: String,
+    /// Port the announcing peer accepts connections on.
+    pub port: u16,
+    pub info_hashes: Vec<Sha1Hash>,
+    /// Identifies the sender, so that it can ignore its own announces.
+    pub cookie: Option<String>,
+}
+
+impl LsdMessage {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let text = std::str::from_utf8(bytes).ok()?;
+        let mut lines = text.split("\r\n");
+        if lines.next()? != SEARCH_LINE {
+            return None;
+        }
+        let (mut host, mut port, mut info_hashes, mut cookie) = (None, None, vec![], None);
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let (name, value) = line.split_once(':')?;
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "host" => host = Some(value.to_string()),
+                "port" => port = Some(value.parse().ok()?),
+                "infohash" => {
+                    let mut info_hash = [0; SHA1_HASH_LEN];
+                    // Hashes of other lengths, e.g. of v2 torrents, aren't ours to know.
+                    if hex::decode_to_slice(value, &mut info_hash).is_ok() {
+                        info_hashes.push(info_hash);
+                    }
+                }
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        if info_hashes.is_empty() {
+            return None;
+        }
+        Some(Self { host: host?, port: port?, info_hashes, cookie })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut message = format!("{SEARCH_LINE}\r\nHost: {}\r\nPort: {}\r\n", self.host, self.port);
+        for info_hash in &self.info_hashes {
+            message.push_str(&format!("Infohash: {}\r\n", hex::encode(info_hash)));
+        }
+        if let Some(cookie) = &self.cookie {
+            message.push_str(&format!("cookie: {cookie}\r\n"));
+        }
+        message.push_str("\r\n\r\n");
+        message.into_bytes()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LsdConfig {
+    /// Port we accept peer connections on, announced to the other peers.
+    pub port: u16,
+    /// Multicast groups to announce to and listen on. Groups that can't be joined, e.g. IPv6
+    /// ones on a host without IPv6, are skipped as long as one of them can.
+    pub groups: Vec<SocketAddr>,
+    /// Interface to use for IPv4 groups; the system picks one when unspecified.
+    pub interface_v4: Ipv4Addr,
+    /// Index of the interface to use for IPv6 groups; the system picks one when 0.
+    pub interface_v6: u32,
+    pub announce_interval: Duration,
+}
+
+impl Default for LsdConfig {
+    fn default() -> Self {
+        Self {
+            port: 6881,
+            groups: vec![SocketAddr::from((LSD_GROUP_V4, LSD_PORT)), SocketAddr::from((LSD_GROUP_V6, LSD_PORT))],
+            interface_v4: Ipv4Addr::UNSPECIFIED,
+            interface_v6: 0,
+            announce_interval: LSD_ANNOUNCE_INTERVAL,
+        }
+    }
+}
+
+struct LsdTorrent {
+    peers: mpsc::Sender<PeerInfo>,
+    announced_at: Option<Instant>,
+    /// When each host last had its announce of the torrent accepted.
+    heard_at: HashMap<IpAddr, Instant>,
+}
+
+/// Announces the added torrents to the local network, and feeds the peers announcing them
+/// into the peer list of each.
+pub struct Lsd {
+    inner: Arc<Inner>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+struct Inner {
+    config: LsdConfig,
+    sockets: Vec<(SocketAddr, UdpSocket)>,
+    cookie: String,
+    torrents: Mutex<HashMap<Sha1Hash, LsdTorrent>>,
+    added: Notify,
+}
+
+/// A socket listening on the group's port, with other processes of the host, and sending to
+/// the group over the configured interface.
+fn join_group(group: SocketAddr, config: &LsdConfig) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(group), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    match group.ip() {
+        IpAddr::V4(ip) => {
+            socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, group.port())).into())?;
+            socket.join_multicast_v4(&ip, &config.interface_v4)?;
+            socket.set_multicast_if_v4(&config.interface_v4)?;
+            socket.set_multicast_loop_v4(true)?;
+        }
+        IpAddr::V6(ip) => {
+            socket.set_only_v6(true)?;
+            socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, group.port())).into())?;
+            socket.join_multicast_v6(&ip, config.interface_v6)?;
+            socket.set_multicast_if_v6(config.interface_v6)?;
+            socket.set_multicast_loop_v6(true)?;
+        }
+    }
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+impl Lsd {
+    pub async fn bind(config: LsdConfig) -> io::Result<Self> {
+        let mut sockets = vec![];
+        let mut first_error = None;
+        for group in &config.groups {
+            match join_group(*group, &config) {
+                Ok(socket) => sockets.push((*group, socket)),
+                Err(err) => first_error = first_error.or(Some(err)),
+            }
+        }
+        if sockets.is_empty() {
+            return Err(first_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No multicast group")));
+        }
+        let inner = Arc::new(Inner {
+            config,
+            sockets,
+            cookie: hex::encode(rand::random::<[u8; 4]>()),
+            torrents: Mutex::new(HashMap::new()),
+            added: Notify::new(),
+        });
+        let mut tasks: Vec<JoinHandle<()>> = (0..inner.sockets.len())
+            .map(|index| tokio::spawn(inner.clone().receive(index)))
+            .collect();
+        tasks.push(tokio::spawn(inner.clone().announce_periodically()));
+        Ok(Self { inner, tasks })
+    }
+
+    /// Announces the torrent from now on, and sends the peers announcing it to `peers`, the
+    /// channel [`crate::tracker::TrackerManager::run`] feeds too. Returns false, adding
+    /// nothing, for private torrents.
+    pub fn add_torrent(&self, torrent: &Torrent, peers: mpsc::Sender<PeerInfo>) -> bool {
+        if torrent.private {
+            return false;
+        }
+        self.inner.torrents.lock().unwrap().insert(torrent.info_hash, LsdTorrent { peers, announced_at: None, heard_at: HashMap::new() });
+        self.inner.added.notify_one();
+        true
+    }
+
+    pub fn remove_torrent(&self, info_hash: &Sha1Hash) {
+        self.inner.torrents.lock().unwrap().remove(info_hash);
+    }
+
+    /// Announces the torrents that weren't for [`MIN_ANNOUNCE_INTERVAL`]. Returns how many
+    /// were.
+    pub async fn announce(&self) -> usize {
+        self.inner.announce().await
+    }
+}
+
+impl Drop for Lsd {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+impl Inner {
+    async fn announce_periodically(self: Arc<Self>) {
+        let mut ticks = interval(self.config.announce_interval);
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => {}
+                _ = self.added.notified() => {}
+            }
+            self.announce().await;
+        }
+    }
+
+    async fn announce(&self) -> usize {
+        let now = Instant::now();
+        let due: Vec<Sha1Hash> = {
+            let mut torrents = self.torrents.lock().unwrap();
+            torrents.iter_mut()
+                .filter(|(_, torrent)| torrent.announced_at.is_none_or(|at| now.duration_since(at) >= MIN_ANNOUNCE_INTERVAL))
+                .map(|(info_hash, torrent)| {
+                    torrent.announced_at = Some(now);
+                    *info_hash
+                })
+                .collect()
+        };
+        for info_hashes in due.chunks(MAX_INFO_HASHES) {
+            for (group, socket) in &self.sockets {
+                let message = LsdMessage {
+                    host: group.to_string(),
+                    port: self.config.port,
+                    info_hashes: info_hashes.to_vec(),
+                    cookie: Some(self.cookie.clone()),
+                };
+                // Groups of a family the network doesn't route don't prevent announcing to others.
+                let _ = socket.send_to(&message.to_bytes(), group).await;
+            }
+        }
+        due.len()
+    }
+
+    async fn receive(self: Arc<Self>, index: usize) {
+        let socket = &self.sockets[index].1;
+        let mut buf = [0u8; MAX_MESSAGE_SIZE];
+        loop {
+            let Ok((n, from)) = socket.recv_from(&mut buf).await else { continue };
+            let Some(message) = LsdMessage::from_bytes(&buf[..n]) else { continue };
+            if message.cookie.as_ref() == Some(&self.cookie) || message.port == 0 {
+                continue;
+            }
+            let peer = PeerInfo { socket_addr: SocketAddr::new(from.ip(), message.port) };
+            let now = Instant::now();
+            let mut torrents = self.torrents.lock().unwrap();
+            for info_hash in &message.info_hashes {
+                let Some(torrent) = torrents.get_mut(info_hash) else { continue };
+                // Hosts heard from longer ago are forgotten, which is also what lets them in again.
+                torrent.heard_at.retain(|_, heard_at| now.duration_since(*heard_at) < MIN_ANNOUNCE_INTERVAL);
+                if torrent.heard_at.insert(from.ip(), now).is_none() {
+                    let _ = torrent.peers.try_send(peer.clone());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_round_trip() {
+        let message = LsdMessage::from_bytes(b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 51413\r\n\
+            Infohash: c12fe1c06bba254a9dc9f519b335aa7c1367a88a\r\nInfohash: 0000\r\ncookie: abc\r\n\r\n\r\n").unwrap();
+        assert_eq!(message, LsdMessage {
+            host: String::from("239.192.152.143:6771"),
+            port: 51413,
+            info_hashes: vec![hex::decode("c12fe1c06bba254a9dc9f519b335aa7c1367a88a").unwrap().try_into().unwrap()],
+            cookie: Some(String::from("abc")),
+        });
+        assert_eq!(LsdMessage::from_bytes(&message.to_bytes()), Some(message));
+        assert!(LsdMessage::from_bytes(b"M-SEARCH * HTTP/1.1\r\nHost: x\r\nPort: 1\r\n\r\n").is_none());
+        assert!(LsdMessage::from_bytes(b"BT-SEARCH * HTTP/1.1\r\nHost: x\r\nPort: 1\r\n\r\n").is_none());
+    }
+
+    async fn lsd(port: u16, group_port: u16) -> Lsd {
+        let config = LsdConfig {
+            port,
+            groups: vec![SocketAddr::from((LSD_GROUP_V4, group_port))],
+            interface_v4: Ipv4Addr::LOCALHOST,
+            ..LsdConfig::default()
+        };
+        Lsd::bind(config).await.unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_peers_find_each_other_on_loopback() {
+        let group_port = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let (first, second) = (lsd(7001, group_port).await, lsd(7002, group_port).await);
+        let torrent = Torrent::fixture(false, &[]);
+        let mut other = torrent.clone();
+        other.info_hash = [0x41; 20];
+        let info_hash = torrent.info_hash;
+        let (first_tx, mut first_rx) = mpsc::channel(8);
+        let (second_tx, mut second_rx) = mpsc::channel(8);
+        let (other_tx, mut other_rx) = mpsc::channel(8);
+        assert!(first.add_torrent(&torrent, first_tx));
+        assert!(first.add_torrent(&other, other_tx));
+        assert!(second.add_torrent(&torrent, second_tx));
+        assert!(!second.add_torrent(&Torrent::fixture(true, &[]), mpsc::channel(1).0));
+
+        let peer = |port: u16| PeerInfo { socket_addr: SocketAddr::from((Ipv4Addr::LOCALHOST, port)) };
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            (first_rx.recv().await.unwrap(), second_rx.recv().await.unwrap())
+        }).await.unwrap();
+        assert_eq!(received, (peer(7002), peer(7001)));
+
+        // Announces are rate limited, and repeated ones aren't fed again.
+        assert_eq!(first.announce().await, 0);
+        first.inner.torrents.lock().unwrap().get_mut(&info_hash).unwrap().announced_at = None;
+        assert_eq!(first.announce().await, 1);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(second_rx.try_recv().is_err());
+        assert!(other_rx.try_recv().is_err());
+        assert!(first_rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,130 @@
+use std::io;
+use std::net::SocketAddr;
+use url::Url;
+
+use crate::model::{Sha1Hash, SHA1_HASH_LEN};
+
+const BTIH_PREFIX: &str = "urn:btih:";
+const BTPK_PREFIX: &str = "urn:btpk:";
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// A `magnet:` link (BEP 9), naming a torrent by info hash or, for a mutable torrent (BEP 46),
+/// by the public key and salt of the DHT item pointing at its current info hash.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MagnetLink {
+    /// The `xt=urn:btih:` key, hex or base32 encoded.
+    pub info_hash: Option<Sha1Hash>,
+    /// The `xs=urn:btpk:` key, hex encoded.
+    pub public_key: Option<[u8; 32]>,
+    /// The `s` key, hex encoded.
+    pub salt: Vec<u8>,
+    /// The `dn` key.
+    pub name: Option<String>,
+    /// The `tr` keys, one tier each.
+    pub trackers: Vec<String>,
+    /// The `x.pe` keys, peers to connect to directly.
+    pub peers: Vec<SocketAddr>,
+}
+
+impl MagnetLink {
+    pub fn parse(link: &str) -> Result<Self, io::Error> {
+        let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid magnet link: {reason}"));
+        let url = Url::parse(link).map_err(|err| invalid(&err.to_string()))?;
+        if url.scheme() != "magnet" {
+            return Err(invalid("not a magnet URI"));
+        }
+        let mut magnet = Self::default();
+        for (key, value) in url.query_pairs() {
+            match &*key {
+                "xt" => if let Some(hash) = value.strip_prefix(BTIH_PREFIX) {
+                    magnet.info_hash = Some(parse_info_hash(hash).ok_or_else(|| invalid("malformed info hash"))?);
+                },
+                "xs" => if let Some(key) = value.strip_prefix(BTPK_PREFIX) {
+                    let mut public_key = [0; 32];
+                    hex::decode_to_slice(key, &mut public_key).map_err(|_| invalid("malformed public key"))?;
+                    magnet.public_key = Some(public_key);
+                },
+                "s" => magnet.salt = hex::decode(&*value).map_err(|_| invalid("malformed salt"))?,
+                "dn" => magnet.name = Some(value.into_owned()),
+                "tr" => magnet.trackers.push(value.into_owned()),
+                // Hostnames are allowed too, but left to whoever resolves them.
+                "x.pe" => magnet.peers.extend(value.parse::<SocketAddr>().ok()),
+                _ => {}
+            }
+        }
+        if magnet.info_hash.is_none() && magnet.public_key.is_none() {
+            return Err(invalid("neither an info hash nor a public key"));
+        }
+        Ok(magnet)
+    }
+
+    /// Whether the link names a mutable torrent, whose info hash is found in the DHT.
+    pub fn is_mutable(&self) -> bool {
+        self.public_key.is_some()
+    }
+
+    /// Tiers of the `tr` keys, as accepted by [`crate::model::Torrent::from_info`].
+    pub fn announce_tiers(&self) -> Vec<Vec<String>> {
+        self.trackers.iter().map(|tracker| vec![tracker.clone()]).collect()
+    }
+}
+
+fn parse_info_hash(encoded: &str) -> Option<Sha1Hash> {
+    match encoded.len() {
+        40 => {
+            let mut hash = [0; SHA1_HASH_LEN];
+            hex::decode_to_slice(encoded, &mut hash).ok()?;
+            Some(hash)
+        }
+        32 => base32_decode(encoded)?.try_into().ok(),
+        _ => None,
+    }
+}
+
+/// RFC 4648 base32 without padding, as older magnet links encode info hashes.
+fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    let (mut buffer, mut bits) = (0u64, 0);
+    for c in encoded.bytes() {
+        let value = BASE32_ALPHABET.iter().position(|letter| *letter == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_magnet_links() {
+        let magnet = MagnetLink::parse("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn=ubuntu.iso\
+            &tr=udp%3A%2F%2Ftracker.example.org%3A6969&tr=http%3A%2F%2Fexample.org%2Fannounce&x.pe=10.0.0.1:6881").unwrap();
+        assert_eq!(magnet.info_hash.map(hex::encode).as_deref(), Some("c12fe1c06bba254a9dc9f519b335aa7c1367a88a"));
+        assert_eq!(magnet.name.as_deref(), Some("ubuntu.iso"));
+        assert_eq!(magnet.announce_tiers(), vec![
+            vec![String::from("udp://tracker.example.org:6969")],
+            vec![String::from("http://example.org/announce")],
+        ]);
+        assert_eq!(magnet.peers, vec!["10.0.0.1:6881".parse().unwrap()]);
+        assert!(!magnet.is_mutable());
+
+        let base32 = MagnetLink::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK").unwrap();
+        assert_eq!(base32.info_hash, magnet.info_hash);
+
+        // Example of BEP 46.
+        let mutable = MagnetLink::parse("magnet:?xs=urn:btpk:8543d3e6115f0f98c944077a4493dcd543e49c739fd998550a1f614ab36ed63e&s=6e").unwrap();
+        assert_eq!(mutable.public_key.map(hex::encode).as_deref(), Some("8543d3e6115f0f98c944077a4493dcd543e49c739fd998550a1f614ab36ed63e"));
+        assert_eq!(mutable.salt, b"n");
+        assert!(mutable.is_mutable());
+
+        for link in ["http://example.org", "magnet:?dn=nothing", "magnet:?xt=urn:btih:abc", "magnet:?xs=urn:btpk:zz"] {
+            assert!(MagnetLink::parse(link).is_err(), "{link}");
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use std::io;
 use bit_vec::BitVec;
 
 pub const CHOKE: u8 = 0;
@@ -9,8 +10,16 @@ pub const BITFIELD: u8 = 5;
 pub const REQUEST: u8 = 6;
 pub const PIECE: u8 = 7;
 pub const CANCEL: u8 = 8;
+/// Extension protocol (BEP 10) messages, only valid once both peers set the extension reserved bit.
+pub const EXTENDED: u8 = 20;
+/// Fast Extension (BEP 6) messages, only valid once both peers set the fast reserved bit.
+pub const SUGGEST_PIECE: u8 = 0x0d;
+pub const HAVE_ALL: u8 = 0x0e;
+pub const HAVE_NONE: u8 = 0x0f;
+pub const REJECT_REQUEST: u8 = 0x10;
+pub const ALLOWED_FAST: u8 = 0x11;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum PeerMessage {
     Choke, 
     Unchoke, 
@@ -34,4 +43,141 @@ pub enum PeerMessage {
         length: u32,
     },
     KeepAlive,
+    SuggestPiece(u32),
+    HaveAll,
+    HaveNone,
+    RejectRequest {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    AllowedFast(u32),
+    /// An extension protocol message: the extended message id and its payload.
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+}
+
+impl PeerMessage {
+    /// Decodes a message from its payload, the bytes following the length prefix.
+    pub fn from_payload(payload: &[u8]) -> Result<Self, io::Error> {
+        let Some((&message_type, body)) = payload.split_first() else {
+            return Ok(PeerMessage::KeepAlive);
+        };
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("Malformed peer message of type {message_type}"));
+        let u32_at = |offset: usize| body.get(offset..offset + 4)
+            .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+            .ok_or_else(invalid);
+        let expect_len = |len: usize| if body.len() == len { Ok(()) } else { Err(invalid()) };
+        match message_type {
+            CHOKE => expect_len(0).map(|_| PeerMessage::Choke),
+            UNCHOKE => expect_len(0).map(|_| PeerMessage::Unchoke),
+            INTERESTED => expect_len(0).map(|_| PeerMessage::Interested),
+            NOT_INTERESTED => expect_len(0).map(|_| PeerMessage::NotInterested),
+            HAVE_ALL => expect_len(0).map(|_| PeerMessage::HaveAll),
+            HAVE_NONE => expect_len(0).map(|_| PeerMessage::HaveNone),
+            HAVE | SUGGEST_PIECE | ALLOWED_FAST => {
+                expect_len(4)?;
+                let index = u32_at(0)?;
+                Ok(match message_type {
+                    HAVE => PeerMessage::Have(index),
+                    SUGGEST_PIECE => PeerMessage::SuggestPiece(index),
+                    _ => PeerMessage::AllowedFast(index),
+                })
+            },
+            BITFIELD => Ok(PeerMessage::Bitfield(BitVec::from_bytes(body))),
+            REQUEST | CANCEL | REJECT_REQUEST => {
+                expect_len(4 * 3)?;
+                let (index, begin, length) = (u32_at(0)?, u32_at(4)?, u32_at(8)?);
+                Ok(match message_type {
+                    REQUEST => PeerMessage::Request { index, begin, length },
+                    CANCEL => PeerMessage::Cancel { index, begin, length },
+                    _ => PeerMessage::RejectRequest { index, begin, length },
+                })
+            },
+            EXTENDED => {
+                let (&id, payload) = body.split_first().ok_or_else(invalid)?;
+                Ok(PeerMessage::Extended { id, payload: payload.to_vec() })
+            },
+            PIECE => Ok(PeerMessage::Piece { index: u32_at(0)?, begin: u32_at(4)?, piece: body[4 * 2..].to_vec() }),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown peer message type {message_type}"))),
+        }
+    }
+
+    /// Encodes the message with its length prefix.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (message_type, body): (u8, Vec<u8>) = match self {
+            PeerMessage::KeepAlive => return 0u32.to_be_bytes().to_vec(),
+            PeerMessage::Choke => (CHOKE, vec![]),
+            PeerMessage::Unchoke => (UNCHOKE, vec![]),
+            PeerMessage::Interested => (INTERESTED, vec![]),
+            PeerMessage::NotInterested => (NOT_INTERESTED, vec![]),
+            PeerMessage::HaveAll => (HAVE_ALL, vec![]),
+            PeerMessage::HaveNone => (HAVE_NONE, vec![]),
+            PeerMessage::Have(index) => (HAVE, index.to_be_bytes().to_vec()),
+            PeerMessage::SuggestPiece(index) => (SUGGEST_PIECE, index.to_be_bytes().to_vec()),
+            PeerMessage::AllowedFast(index) => (ALLOWED_FAST, index.to_be_bytes().to_vec()),
+            PeerMessage::Bitfield(bits) => (BITFIELD, bits.to_bytes()),
+            PeerMessage::Request { index, begin, length } => (REQUEST, [index.to_be_bytes(), begin.to_be_bytes(), length.to_be_bytes()].concat()),
+            PeerMessage::Cancel { index, begin, length } => (CANCEL, [index.to_be_bytes(), begin.to_be_bytes(), length.to_be_bytes()].concat()),
+            PeerMessage::RejectRequest { index, begin, length } => {
+                (REJECT_REQUEST, [index.to_be_bytes(), begin.to_be_bytes(), length.to_be_bytes()].concat())
+            },
+            PeerMessage::Extended { id, payload } => (EXTENDED, [&[*id][..], payload].concat()),
+            PeerMessage::Piece { index, begin, piece } => (PIECE, [&index.to_be_bytes()[..], &begin.to_be_bytes(), piece].concat()),
+        };
+        let mut buf = Vec::with_capacity(4 + 1 + body.len());
+        buf.extend_from_slice(&(1 + body.len() as u32).to_be_bytes());
+        buf.push(message_type);
+        buf.extend_from_slice(&body);
+        buf
+    }
+
+    /// Whether the message belongs to the Fast Extension.
+    pub fn is_fast_extension(&self) -> bool {
+        matches!(self,
+            PeerMessage::SuggestPiece(_)
+            | PeerMessage::HaveAll
+            | PeerMessage::HaveNone
+            | PeerMessage::RejectRequest { .. }
+            | PeerMessage::AllowedFast(_))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages_round_trip() {
+        let messages = [
+            PeerMessage::Choke,
+            PeerMessage::Have(7),
+            PeerMessage::Bitfield(BitVec::from_bytes(&[0b1010_0000])),
+            PeerMessage::Request { index: 1, begin: 16384, length: 16384 },
+            PeerMessage::Piece { index: 1, begin: 0, piece: vec![1, 2, 3] },
+            PeerMessage::SuggestPiece(3),
+            PeerMessage::HaveAll,
+            PeerMessage::HaveNone,
+            PeerMessage::RejectRequest { index: 2, begin: 0, length: 16384 },
+            PeerMessage::AllowedFast(9),
+            PeerMessage::Extended { id: 0, payload: b"de".to_vec() },
+        ];
+        for message in messages {
+            let bytes = message.to_bytes();
+            assert_eq!(u32::from_be_bytes(bytes[..4].try_into().unwrap()) as usize, bytes.len() - 4);
+            assert_eq!(PeerMessage::from_payload(&bytes[4..]).unwrap(), message);
+        }
+        assert_eq!(PeerMessage::HaveAll.to_bytes(), vec![0, 0, 0, 1, HAVE_ALL]);
+        assert_eq!(PeerMessage::AllowedFast(1).to_bytes(), vec![0, 0, 0, 5, ALLOWED_FAST, 0, 0, 0, 1]);
+        assert_eq!(PeerMessage::from_payload(&[]).unwrap(), PeerMessage::KeepAlive);
+    }
+
+    #[test]
+    fn test_malformed_messages() {
+        for payload in [&[HAVE_ALL, 0][..], &[REJECT_REQUEST, 0, 0, 0, 1], &[ALLOWED_FAST], &[PIECE, 0], &[EXTENDED], &[0x42]] {
+            assert_eq!(PeerMessage::from_payload(payload).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
 }
\ No newline at end of file
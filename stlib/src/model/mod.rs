@@ -1,10 +1,12 @@
 mod tracker;
 mod torrent;
 mod message;
+mod magnet;
 
 pub use tracker::*;
 pub use torrent::*;
 pub use message::*;
+pub use magnet::*;
 
 pub const SHA1_HASH_LEN: usize = 20;
 
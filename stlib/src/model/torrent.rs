@@ -1,15 +1,16 @@
-use std::fs::File;
 use std::{fs, io};
 use std::path::{Path, PathBuf};
 use serde::{Deserialize, Serialize};
 use crate::model::{SHA1_HASH_LEN, Sha1Hash};
 
+use crate::util::bencode::dict_value;
 use crate::util::common::sha1_hash;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Torrent {
     pub announce: String,
     pub announce_list: Vec<String>,
+    pub announce_tiers: Vec<Vec<String>>,
     pub created_by :String,
     pub comment: String,
     pub encoding: String,
@@ -18,15 +19,21 @@ pub struct Torrent {
     pub pieces: Vec<Sha1Hash>,
     pub info_hash: Sha1Hash,
     pub variant: TorrentVariant,
+    /// Set by the `private` info key (BEP 27): peers may only come from the torrent's trackers.
+    pub private: bool,
+    /// The bencoded info dictionary the info hash is computed from, as served to peers (BEP 9).
+    pub info_bytes: Vec<u8>,
+    /// DHT nodes to bootstrap from, as host and port (BEP 5).
+    pub nodes: Vec<(String, u16)>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum TorrentVariant {
     SingleFile(u64),
     MultiFile(Vec<FileEntry>)
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct FileEntry {
     pub path: PathBuf,
     pub length: u64,
@@ -44,14 +51,45 @@ impl Torrent {
             created_by,
             comment,
             encoding,
+            nodes,
             info,
         } = match serde_bencode::from_bytes(content) {
             Ok(torrent) => torrent,
             Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidInput, err)),
         };
-        let info_hash = info.sha1_hash();
+        // Re-encoding the parsed dictionary would drop the keys we don't model, like `source`.
+        let info_bytes = dict_value(content, b"info")
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Malformed info dictionary"))?
+            .to_vec();
+        let announce_tiers = Self::make_announce_tiers(&announce, &announce_list);
+        let mut torrent = Self::from_info_dict(info, info_bytes)?;
+        torrent.announce = announce;
+        torrent.announce_list = announce_list.into_iter().flatten().collect();
+        torrent.announce_tiers = announce_tiers;
+        torrent.created_by = created_by;
+        torrent.comment = comment;
+        torrent.encoding = encoding;
+        torrent.nodes = nodes;
+        Ok(torrent)
+    }
+
+    /// Builds a torrent from an info dictionary obtained from peers (BEP 9), announcing to
+    /// `announce_tiers`. The info hash is the hash of `info_bytes` as given.
+    pub fn from_info(info_bytes: &[u8], announce_tiers: Vec<Vec<String>>) -> Result<Self, io::Error> {
+        let info: TorrentInfo = serde_bencode::from_bytes(info_bytes)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let mut torrent = Self::from_info_dict(info, info_bytes.to_vec())?;
+        torrent.announce = announce_tiers.first().and_then(|tier| tier.first()).cloned().unwrap_or_default();
+        torrent.announce_list = announce_tiers.iter().flatten().cloned().collect();
+        torrent.announce_tiers = announce_tiers.into_iter().filter(|tier| !tier.is_empty()).collect();
+        Ok(torrent)
+    }
+
+    fn from_info_dict(info: TorrentInfo, info_bytes: Vec<u8>) -> Result<Self, io::Error> {
+        let info_hash = sha1_hash(&info_bytes);
+        let private = info.is_private();
         let (name, piece_length, pieces, variant) = match info {
-            TorrentInfo::MultiFile { name, piece_length, pieces, files } => {
+            TorrentInfo::MultiFile { name, piece_length, pieces, files, .. } => {
                 (name, piece_length, pieces, TorrentVariant::MultiFile(files.into_iter()
                     .map(|file| {
                         let FileEntryBencode { length, path } = file;
@@ -59,30 +97,56 @@ impl Torrent {
                         for component in path {
                             path_buf.push(component);
                         }
-                        FileEntry { path: path_buf, length: file.length }
+                        FileEntry { path: path_buf, length }
                     }).collect()))
             }
-            TorrentInfo::SingleFile { name, piece_length, pieces, length }
+            TorrentInfo::SingleFile { name, piece_length, pieces, length, .. }
                 => (name, piece_length, pieces, TorrentVariant::SingleFile(length))
         };
+        if piece_length == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, String::from("Piece length is zero")));
+        }
         if pieces.len() % SHA1_HASH_LEN != 0 {
             return Err(io::Error::new(io::ErrorKind::InvalidInput, String::from("Pieces hashes aren't multiple of 20")));
         }
         Ok(Self {
-            announce,
-            announce_list: announce_list.into_iter().flatten().collect(),
-            created_by,
-            comment,
-            encoding,
+            announce: String::new(),
+            announce_list: vec![],
+            announce_tiers: vec![],
+            created_by: String::new(),
+            comment: String::new(),
+            encoding: utf_8(),
             root_name: name,
             piece_length,
             pieces: pieces.chunks(SHA1_HASH_LEN).map(|hash| hash.try_into().unwrap()).collect(),
             info_hash,
             variant,
+            private,
+            info_bytes,
+            nodes: vec![],
         })
     }
 }
 
+impl Torrent {
+    /// Tiers as described by BEP 12: `announce-list` takes precedence over `announce`,
+    /// and empty tiers are dropped.
+    fn make_announce_tiers(announce: &str, announce_list: &[Vec<String>]) -> Vec<Vec<String>> {
+        let tiers: Vec<Vec<String>> = announce_list.iter()
+            .filter(|tier| !tier.is_empty())
+            .cloned()
+            .collect();
+        if !tiers.is_empty() {
+            return tiers;
+        }
+        if announce.is_empty() {
+            vec![]
+        } else {
+            vec![vec![announce.to_string()]]
+        }
+    }
+}
+
 impl Torrent {
     pub fn is_single_file(&self) -> bool {
         match self.variant {
@@ -115,8 +179,76 @@ impl Torrent {
     }
 }
 
+impl Torrent {
+    /// Files of `previous` the torrent can take over as they are, e.g. when a mutable torrent
+    /// (BEP 46) moves to a new version. They have the same path and length, and the pieces
+    /// wholly inside them have the same hashes; files holding no whole piece can't be told
+    /// apart from changed ones and are left out. Pieces shared with neighbouring files should
+    /// still be checked once taken over. Paths start with the root name.
+    pub fn identical_files(&self, previous: &Torrent) -> Vec<PathBuf> {
+        let previous_files = previous.file_spans();
+        self.file_spans().into_iter()
+            .filter(|(path, offset, length)| previous_files.iter().any(|(previous_path, previous_offset, previous_length)| {
+                previous_path == path
+                    && previous_length == length
+                    && self.same_pieces(previous, *offset, *previous_offset, *length)
+            }))
+            .map(|(path, ..)| path)
+            .collect()
+    }
+
+    /// Path, offset and length of every file, in the order of the pieces.
+    fn file_spans(&self) -> Vec<(PathBuf, u64, u64)> {
+        match &self.variant {
+            TorrentVariant::SingleFile(length) => vec![(PathBuf::from(&self.root_name), 0, *length)],
+            TorrentVariant::MultiFile(files) => {
+                let mut offset = 0;
+                files.iter()
+                    .map(|file| {
+                        offset += file.length;
+                        (Path::new(&self.root_name).join(&file.path), offset - file.length, file.length)
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Whether `length` bytes at `offset` wholly hold pieces, which all hash like those at
+    /// `previous_offset` in `previous`.
+    fn same_pieces(&self, previous: &Torrent, offset: u64, previous_offset: u64, length: u64) -> bool {
+        let piece_length = self.piece_length;
+        let first = offset.div_ceil(piece_length);
+        let end = (offset + length) / piece_length;
+        if first >= end {
+            return false;
+        }
+        let shift = first * piece_length - offset;
+        if previous.piece_length != piece_length || !(previous_offset + shift).is_multiple_of(piece_length) {
+            return false;
+        }
+        let previous_first = (previous_offset + shift) / piece_length;
+        (first..end).all(|index| {
+            let previous_index = previous_first + index - first;
+            self.pieces.get(index as usize).is_some()
+                && self.pieces.get(index as usize) == previous.pieces.get(previous_index as usize)
+        })
+    }
+}
+
+#[cfg(test)]
+impl Torrent {
+    /// A torrent of a single one byte piece, announcing to `trackers` one tier each.
+    pub(crate) fn fixture(private: bool, trackers: &[&str]) -> Self {
+        let info = format!("d6:lengthi1e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei{}ee", private as u8);
+        let tiers: String = trackers.iter().map(|url| format!("l{}:{url}e", url.len())).collect();
+        let content = format!("d8:announce0:13:announce-listl{tiers}e4:info{info}e");
+        Self::from_bencode(content.as_bytes()).unwrap()
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 struct TorrentBencode {
+    #[serde(default = "empty_string")]
     pub announce: String,
     #[serde(rename = "announce-list", default = "empty_vec")]
     pub announce_list: Vec<Vec<String>>,
@@ -127,6 +259,9 @@ struct TorrentBencode {
 
     #[serde(default = "utf_8")]
     pub encoding: String,
+    /// DHT nodes of a trackerless torrent (BEP 5), as host and port.
+    #[serde(default)]
+    pub nodes: Vec<(String, u16)>,
 
     pub info: TorrentInfo,
 }
@@ -141,6 +276,8 @@ enum TorrentInfo {
         #[serde(with = "serde_bytes")]
         pieces: Vec<u8>,
         files: Vec<FileEntryBencode>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        private: Option<i64>,
     },
     SingleFile {
         name: String,
@@ -149,14 +286,16 @@ enum TorrentInfo {
         #[serde(with = "serde_bytes")]
         pieces: Vec<u8>,
         length: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        private: Option<i64>,
     }
 }
 
 impl TorrentInfo {
-    pub fn sha1_hash(&self) -> Sha1Hash {
-        let serialized_bytes = serde_bencode::to_bytes(self)
-            .expect("Couldn't serialize info dictionary for torrent file");
-        sha1_hash(serialized_bytes)
+    fn is_private(&self) -> bool {
+        match self {
+            TorrentInfo::MultiFile { private, .. } | TorrentInfo::SingleFile { private, .. } => *private == Some(1),
+        }
     }
 }
 
@@ -193,7 +332,69 @@ mod tests {
         assert_eq!(torrent.comment, "");
         assert_eq!(torrent.root_name, "sample.txt");
         assert_eq!(hex::encode(torrent.info_hash), "d69f91e6b2ae4c542468d1073a71d4ea13879a7f");
+        assert_eq!(torrent.announce_tiers, vec![vec![torrent.announce.clone()]]);
         assert!(matches!(torrent.variant, TorrentVariant::SingleFile(92063)));
+        assert!(!torrent.private);
+    }
+
+    #[test]
+    fn test_parse_private_torrent() {
+        let info = b"d6:lengthi1e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1ee";
+        let content = [&b"d8:announce20:http://t.example/ann4:info"[..], info, b"e"].concat();
+        let torrent = Torrent::from_bencode(&content).unwrap();
+        assert!(torrent.private);
+        assert_eq!(torrent.info_hash, sha1_hash(info));
+        assert_eq!(torrent.info_bytes, info);
+    }
+
+    #[test]
+    fn test_info_hash_covers_unknown_keys() {
+        let info = b"d5:filesld4:attr1:x6:lengthi1e4:pathl1:aeee4:name1:r10:name.utf-81:r12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa6:source3:abce";
+        let content = [&b"d8:announce20:http://t.example/ann4:info"[..], info, b"e"].concat();
+        let torrent = Torrent::from_bencode(&content).unwrap();
+        assert_eq!(torrent.info_bytes, info);
+        assert_eq!(torrent.info_hash, sha1_hash(info));
+    }
+
+    #[test]
+    fn test_parse_trackerless_torrent_nodes() {
+        let content = b"d4:infod6:lengthi1e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae5:nodesll9:127.0.0.1i6881eel15:dht.example.comi6882eeee";
+        let torrent = Torrent::from_bencode(content).unwrap();
+        assert!(torrent.announce_tiers.is_empty());
+        assert_eq!(torrent.nodes, vec![(String::from("127.0.0.1"), 6881), (String::from("dht.example.com"), 6882)]);
+    }
+
+    #[test]
+    fn test_torrent_from_info() {
+        let torrent = Torrent::from_file("test-resources/torrent/bunny.torrent").unwrap();
+        let rebuilt = Torrent::from_info(&torrent.info_bytes, vec![vec![String::from("udp://a.example:1")], vec![]]).unwrap();
+        assert_eq!(rebuilt.info_hash, torrent.info_hash);
+        assert_eq!(rebuilt.get_files(), torrent.get_files());
+        assert_eq!(rebuilt.pieces, torrent.pieces);
+        assert_eq!((rebuilt.announce.as_str(), rebuilt.announce_tiers.len()), ("udp://a.example:1", 1));
+        assert!(Torrent::from_info(b"d4:name1:ae", vec![]).is_err());
+        assert!(Torrent::from_info(b"d6:lengthi1e4:name1:a12:piece lengthi0e6:pieces20:aaaaaaaaaaaaaaaaaaaae", vec![]).is_err());
+    }
+
+    #[test]
+    fn test_identical_files() {
+        // Pieces of 4 bytes, hashed as 20 times the given letter.
+        let torrent = |files: &[(&str, u64)], pieces: &str| {
+            let files: String = files.iter().map(|(path, length)| format!("d6:lengthi{length}e4:pathl{}:{path}ee", path.len())).collect();
+            let pieces: String = pieces.chars().map(|piece| piece.to_string().repeat(SHA1_HASH_LEN)).collect();
+            let info = format!("d5:filesl{files}e4:name4:root12:piece lengthi4e6:pieces{}:{pieces}e", pieces.len());
+            Torrent::from_info(info.as_bytes(), vec![]).unwrap()
+        };
+        let previous = torrent(&[("a", 8), ("b", 4), ("c", 2)], "xyzw");
+        let next = torrent(&[("a", 8), ("b", 4), ("c", 2), ("d", 6)], "xyvwu");
+        // `c` shares its only piece with `d`, so whether it changed is unknown.
+        assert_eq!(next.identical_files(&previous), vec![PathBuf::from("root/a")]);
+
+        // Pieces no longer line up with a file moved by a new one before it.
+        let shifted = torrent(&[("new", 2), ("a", 8)], "pqrs");
+        assert!(shifted.identical_files(&previous).is_empty());
+        let single = Torrent::from_info(b"d6:lengthi8e4:name4:root12:piece lengthi4e6:pieces40:xxxxxxxxxxxxxxxxxxxxyyyyyyyyyyyyyyyyyyyye", vec![]).unwrap();
+        assert_eq!(single.identical_files(&single), vec![PathBuf::from("root")]);
     }
 
     #[test]
@@ -210,11 +411,13 @@ mod tests {
             "wss://tracker.openwebtorrent.com".to_string(),
             "wss://tracker.fastcast.nz".to_string(),
         ]);
+        assert_eq!(torrent.announce_tiers.len(), 8);
+        assert!(torrent.announce_tiers.iter().all(|tier| tier.len() == 1));
         assert_eq!(torrent.comment, "WebTorrent <https://webtorrent.io>");
         assert_eq!(torrent.created_by, "WebTorrent <https://webtorrent.io>");
         assert_eq!(torrent.encoding, "UTF-8");
         assert!(torrent.is_multi_file());
-        let files = vec![
+        let files = [
             FileEntry { path: PathBuf::from("Big Buck Bunny.en.srt"), length: 140 },
             FileEntry { path: PathBuf::from("Big Buck Bunny.mp4"), length: 276134947 },
             FileEntry { path: PathBuf::from("poster.jpg"), length: 310380 },
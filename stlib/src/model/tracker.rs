@@ -1,67 +1,233 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use serde::Deserialize;
-use crate::model::Sha1Hash;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use crate::tracker::{RetryIn, TrackerError};
+use crate::util::bencode;
 
-#[derive(Debug)]
+const COMPACT_PEER_LEN: usize = 4 + 2;
+const COMPACT_PEER6_LEN: usize = 16 + 2;
+
+#[derive(Debug, Default)]
 pub struct TrackerNetworkInfo {
     pub interval: u32,
-    pub leechers: Option<u32>,
-    pub seeders: Option<u32>,
+    pub min_interval: Option<u32>,
+    /// Number of seeders.
+    pub complete: Option<u32>,
+    /// Number of leechers.
+    pub incomplete: Option<u32>,
     pub peers: Vec<PeerInfo>,
+    pub warning_message: Option<String>,
+    /// Opaque id that must be echoed back on the following announces.
+    pub tracker_id: Option<String>,
+    /// Our address as seen by the tracker (BEP 24).
+    pub external_ip: Option<IpAddr>,
 } 
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct PeerInfo {
     pub socket_addr: SocketAddr,
 }
 
 impl TrackerNetworkInfo {
-    pub fn from_bencode(bytes: &[u8]) -> Result<Self, String> {
-        let response: TrackerDiscoveryResponse =  serde_bencode::from_bytes(bytes).unwrap();
-        match response {
-            TrackerDiscoveryResponse::Error { failure_reason } => Err(failure_reason),
-            TrackerDiscoveryResponse::Response { interval, leechers, seeders, peers } => Ok(Self {
-                interval,
-                leechers,
-                seeders,
-                peers: Self::parse_peers(peers),
-            })
+    pub fn from_bencode(bytes: &[u8]) -> Result<Self, TrackerError> {
+        let response: TrackerDiscoveryResponse = bencode::from_bytes(bytes)?;
+        if let Some(failure_reason) = response.failure_reason {
+            return Err(TrackerError::Failure {
+                reason: lossy_string(failure_reason),
+                retry_in: response.retry_in.and_then(RetryInBencode::into_retry_in),
+            });
+        }
+        let mut peers = match response.peers {
+            Some(peers) => Self::parse_peers(peers)?,
+            None => vec![],
+        };
+        if let Some(peers6) = &response.peers6 {
+            peers.extend(parse_compact_peers6(peers6)?);
         }
+        Ok(Self {
+            interval: response.interval.unwrap_or(0),
+            min_interval: response.min_interval,
+            complete: response.complete,
+            incomplete: response.incomplete,
+            peers,
+            warning_message: response.warning_message.map(lossy_string),
+            tracker_id: response.tracker_id.map(lossy_string),
+            external_ip: response.external_ip.and_then(|ip| parse_external_ip(&ip)),
+        })
     }
 
-    fn parse_peers(peers: TrackersPeersResponse) -> Vec<PeerInfo> {
+    fn parse_peers(peers: TrackersPeersResponse) -> Result<Vec<PeerInfo>, TrackerError> {
         match peers {
-            TrackersPeersResponse::Legacy(peers) => peers.into_iter()
-                .map(|peer| PeerInfo { socket_addr: SocketAddr::new(peer.ip.parse().unwrap(), peer.port) })
-                .collect(),
-            TrackersPeersResponse::Compact(peers) => peers.chunks(6).map(|chunk| PeerInfo {
-                socket_addr: SocketAddr::new(IpAddr::from(
-                    Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3])), u16::from_be_bytes(chunk[4..].try_into().unwrap())
-                ),
-            }).collect()
+            TrackersPeersResponse::Legacy(peers) => Ok(peers.into_iter()
+                .filter_map(|peer| legacy_peer_addr(&lossy_string(peer.ip), peer.port))
+                .map(|socket_addr| PeerInfo { socket_addr })
+                .collect()),
+            TrackersPeersResponse::Compact(peers) => parse_compact_peers(&peers),
         }
     }
+
+    /// Encodes the response as a tracker would send it. IPv6 peers always go in the compact
+    /// `peers6` key (BEP 7); IPv4 peers are compact (BEP 23) or a list of dictionaries.
+    pub fn to_bencode(&self, compact: bool) -> Vec<u8> {
+        let (peers_v4, peers_v6): (Vec<&PeerInfo>, Vec<&PeerInfo>) = self.peers.iter()
+            .partition(|peer| peer.socket_addr.is_ipv4());
+        let peers = if compact {
+            TrackersPeersResponse::Compact(peers_v4.iter().flat_map(|peer| compact_peer(&peer.socket_addr)).collect())
+        } else {
+            TrackersPeersResponse::Legacy(peers_v4.iter()
+                .map(|peer| LegacyPeerInfo {
+                    ip: ByteBuf::from(peer.socket_addr.ip().to_string()),
+                    port: peer.socket_addr.port(),
+                })
+                .collect())
+        };
+        let response = TrackerDiscoveryResponse {
+            warning_message: self.warning_message.clone().map(ByteBuf::from),
+            interval: Some(self.interval),
+            min_interval: self.min_interval,
+            tracker_id: self.tracker_id.clone().map(ByteBuf::from),
+            complete: self.complete,
+            incomplete: self.incomplete,
+            peers: Some(peers),
+            peers6: if peers_v6.is_empty() {
+                None
+            } else {
+                Some(ByteBuf::from(peers_v6.iter().flat_map(|peer| compact_peer(&peer.socket_addr)).collect::<Vec<u8>>()))
+            },
+            external_ip: self.external_ip.map(|ip| ByteBuf::from(match ip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            })),
+            ..Default::default()
+        };
+        serde_bencode::to_bytes(&response).expect("Tracker response is always serializable")
+    }
 }
 
+/// Encodes a `failure reason` response, with the BEP 31 `retry in` key when given.
+pub fn failure_to_bencode(reason: &str, retry_in: Option<RetryIn>) -> Vec<u8> {
+    let response = TrackerDiscoveryResponse {
+        failure_reason: Some(ByteBuf::from(reason)),
+        retry_in: retry_in.map(|retry_in| match retry_in {
+            RetryIn::Minutes(minutes) => RetryInBencode::Minutes(minutes),
+            RetryIn::Never => RetryInBencode::Text(b"never".to_vec()),
+        }),
+        ..Default::default()
+    };
+    serde_bencode::to_bytes(&response).expect("Tracker response is always serializable")
+}
 
+/// Compact representation of an address: the address bytes followed by the port, both big endian.
+pub fn compact_peer(socket_addr: &SocketAddr) -> Vec<u8> {
+    let mut bytes = match socket_addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    bytes.extend_from_slice(&socket_addr.port().to_be_bytes());
+    bytes
+}
 
-#[derive(Debug, Deserialize)]
-#[serde(untagged)]
-enum TrackerDiscoveryResponse {
-    Error {
-        #[serde(rename = "failure reason")]
-        failure_reason: String,
-    },
-    Response {
-        interval: u32,
-        leechers: Option<u32>,
-        seeders: Option<u32>,
-        peers: TrackersPeersResponse,
+/// Parses the BEP 7 compact IPv6 peer list: 16 bytes of address followed by 2 bytes of port per peer.
+pub fn parse_compact_peers6(bytes: &[u8]) -> Result<Vec<PeerInfo>, TrackerError> {
+    if !bytes.len().is_multiple_of(COMPACT_PEER6_LEN) {
+        return Err(TrackerError::InvalidResponse(format!(
+            "Compact peers6 length {} isn't a multiple of {COMPACT_PEER6_LEN}", bytes.len()
+        )));
     }
-} 
+    Ok(bytes.chunks_exact(COMPACT_PEER6_LEN)
+        .map(|chunk| {
+            let octets: [u8; 16] = chunk[..16].try_into().expect("chunk is 18 bytes long");
+            PeerInfo {
+                socket_addr: SocketAddr::new(IpAddr::from(Ipv6Addr::from(octets)), u16::from_be_bytes([chunk[16], chunk[17]])),
+            }
+        })
+        .collect())
+}
+
+/// Parses the BEP 23 compact peer list: 4 bytes of IPv4 address followed by 2 bytes of port per peer.
+pub fn parse_compact_peers(bytes: &[u8]) -> Result<Vec<PeerInfo>, TrackerError> {
+    if !bytes.len().is_multiple_of(COMPACT_PEER_LEN) {
+        return Err(TrackerError::InvalidResponse(format!(
+            "Compact peers length {} isn't a multiple of {COMPACT_PEER_LEN}", bytes.len()
+        )));
+    }
+    Ok(bytes.chunks_exact(COMPACT_PEER_LEN)
+        .map(|chunk| PeerInfo {
+            socket_addr: SocketAddr::new(
+                IpAddr::from(Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3])),
+                u16::from_be_bytes([chunk[4], chunk[5]]),
+            ),
+        })
+        .collect())
+}
+
+/// Legacy peer lists may carry a hostname instead of an address. Such entries are skipped
+/// rather than resolved: decoding must not block, nor look up names a tracker chooses.
+fn legacy_peer_addr(ip: &str, port: u16) -> Option<SocketAddr> {
+    ip.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, port))
+}
+
+fn lossy_string(bytes: ByteBuf) -> String {
+    String::from_utf8_lossy(&bytes).into_owned()
+}
 
+fn parse_external_ip(bytes: &[u8]) -> Option<IpAddr> {
+    if let Ok(octets) = <[u8; 4]>::try_from(bytes) {
+        Some(IpAddr::from(Ipv4Addr::from(octets)))
+    } else if let Ok(octets) = <[u8; 16]>::try_from(bytes) {
+        Some(IpAddr::from(Ipv6Addr::from(octets)))
+    } else {
+        None
+    }
+}
+
+/// Every key is optional so that a tracker omitting one of them is reported
+/// through the resulting [`TrackerNetworkInfo`] instead of failing the whole response.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct TrackerDiscoveryResponse {
+    #[serde(rename = "failure reason", skip_serializing_if = "Option::is_none")]
+    failure_reason: Option<ByteBuf>,
+    #[serde(rename = "retry in", skip_serializing_if = "Option::is_none")]
+    retry_in: Option<RetryInBencode>,
+    #[serde(rename = "warning message", skip_serializing_if = "Option::is_none")]
+    warning_message: Option<ByteBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interval: Option<u32>,
+    #[serde(rename = "min interval", skip_serializing_if = "Option::is_none")]
+    min_interval: Option<u32>,
+    #[serde(rename = "tracker id", skip_serializing_if = "Option::is_none")]
+    tracker_id: Option<ByteBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    complete: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    incomplete: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peers: Option<TrackersPeersResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peers6: Option<ByteBuf>,
+    #[serde(rename = "external ip", skip_serializing_if = "Option::is_none")]
+    external_ip: Option<ByteBuf>,
+}
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum RetryInBencode {
+    Minutes(u32),
+    #[serde(with = "serde_bytes")]
+    Text(Vec<u8>),
+}
+
+impl RetryInBencode {
+    fn into_retry_in(self) -> Option<RetryIn> {
+        match self {
+            Self::Minutes(minutes) => Some(RetryIn::Minutes(minutes)),
+            Self::Text(text) if text == b"never" => Some(RetryIn::Never),
+            Self::Text(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(untagged)]
 enum TrackersPeersResponse {
     #[serde(with = "serde_bytes")]
@@ -69,10 +235,98 @@ enum TrackersPeersResponse {
     Legacy(Vec<LegacyPeerInfo>),
 }
 
-#[derive(Debug, Deserialize)]
+/// Entry of a non-compact peer list. The `peer id` key is ignored since
+/// trackers drop it when `no_peer_id` is requested.
+#[derive(Debug, Serialize, Deserialize)]
 struct LegacyPeerInfo {
-    #[serde(with = "serde_bytes")]
-    pub id: Sha1Hash,
-    pub ip: String,
-    pub port: u16
-}
\ No newline at end of file
+    ip: ByteBuf,
+    port: u16,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::*;
+
+    #[test]
+    fn test_parse_full_response() {
+        let bytes = b"d8:completei5e11:external ip4:\x0a\x00\x00\x0110:incompletei3e8:intervali1800e12:min intervali900e5:peers6:\x7f\x00\x00\x01\x1a\xe110:tracker id3:abc15:warning message4:slowe";
+        let info = TrackerNetworkInfo::from_bencode(bytes).unwrap();
+        assert_eq!(info.interval, 1800);
+        assert_eq!(info.min_interval, Some(900));
+        assert_eq!(info.complete, Some(5));
+        assert_eq!(info.incomplete, Some(3));
+        assert_eq!(info.tracker_id.as_deref(), Some("abc"));
+        assert_eq!(info.warning_message.as_deref(), Some("slow"));
+        assert_eq!(info.external_ip, Some(IpAddr::from([10, 0, 0, 1])));
+        assert_eq!(info.peers, vec![PeerInfo { socket_addr: "127.0.0.1:6881".parse().unwrap() }]);
+    }
+
+    #[test]
+    fn test_parse_failure_with_retry_in() {
+        let err = TrackerNetworkInfo::from_bencode(b"d14:failure reason6:banned8:retry in5:nevere").unwrap_err();
+        assert!(matches!(err, TrackerError::Failure { ref reason, retry_in: Some(RetryIn::Never) } if reason == "banned"));
+
+        let err = TrackerNetworkInfo::from_bencode(b"d14:failure reason4:busy8:retry ini10ee").unwrap_err();
+        assert!(matches!(err, TrackerError::Failure { retry_in: Some(RetryIn::Minutes(10)), .. }));
+    }
+
+    #[test]
+    fn test_parse_response_with_missing_optional_keys() {
+        let info = TrackerNetworkInfo::from_bencode(b"d8:intervali900ee").unwrap();
+        assert_eq!(info.interval, 900);
+        assert!(info.peers.is_empty());
+        assert_eq!((info.complete, info.incomplete, info.min_interval), (None, None, None));
+    }
+
+    #[test]
+    fn test_parse_legacy_peers_without_peer_id() {
+        let bytes = b"d8:intervali900e5:peersld2:ip8:10.0.0.24:porti6881eed2:ip9:localhost4:porti51413eeee";
+        let info = TrackerNetworkInfo::from_bencode(bytes).unwrap();
+        // The hostname isn't resolved.
+        assert_eq!(info.peers, vec![PeerInfo { socket_addr: "10.0.0.2:6881".parse().unwrap() }]);
+    }
+
+    #[test]
+    fn test_parse_malformed_corpus() {
+        let entries = fs::read_dir("test-resources/tracker/malformed").unwrap();
+        for entry in entries {
+            let path = entry.unwrap().path();
+            let bytes = fs::read(&path).unwrap();
+            let result = TrackerNetworkInfo::from_bencode(&bytes);
+            assert!(matches!(result, Err(TrackerError::InvalidResponse(_))), "{path:?} gave {result:?}");
+        }
+    }
+
+    #[test]
+    fn test_parse_truncated_responses() {
+        let bytes = b"d8:completei5e10:incompletei3e8:intervali1800e5:peers12:\x7f\x00\x00\x01\x1a\xe1\x7f\x00\x00\x02\x1a\xe1e";
+        assert_eq!(TrackerNetworkInfo::from_bencode(bytes).unwrap().peers.len(), 2);
+        for len in 0..bytes.len() {
+            assert!(TrackerNetworkInfo::from_bencode(&bytes[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_response_round_trip() {
+        let info = TrackerNetworkInfo {
+            interval: 600,
+            complete: Some(1),
+            incomplete: Some(2),
+            peers: vec![
+                PeerInfo { socket_addr: "10.0.0.1:6881".parse().unwrap() },
+                PeerInfo { socket_addr: "[::1]:6882".parse().unwrap() },
+            ],
+            ..Default::default()
+        };
+        for compact in [true, false] {
+            let decoded = TrackerNetworkInfo::from_bencode(&info.to_bencode(compact)).unwrap();
+            assert_eq!(decoded.interval, 600);
+            assert_eq!((decoded.complete, decoded.incomplete), (Some(1), Some(2)));
+            assert_eq!(decoded.peers, info.peers);
+        }
+
+        let err = TrackerNetworkInfo::from_bencode(&failure_to_bencode("denied", Some(RetryIn::Minutes(5)))).unwrap_err();
+        assert!(matches!(err, TrackerError::Failure { ref reason, retry_in: Some(RetryIn::Minutes(5)) } if reason == "denied"));
+    }
+}
@@ -0,0 +1,41 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::util::proxy::{connect_tcp, ProxyConfig};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Opens an outgoing connection to a peer, through `proxy` when one is configured.
+pub async fn connect(addr: SocketAddr, proxy: Option<&ProxyConfig>) -> io::Result<TcpStream> {
+    timeout(CONNECT_TIMEOUT, connect_tcp(proxy, &addr.ip().to_string(), addr.port())).await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use crate::util::mock_proxy::MockProxy;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_connect_to_peer_through_socks5() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = listener.local_addr().unwrap();
+        let proxy = MockProxy::start(ProxyConfig::socks5("")).await.unwrap();
+
+        let mut stream = connect(peer_addr, Some(&proxy.config())).await.unwrap();
+        let (mut peer, _) = listener.accept().await.unwrap();
+        stream.write_all(b"\x13BitTorrent protocol").await.unwrap();
+        let mut buf = [0u8; 20];
+        peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"\x13BitTorrent protocol");
+        assert_eq!(proxy.relayed(), 1);
+
+        connect(peer_addr, None).await.unwrap();
+        assert_eq!(proxy.relayed(), 1);
+    }
+}
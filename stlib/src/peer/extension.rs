@@ -0,0 +1,335 @@
+//! Extension protocol (BEP 10): message id 20 carries an extended message id followed by its
+//! payload. Id 0 is the extended handshake, in which each side maps the extension names it
+//! supports to the ids it wants to receive them on.
+//!
+//! Extensions implement [`Extension`] and are registered per connection in an
+//! [`ExtensionRegistry`], which assigns their ids and routes messages to them.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use serde_bencode::value::Value;
+use tokio::time::Instant;
+
+use crate::model::PeerMessage;
+use crate::util::bencode;
+
+/// Extended message id of the extended handshake.
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+
+/// The extended handshake dictionary.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ExtendedHandshake {
+    /// Extension names mapped to the ids the sender receives them on; id 0 disables one.
+    pub m: BTreeMap<String, u8>,
+    /// Client name and version.
+    pub v: Option<String>,
+    /// The receiver's address as seen by the sender.
+    pub yourip: Option<IpAddr>,
+    /// Number of outstanding requests the sender accepts.
+    pub reqq: Option<u32>,
+    /// Listen port of the sender.
+    pub p: Option<u16>,
+    /// Size of the info dictionary (BEP 9).
+    pub metadata_size: Option<u64>,
+    /// Keys of other extensions, kept as decoded.
+    pub extra: BTreeMap<String, Value>,
+}
+
+impl ExtendedHandshake {
+    pub fn from_bencode(bytes: &[u8]) -> Result<Self, ExtensionError> {
+        let invalid = |reason: &str| ExtensionError::InvalidMessage(format!("Extended handshake: {reason}"));
+        let Ok(Value::Dict(dict)) = bencode::from_bytes::<Value>(bytes) else {
+            return Err(invalid("not a dictionary"));
+        };
+        let mut handshake = Self::default();
+        for (key, value) in dict {
+            let key = String::from_utf8_lossy(&key).into_owned();
+            match (key.as_str(), value) {
+                ("m", Value::Dict(m)) => {
+                    for (name, id) in m {
+                        let Value::Int(id) = id else {
+                            return Err(invalid("extension id isn't an integer"));
+                        };
+                        let id = u8::try_from(id).map_err(|_| invalid("extension id out of range"))?;
+                        handshake.m.insert(String::from_utf8_lossy(&name).into_owned(), id);
+                    }
+                },
+                ("m", _) => return Err(invalid("`m` isn't a dictionary")),
+                ("v", Value::Bytes(v)) => handshake.v = Some(String::from_utf8_lossy(&v).into_owned()),
+                ("yourip", Value::Bytes(ip)) => handshake.yourip = match ip.len() {
+                    4 => Some(IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(ip).unwrap()))),
+                    16 => Some(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(ip).unwrap()))),
+                    _ => None,
+                },
+                ("reqq", Value::Int(reqq)) => handshake.reqq = u32::try_from(reqq).ok(),
+                ("p", Value::Int(port)) => handshake.p = u16::try_from(port).ok(),
+                ("metadata_size", Value::Int(size)) => handshake.metadata_size = u64::try_from(size).ok(),
+                (_, value) => {
+                    handshake.extra.insert(key, value);
+                },
+            }
+        }
+        Ok(handshake)
+    }
+
+    pub fn to_bencode(&self) -> Vec<u8> {
+        let mut dict: HashMap<Vec<u8>, Value> = self.extra.iter()
+            .map(|(key, value)| (key.as_bytes().to_vec(), value.clone()))
+            .collect();
+        let m = self.m.iter()
+            .map(|(name, id)| (name.as_bytes().to_vec(), Value::Int(*id as i64)))
+            .collect();
+        dict.insert(b"m".to_vec(), Value::Dict(m));
+        if let Some(v) = &self.v {
+            dict.insert(b"v".to_vec(), Value::Bytes(v.as_bytes().to_vec()));
+        }
+        if let Some(ip) = self.yourip {
+            let octets = match ip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            };
+            dict.insert(b"yourip".to_vec(), Value::Bytes(octets));
+        }
+        if let Some(reqq) = self.reqq {
+            dict.insert(b"reqq".to_vec(), Value::Int(reqq as i64));
+        }
+        if let Some(port) = self.p {
+            dict.insert(b"p".to_vec(), Value::Int(port as i64));
+        }
+        if let Some(size) = self.metadata_size {
+            dict.insert(b"metadata_size".to_vec(), Value::Int(size as i64));
+        }
+        serde_bencode::to_bytes(&Value::Dict(dict)).expect("Extended handshake is always serializable")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExtensionError {
+    /// A payload couldn't be decoded; the connection should be closed.
+    InvalidMessage(String),
+    /// The peer sent an extended message id we never advertised.
+    UnknownId(u8),
+    /// An extension with the same name is already registered.
+    DuplicateName(String),
+    /// Every extended message id is taken.
+    TooManyExtensions,
+}
+
+impl fmt::Display for ExtensionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidMessage(reason) => write!(f, "Invalid extended message: {reason}"),
+            Self::UnknownId(id) => write!(f, "Unknown extended message id {id}"),
+            Self::DuplicateName(name) => write!(f, "Extension '{name}' is already registered"),
+            Self::TooManyExtensions => write!(f, "No extended message id left"),
+        }
+    }
+}
+
+impl std::error::Error for ExtensionError {}
+
+/// An extension of the peer wire protocol, instantiated once per connection.
+///
+/// Methods return the payloads to send to the peer under this extension's name; the registry
+/// adds the id the peer asked for and drops them if the peer doesn't support the extension.
+pub trait Extension: Send {
+    /// Name of the extension in the `m` dictionary, e.g. `ut_metadata`.
+    fn name(&self) -> &str;
+
+    /// Adds the extension's own keys to our extended handshake.
+    fn extend_handshake(&self, _handshake: &mut ExtendedHandshake) {}
+
+    /// Called when the peer's extended handshake arrives, if it supports the extension.
+    fn on_handshake(&mut self, _handshake: &ExtendedHandshake) -> Vec<Vec<u8>> {
+        vec![]
+    }
+
+    /// Handles a payload the peer sent for this extension.
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, ExtensionError>;
+
+    /// Called periodically, for extensions sending on their own schedule.
+    fn tick(&mut self, _now: Instant) -> Vec<Vec<u8>> {
+        vec![]
+    }
+}
+
+/// The extensions of a connection. Local ids follow the registration order, starting at 1.
+#[derive(Default)]
+pub struct ExtensionRegistry {
+    extensions: Vec<Box<dyn Extension>>,
+    remote: Option<ExtendedHandshake>,
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers an extension and returns the id the peer should send its messages with.
+    pub fn register(&mut self, extension: Box<dyn Extension>) -> Result<u8, ExtensionError> {
+        if self.local_id(extension.name()).is_some() {
+            return Err(ExtensionError::DuplicateName(extension.name().to_string()));
+        }
+        if self.extensions.len() >= u8::MAX as usize {
+            return Err(ExtensionError::TooManyExtensions);
+        }
+        self.extensions.push(extension);
+        Ok(self.extensions.len() as u8)
+    }
+
+    pub fn local_id(&self, name: &str) -> Option<u8> {
+        self.extensions.iter()
+            .position(|extension| extension.name() == name)
+            .map(|index| index as u8 + 1)
+    }
+
+    /// The peer's extended handshake, once received.
+    pub fn remote_handshake(&self) -> Option<&ExtendedHandshake> {
+        self.remote.as_ref()
+    }
+
+    /// The id the peer receives an extension's messages on, if it supports it.
+    pub fn remote_id(&self, name: &str) -> Option<u8> {
+        self.remote.as_ref()?.m.get(name).copied().filter(|id| *id != 0)
+    }
+
+    /// Our extended handshake: `base` with every registered extension in `m`.
+    pub fn handshake(&self, mut base: ExtendedHandshake) -> PeerMessage {
+        for (index, extension) in self.extensions.iter().enumerate() {
+            base.m.insert(extension.name().to_string(), index as u8 + 1);
+            extension.extend_handshake(&mut base);
+        }
+        PeerMessage::Extended { id: EXTENDED_HANDSHAKE_ID, payload: base.to_bencode() }
+    }
+
+    /// Routes an extended message to its extension and returns the messages to send back.
+    pub fn handle(&mut self, id: u8, payload: &[u8]) -> Result<Vec<PeerMessage>, ExtensionError> {
+        if id == EXTENDED_HANDSHAKE_ID {
+            let handshake = ExtendedHandshake::from_bencode(payload)?;
+            self.remote = Some(handshake.clone());
+            let mut messages = vec![];
+            for index in 0..self.extensions.len() {
+                if self.remote_id(self.extensions[index].name()).is_some() {
+                    let payloads = self.extensions[index].on_handshake(&handshake);
+                    messages.extend(self.wrap(index, payloads));
+                }
+            }
+            return Ok(messages);
+        }
+        let index = id as usize - 1;
+        let extension = self.extensions.get_mut(index).ok_or(ExtensionError::UnknownId(id))?;
+        let payloads = extension.on_message(payload)?;
+        Ok(self.wrap(index, payloads))
+    }
+
+    /// Gives every extension the peer supports a chance to send messages.
+    pub fn tick(&mut self, now: Instant) -> Vec<PeerMessage> {
+        let mut messages = vec![];
+        for index in 0..self.extensions.len() {
+            if self.remote_id(self.extensions[index].name()).is_some() {
+                let payloads = self.extensions[index].tick(now);
+                messages.extend(self.wrap(index, payloads));
+            }
+        }
+        messages
+    }
+
+    /// Wraps a payload for the named extension, if the peer supports it.
+    pub fn message(&self, name: &str, payload: Vec<u8>) -> Option<PeerMessage> {
+        self.remote_id(name).map(|id| PeerMessage::Extended { id, payload })
+    }
+
+    fn wrap(&self, index: usize, payloads: Vec<Vec<u8>>) -> Vec<PeerMessage> {
+        let name = self.extensions[index].name();
+        payloads.into_iter()
+            .filter_map(|payload| self.message(name, payload))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers every message with its payload reversed and says hello on handshake.
+    struct Echo {
+        name: &'static str,
+    }
+
+    impl Extension for Echo {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn extend_handshake(&self, handshake: &mut ExtendedHandshake) {
+            handshake.extra.insert(format!("{}_version", self.name), Value::Int(2));
+        }
+
+        fn on_handshake(&mut self, _handshake: &ExtendedHandshake) -> Vec<Vec<u8>> {
+            vec![b"hello".to_vec()]
+        }
+
+        fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, ExtensionError> {
+            if payload.is_empty() {
+                return Err(ExtensionError::InvalidMessage(String::from("empty")));
+            }
+            Ok(vec![payload.iter().rev().copied().collect()])
+        }
+    }
+
+    fn handshake_payload(message: PeerMessage) -> Vec<u8> {
+        match message {
+            PeerMessage::Extended { id: EXTENDED_HANDSHAKE_ID, payload } => payload,
+            message => panic!("unexpected {message:?}"),
+        }
+    }
+
+    #[test]
+    fn test_handshake_round_trip() {
+        let handshake = ExtendedHandshake {
+            m: BTreeMap::from([(String::from("ut_metadata"), 3), (String::from("ut_pex"), 0)]),
+            v: Some(String::from("engine 0.1")),
+            yourip: Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))),
+            reqq: Some(250),
+            p: Some(6881),
+            metadata_size: Some(31235),
+            extra: BTreeMap::from([(String::from("tr"), Value::Bytes(vec![1; 20]))]),
+        };
+        assert_eq!(ExtendedHandshake::from_bencode(&handshake.to_bencode()).unwrap(), handshake);
+
+        let decoded = ExtendedHandshake::from_bencode(b"d1:md11:ut_metadatai2ee6:yourip16:\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01e").unwrap();
+        assert_eq!(decoded.m.get("ut_metadata"), Some(&2));
+        assert_eq!(decoded.yourip, Some(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+        for payload in [&b"le"[..], b"d1:mi1ee", b"d1:md1:ai300eee", b"garbage"] {
+            assert!(matches!(ExtendedHandshake::from_bencode(payload), Err(ExtensionError::InvalidMessage(_))));
+        }
+    }
+
+    #[test]
+    fn test_routes_messages_by_negotiated_id() {
+        let mut registry = ExtensionRegistry::new();
+        assert_eq!(registry.register(Box::new(Echo { name: "a_echo" })), Ok(1));
+        assert_eq!(registry.register(Box::new(Echo { name: "b_echo" })), Ok(2));
+        assert_eq!(registry.register(Box::new(Echo { name: "a_echo" })), Err(ExtensionError::DuplicateName(String::from("a_echo"))));
+
+        let ours = ExtendedHandshake::from_bencode(&handshake_payload(registry.handshake(ExtendedHandshake::default()))).unwrap();
+        assert_eq!(ours.m, BTreeMap::from([(String::from("a_echo"), 1), (String::from("b_echo"), 2)]));
+        assert_eq!(ours.extra.get("b_echo_version"), Some(&Value::Int(2)));
+
+        // Messages aren't sent before the peer told us its ids.
+        assert_eq!(registry.handle(1, b"ab").unwrap(), vec![]);
+        let theirs = ExtendedHandshake {
+            m: BTreeMap::from([(String::from("b_echo"), 7), (String::from("a_echo"), 0)]),
+            ..Default::default()
+        };
+        assert_eq!(registry.handle(EXTENDED_HANDSHAKE_ID, &theirs.to_bencode()).unwrap(), vec![
+            PeerMessage::Extended { id: 7, payload: b"hello".to_vec() },
+        ]);
+        assert_eq!(registry.remote_id("a_echo"), None);
+        assert_eq!(registry.handle(2, b"ab").unwrap(), vec![PeerMessage::Extended { id: 7, payload: b"ba".to_vec() }]);
+        assert_eq!(registry.handle(9, b"ab"), Err(ExtensionError::UnknownId(9)));
+        assert!(matches!(registry.handle(2, b""), Err(ExtensionError::InvalidMessage(_))));
+        assert_eq!(registry.message("b_echo", vec![1]), Some(PeerMessage::Extended { id: 7, payload: vec![1] }));
+    }
+}
@@ -0,0 +1,49 @@
+use std::net::Ipv4Addr;
+
+use crate::model::Sha1Hash;
+use crate::util::common::sha1_hash;
+
+/// Number of pieces BEP 6 suggests granting to a choked peer.
+pub const ALLOWED_FAST_SET_SIZE: usize = 10;
+
+/// The canonical allowed fast set of BEP 6: up to `k` piece indices derived from the peer's
+/// /24 network and the info hash, so that every peer of that network gets the same pieces.
+pub fn allowed_fast_set(ip: Ipv4Addr, info_hash: &Sha1Hash, num_pieces: u32, k: usize) -> Vec<u32> {
+    let k = k.min(num_pieces as usize);
+    let mut allowed = Vec::with_capacity(k);
+    let mut x = [&(u32::from(ip) & 0xffff_ff00).to_be_bytes()[..], info_hash].concat();
+    while allowed.len() < k {
+        x = sha1_hash(&x).to_vec();
+        for chunk in x.chunks_exact(4) {
+            if allowed.len() >= k {
+                break;
+            }
+            let index = u32::from_be_bytes(chunk.try_into().unwrap()) % num_pieces;
+            if !allowed.contains(&index) {
+                allowed.push(index);
+            }
+        }
+    }
+    allowed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed_fast_set_matches_spec() {
+        let ip = Ipv4Addr::new(80, 4, 4, 200);
+        assert_eq!(allowed_fast_set(ip, &[0xaa; 20], 1313, 7), vec![1059, 431, 808, 1217, 287, 376, 1188]);
+        assert_eq!(allowed_fast_set(ip, &[0xaa; 20], 1313, 9), vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]);
+        assert_eq!(allowed_fast_set(Ipv4Addr::new(80, 4, 4, 1), &[0xaa; 20], 1313, 7), allowed_fast_set(ip, &[0xaa; 20], 1313, 7));
+    }
+
+    #[test]
+    fn test_allowed_fast_set_of_small_torrents() {
+        let mut set = allowed_fast_set(Ipv4Addr::LOCALHOST, &[1; 20], 3, ALLOWED_FAST_SET_SIZE);
+        set.sort_unstable();
+        assert_eq!(set, vec![0, 1, 2]);
+        assert!(allowed_fast_set(Ipv4Addr::LOCALHOST, &[1; 20], 0, ALLOWED_FAST_SET_SIZE).is_empty());
+    }
+}
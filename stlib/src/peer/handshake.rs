@@ -0,0 +1,335 @@
+//! The BitTorrent handshake: `\x13BitTorrent protocol`, eight reserved bytes advertising
+//! optional features, the info hash and the peer id.
+
+use std::{fmt, io};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::model::Sha1Hash;
+use crate::util::proxy::ProxyConfig;
+
+use super::connection::connect;
+use super::peer::PeerId;
+
+pub const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+pub const HANDSHAKE_LEN: usize = 1 + 19 + 8 + 20 + 20;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reserved bit positions, as `(byte, mask)`.
+const EXTENSION_PROTOCOL_BIT: (usize, u8) = (5, 0x10);
+const V2_UPGRADE_BIT: (usize, u8) = (7, 0x10);
+const FAST_BIT: (usize, u8) = (7, 0x04);
+const DHT_BIT: (usize, u8) = (7, 0x01);
+
+/// Optional features announced in the reserved bytes of the handshake.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCapabilities {
+    /// BEP 10 extension protocol.
+    pub extension_protocol: bool,
+    /// BEP 6 Fast Extension.
+    pub fast: bool,
+    /// BEP 5 DHT, the peer accepts `port` messages.
+    pub dht: bool,
+    /// BEP 52 upgrade to a v2 torrent.
+    pub v2_upgrade: bool,
+}
+
+impl PeerCapabilities {
+    pub fn all() -> Self {
+        Self { extension_protocol: true, fast: true, dht: true, v2_upgrade: true }
+    }
+
+    pub fn from_reserved(reserved: &[u8; 8]) -> Self {
+        let is_set = |(byte, mask): (usize, u8)| reserved[byte] & mask != 0;
+        Self {
+            extension_protocol: is_set(EXTENSION_PROTOCOL_BIT),
+            fast: is_set(FAST_BIT),
+            dht: is_set(DHT_BIT),
+            v2_upgrade: is_set(V2_UPGRADE_BIT),
+        }
+    }
+
+    pub fn to_reserved(&self) -> [u8; 8] {
+        let mut reserved = [0u8; 8];
+        for (enabled, (byte, mask)) in [
+            (self.extension_protocol, EXTENSION_PROTOCOL_BIT),
+            (self.fast, FAST_BIT),
+            (self.dht, DHT_BIT),
+            (self.v2_upgrade, V2_UPGRADE_BIT),
+        ] {
+            if enabled {
+                reserved[byte] |= mask;
+            }
+        }
+        reserved
+    }
+
+    /// The features both sides support, the only ones that may be used on the connection.
+    pub fn negotiate(&self, remote: &Self) -> Self {
+        Self {
+            extension_protocol: self.extension_protocol && remote.extension_protocol,
+            fast: self.fast && remote.fast,
+            dht: self.dht && remote.dht,
+            v2_upgrade: self.v2_upgrade && remote.v2_upgrade,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    pub reserved: [u8; 8],
+    pub info_hash: Sha1Hash,
+    pub peer_id: PeerId,
+}
+
+impl Handshake {
+    pub fn new(capabilities: &PeerCapabilities, info_hash: Sha1Hash, peer_id: PeerId) -> Self {
+        Self { reserved: capabilities.to_reserved(), info_hash, peer_id }
+    }
+
+    pub fn to_bytes(&self) -> [u8; HANDSHAKE_LEN] {
+        let mut buf = [0u8; HANDSHAKE_LEN];
+        buf[0] = PROTOCOL.len() as u8;
+        buf[1..20].copy_from_slice(PROTOCOL);
+        buf[20..28].copy_from_slice(&self.reserved);
+        buf[28..48].copy_from_slice(&self.info_hash);
+        buf[48..68].copy_from_slice(&self.peer_id);
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8; HANDSHAKE_LEN]) -> Result<Self, HandshakeError> {
+        if buf[0] as usize != PROTOCOL.len() || &buf[1..20] != PROTOCOL {
+            return Err(HandshakeError::InvalidProtocol);
+        }
+        Ok(Self {
+            reserved: buf[20..28].try_into().unwrap(),
+            info_hash: buf[28..48].try_into().unwrap(),
+            peer_id: buf[48..68].try_into().unwrap(),
+        })
+    }
+
+    pub fn capabilities(&self) -> PeerCapabilities {
+        PeerCapabilities::from_reserved(&self.reserved)
+    }
+
+    async fn read<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Self, HandshakeError> {
+        let mut buf = [0u8; HANDSHAKE_LEN];
+        // The protocol string comes first, so garbage is rejected without waiting for 68 bytes.
+        stream.read_exact(&mut buf[..20]).await?;
+        if buf[0] as usize != PROTOCOL.len() || &buf[1..20] != PROTOCOL {
+            return Err(HandshakeError::InvalidProtocol);
+        }
+        stream.read_exact(&mut buf[20..]).await?;
+        Self::from_bytes(&buf)
+    }
+}
+
+/// What was learned about the remote peer from its handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakeOutcome {
+    pub info_hash: Sha1Hash,
+    pub peer_id: PeerId,
+    /// Features advertised by the remote peer.
+    pub remote: PeerCapabilities,
+    /// Features both sides advertised.
+    pub negotiated: PeerCapabilities,
+}
+
+impl HandshakeOutcome {
+    fn new(local: &PeerCapabilities, remote: &Handshake) -> Self {
+        let remote_capabilities = remote.capabilities();
+        Self {
+            info_hash: remote.info_hash,
+            peer_id: remote.peer_id,
+            remote: remote_capabilities,
+            negotiated: local.negotiate(&remote_capabilities),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    /// The peer doesn't speak `BitTorrent protocol`.
+    InvalidProtocol,
+    /// The peer answered with another torrent than the one we asked for.
+    InfoHashMismatch { expected: Sha1Hash, received: Sha1Hash },
+    /// An incoming peer asked for a torrent we don't serve.
+    UnknownInfoHash(Sha1Hash),
+    /// The peer didn't complete the handshake in time.
+    Timeout,
+    Io(io::Error),
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidProtocol => write!(f, "Peer doesn't speak the BitTorrent protocol"),
+            Self::InfoHashMismatch { expected, received } => {
+                write!(f, "Peer answered for info hash {} instead of {}", hex::encode(received), hex::encode(expected))
+            },
+            Self::UnknownInfoHash(info_hash) => write!(f, "Peer asked for unknown info hash {}", hex::encode(info_hash)),
+            Self::Timeout => write!(f, "Peer handshake timed out"),
+            Self::Io(err) => write!(f, "Peer handshake I/O error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+impl From<io::Error> for HandshakeError {
+    fn from(err: io::Error) -> Self {
+        if err.kind() == io::ErrorKind::TimedOut {
+            Self::Timeout
+        } else {
+            Self::Io(err)
+        }
+    }
+}
+
+async fn with_timeout<T>(future: impl std::future::Future<Output = Result<T, HandshakeError>>) -> Result<T, HandshakeError> {
+    timeout(HANDSHAKE_TIMEOUT, future).await.map_err(|_| HandshakeError::Timeout)?
+}
+
+/// Sends our handshake for `info_hash` and checks the peer answers for the same torrent.
+pub async fn handshake_outgoing<S>(stream: &mut S, info_hash: Sha1Hash, peer_id: PeerId, capabilities: &PeerCapabilities)
+    -> Result<HandshakeOutcome, HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    with_timeout(async {
+        stream.write_all(&Handshake::new(capabilities, info_hash, peer_id).to_bytes()).await?;
+        let remote = Handshake::read(stream).await?;
+        if remote.info_hash != info_hash {
+            return Err(HandshakeError::InfoHashMismatch { expected: info_hash, received: remote.info_hash });
+        }
+        Ok(HandshakeOutcome::new(capabilities, &remote))
+    }).await
+}
+
+/// Waits for the handshake of a peer that connected to us and answers it if `serves` accepts
+/// the torrent it asked for.
+pub async fn handshake_incoming<S, F>(stream: &mut S, serves: F, peer_id: PeerId, capabilities: &PeerCapabilities)
+    -> Result<HandshakeOutcome, HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: FnOnce(&Sha1Hash) -> bool,
+{
+    with_timeout(async {
+        let remote = Handshake::read(stream).await?;
+        if !serves(&remote.info_hash) {
+            return Err(HandshakeError::UnknownInfoHash(remote.info_hash));
+        }
+        stream.write_all(&Handshake::new(capabilities, remote.info_hash, peer_id).to_bytes()).await?;
+        Ok(HandshakeOutcome::new(capabilities, &remote))
+    }).await
+}
+
+/// Connects to a peer, through `proxy` when set, and performs the outgoing handshake.
+pub async fn connect_and_handshake(
+    addr: SocketAddr,
+    proxy: Option<&ProxyConfig>,
+    info_hash: Sha1Hash,
+    peer_id: PeerId,
+    capabilities: &PeerCapabilities,
+) -> Result<(TcpStream, HandshakeOutcome), HandshakeError> {
+    let mut stream = connect(addr, proxy).await?;
+    let outcome = handshake_outgoing(&mut stream, info_hash, peer_id, capabilities).await?;
+    Ok((stream, outcome))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+    use tokio::net::TcpListener;
+    use super::*;
+
+    #[test]
+    fn test_reserved_bits() {
+        let reserved = PeerCapabilities::all().to_reserved();
+        assert_eq!(reserved, [0, 0, 0, 0, 0, 0x10, 0, 0x15]);
+        assert_eq!(PeerCapabilities::from_reserved(&reserved), PeerCapabilities::all());
+        assert_eq!(PeerCapabilities::from_reserved(&[0xff, 0, 0, 0, 0, 0, 0, 0x01]), PeerCapabilities { dht: true, ..Default::default() });
+
+        let local = PeerCapabilities { extension_protocol: true, fast: true, ..Default::default() };
+        let remote = PeerCapabilities { fast: true, dht: true, ..Default::default() };
+        assert_eq!(local.negotiate(&remote), PeerCapabilities { fast: true, ..Default::default() });
+    }
+
+    #[test]
+    fn test_handshake_round_trip() {
+        let handshake = Handshake::new(&PeerCapabilities::all(), [1; 20], [2; 20]);
+        let bytes = handshake.to_bytes();
+        assert_eq!(&bytes[..20], b"\x13BitTorrent protocol");
+        assert_eq!(Handshake::from_bytes(&bytes).unwrap(), handshake);
+
+        let mut bytes = bytes;
+        bytes[1] = b'b';
+        assert!(matches!(Handshake::from_bytes(&bytes), Err(HandshakeError::InvalidProtocol)));
+    }
+
+    #[tokio::test]
+    async fn test_outgoing_and_incoming_negotiate() {
+        let (mut client, mut server) = duplex(256);
+        let local = PeerCapabilities::all();
+        let remote = PeerCapabilities { fast: true, ..Default::default() };
+        let (outgoing, incoming) = tokio::join!(
+            handshake_outgoing(&mut client, [1; 20], [b'a'; 20], &local),
+            handshake_incoming(&mut server, |info_hash| *info_hash == [1; 20], [b'b'; 20], &remote),
+        );
+        let (outgoing, incoming) = (outgoing.unwrap(), incoming.unwrap());
+        assert_eq!((outgoing.peer_id, outgoing.info_hash), ([b'b'; 20], [1; 20]));
+        assert_eq!(outgoing.negotiated, remote);
+        assert_eq!(incoming.peer_id, [b'a'; 20]);
+        assert_eq!(incoming.remote, local);
+        assert_eq!(incoming.negotiated, remote);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_mismatches() {
+        let caps = PeerCapabilities::default();
+        let (mut client, mut server) = duplex(256);
+        let (outgoing, incoming) = tokio::join!(
+            handshake_outgoing(&mut client, [1; 20], [b'a'; 20], &caps),
+            async {
+                let request = Handshake::read(&mut server).await.unwrap();
+                server.write_all(&Handshake::new(&caps, [2; 20], [b'b'; 20]).to_bytes()).await.unwrap();
+                request
+            },
+        );
+        assert_eq!(incoming.info_hash, [1; 20]);
+        assert!(matches!(outgoing, Err(HandshakeError::InfoHashMismatch { expected: [1, ..], received: [2, ..] })));
+
+        let (mut client, mut server) = duplex(256);
+        client.write_all(&Handshake::new(&caps, [3; 20], [b'a'; 20]).to_bytes()).await.unwrap();
+        let err = handshake_incoming(&mut server, |_| false, [b'b'; 20], &caps).await.unwrap_err();
+        assert!(matches!(err, HandshakeError::UnknownInfoHash([3, ..])));
+
+        let (mut client, mut server) = duplex(256);
+        client.write_all(b"\x13BitTorrent protocoX").await.unwrap();
+        let err = handshake_incoming(&mut server, |_| true, [b'b'; 20], &caps).await.unwrap_err();
+        assert!(matches!(err, HandshakeError::InvalidProtocol));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_silent_peer_times_out() {
+        let (mut client, _server) = duplex(256);
+        let err = handshake_outgoing(&mut client, [1; 20], [b'a'; 20], &PeerCapabilities::default()).await.unwrap_err();
+        assert!(matches!(err, HandshakeError::Timeout));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_connect_and_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            handshake_incoming(&mut stream, |_| true, [b'b'; 20], &PeerCapabilities::all()).await.unwrap()
+        });
+        let (_, outcome) = connect_and_handshake(addr, None, [1; 20], [b'a'; 20], &PeerCapabilities::all()).await.unwrap();
+        assert_eq!(outcome.negotiated, PeerCapabilities::all());
+        assert_eq!(server.await.unwrap().peer_id, [b'a'; 20]);
+    }
+}
@@ -0,0 +1,518 @@
+//! Metadata exchange (BEP 9): fetches the info dictionary of a torrent known only by its info
+//! hash from peers, and serves ours to peers that ask for it.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use serde_bencode::value::Value;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+
+use crate::model::{PeerMessage, Sha1Hash, Torrent};
+use crate::peer::extension::{ExtendedHandshake, Extension, ExtensionError, ExtensionRegistry};
+use crate::peer::handshake::{connect_and_handshake, PeerCapabilities};
+use crate::peer::peer::PeerId;
+use crate::util::bencode;
+use crate::util::common::sha1_hash;
+use crate::util::proxy::ProxyConfig;
+
+pub const UT_METADATA: &str = "ut_metadata";
+/// Size of every metadata piece but the last.
+pub const METADATA_PIECE_SIZE: usize = 16 * 1024;
+/// Larger metadata announced by a peer is refused rather than allocated.
+pub const MAX_METADATA_SIZE: usize = 8 * 1024 * 1024;
+/// Pieces requested from a single peer at once.
+const MAX_OUTSTANDING_REQUESTS: usize = 2;
+/// Time a single peer is given to deliver the whole metadata.
+pub const METADATA_FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+/// Larger messages received while exchanging metadata close the connection.
+const MAX_MESSAGE_LEN: usize = 1024 * 1024;
+
+const MSG_REQUEST: i64 = 0;
+const MSG_DATA: i64 = 1;
+const MSG_REJECT: i64 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataMessage {
+    Request(u32),
+    Data { piece: u32, total_size: usize, data: Vec<u8> },
+    Reject(u32),
+}
+
+impl MetadataMessage {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ExtensionError> {
+        let invalid = |reason: &str| ExtensionError::InvalidMessage(format!("ut_metadata: {reason}"));
+        // The dictionary of a data message is followed by the piece.
+        let dict_len = bencode::value_end(bytes, 0).ok_or_else(|| invalid("malformed dictionary"))?;
+        let dict = match serde_bencode::from_bytes(&bytes[..dict_len]) {
+            Ok(Value::Dict(dict)) => dict,
+            _ => return Err(invalid("not a dictionary")),
+        };
+        let int = |key: &[u8]| match dict.get(key) {
+            Some(Value::Int(value)) if *value >= 0 => Some(*value),
+            _ => None,
+        };
+        let piece = int(b"piece")
+            .and_then(|piece| u32::try_from(piece).ok())
+            .ok_or_else(|| invalid("missing piece"))?;
+        match int(b"msg_type") {
+            Some(MSG_REQUEST) => Ok(Self::Request(piece)),
+            Some(MSG_DATA) => {
+                let total_size = int(b"total_size")
+                    .and_then(|size| usize::try_from(size).ok())
+                    .ok_or_else(|| invalid("missing total_size"))?;
+                Ok(Self::Data { piece, total_size, data: bytes[dict_len..].to_vec() })
+            }
+            Some(MSG_REJECT) => Ok(Self::Reject(piece)),
+            _ => Err(invalid("unknown msg_type")),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (msg_type, piece) = match self {
+            Self::Request(piece) => (MSG_REQUEST, *piece),
+            Self::Data { piece, .. } => (MSG_DATA, *piece),
+            Self::Reject(piece) => (MSG_REJECT, *piece),
+        };
+        let mut dict = HashMap::from([
+            (b"msg_type".to_vec(), Value::Int(msg_type)),
+            (b"piece".to_vec(), Value::Int(piece as i64)),
+        ]);
+        if let Self::Data { total_size, .. } = self {
+            dict.insert(b"total_size".to_vec(), Value::Int(*total_size as i64));
+        }
+        let mut bytes = serde_bencode::to_bytes(&Value::Dict(dict)).expect("Couldn't serialize ut_metadata message");
+        if let Self::Data { data, .. } = self {
+            bytes.extend_from_slice(data);
+        }
+        bytes
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataError {
+    /// The peer announced a size we can't accept or sent a piece of the wrong length.
+    InvalidSize(usize),
+    /// All pieces arrived but don't hash to the info hash; the download starts over.
+    HashMismatch,
+}
+
+impl fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetadataError::InvalidSize(size) => write!(f, "invalid metadata size {size}"),
+            MetadataError::HashMismatch => write!(f, "metadata doesn't match the info hash"),
+        }
+    }
+}
+
+impl std::error::Error for MetadataError {}
+
+/// The metadata of one torrent, shared by all its connections: assembled piece by piece until
+/// it is verified, then served to others.
+#[derive(Debug)]
+pub struct MetadataDownload {
+    info_hash: Sha1Hash,
+    size: Option<usize>,
+    pieces: Vec<Option<Vec<u8>>>,
+    /// Pieces requested from some connection and not answered yet.
+    requested: BTreeSet<u32>,
+    metadata: Option<Vec<u8>>,
+}
+
+impl MetadataDownload {
+    pub fn new(info_hash: Sha1Hash) -> Self {
+        Self {
+            info_hash,
+            size: None,
+            pieces: vec![],
+            requested: BTreeSet::new(),
+            metadata: None,
+        }
+    }
+
+    /// Metadata that is already known and is only served.
+    pub fn from_torrent(torrent: &Torrent) -> Self {
+        Self {
+            size: Some(torrent.info_bytes.len()),
+            metadata: Some(torrent.info_bytes.clone()),
+            ..Self::new(torrent.info_hash)
+        }
+    }
+
+    pub fn into_shared(self) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(self))
+    }
+
+    pub fn info_hash(&self) -> &Sha1Hash {
+        &self.info_hash
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.metadata.is_some()
+    }
+
+    pub fn metadata(&self) -> Option<&[u8]> {
+        self.metadata.as_deref()
+    }
+
+    pub fn size(&self) -> Option<usize> {
+        self.size
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.size.map_or(0, |size| size.div_ceil(METADATA_PIECE_SIZE))
+    }
+
+    /// Adopts the size announced by a peer if none is known yet.
+    pub fn set_size(&mut self, size: usize) -> Result<(), MetadataError> {
+        if size == 0 || size > MAX_METADATA_SIZE {
+            return Err(MetadataError::InvalidSize(size));
+        }
+        if self.size.is_none() {
+            self.size = Some(size);
+            self.pieces = vec![None; self.num_pieces()];
+        }
+        Ok(())
+    }
+
+    /// Picks a piece nobody was asked for yet.
+    pub fn next_request(&mut self) -> Option<u32> {
+        if self.is_complete() {
+            return None;
+        }
+        let piece = (0..self.pieces.len() as u32)
+            .find(|piece| self.pieces[*piece as usize].is_none() && !self.requested.contains(piece))?;
+        self.requested.insert(piece);
+        Some(piece)
+    }
+
+    /// Makes a requested piece available to other connections again.
+    pub fn release(&mut self, piece: u32) {
+        self.requested.remove(&piece);
+    }
+
+    /// Stores a received piece. Once all pieces arrived, they are verified against the info hash;
+    /// returns whether the metadata is complete.
+    pub fn receive(&mut self, piece: u32, total_size: usize, data: Vec<u8>) -> Result<bool, MetadataError> {
+        self.requested.remove(&piece);
+        if self.is_complete() {
+            return Ok(true);
+        }
+        if Some(total_size) != self.size {
+            return Err(MetadataError::InvalidSize(total_size));
+        }
+        let expected_len = self.piece_len(piece).ok_or(MetadataError::InvalidSize(data.len()))?;
+        if data.len() != expected_len {
+            return Err(MetadataError::InvalidSize(data.len()));
+        }
+        self.pieces[piece as usize] = Some(data);
+        if self.pieces.iter().any(Option::is_none) {
+            return Ok(false);
+        }
+        let metadata: Vec<u8> = self.pieces.drain(..).flatten().flatten().collect();
+        if sha1_hash(&metadata) != self.info_hash {
+            // Any piece may be the bad one, and the size itself may be a lie.
+            self.size = None;
+            self.requested.clear();
+            return Err(MetadataError::HashMismatch);
+        }
+        self.metadata = Some(metadata);
+        Ok(true)
+    }
+
+    /// A piece of the verified metadata, for serving.
+    pub fn piece(&self, piece: u32) -> Option<&[u8]> {
+        let start = piece as usize * METADATA_PIECE_SIZE;
+        let len = self.piece_len(piece)?;
+        self.metadata.as_ref().map(|metadata| &metadata[start..start + len])
+    }
+
+    /// Builds the torrent once the metadata is complete.
+    pub fn torrent(&self, announce_tiers: Vec<Vec<String>>) -> Option<io::Result<Torrent>> {
+        self.metadata.as_ref().map(|metadata| Torrent::from_info(metadata, announce_tiers))
+    }
+
+    fn piece_len(&self, piece: u32) -> Option<usize> {
+        let start = (piece as usize).checked_mul(METADATA_PIECE_SIZE)?;
+        let size = self.size?;
+        (start < size).then(|| (size - start).min(METADATA_PIECE_SIZE))
+    }
+}
+
+/// The `ut_metadata` extension of one connection.
+pub struct MetadataExtension {
+    download: Arc<Mutex<MetadataDownload>>,
+    /// Pieces this peer was asked for.
+    outstanding: Vec<u32>,
+    /// Metadata size announced by the peer, unless it refused to share the metadata.
+    peer_size: Option<usize>,
+}
+
+impl MetadataExtension {
+    pub fn new(download: Arc<Mutex<MetadataDownload>>) -> Self {
+        Self { download, outstanding: vec![], peer_size: None }
+    }
+
+    fn requests(&mut self) -> Vec<Vec<u8>> {
+        let Some(size) = self.peer_size else { return vec![] };
+        let mut download = self.download.lock().unwrap();
+        // After a failed verification, the size is taken from the next peer asked.
+        if download.set_size(size).is_err() {
+            return vec![];
+        }
+        let mut requests = vec![];
+        while self.outstanding.len() < MAX_OUTSTANDING_REQUESTS {
+            let Some(piece) = download.next_request() else { break };
+            self.outstanding.push(piece);
+            requests.push(MetadataMessage::Request(piece).to_bytes());
+        }
+        requests
+    }
+}
+
+impl Extension for MetadataExtension {
+    fn name(&self) -> &str {
+        UT_METADATA
+    }
+
+    fn extend_handshake(&self, handshake: &mut ExtendedHandshake) {
+        let download = self.download.lock().unwrap();
+        if let Some(metadata) = download.metadata() {
+            handshake.metadata_size = Some(metadata.len() as u64);
+        }
+    }
+
+    fn on_handshake(&mut self, handshake: &ExtendedHandshake) -> Vec<Vec<u8>> {
+        self.peer_size = handshake.metadata_size.and_then(|size| usize::try_from(size).ok());
+        self.requests()
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, ExtensionError> {
+        match MetadataMessage::from_bytes(payload)? {
+            MetadataMessage::Request(piece) => {
+                let download = self.download.lock().unwrap();
+                let reply = match (download.piece(piece), download.size()) {
+                    (Some(data), Some(total_size)) => MetadataMessage::Data { piece, total_size, data: data.to_vec() },
+                    _ => MetadataMessage::Reject(piece),
+                };
+                Ok(vec![reply.to_bytes()])
+            }
+            MetadataMessage::Data { piece, total_size, data } => {
+                if !self.outstanding.contains(&piece) {
+                    return Ok(vec![]);
+                }
+                self.outstanding.retain(|outstanding| *outstanding != piece);
+                let received = self.download.lock().unwrap().receive(piece, total_size, data);
+                match received {
+                    Ok(_) => Ok(self.requests()),
+                    Err(err @ MetadataError::InvalidSize(_)) => {
+                        self.peer_size = None;
+                        Err(ExtensionError::InvalidMessage(err.to_string()))
+                    }
+                    // The culprit is unknown, so the download restarts with every peer.
+                    Err(MetadataError::HashMismatch) => Ok(self.requests()),
+                }
+            }
+            MetadataMessage::Reject(piece) => {
+                self.peer_size = None;
+                self.outstanding.retain(|outstanding| *outstanding != piece);
+                self.download.lock().unwrap().release(piece);
+                Ok(vec![])
+            }
+        }
+    }
+}
+
+impl Drop for MetadataExtension {
+    fn drop(&mut self) {
+        if let Ok(mut download) = self.download.lock() {
+            for piece in self.outstanding.drain(..) {
+                download.release(piece);
+            }
+        }
+    }
+}
+
+/// Runs `ut_metadata` alone over a connection whose handshake negotiated the extension
+/// protocol, until `download` completes. Metadata complete from the start is served until the
+/// connection fails, usually by the peer closing it.
+pub async fn exchange_metadata<S>(stream: &mut S, download: Arc<Mutex<MetadataDownload>>) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let invalid = |err: ExtensionError| io::Error::new(io::ErrorKind::InvalidData, err.to_string());
+    let fetching = !download.lock().unwrap().is_complete();
+    let mut registry = ExtensionRegistry::new();
+    registry.register(Box::new(MetadataExtension::new(download.clone()))).map_err(invalid)?;
+    stream.write_all(&registry.handshake(ExtendedHandshake::default()).to_bytes()).await?;
+    while !fetching || !download.lock().unwrap().is_complete() {
+        let len = stream.read_u32().await? as usize;
+        if len > MAX_MESSAGE_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Peer message too long"));
+        }
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).await?;
+        if let PeerMessage::Extended { id, payload } = PeerMessage::from_payload(&payload)? {
+            for reply in registry.handle(id, &payload).map_err(invalid)? {
+                stream.write_all(&reply.to_bytes()).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fetches the info dictionary of `info_hash` from the first of `peers` to deliver it, each
+/// being given [`METADATA_FETCH_TIMEOUT`].
+pub async fn fetch_metadata(peers: &[SocketAddr], info_hash: Sha1Hash, peer_id: PeerId, proxy: Option<&ProxyConfig>) -> Option<Vec<u8>> {
+    let capabilities = PeerCapabilities { extension_protocol: true, ..Default::default() };
+    for addr in peers {
+        let download = MetadataDownload::new(info_hash).into_shared();
+        let fetched = timeout(METADATA_FETCH_TIMEOUT, async {
+            let (mut stream, outcome) = connect_and_handshake(*addr, proxy, info_hash, peer_id, &capabilities).await
+                .map_err(|err| io::Error::other(err.to_string()))?;
+            if !outcome.negotiated.extension_protocol {
+                return Err(io::Error::new(io::ErrorKind::Unsupported, "Peer doesn't speak the extension protocol"));
+            }
+            exchange_metadata(&mut stream, download.clone()).await
+        }).await;
+        if matches!(fetched, Ok(Ok(()))) {
+            return download.lock().unwrap().metadata().map(<[u8]>::to_vec);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::extension::EXTENDED_HANDSHAKE_ID;
+
+    fn registry(download: &Arc<Mutex<MetadataDownload>>) -> ExtensionRegistry {
+        let mut registry = ExtensionRegistry::new();
+        registry.register(Box::new(MetadataExtension::new(download.clone()))).unwrap();
+        registry
+    }
+
+    /// Delivers messages back and forth until both sides are quiet.
+    fn exchange(a: &mut ExtensionRegistry, b: &mut ExtensionRegistry) {
+        let mut to_b = vec![a.handshake(ExtendedHandshake::default())];
+        let mut to_a = vec![b.handshake(ExtendedHandshake::default())];
+        while !to_a.is_empty() || !to_b.is_empty() {
+            let mut next_to_a = vec![];
+            for message in to_b.drain(..) {
+                if let PeerMessage::Extended { id, payload } = message {
+                    next_to_a.extend(b.handle(id, &payload).unwrap());
+                }
+            }
+            for message in to_a.drain(..) {
+                if let PeerMessage::Extended { id, payload } = message {
+                    to_b.extend(a.handle(id, &payload).unwrap());
+                }
+            }
+            to_a = next_to_a;
+        }
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let messages = [
+            MetadataMessage::Request(3),
+            MetadataMessage::Data { piece: 1, total_size: 20000, data: b"d1:ai1ee raw".to_vec() },
+            MetadataMessage::Reject(0),
+        ];
+        for message in messages {
+            assert_eq!(MetadataMessage::from_bytes(&message.to_bytes()).unwrap(), message);
+        }
+        assert_eq!(
+            MetadataMessage::from_bytes(b"d8:msg_typei0e5:piecei0ee").unwrap(),
+            MetadataMessage::Request(0),
+        );
+        let deep = [&b"d"[..], &[b'l'; 200_000]].concat();
+        for payload in [&b"d8:msg_typei1e5:piecei0ee"[..], b"d8:msg_typei7e5:piecei0ee", b"d5:piecei0e", b"d8:msg_type", b"x", &deep] {
+            assert!(MetadataMessage::from_bytes(payload).is_err());
+        }
+    }
+
+    #[test]
+    fn test_downloads_metadata_from_a_peer() {
+        let torrent = Torrent::from_file("test-resources/torrent/bunny.torrent").unwrap();
+        assert!(torrent.info_bytes.len() > METADATA_PIECE_SIZE);
+        let seeder = MetadataDownload::from_torrent(&torrent).into_shared();
+        let leecher = MetadataDownload::new(torrent.info_hash).into_shared();
+
+        exchange(&mut registry(&leecher), &mut registry(&seeder));
+        let download = leecher.lock().unwrap();
+        assert!(download.is_complete());
+        assert_eq!(download.num_pieces(), torrent.info_bytes.len().div_ceil(METADATA_PIECE_SIZE));
+        let fetched = download.torrent(vec![vec![String::from("udp://tracker.example:80")]]).unwrap().unwrap();
+        assert_eq!(fetched.info_hash, torrent.info_hash);
+        assert_eq!(fetched.pieces, torrent.pieces);
+    }
+
+    #[test]
+    fn test_serves_rejects_and_releases() {
+        let torrent = Torrent::from_file("test-resources/torrent/bunny.torrent").unwrap();
+        let seeder = MetadataDownload::from_torrent(&torrent).into_shared();
+        let mut registry = registry(&seeder);
+        let handshake = ExtendedHandshake::from_bencode(&match registry.handshake(ExtendedHandshake::default()) {
+            PeerMessage::Extended { payload, .. } => payload,
+            message => panic!("unexpected {message:?}"),
+        }).unwrap();
+        assert_eq!(handshake.metadata_size, Some(torrent.info_bytes.len() as u64));
+
+        let peer = ExtendedHandshake { m: [(String::from(UT_METADATA), 5)].into(), ..Default::default() };
+        registry.handle(EXTENDED_HANDSHAKE_ID, &peer.to_bencode()).unwrap();
+        assert_eq!(registry.handle(1, &MetadataMessage::Request(99).to_bytes()).unwrap(), vec![
+            PeerMessage::Extended { id: 5, payload: MetadataMessage::Reject(99).to_bytes() },
+        ]);
+
+        // A leecher without the metadata rejects, and its pieces go to other peers.
+        let leecher = MetadataDownload::new([7; 20]).into_shared();
+        let mut ext = MetadataExtension::new(leecher.clone());
+        assert_eq!(ext.on_message(&MetadataMessage::Request(0).to_bytes()).unwrap(), vec![MetadataMessage::Reject(0).to_bytes()]);
+        let requests = ext.on_handshake(&ExtendedHandshake { metadata_size: Some(40000), ..Default::default() });
+        assert_eq!(requests, vec![MetadataMessage::Request(0).to_bytes(), MetadataMessage::Request(1).to_bytes()]);
+        assert_eq!(ext.on_message(&MetadataMessage::Reject(0).to_bytes()).unwrap(), Vec::<Vec<u8>>::new());
+        assert_eq!(leecher.lock().unwrap().next_request(), Some(0));
+        drop(ext);
+        assert_eq!(leecher.lock().unwrap().next_request(), Some(1));
+    }
+
+    #[test]
+    fn test_rejects_bad_sizes_and_hashes() {
+        let mut download = MetadataDownload::new([7; 20]);
+        assert_eq!(download.set_size(MAX_METADATA_SIZE + 1), Err(MetadataError::InvalidSize(MAX_METADATA_SIZE + 1)));
+        download.set_size(20000).unwrap();
+        assert_eq!(download.next_request(), Some(0));
+        assert_eq!(download.receive(0, 20000, vec![0; 100]), Err(MetadataError::InvalidSize(100)));
+        assert_eq!(download.receive(0, 30000, vec![0; METADATA_PIECE_SIZE]), Err(MetadataError::InvalidSize(30000)));
+        assert_eq!(download.receive(0, 20000, vec![0; METADATA_PIECE_SIZE]), Ok(false));
+        assert_eq!(download.receive(1, 20000, vec![0; 20000 - METADATA_PIECE_SIZE]), Err(MetadataError::HashMismatch));
+        assert!(!download.is_complete());
+        assert_eq!((download.size(), download.next_request()), (None, None));
+        download.set_size(100).unwrap();
+        assert_eq!(download.next_request(), Some(0));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fetches_metadata_over_a_connection() {
+        let torrent = Torrent::from_file("test-resources/torrent/bunny.torrent").unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let seeder_addr = listener.local_addr().unwrap();
+        let seeder = MetadataDownload::from_torrent(&torrent).into_shared();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let capabilities = PeerCapabilities { extension_protocol: true, ..Default::default() };
+            crate::peer::handshake::handshake_incoming(&mut stream, |_| true, [2; 20], &capabilities).await.unwrap();
+            let _ = exchange_metadata(&mut stream, seeder).await;
+        });
+        let dead = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        let metadata = fetch_metadata(&[dead, seeder_addr], torrent.info_hash, [1; 20], None).await;
+        assert_eq!(metadata, Some(torrent.info_bytes));
+    }
+}
@@ -1 +1,11 @@
+#[allow(clippy::module_inception)]
 pub mod peer;
+pub mod connection;
+pub mod handshake;
+pub mod fast;
+pub mod session;
+pub mod extension;
+pub mod metadata;
+pub mod pex;
+pub mod source;
+pub mod peer_list;
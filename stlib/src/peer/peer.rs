@@ -7,14 +7,16 @@ pub struct Peer {
     pub id: PeerId,
     pub ip: u32,
     pub port: u16,
-    choked: bool,
-    chocking: bool,
-    interested: bool,
-    bitfield: BitVec,
+    /// We choke the peer.
+    pub(super) choked: bool,
+    /// The peer chokes us.
+    pub(super) chocking: bool,
+    pub(super) interested: bool,
+    pub(super) bitfield: BitVec,
 }
 
 impl Peer {
-    fn new(ip: u32, port: u16) -> Self {
+    pub fn new(ip: u32, port: u16) -> Self {
         Self {
             ip,
             port,
@@ -23,4 +25,4 @@ impl Peer {
             ..Default::default()
         }
     }
-}
\ No newline at end of file
+}
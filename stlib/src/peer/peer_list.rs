@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::model::PeerInfo;
+use crate::peer::source::{DiscoveredPeer, PeerOrigin, PeerSource};
+
+/// Upper bound on the peers a torrent keeps candidates of, manually added ones aside.
+pub const MAX_KNOWN_PEERS: usize = 2000;
+/// Failed connection attempts after which a discovered peer is forgotten.
+pub const MAX_CONNECT_FAILURES: u32 = 5;
+/// Delay before retrying a peer after its first failure, doubled on every further one.
+pub const CONNECT_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerState {
+    Idle,
+    Connecting,
+    Connected,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KnownPeer {
+    /// Every mechanism the peer was discovered through, first one first.
+    pub origins: Vec<PeerOrigin>,
+    pub state: PeerState,
+    /// Failed connection attempts since the last successful one.
+    pub failures: u32,
+    /// The peer isn't a candidate again before.
+    pub retry_at: Option<Instant>,
+}
+
+impl KnownPeer {
+    pub fn is_manual(&self) -> bool {
+        self.origins.contains(&PeerOrigin::Manual)
+    }
+}
+
+/// The peers of a torrent, deduplicated by address, that it may connect to.
+#[derive(Debug, Default)]
+pub struct PeerList {
+    peers: HashMap<SocketAddr, KnownPeer>,
+}
+
+impl PeerList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&KnownPeer> {
+        self.peers.get(addr)
+    }
+
+    /// Returns whether the peer is new to the list. Peers already known only gain the origin,
+    /// so rediscovering a failing peer doesn't cut its backoff short.
+    pub fn add(&mut self, peer: DiscoveredPeer) -> bool {
+        let addr = peer.info.socket_addr;
+        if addr.port() == 0 || addr.ip().is_unspecified() {
+            return false;
+        }
+        if let Some(known) = self.peers.get_mut(&addr) {
+            if !known.origins.contains(&peer.origin) {
+                known.origins.push(peer.origin);
+            }
+            return false;
+        }
+        if peer.origin != PeerOrigin::Manual && self.peers.len() >= MAX_KNOWN_PEERS {
+            return false;
+        }
+        self.peers.insert(addr, KnownPeer { origins: vec![peer.origin], state: PeerState::Idle, failures: 0, retry_at: None });
+        true
+    }
+
+    /// Adds a peer the user asked for: it's tried first, right away even if it was failing,
+    /// and never forgotten.
+    pub fn add_manual(&mut self, addr: SocketAddr) -> bool {
+        let added = self.add(DiscoveredPeer { info: PeerInfo { socket_addr: addr }, origin: PeerOrigin::Manual });
+        if let Some(known) = self.peers.get_mut(&addr) {
+            known.retry_at = None;
+        }
+        added
+    }
+
+    pub fn remove(&mut self, addr: &SocketAddr) -> Option<KnownPeer> {
+        self.peers.remove(addr)
+    }
+
+    /// Up to `max` idle peers not backing off, manually added ones then those failing least
+    /// first, marked as connecting.
+    pub fn candidates(&mut self, max: usize, now: Instant) -> Vec<SocketAddr> {
+        let mut candidates: Vec<(&SocketAddr, &KnownPeer)> = self.peers.iter()
+            .filter(|(_, peer)| peer.state == PeerState::Idle && peer.retry_at.is_none_or(|at| at <= now))
+            .collect();
+        candidates.sort_by_key(|(_, peer)| (!peer.is_manual(), peer.failures));
+        let candidates: Vec<SocketAddr> = candidates.into_iter().take(max).map(|(addr, _)| *addr).collect();
+        for addr in &candidates {
+            self.peers.get_mut(addr).expect("Candidate is known").state = PeerState::Connecting;
+        }
+        candidates
+    }
+
+    pub fn connected(&mut self, addr: &SocketAddr) {
+        if let Some(peer) = self.peers.get_mut(addr) {
+            peer.state = PeerState::Connected;
+            peer.failures = 0;
+            peer.retry_at = None;
+        }
+    }
+
+    /// Records a failed connection attempt, backing off before the next one.
+    pub fn failed(&mut self, addr: &SocketAddr, now: Instant) {
+        let Some(peer) = self.peers.get_mut(addr) else { return };
+        peer.failures += 1;
+        if peer.failures >= MAX_CONNECT_FAILURES && !peer.is_manual() {
+            self.peers.remove(addr);
+            return;
+        }
+        peer.state = PeerState::Idle;
+        let exponent = (peer.failures - 1).min(MAX_CONNECT_FAILURES);
+        peer.retry_at = Some(now + CONNECT_RETRY_BACKOFF * 2u32.pow(exponent));
+    }
+
+    pub fn disconnected(&mut self, addr: &SocketAddr) {
+        if let Some(peer) = self.peers.get_mut(addr) {
+            peer.state = PeerState::Idle;
+        }
+    }
+
+    /// Adds the peers of `source` to `list` until it has no more.
+    pub async fn collect(list: Arc<Mutex<Self>>, mut source: impl PeerSource) {
+        while let Some(info) = source.next_peer().await {
+            let origin = source.origin();
+            list.lock().unwrap().add(DiscoveredPeer { info, origin });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::peer::source::ChannelSource;
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    fn discovered(port: u16, origin: PeerOrigin) -> DiscoveredPeer {
+        DiscoveredPeer { info: PeerInfo { socket_addr: addr(port) }, origin }
+    }
+
+    #[test]
+    fn test_deduplicates_and_backs_off() {
+        let mut list = PeerList::new();
+        assert!(list.add(discovered(1, PeerOrigin::Tracker)));
+        assert!(!list.add(discovered(1, PeerOrigin::Dht)));
+        assert!(!list.add(discovered(1, PeerOrigin::Tracker)));
+        assert!(!list.add(discovered(0, PeerOrigin::Pex)));
+        assert!(list.add(discovered(2, PeerOrigin::Pex)));
+        assert_eq!(list.len(), 2);
+        assert_eq!(list.get(&addr(1)).unwrap().origins, vec![PeerOrigin::Tracker, PeerOrigin::Dht]);
+
+        let now = Instant::now();
+        let mut candidates = list.candidates(10, now);
+        candidates.sort();
+        assert_eq!(candidates, vec![addr(1), addr(2)]);
+        assert!(list.candidates(10, now).is_empty());
+
+        list.connected(&addr(2));
+        list.failed(&addr(1), now);
+        assert!(list.candidates(10, now).is_empty());
+        assert_eq!(list.candidates(10, now + CONNECT_RETRY_BACKOFF), vec![addr(1)]);
+        list.failed(&addr(1), now);
+        assert!(list.candidates(10, now + CONNECT_RETRY_BACKOFF).is_empty());
+        assert_eq!(list.candidates(10, now + CONNECT_RETRY_BACKOFF * 2), vec![addr(1)]);
+        for _ in 2..MAX_CONNECT_FAILURES {
+            list.failed(&addr(1), now);
+        }
+        assert!(list.get(&addr(1)).is_none());
+    }
+
+    #[test]
+    fn test_manual_peers_come_first_and_stay() {
+        let mut list = PeerList::new();
+        list.add(discovered(1, PeerOrigin::Tracker));
+        let now = Instant::now();
+        assert!(list.add_manual(addr(2)));
+        assert_eq!(list.candidates(1, now), vec![addr(2)]);
+        for _ in 0..MAX_CONNECT_FAILURES {
+            list.failed(&addr(2), now);
+        }
+        assert!(list.get(&addr(2)).is_some());
+        assert!(list.candidates(10, now).iter().all(|candidate| *candidate != addr(2)));
+        assert!(!list.add_manual(addr(2)));
+        assert!(list.candidates(10, now).contains(&addr(2)));
+    }
+
+    #[tokio::test]
+    async fn test_collects_sources() {
+        let list = Arc::new(Mutex::new(PeerList::new()));
+        let (tx, source) = ChannelSource::channel(PeerOrigin::Pex, 4);
+        tx.send(PeerInfo { socket_addr: addr(1) }).await.unwrap();
+        tx.send(PeerInfo { socket_addr: addr(1) }).await.unwrap();
+        drop(tx);
+        PeerList::collect(list.clone(), source).await;
+        let list = list.lock().unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list.get(&addr(1)).unwrap().origins, vec![PeerOrigin::Pex]);
+    }
+}
@@ -0,0 +1,309 @@
+//! Peer exchange (BEP 11 `ut_pex`): connected peers periodically tell each other which peers
+//! they connected to and disconnected from since their last message.
+//!
+//! Nothing is exchanged for private torrents (BEP 27).
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+
+use crate::model::{compact_peer, parse_compact_peers, parse_compact_peers6, PeerInfo, Torrent};
+use crate::peer::extension::{Extension, ExtensionError};
+use crate::util::bencode;
+
+/// Name of the extension in the extension handshake.
+pub const UT_PEX: &str = "ut_pex";
+/// Minimum delay between two messages, both sent and accepted.
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+/// Upper bound on the added, and on the dropped, peers of a single message.
+pub const MAX_PEX_PEERS: usize = 50;
+
+/// Per-peer flags of the `added.f` and `added6.f` keys.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PexFlags {
+    /// The peer prefers encrypted connections.
+    pub encryption: bool,
+    pub seed: bool,
+    /// The peer supports uTP.
+    pub utp: bool,
+    /// The peer supports the `ut_holepunch` extension.
+    pub holepunch: bool,
+    /// The peer accepted an incoming connection from us, so it can be connected to.
+    pub reachable: bool,
+}
+
+const FLAG_ENCRYPTION: u8 = 0x01;
+const FLAG_SEED: u8 = 0x02;
+const FLAG_UTP: u8 = 0x04;
+const FLAG_HOLEPUNCH: u8 = 0x08;
+const FLAG_REACHABLE: u8 = 0x10;
+
+impl PexFlags {
+    pub fn from_byte(byte: u8) -> Self {
+        Self {
+            encryption: byte & FLAG_ENCRYPTION != 0,
+            seed: byte & FLAG_SEED != 0,
+            utp: byte & FLAG_UTP != 0,
+            holepunch: byte & FLAG_HOLEPUNCH != 0,
+            reachable: byte & FLAG_REACHABLE != 0,
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        [
+            (self.encryption, FLAG_ENCRYPTION),
+            (self.seed, FLAG_SEED),
+            (self.utp, FLAG_UTP),
+            (self.holepunch, FLAG_HOLEPUNCH),
+            (self.reachable, FLAG_REACHABLE),
+        ].into_iter()
+            .filter(|(enabled, _)| *enabled)
+            .fold(0, |byte, (_, mask)| byte | mask)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PexMessage {
+    pub added: Vec<(SocketAddr, PexFlags)>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PexMessageBencode {
+    #[serde(default)]
+    added: ByteBuf,
+    #[serde(rename = "added.f", default)]
+    added_flags: ByteBuf,
+    #[serde(default)]
+    added6: ByteBuf,
+    #[serde(rename = "added6.f", default)]
+    added6_flags: ByteBuf,
+    #[serde(default)]
+    dropped: ByteBuf,
+    #[serde(default)]
+    dropped6: ByteBuf,
+}
+
+impl PexMessage {
+    /// Missing flags are taken as none set.
+    pub fn from_bencode(bytes: &[u8]) -> Result<Self, ExtensionError> {
+        let invalid = |err: &dyn std::fmt::Display| ExtensionError::InvalidMessage(format!("ut_pex: {err}"));
+        let message: PexMessageBencode = bencode::from_bytes(bytes).map_err(|err| invalid(&err))?;
+        let with_flags = |peers: Vec<PeerInfo>, flags: &[u8]| peers.into_iter()
+            .enumerate()
+            .map(|(i, peer)| (peer.socket_addr, PexFlags::from_byte(flags.get(i).copied().unwrap_or_default())))
+            .collect::<Vec<_>>();
+        let mut added = with_flags(parse_compact_peers(&message.added).map_err(|err| invalid(&err))?, &message.added_flags);
+        added.extend(with_flags(parse_compact_peers6(&message.added6).map_err(|err| invalid(&err))?, &message.added6_flags));
+        let dropped = parse_compact_peers(&message.dropped).map_err(|err| invalid(&err))?.into_iter()
+            .chain(parse_compact_peers6(&message.dropped6).map_err(|err| invalid(&err))?)
+            .map(|peer| peer.socket_addr)
+            .collect();
+        Ok(Self { added, dropped })
+    }
+
+    pub fn to_bencode(&self) -> Vec<u8> {
+        let mut message = PexMessageBencode::default();
+        for (addr, flags) in &self.added {
+            let (peers, peer_flags) = if addr.is_ipv4() {
+                (&mut message.added, &mut message.added_flags)
+            } else {
+                (&mut message.added6, &mut message.added6_flags)
+            };
+            peers.extend_from_slice(&compact_peer(addr));
+            peer_flags.push(flags.to_byte());
+        }
+        for addr in &self.dropped {
+            let peers = if addr.is_ipv4() { &mut message.dropped } else { &mut message.dropped6 };
+            peers.extend_from_slice(&compact_peer(addr));
+        }
+        serde_bencode::to_bytes(&message).expect("Pex message is always serializable")
+    }
+}
+
+/// The torrent's connected peers, as followed by every [`PexExtension`].
+pub type ConnectedPeers = HashMap<SocketAddr, PexFlags>;
+
+/// `ut_pex` on a single connection: sends the changes to the torrent's connected peers at most
+/// every [`PEX_INTERVAL`], and feeds the peers it learns into the torrent's peer list.
+pub struct PexExtension {
+    remote: SocketAddr,
+    connected: watch::Receiver<ConnectedPeers>,
+    peers: mpsc::Sender<PeerInfo>,
+    /// The connected peers as last told to this peer.
+    sent: ConnectedPeers,
+    next_message: Option<Instant>,
+    last_received: Option<Instant>,
+}
+
+impl PexExtension {
+    /// The extension of the connection to `remote`, `None` for private torrents. `connected`
+    /// follows the torrent's connected peers, and `peers` is the peer list tracker announces
+    /// feed too.
+    pub fn new(torrent: &Torrent, remote: SocketAddr, connected: watch::Receiver<ConnectedPeers>, peers: mpsc::Sender<PeerInfo>) -> Option<Self> {
+        if torrent.private {
+            return None;
+        }
+        Some(Self {
+            remote,
+            connected,
+            peers,
+            sent: HashMap::new(),
+            next_message: None,
+            last_received: None,
+        })
+    }
+
+    fn outgoing(&mut self) -> Option<PexMessage> {
+        let connected = self.connected.borrow();
+        let added: Vec<(SocketAddr, PexFlags)> = connected.iter()
+            .filter(|(addr, flags)| **addr != self.remote && self.sent.get(addr) != Some(flags))
+            .take(MAX_PEX_PEERS)
+            .map(|(addr, flags)| (*addr, *flags))
+            .collect();
+        let dropped: Vec<SocketAddr> = self.sent.keys()
+            .filter(|addr| !connected.contains_key(addr))
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+        for addr in &dropped {
+            self.sent.remove(addr);
+        }
+        self.sent.extend(added.iter().copied());
+        Some(PexMessage { added, dropped })
+    }
+}
+
+impl Extension for PexExtension {
+    fn name(&self) -> &str {
+        UT_PEX
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, ExtensionError> {
+        let message = PexMessage::from_bencode(payload)?;
+        // Flooding peers are ignored rather than trusted.
+        let now = Instant::now();
+        if self.last_received.is_some_and(|last| now < last + PEX_INTERVAL) {
+            return Ok(vec![]);
+        }
+        self.last_received = Some(now);
+        for (addr, _) in message.added.into_iter().take(MAX_PEX_PEERS) {
+            if addr.port() != 0 && addr != self.remote {
+                // A full peer list already has plenty of candidates.
+                let _ = self.peers.try_send(PeerInfo { socket_addr: addr });
+            }
+        }
+        Ok(vec![])
+    }
+
+    fn tick(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        if self.next_message.is_some_and(|next_message| now < next_message) {
+            return vec![];
+        }
+        match self.outgoing() {
+            Some(message) => {
+                self.next_message = Some(now + PEX_INTERVAL);
+                vec![message.to_bencode()]
+            }
+            None => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use super::*;
+    use crate::model::PeerMessage;
+    use crate::peer::extension::{ExtendedHandshake, ExtensionRegistry};
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    const SEED: PexFlags = PexFlags { encryption: false, seed: true, utp: false, holepunch: false, reachable: true };
+
+    #[test]
+    fn test_flags_round_trip() {
+        assert_eq!(SEED.to_byte(), 0x12);
+        for byte in 0..0x20 {
+            assert_eq!(PexFlags::from_byte(byte).to_byte(), byte);
+        }
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let message = PexMessage {
+            added: vec![(addr("10.0.0.1:6881"), SEED), (addr("[::1]:6882"), PexFlags::default())],
+            dropped: vec![addr("10.0.0.2:6881"), addr("[::2]:80")],
+        };
+        assert_eq!(PexMessage::from_bencode(&message.to_bencode()).unwrap(), message);
+        let decoded = PexMessage::from_bencode(b"d5:added6:\x0a\x00\x00\x01\x1a\xe1e").unwrap();
+        assert_eq!(decoded.added, vec![(addr("10.0.0.1:6881"), PexFlags::default())]);
+        assert!(PexMessage::from_bencode(b"d5:added5:abcdee").is_err());
+        assert!(PexMessage::from_bencode(b"li1ee").is_err());
+    }
+
+    #[test]
+    fn test_private_torrents_exchange_nothing() {
+        let (_, connected) = watch::channel(HashMap::new());
+        assert!(PexExtension::new(&Torrent::fixture(true, &[]), addr("10.0.0.9:1"), connected.clone(), mpsc::channel(1).0).is_none());
+        assert!(PexExtension::new(&Torrent::fixture(false, &[]), addr("10.0.0.9:1"), connected, mpsc::channel(1).0).is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sends_changes_on_schedule() {
+        let remote = addr("10.0.0.9:6881");
+        let (connected_tx, connected_rx) = watch::channel(HashMap::from([(addr("10.0.0.1:6881"), SEED), (remote, SEED)]));
+        let mut registry = ExtensionRegistry::new();
+        registry.register(Box::new(PexExtension::new(&Torrent::fixture(false, &[]), remote, connected_rx, mpsc::channel(1).0).unwrap())).unwrap();
+        let theirs = ExtendedHandshake { m: BTreeMap::from([(String::from(UT_PEX), 4)]), ..Default::default() };
+        registry.handle(0, &theirs.to_bencode()).unwrap();
+
+        let message = |message: PexMessage| PeerMessage::Extended { id: 4, payload: message.to_bencode() };
+        let now = Instant::now();
+        assert_eq!(registry.tick(now), vec![message(PexMessage { added: vec![(addr("10.0.0.1:6881"), SEED)], dropped: vec![] })]);
+        connected_tx.send_modify(|peers| {
+            peers.remove(&addr("10.0.0.1:6881"));
+            peers.insert(addr("[::1]:6881"), PexFlags::default());
+        });
+        assert_eq!(registry.tick(now + Duration::from_secs(30)), vec![]);
+        assert_eq!(registry.tick(now + PEX_INTERVAL), vec![message(PexMessage {
+            added: vec![(addr("[::1]:6881"), PexFlags::default())],
+            dropped: vec![addr("10.0.0.1:6881")],
+        })]);
+        assert_eq!(registry.tick(now + PEX_INTERVAL * 2), vec![]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_feeds_peer_list_and_ignores_floods() {
+        let remote = addr("10.0.0.9:6881");
+        let (peers_tx, mut peers_rx) = mpsc::channel(100);
+        let (_, connected) = watch::channel(HashMap::new());
+        let mut pex = PexExtension::new(&Torrent::fixture(false, &[]), remote, connected, peers_tx).unwrap();
+
+        let added = (1..=60).map(|i| (addr(&format!("10.0.1.{i}:6881")), PexFlags::default()));
+        let message = PexMessage { added: [(remote, SEED), (addr("10.0.0.1:0"), SEED)].into_iter().chain(added).collect(), dropped: vec![] };
+        pex.on_message(&message.to_bencode()).unwrap();
+        let mut received = vec![];
+        while let Ok(peer) = peers_rx.try_recv() {
+            received.push(peer.socket_addr);
+        }
+        assert_eq!(received.len(), MAX_PEX_PEERS - 2);
+        assert_eq!(received[0], addr("10.0.1.1:6881"));
+
+        let flood = PexMessage { added: vec![(addr("10.0.2.1:6881"), SEED)], dropped: vec![] };
+        pex.on_message(&flood.to_bencode()).unwrap();
+        assert!(peers_rx.try_recv().is_err());
+        tokio::time::advance(PEX_INTERVAL).await;
+        pex.on_message(&flood.to_bencode()).unwrap();
+        assert_eq!(peers_rx.try_recv().unwrap().socket_addr, addr("10.0.2.1:6881"));
+    }
+}
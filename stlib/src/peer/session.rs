@@ -0,0 +1,377 @@
+//! Protocol state of a connection to a peer, kept apart from the socket: incoming messages
+//! update it and yield the messages to send back.
+
+use std::collections::{HashSet, VecDeque};
+use std::io;
+use std::net::Ipv4Addr;
+use bit_vec::BitVec;
+
+use crate::model::{PeerMessage, Sha1Hash};
+
+use super::fast::{allowed_fast_set, ALLOWED_FAST_SET_SIZE};
+use super::handshake::PeerCapabilities;
+use super::peer::Peer;
+
+/// Largest block a peer may request, the size every client requests in.
+pub const MAX_REQUEST_LENGTH: u32 = 16 * 1024;
+/// Requests of a peer queued at once, to advertise as `reqq` in the extension handshake.
+/// Further ones are rejected with the Fast Extension and close the connection without it.
+pub const MAX_INCOMING_REQUESTS: usize = 250;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockRequest {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+impl BlockRequest {
+    fn reject(&self) -> PeerMessage {
+        PeerMessage::RejectRequest { index: self.index, begin: self.begin, length: self.length }
+    }
+}
+
+pub struct PeerSession {
+    peer: Peer,
+    capabilities: PeerCapabilities,
+    num_pieces: u32,
+    /// Pieces the peer may request while we choke it.
+    allowed_fast_granted: HashSet<u32>,
+    /// Pieces we may request while the peer chokes us.
+    allowed_fast_received: HashSet<u32>,
+    suggested: Vec<u32>,
+    /// Requests of the peer we are yet to serve, in the order they came.
+    incoming_requests: VecDeque<BlockRequest>,
+    /// The same requests, to find duplicates.
+    queued_requests: HashSet<BlockRequest>,
+    /// Our requests the peer is yet to serve or reject.
+    outgoing_requests: Vec<BlockRequest>,
+}
+
+fn protocol_error(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+impl PeerSession {
+    /// Starts a session with both sides choked and uninterested. `capabilities` are the ones
+    /// negotiated by the handshake.
+    pub fn new(mut peer: Peer, capabilities: PeerCapabilities, num_pieces: u32) -> Self {
+        peer.bitfield = BitVec::from_elem(num_pieces as usize, false);
+        Self {
+            peer,
+            capabilities,
+            num_pieces,
+            allowed_fast_granted: HashSet::new(),
+            allowed_fast_received: HashSet::new(),
+            suggested: vec![],
+            incoming_requests: VecDeque::new(),
+            queued_requests: HashSet::new(),
+            outgoing_requests: vec![],
+        }
+    }
+
+    pub fn peer(&self) -> &Peer {
+        &self.peer
+    }
+
+    pub fn capabilities(&self) -> &PeerCapabilities {
+        &self.capabilities
+    }
+
+    /// Pieces the peer suggested we download, oldest first.
+    pub fn suggested_pieces(&self) -> &[u32] {
+        &self.suggested
+    }
+
+    pub fn outgoing_requests(&self) -> &[BlockRequest] {
+        &self.outgoing_requests
+    }
+
+    /// The message announcing the pieces we have, `have all` or `have none` when the Fast
+    /// Extension allows it.
+    pub fn have_message(&self, have: &BitVec) -> PeerMessage {
+        if self.capabilities.fast && have.all() {
+            PeerMessage::HaveAll
+        } else if self.capabilities.fast && have.none() {
+            PeerMessage::HaveNone
+        } else {
+            PeerMessage::Bitfield(have.clone())
+        }
+    }
+
+    /// Applies a message received from the peer and returns the replies it calls for.
+    /// Protocol violations are `InvalidData` errors, after which the connection should be closed.
+    pub fn handle(&mut self, message: PeerMessage) -> Result<Vec<PeerMessage>, io::Error> {
+        if message.is_fast_extension() && !self.capabilities.fast {
+            return Err(protocol_error("Fast Extension message without negotiating it"));
+        }
+        if matches!(message, PeerMessage::Extended { .. }) && !self.capabilities.extension_protocol {
+            return Err(protocol_error("Extended message without negotiating the extension protocol"));
+        }
+        match message {
+            // Extended messages are routed by an `ExtensionRegistry`.
+            PeerMessage::KeepAlive | PeerMessage::Extended { .. } => {},
+            PeerMessage::Choke => {
+                self.peer.chocking = true;
+                // Without the Fast Extension, a choke silently discards every pending request.
+                if !self.capabilities.fast {
+                    self.outgoing_requests.clear();
+                }
+            },
+            PeerMessage::Unchoke => self.peer.chocking = false,
+            PeerMessage::Interested => self.peer.interested = true,
+            PeerMessage::NotInterested => self.peer.interested = false,
+            PeerMessage::Have(index) => {
+                self.check_index(index)?;
+                self.peer.bitfield.set(index as usize, true);
+            },
+            PeerMessage::Bitfield(mut bits) => {
+                bits.truncate(self.num_pieces as usize);
+                if bits.len() < self.num_pieces as usize {
+                    return Err(protocol_error("Bitfield is shorter than the number of pieces"));
+                }
+                self.peer.bitfield = bits;
+            },
+            PeerMessage::HaveAll => self.peer.bitfield.set_all(),
+            PeerMessage::HaveNone => self.peer.bitfield.clear(),
+            PeerMessage::Request { index, begin, length } => {
+                self.check_index(index)?;
+                if length == 0 || length > MAX_REQUEST_LENGTH {
+                    return Err(protocol_error("Requested block length out of range"));
+                }
+                let request = BlockRequest { index, begin, length };
+                if self.peer.choked && !self.allowed_fast_granted.contains(&index) {
+                    return Ok(self.reject(request));
+                }
+                if self.queued_requests.contains(&request) {
+                    return Ok(vec![]);
+                }
+                if self.incoming_requests.len() >= MAX_INCOMING_REQUESTS {
+                    if !self.capabilities.fast {
+                        return Err(protocol_error("Too many pending requests"));
+                    }
+                    return Ok(self.reject(request));
+                }
+                self.queued_requests.insert(request);
+                self.incoming_requests.push_back(request);
+            },
+            PeerMessage::Cancel { index, begin, length } => {
+                let request = BlockRequest { index, begin, length };
+                // With the Fast Extension every request gets an answer, even a cancelled one.
+                if self.queued_requests.remove(&request) {
+                    self.incoming_requests.retain(|queued| *queued != request);
+                    return Ok(self.reject(request));
+                }
+            },
+            PeerMessage::Piece { index, begin, .. } => {
+                self.outgoing_requests.retain(|request| request.index != index || request.begin != begin);
+            },
+            PeerMessage::RejectRequest { index, begin, length } => {
+                let request = BlockRequest { index, begin, length };
+                let Some(position) = self.outgoing_requests.iter().position(|pending| *pending == request) else {
+                    return Err(protocol_error("Rejected a request that was never sent"));
+                };
+                self.outgoing_requests.remove(position);
+            },
+            PeerMessage::AllowedFast(index) => {
+                if index < self.num_pieces {
+                    self.allowed_fast_received.insert(index);
+                }
+            },
+            PeerMessage::SuggestPiece(index) => {
+                if index < self.num_pieces && !self.suggested.contains(&index) {
+                    self.suggested.push(index);
+                }
+            },
+        }
+        Ok(vec![])
+    }
+
+    /// Chokes the peer. Pending requests are rejected with the Fast Extension, except for
+    /// allowed fast pieces, and silently dropped without it.
+    pub fn choke(&mut self) -> Vec<PeerMessage> {
+        if self.peer.choked {
+            return vec![];
+        }
+        self.peer.choked = true;
+        let mut messages = vec![PeerMessage::Choke];
+        if self.capabilities.fast {
+            let granted = &self.allowed_fast_granted;
+            messages.extend(self.incoming_requests.iter()
+                .filter(|request| !granted.contains(&request.index))
+                .map(BlockRequest::reject));
+            self.incoming_requests.retain(|request| granted.contains(&request.index));
+            self.queued_requests.retain(|request| granted.contains(&request.index));
+        } else {
+            self.incoming_requests.clear();
+            self.queued_requests.clear();
+        }
+        messages
+    }
+
+    pub fn unchoke(&mut self) -> Vec<PeerMessage> {
+        if !self.peer.choked {
+            return vec![];
+        }
+        self.peer.choked = false;
+        vec![PeerMessage::Unchoke]
+    }
+
+    /// Grants the peer its BEP 6 allowed fast set and returns the `allowed fast` messages
+    /// for the pieces it wasn't granted yet.
+    pub fn grant_allowed_fast(&mut self, info_hash: &Sha1Hash) -> Vec<PeerMessage> {
+        if !self.capabilities.fast {
+            return vec![];
+        }
+        allowed_fast_set(Ipv4Addr::from(self.peer.ip), info_hash, self.num_pieces, ALLOWED_FAST_SET_SIZE).into_iter()
+            .filter(|index| self.allowed_fast_granted.insert(*index))
+            .map(PeerMessage::AllowedFast)
+            .collect()
+    }
+
+    /// Whether the peer would serve a request for this piece now.
+    pub fn can_request(&self, index: u32) -> bool {
+        let has_piece = self.peer.bitfield.get(index as usize).unwrap_or(false);
+        let allowed = !self.peer.chocking || (self.capabilities.fast && self.allowed_fast_received.contains(&index));
+        has_piece && allowed
+    }
+
+    /// Records a request to send to the peer, unless it can't be served right now.
+    pub fn request(&mut self, request: BlockRequest) -> Option<PeerMessage> {
+        if !self.can_request(request.index) || self.outgoing_requests.contains(&request) {
+            return None;
+        }
+        self.outgoing_requests.push(request);
+        Some(PeerMessage::Request { index: request.index, begin: request.begin, length: request.length })
+    }
+
+    /// The next request of the peer to serve.
+    pub fn next_incoming_request(&mut self) -> Option<BlockRequest> {
+        let request = self.incoming_requests.pop_front()?;
+        self.queued_requests.remove(&request);
+        Some(request)
+    }
+
+    fn check_index(&self, index: u32) -> Result<(), io::Error> {
+        if index < self.num_pieces {
+            Ok(())
+        } else {
+            Err(protocol_error("Piece index out of range"))
+        }
+    }
+
+    fn reject(&self, request: BlockRequest) -> Vec<PeerMessage> {
+        if self.capabilities.fast {
+            vec![request.reject()]
+        } else {
+            vec![]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(fast: bool) -> PeerSession {
+        let capabilities = PeerCapabilities { fast, ..Default::default() };
+        PeerSession::new(Peer::new(u32::from(Ipv4Addr::new(80, 4, 4, 200)), 6881), capabilities, 1313)
+    }
+
+    fn request(index: u32) -> PeerMessage {
+        PeerMessage::Request { index, begin: 0, length: 16384 }
+    }
+
+    #[test]
+    fn test_requests_while_choking_are_rejected_with_fast() {
+        let mut fast = session(true);
+        assert_eq!(fast.handle(request(1)).unwrap(), vec![PeerMessage::RejectRequest { index: 1, begin: 0, length: 16384 }]);
+        let mut plain = session(false);
+        assert_eq!(plain.handle(request(1)).unwrap(), vec![]);
+        assert_eq!(plain.next_incoming_request(), None);
+    }
+
+    #[test]
+    fn test_choke_rejects_queued_requests_except_allowed_fast() {
+        let mut session = session(true);
+        let granted = session.grant_allowed_fast(&[0xaa; 20]);
+        assert_eq!(granted[..3], [PeerMessage::AllowedFast(1059), PeerMessage::AllowedFast(431), PeerMessage::AllowedFast(808)]);
+        assert!(session.grant_allowed_fast(&[0xaa; 20]).is_empty());
+        session.unchoke();
+        session.handle(request(1)).unwrap();
+        session.handle(request(1059)).unwrap();
+
+        assert_eq!(session.choke(), vec![PeerMessage::Choke, PeerMessage::RejectRequest { index: 1, begin: 0, length: 16384 }]);
+        assert_eq!(session.handle(request(431)).unwrap(), vec![]);
+        assert_eq!(session.next_incoming_request().map(|request| request.index), Some(1059));
+        assert_eq!(session.next_incoming_request().map(|request| request.index), Some(431));
+    }
+
+    #[test]
+    fn test_request_queue_and_length_are_bounded() {
+        let mut fast = session(true);
+        fast.unchoke();
+        assert!(fast.handle(PeerMessage::Request { index: 1, begin: 0, length: MAX_REQUEST_LENGTH + 1 }).is_err());
+        assert!(fast.handle(PeerMessage::Request { index: 1, begin: 0, length: 0 }).is_err());
+        let block = |begin: usize| PeerMessage::Request { index: 1, begin: (begin * 16384) as u32, length: 16384 };
+        for begin in 0..MAX_INCOMING_REQUESTS {
+            assert_eq!(fast.handle(block(begin)).unwrap(), vec![]);
+        }
+        assert_eq!(fast.handle(block(0)).unwrap(), vec![]);
+        let overflow = (MAX_INCOMING_REQUESTS * 16384) as u32;
+        assert_eq!(fast.handle(block(MAX_INCOMING_REQUESTS)).unwrap(), vec![PeerMessage::RejectRequest { index: 1, begin: overflow, length: 16384 }]);
+        fast.next_incoming_request();
+        assert_eq!(fast.handle(block(0)).unwrap(), vec![]);
+
+        let mut plain = session(false);
+        plain.unchoke();
+        for begin in 0..MAX_INCOMING_REQUESTS {
+            plain.handle(block(begin)).unwrap();
+        }
+        assert!(plain.handle(block(MAX_INCOMING_REQUESTS)).is_err());
+    }
+
+    #[test]
+    fn test_cancel_is_answered_with_reject() {
+        let mut session = session(true);
+        session.unchoke();
+        session.handle(request(2)).unwrap();
+        let cancel = PeerMessage::Cancel { index: 2, begin: 0, length: 16384 };
+        assert_eq!(session.handle(cancel.clone()).unwrap(), vec![PeerMessage::RejectRequest { index: 2, begin: 0, length: 16384 }]);
+        assert_eq!(session.handle(cancel).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_outgoing_requests_with_fast() {
+        let mut session = session(true);
+        session.handle(PeerMessage::HaveAll).unwrap();
+        assert!(!session.can_request(5));
+        session.handle(PeerMessage::AllowedFast(5)).unwrap();
+        let block = BlockRequest { index: 5, begin: 0, length: 16384 };
+        assert!(session.request(block).is_some());
+        session.handle(PeerMessage::Choke).unwrap();
+        assert_eq!(session.outgoing_requests(), &[block]);
+        session.handle(PeerMessage::RejectRequest { index: 5, begin: 0, length: 16384 }).unwrap();
+        assert!(session.outgoing_requests().is_empty());
+        assert!(session.handle(PeerMessage::RejectRequest { index: 5, begin: 0, length: 16384 }).is_err());
+
+        session.handle(PeerMessage::SuggestPiece(7)).unwrap();
+        session.handle(PeerMessage::HaveNone).unwrap();
+        assert_eq!(session.suggested_pieces(), &[7]);
+        assert!(!session.can_request(5));
+    }
+
+    #[test]
+    fn test_fast_messages_require_negotiation() {
+        let mut session = session(false);
+        session.handle(PeerMessage::Bitfield(BitVec::from_elem(1320, true))).unwrap();
+        session.handle(PeerMessage::Unchoke).unwrap();
+        assert!(session.request(BlockRequest { index: 1, begin: 0, length: 16384 }).is_some());
+        session.handle(PeerMessage::Choke).unwrap();
+        assert!(session.outgoing_requests().is_empty());
+        assert!(session.handle(PeerMessage::HaveAll).is_err());
+        assert!(session.handle(PeerMessage::Extended { id: 0, payload: b"de".to_vec() }).is_err());
+        assert!(session.handle(PeerMessage::Have(1313)).is_err());
+        assert_eq!(session.have_message(&BitVec::from_elem(8, true)), PeerMessage::Bitfield(BitVec::from_elem(8, true)));
+    }
+}
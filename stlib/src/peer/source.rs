@@ -0,0 +1,166 @@
+//! Where peers come from: every discovery mechanism is a [`PeerSource`], whose peers are
+//! collected into the torrent's [`crate::peer::peer_list::PeerList`].
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use futures_util::stream::{self, Stream};
+use tokio::sync::mpsc;
+use tokio::time::{interval, Interval};
+
+use crate::dht::Dht;
+use crate::model::{MagnetLink, PeerInfo, Sha1Hash, Torrent};
+
+/// How often the DHT is asked for the peers of a torrent.
+pub const DHT_PEERS_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// The mechanism a peer was discovered through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerOrigin {
+    Tracker,
+    Dht,
+    Pex,
+    Lsd,
+    /// The `x.pe` keys of a magnet link.
+    Magnet,
+    /// Added by the user, see [`crate::peer::peer_list::PeerList::add_manual`].
+    Manual,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredPeer {
+    pub info: PeerInfo,
+    pub origin: PeerOrigin,
+}
+
+pub trait PeerSource {
+    fn origin(&self) -> PeerOrigin;
+    /// The next peer found, `None` once the source has no more.
+    fn next_peer(&mut self) -> impl Future<Output = Option<PeerInfo>> + Send;
+
+    /// The peers found, tagged with the origin of the source.
+    fn into_stream(self) -> impl Stream<Item = DiscoveredPeer> + Send where Self: Sized + Send {
+        stream::unfold(self, |mut source| async move {
+            let info = source.next_peer().await?;
+            let origin = source.origin();
+            Some((DiscoveredPeer { info, origin }, source))
+        })
+    }
+}
+
+/// The peers sent to a channel, as [`crate::tracker::TrackerManager::run`],
+/// [`crate::peer::pex::PexExtension`] and [`crate::lsd::Lsd::add_torrent`] feed them.
+pub struct ChannelSource {
+    origin: PeerOrigin,
+    peers: mpsc::Receiver<PeerInfo>,
+}
+
+impl ChannelSource {
+    pub fn new(origin: PeerOrigin, peers: mpsc::Receiver<PeerInfo>) -> Self {
+        Self { origin, peers }
+    }
+
+    /// A source and the sender to hand to the mechanism feeding it.
+    pub fn channel(origin: PeerOrigin, capacity: usize) -> (mpsc::Sender<PeerInfo>, Self) {
+        let (tx, rx) = mpsc::channel(capacity);
+        (tx, Self::new(origin, rx))
+    }
+}
+
+impl PeerSource for ChannelSource {
+    fn origin(&self) -> PeerOrigin {
+        self.origin
+    }
+
+    async fn next_peer(&mut self) -> Option<PeerInfo> {
+        self.peers.recv().await
+    }
+}
+
+/// The peers named by the `x.pe` keys of a magnet link.
+pub struct MagnetSource {
+    peers: std::vec::IntoIter<SocketAddr>,
+}
+
+impl MagnetSource {
+    pub fn new(magnet: &MagnetLink) -> Self {
+        Self { peers: magnet.peers.clone().into_iter() }
+    }
+}
+
+impl PeerSource for MagnetSource {
+    fn origin(&self) -> PeerOrigin {
+        PeerOrigin::Magnet
+    }
+
+    async fn next_peer(&mut self) -> Option<PeerInfo> {
+        self.peers.next().map(|socket_addr| PeerInfo { socket_addr })
+    }
+}
+
+/// The peers the DHT knows for a torrent, looked up every `poll_interval`.
+pub struct DhtSource {
+    dht: Arc<Dht>,
+    info_hash: Sha1Hash,
+    ticks: Interval,
+    found: Vec<SocketAddr>,
+}
+
+impl DhtSource {
+    /// `None` for private torrents, whose peers must only come from their trackers (BEP 27).
+    pub fn new(dht: Arc<Dht>, torrent: &Torrent, poll_interval: Duration) -> Option<Self> {
+        if torrent.private {
+            return None;
+        }
+        Some(Self { dht, info_hash: torrent.info_hash, ticks: interval(poll_interval), found: vec![] })
+    }
+}
+
+impl PeerSource for DhtSource {
+    fn origin(&self) -> PeerOrigin {
+        PeerOrigin::Dht
+    }
+
+    async fn next_peer(&mut self) -> Option<PeerInfo> {
+        loop {
+            if let Some(socket_addr) = self.found.pop() {
+                return Some(PeerInfo { socket_addr });
+            }
+            self.ticks.tick().await;
+            self.found = self.dht.get_peers(self.info_hash).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+    use super::*;
+    use crate::dht::DhtConfig;
+
+    #[tokio::test]
+    async fn test_streams_tag_origins() {
+        let magnet = MagnetLink::parse("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&x.pe=10.0.0.1:6881&x.pe=10.0.0.2:6881").unwrap();
+        let peers: Vec<DiscoveredPeer> = MagnetSource::new(&magnet).into_stream().collect().await;
+        assert_eq!(peers, magnet.peers.iter().map(|addr| DiscoveredPeer {
+            info: PeerInfo { socket_addr: *addr },
+            origin: PeerOrigin::Magnet,
+        }).collect::<Vec<_>>());
+
+        let (tx, source) = ChannelSource::channel(PeerOrigin::Lsd, 4);
+        let peer = PeerInfo { socket_addr: "10.0.0.3:6881".parse().unwrap() };
+        tx.send(peer.clone()).await.unwrap();
+        drop(tx);
+        let peers: Vec<DiscoveredPeer> = source.into_stream().collect().await;
+        assert_eq!(peers, vec![DiscoveredPeer { info: peer, origin: PeerOrigin::Lsd }]);
+    }
+
+    #[tokio::test]
+    async fn test_dht_source_refuses_private_torrents() {
+        let config = DhtConfig { bind_addrs: vec![SocketAddr::from(([127, 0, 0, 1], 0))], ..DhtConfig::default() };
+        let dht = Arc::new(Dht::bind(config).await.unwrap());
+        assert!(DhtSource::new(dht.clone(), &Torrent::fixture(false, &[]), DHT_PEERS_INTERVAL).is_some());
+        assert!(DhtSource::new(dht, &Torrent::fixture(true, &[]), DHT_PEERS_INTERVAL).is_none());
+    }
+}
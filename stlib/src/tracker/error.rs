@@ -0,0 +1,96 @@
+use std::{fmt, io};
+
+/// How long a tracker asked us to wait before retrying after a failure (BEP 31).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryIn {
+    Minutes(u32),
+    Never,
+}
+
+#[derive(Debug)]
+pub enum TrackerError {
+    /// The tracker answered with a `failure reason`.
+    Failure {
+        reason: String,
+        retry_in: Option<RetryIn>,
+    },
+    /// The tracker answered with something that isn't a valid response.
+    InvalidResponse(String),
+    /// The tracker's response is larger than the given number of bytes.
+    ResponseTooLarge(usize),
+    /// The tracker didn't answer in time.
+    Timeout,
+    /// The announce url uses a scheme or format no connector handles.
+    UnsupportedUrl(String),
+    /// Every tracker failed or is waiting for its retry delay to expire.
+    NoTrackerAvailable,
+    /// The announce was attempted after `stopped` was sent.
+    Stopped,
+    Io(io::Error),
+    Http(reqwest::Error),
+    WebSocket(Box<tokio_tungstenite::tungstenite::Error>),
+}
+
+impl fmt::Display for TrackerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Failure { reason, retry_in: None } => write!(f, "Tracker failure: {reason}"),
+            Self::Failure { reason, retry_in: Some(RetryIn::Never) } => write!(f, "Tracker failure: {reason} (retry never)"),
+            Self::Failure { reason, retry_in: Some(RetryIn::Minutes(minutes)) } => write!(f, "Tracker failure: {reason} (retry in {minutes} minutes)"),
+            Self::InvalidResponse(reason) => write!(f, "Invalid tracker response: {reason}"),
+            Self::ResponseTooLarge(limit) => write!(f, "Tracker response larger than {limit} bytes"),
+            Self::Timeout => write!(f, "Tracker request timed out"),
+            Self::UnsupportedUrl(url) => write!(f, "Unsupported tracker url '{url}'"),
+            Self::NoTrackerAvailable => write!(f, "No tracker available"),
+            Self::Stopped => write!(f, "Tracker announces are stopped"),
+            Self::Io(err) => write!(f, "Tracker I/O error: {err}"),
+            Self::Http(err) => write!(f, "Tracker HTTP error: {err}"),
+            Self::WebSocket(err) => write!(f, "Tracker WebSocket error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for TrackerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            Self::Http(err) => Some(err),
+            Self::WebSocket(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for TrackerError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::TimedOut => Self::Timeout,
+            _ => Self::Io(err),
+        }
+    }
+}
+
+impl From<reqwest::Error> for TrackerError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            Self::Timeout
+        } else {
+            Self::Http(err)
+        }
+    }
+}
+
+impl From<tokio_tungstenite::tungstenite::Error> for TrackerError {
+    fn from(err: tokio_tungstenite::tungstenite::Error) -> Self {
+        match err {
+            tokio_tungstenite::tungstenite::Error::Io(err) => err.into(),
+            err => Self::WebSocket(Box::new(err)),
+        }
+    }
+}
+
+impl From<serde_bencode::Error> for TrackerError {
+    fn from(err: serde_bencode::Error) -> Self {
+        Self::InvalidResponse(err.to_string())
+    }
+}
@@ -1,17 +1,51 @@
+use std::time::Duration;
 use reqwest::Client;
 
 use crate::model::TrackerNetworkInfo;
+use crate::util::proxy::ProxyConfig;
 
-use super::{TrackerConnector, TrackerEvent};
+use super::{TrackerConnector, TrackerError, TrackerEvent, TrackerScrapeRequest, TrackerScrapeResponse, TrackerUrl};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Larger response bodies are refused rather than read into memory.
+pub const MAX_RESPONSE_SIZE: usize = 1024 * 1024;
 
 pub struct HttpTrackerConnector {
     client: Client,
 }
 
+impl Default for HttpTrackerConnector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HttpTrackerConnector {
+    pub fn new() -> Self {
+        Self::with_timeout(DEFAULT_TIMEOUT)
+    }
+
+    pub fn with_timeout(timeout: Duration) -> Self {
+        let client = Client::builder()
+            .timeout(timeout)
+            .build()
+            .expect("Couldn't build HTTP client");
+        Self { client }
+    }
+
+    /// Sends announces through `proxy` instead of the proxy named by the environment, if any.
+    pub fn with_proxy(proxy: &ProxyConfig) -> Result<Self, TrackerError> {
+        let client = Client::builder()
+            .timeout(DEFAULT_TIMEOUT)
+            .proxy(proxy.to_reqwest()?)
+            .build()?;
+        Ok(Self { client })
+    }
+}
+
 impl TrackerConnector for HttpTrackerConnector {
-    async fn announce(&mut self, request: &super::TrackerAnnounceRequest) -> Result<TrackerNetworkInfo, String> {
-        let url = format!("{}?info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact={}&event={}", 
-            request.url,
+    async fn announce(&mut self, request: &super::TrackerAnnounceRequest) -> Result<TrackerNetworkInfo, TrackerError> {
+        let mut query = format!("info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact={}",
             urlencoding::encode_binary(&request.info_hash),
             urlencoding::encode_binary(&request.peer_id),
             request.port,
@@ -19,25 +53,59 @@ impl TrackerConnector for HttpTrackerConnector {
             request.downloaded,
             request.left,
             if request.compact { "1" } else { "0" },
-            event_to_string(&request.event),
-        );  
-        
+        );
+        if request.event != TrackerEvent::None {
+            query.push_str("&event=");
+            query.push_str(event_to_string(&request.event));
+        }
+        if let Some(num_want) = request.num_want {
+            query.push_str(&format!("&numwant={num_want}"));
+        }
+        if let Some(tracker_id) = &request.tracker_id {
+            query.push_str("&trackerid=");
+            query.push_str(&urlencoding::encode(tracker_id));
+        }
+
+        let response = self.client
+            .get(TrackerUrl::parse(&request.url)?.with_query(&query))
+            .send()
+            .await?;
+        TrackerNetworkInfo::from_bencode(&read_body(response).await?)
+    }
+
+    async fn scrape(&mut self, request: &TrackerScrapeRequest) -> Result<TrackerScrapeResponse, TrackerError> {
+        let url = TrackerUrl::parse(request.url())?;
+        let scrape_url = url.scrape_url().ok_or_else(|| TrackerError::UnsupportedUrl(request.url().to_string()))?;
+        let query = request.info_hashes().iter()
+            .map(|info_hash| format!("info_hash={}", urlencoding::encode_binary(info_hash)))
+            .collect::<Vec<_>>()
+            .join("&");
         let response = self.client
-            .get(url)
+            .get(scrape_url.with_query(&query))
             .send()
-            .await
-            .expect("Something went wrong with request")
-            .bytes()
-            .await 
-            .expect("Couldn't convert response body to bytes");
-        
-        Ok(TrackerNetworkInfo::from_bencode(&response)?)
+            .await?;
+        TrackerScrapeResponse::from_bencode(&read_body(response).await?)
     }
+}
 
+/// The body of `response`, as long as it's no larger than [`MAX_RESPONSE_SIZE`], whatever its
+/// `Content-Length` says.
+async fn read_body(mut response: reqwest::Response) -> Result<Vec<u8>, TrackerError> {
+    if response.content_length().is_some_and(|len| len > MAX_RESPONSE_SIZE as u64) {
+        return Err(TrackerError::ResponseTooLarge(MAX_RESPONSE_SIZE));
+    }
+    let mut body = vec![];
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > MAX_RESPONSE_SIZE {
+            return Err(TrackerError::ResponseTooLarge(MAX_RESPONSE_SIZE));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
 }
 
 
-fn event_to_string(event: &TrackerEvent) -> &'static str {
+pub(super) fn event_to_string(event: &TrackerEvent) -> &'static str {
     match event {
         TrackerEvent::Completed => "completed",
         TrackerEvent::Started => "started",
@@ -46,3 +114,94 @@ fn event_to_string(event: &TrackerEvent) -> &'static str {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::PeerInfo;
+    use crate::tracker::mock::{MockResponse, MockTracker};
+    use crate::tracker::TrackerAnnounceRequest;
+    use crate::util::mock_proxy::MockProxy;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_announce_sends_request_parameters() {
+        let tracker = MockTracker::start().await.unwrap();
+        let peer = PeerInfo { socket_addr: "10.0.0.1:6881".parse().unwrap() };
+        tracker.push(MockResponse::Response(TrackerNetworkInfo {
+            interval: 60,
+            peers: vec![peer.clone()],
+            tracker_id: Some(String::from("tracker 1")),
+            ..Default::default()
+        }));
+        let mut request = TrackerAnnounceRequest::new(tracker.http_url(), [0xff; 20], [b'a'; 20], 6881);
+        request.event = TrackerEvent::Started;
+        request.left = 100;
+        request.num_want = Some(5);
+
+        let mut connector = HttpTrackerConnector::new();
+        let info = connector.announce(&request).await.unwrap();
+        assert_eq!((info.interval, info.peers), (60, vec![peer]));
+        request.tracker_id = info.tracker_id;
+        request.event = TrackerEvent::None;
+        connector.announce(&request).await.unwrap();
+
+        let announces = tracker.announces();
+        assert_eq!(announces[0].info_hash, [0xff; 20]);
+        assert_eq!((announces[0].event, announces[0].left, announces[0].num_want), (TrackerEvent::Started, 100, Some(5)));
+        assert_eq!(announces[0].tracker_id, None);
+        assert_eq!(announces[1].event, TrackerEvent::None);
+        assert_eq!(announces[1].tracker_id.as_deref(), Some("tracker 1"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_announce_through_proxies() {
+        let tracker = MockTracker::start().await.unwrap();
+        let request = TrackerAnnounceRequest::new(tracker.http_url(), [1; 20], [2; 20], 6881);
+        for template in [ProxyConfig::socks5("").with_credentials("user", "secret"), ProxyConfig::http("")] {
+            let proxy = MockProxy::start(template).await.unwrap();
+            let mut connector = HttpTrackerConnector::with_proxy(&proxy.config()).unwrap();
+            connector.announce(&request).await.unwrap();
+            assert_eq!(proxy.relayed(), 1);
+        }
+        assert_eq!(tracker.announces().len(), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_announce_failure() {
+        let tracker = MockTracker::start().await.unwrap();
+        tracker.push(MockResponse::Failure(String::from("unregistered torrent")));
+        let request = TrackerAnnounceRequest::new(tracker.http_url(), [1; 20], [2; 20], 6881);
+        let err = HttpTrackerConnector::new().announce(&request).await.unwrap_err();
+        assert!(matches!(err, TrackerError::Failure { ref reason, .. } if reason == "unregistered torrent"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_announce_times_out() {
+        let tracker = MockTracker::start().await.unwrap();
+        tracker.push(MockResponse::Timeout);
+        let request = TrackerAnnounceRequest::new(tracker.http_url(), [1; 20], [2; 20], 6881);
+        let err = HttpTrackerConnector::with_timeout(Duration::from_millis(100)).announce(&request).await.unwrap_err();
+        assert!(matches!(err, TrackerError::Timeout), "{err:?}");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_announce_rejects_malformed_bodies() {
+        let tracker = MockTracker::start().await.unwrap();
+        tracker.push(MockResponse::Truncated);
+        tracker.push(MockResponse::Raw(b"<html>".to_vec()));
+        let request = TrackerAnnounceRequest::new(tracker.http_url(), [1; 20], [2; 20], 6881);
+        let mut connector = HttpTrackerConnector::new();
+        for _ in 0..2 {
+            let err = connector.announce(&request).await.unwrap_err();
+            assert!(matches!(err, TrackerError::InvalidResponse(_)), "{err:?}");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_announce_rejects_oversized_bodies() {
+        let tracker = MockTracker::start().await.unwrap();
+        tracker.push(MockResponse::Raw(vec![b'l'; MAX_RESPONSE_SIZE + 1]));
+        let request = TrackerAnnounceRequest::new(tracker.http_url(), [1; 20], [2; 20], 6881);
+        let err = HttpTrackerConnector::new().announce(&request).await.unwrap_err();
+        assert!(matches!(err, TrackerError::ResponseTooLarge(MAX_RESPONSE_SIZE)), "{err:?}");
+    }
+}
@@ -0,0 +1,823 @@
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use rand::seq::SliceRandom;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{sleep_until, timeout, Instant};
+
+use crate::model::{PeerInfo, Sha1Hash, Torrent, TrackerNetworkInfo};
+use crate::peer::peer::PeerId;
+use crate::util::proxy::ProxyConfig;
+
+use super::{
+    AnyTrackerConnector, RetryIn, TrackerAnnounceRequest, TrackerConnector, TrackerError, TrackerEvent, TrackerScrapeRequest,
+    TrackerUrl,
+};
+
+const DEFAULT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30 * 60);
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(15);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(60 * 60);
+/// Upper bound on the peers remembered to deduplicate the ones returned by trackers.
+pub const MAX_REMEMBERED_PEERS: usize = 10_000;
+/// Time a tracker learned from a peer is given to answer its test announce, retries included.
+pub const TEST_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TransferProgress {
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+}
+
+#[derive(Debug)]
+pub enum TrackerCommand {
+    Progress(TransferProgress),
+    Completed,
+    /// Asks for a snapshot of [`TrackerManager::stats`].
+    Stats(oneshot::Sender<Vec<TrackerStats>>),
+    Stop,
+}
+
+/// Health of a single tracker, as seen by the announces and scrapes sent to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackerStats {
+    pub url: String,
+    pub tier: usize,
+    pub last_announce: Option<Instant>,
+    pub last_error: Option<String>,
+    pub consecutive_failures: u32,
+    /// How long the last announce took to succeed or fail.
+    pub latency: Option<Duration>,
+    pub peers_returned: Option<usize>,
+    pub seeders: Option<u32>,
+    pub leechers: Option<u32>,
+    /// Number of times the torrent was completed, as last scraped.
+    pub completed: Option<u32>,
+    pub last_scrape_error: Option<String>,
+    /// When the tracker will next be announced to, if ever.
+    pub next_announce: Option<Instant>,
+    pub disabled: bool,
+}
+
+/// A tracker learned from a peer that answered its test announce, to be added with
+/// [`TrackerManager::add_tested_tracker`].
+pub struct TestedTracker<C> {
+    pub url: String,
+    pub connector: C,
+    pub info: TrackerNetworkInfo,
+    pub announced_at: Instant,
+    pub latency: Duration,
+}
+
+impl<C: TrackerConnector> TestedTracker<C> {
+    /// Sends `request` to `url` as a test announce, failing with [`TrackerError::Timeout`] after
+    /// [`TEST_ANNOUNCE_TIMEOUT`] whatever the connector's own retries.
+    pub async fn test(url: String, mut connector: C, request: &TrackerAnnounceRequest) -> Result<Self, TrackerError> {
+        let mut request = request.clone();
+        request.url.clone_from(&url);
+        let announced_at = Instant::now();
+        let info = timeout(TEST_ANNOUNCE_TIMEOUT, connector.announce(&request)).await
+            .map_err(|_| TrackerError::Timeout)??;
+        Ok(Self { url, connector, info, announced_at, latency: announced_at.elapsed() })
+    }
+}
+
+struct TrackerEntry<C> {
+    url: String,
+    connector: C,
+    tracker_id: Option<String>,
+    consecutive_failures: u32,
+    retry_at: Option<Instant>,
+    disabled: bool,
+    last_announce: Option<Instant>,
+    last_error: Option<String>,
+    latency: Option<Duration>,
+    peers_returned: Option<usize>,
+    seeders: Option<u32>,
+    leechers: Option<u32>,
+    completed: Option<u32>,
+    last_scrape_error: Option<String>,
+}
+
+impl<C> TrackerEntry<C> {
+    fn new(url: String, connector: C) -> Self {
+        Self {
+            url,
+            connector,
+            tracker_id: None,
+            consecutive_failures: 0,
+            retry_at: None,
+            disabled: false,
+            last_announce: None,
+            last_error: None,
+            latency: None,
+            peers_returned: None,
+            seeders: None,
+            leechers: None,
+            completed: None,
+            last_scrape_error: None,
+        }
+    }
+
+    fn is_available(&self, now: Instant) -> bool {
+        !self.disabled && self.retry_at.is_none_or(|retry_at| retry_at <= now)
+    }
+
+    fn record_success(&mut self, now: Instant, info: &TrackerNetworkInfo) {
+        self.consecutive_failures = 0;
+        self.retry_at = None;
+        self.record_response(now, info);
+        if info.tracker_id.is_some() {
+            self.tracker_id.clone_from(&info.tracker_id);
+        }
+    }
+
+    /// Updates the stats alone, leaving the schedule as it is.
+    fn record_response(&mut self, now: Instant, info: &TrackerNetworkInfo) {
+        self.last_announce = Some(now);
+        self.last_error = None;
+        self.latency = Some(now.elapsed());
+        self.peers_returned = Some(info.peers.len());
+        if info.complete.is_some() || info.incomplete.is_some() {
+            self.seeders = info.complete;
+            self.leechers = info.incomplete;
+        }
+    }
+
+    fn record_failure(&mut self, now: Instant, err: &TrackerError) {
+        self.consecutive_failures += 1;
+        self.record_error(now, err);
+        let delay = match err {
+            TrackerError::Failure { retry_in: Some(RetryIn::Never), .. } => {
+                self.disabled = true;
+                return;
+            },
+            TrackerError::Failure { retry_in: Some(RetryIn::Minutes(minutes)), .. } => {
+                Duration::from_secs(*minutes as u64 * 60)
+            },
+            _ => {
+                let exponent = (self.consecutive_failures - 1).min(16);
+                RETRY_BASE_DELAY.saturating_mul(2u32.pow(exponent)).min(RETRY_MAX_DELAY)
+            },
+        };
+        self.retry_at = Some(now + delay);
+    }
+
+    /// Updates the stats alone, leaving the schedule as it is.
+    fn record_error(&mut self, now: Instant, err: &TrackerError) {
+        self.last_announce = Some(now);
+        self.last_error = Some(err.to_string());
+        self.latency = Some(now.elapsed());
+    }
+}
+
+/// Owns every tracker tier of a torrent and announces to them following BEP 12:
+/// trackers of a tier are tried in order, a responding tracker is moved to the front
+/// of its tier, and the next tier is only used once every tracker of the previous one failed.
+pub struct TrackerManager<C: TrackerConnector> {
+    tiers: Vec<Vec<TrackerEntry<C>>>,
+    info_hash: Sha1Hash,
+    peer_id: PeerId,
+    port: u16,
+    progress: TransferProgress,
+    started: bool,
+    completed_pending: bool,
+    completed_sent: bool,
+    stopped: bool,
+    next_announce: Instant,
+    known_peers: HashSet<SocketAddr>,
+    external_ip: Option<IpAddr>,
+    /// Index of the tier holding trackers learned from peers rather than the metainfo.
+    exchanged_tier: Option<usize>,
+}
+
+impl TrackerManager<AnyTrackerConnector> {
+    /// Builds a manager for the torrent's announce tiers, shuffling each tier as BEP 12 asks.
+    /// Trackers whose protocol isn't supported are skipped.
+    pub fn from_torrent(torrent: &Torrent, peer_id: PeerId, port: u16) -> Self {
+        Self::from_torrent_with_proxy(torrent, peer_id, port, None)
+    }
+
+    /// Like [`Self::from_torrent`], with every tracker reached through `proxy` when set.
+    pub fn from_torrent_with_proxy(torrent: &Torrent, peer_id: PeerId, port: u16, proxy: Option<&ProxyConfig>) -> Self {
+        let mut rng = rand::thread_rng();
+        let connector = |url: &String| {
+            let tracker_url = TrackerUrl::parse(url).ok()?;
+            let connector = match proxy {
+                Some(proxy) => tracker_url.proxied_connector(proxy).ok()?,
+                None => tracker_url.connector(),
+            };
+            Some((url.clone(), connector))
+        };
+        let tiers = torrent.announce_tiers.iter()
+            .map(|tier| {
+                let mut tier: Vec<(String, AnyTrackerConnector)> = tier.iter()
+                    .filter_map(connector)
+                    .collect();
+                tier.shuffle(&mut rng);
+                tier
+            })
+            .collect();
+        Self::new(torrent.info_hash, peer_id, port, tiers)
+    }
+}
+
+impl<C: TrackerConnector> TrackerManager<C> {
+    pub fn new(info_hash: Sha1Hash, peer_id: PeerId, port: u16, tiers: Vec<Vec<(String, C)>>) -> Self {
+        let tiers = tiers.into_iter()
+            .filter(|tier| !tier.is_empty())
+            .map(|tier| tier.into_iter()
+                .map(|(url, connector)| TrackerEntry::new(url, connector))
+                .collect())
+            .collect();
+        Self {
+            tiers,
+            info_hash,
+            peer_id,
+            port,
+            progress: TransferProgress::default(),
+            started: false,
+            completed_pending: false,
+            completed_sent: false,
+            stopped: false,
+            next_announce: Instant::now(),
+            known_peers: HashSet::new(),
+            external_ip: None,
+            exchanged_tier: None,
+        }
+    }
+
+    pub fn set_progress(&mut self, progress: TransferProgress) {
+        self.progress = progress;
+    }
+
+    /// Queues a `completed` event and makes the next announce due immediately.
+    pub fn completed(&mut self) {
+        if self.completed_sent || self.completed_pending {
+            return;
+        }
+        self.completed_pending = true;
+        self.next_announce = Instant::now();
+    }
+
+    pub fn next_announce(&self) -> Instant {
+        self.next_announce
+    }
+
+    /// Our address as last reported by a tracker (BEP 24).
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        self.external_ip
+    }
+
+    pub fn tracker_urls(&self) -> Vec<Vec<&str>> {
+        self.tiers.iter()
+            .map(|tier| tier.iter().map(|entry| entry.url.as_str()).collect())
+            .collect()
+    }
+
+    /// A snapshot of every tracker's health, in tier order.
+    pub fn stats(&self) -> Vec<TrackerStats> {
+        self.tiers.iter()
+            .enumerate()
+            .flat_map(|(tier, entries)| entries.iter().map(move |entry| (tier, entry)))
+            .map(|(tier, entry)| TrackerStats {
+                url: entry.url.clone(),
+                tier,
+                last_announce: entry.last_announce,
+                last_error: entry.last_error.clone(),
+                consecutive_failures: entry.consecutive_failures,
+                latency: entry.latency,
+                peers_returned: entry.peers_returned,
+                seeders: entry.seeders,
+                leechers: entry.leechers,
+                completed: entry.completed,
+                last_scrape_error: entry.last_scrape_error.clone(),
+                next_announce: match entry.retry_at {
+                    _ if entry.disabled || self.stopped => None,
+                    Some(retry_at) => Some(retry_at.max(self.next_announce)),
+                    None => Some(self.next_announce),
+                },
+                disabled: entry.disabled,
+            })
+            .collect()
+    }
+
+    /// Announces to and scrapes every tracker that isn't disabled, ignoring backoff, and returns
+    /// the resulting stats. Unlike [`Self::announce`] this sends no lifecycle event and leaves the
+    /// tier order, the known peers and the schedule alone: failures count towards no backoff and
+    /// disable no tracker.
+    pub async fn probe(&mut self) -> Vec<TrackerStats> {
+        let mut request = self.announce_request(TrackerEvent::None);
+        for entry in self.tiers.iter_mut().flatten().filter(|entry| !entry.disabled) {
+            request.url.clone_from(&entry.url);
+            request.tracker_id.clone_from(&entry.tracker_id);
+            let now = Instant::now();
+            match entry.connector.announce(&request).await {
+                Ok(info) => entry.record_response(now, &info),
+                Err(err) => entry.record_error(now, &err),
+            }
+            let scrape = TrackerScrapeRequest::new(entry.url.clone(), vec![self.info_hash]);
+            match entry.connector.scrape(&scrape).await {
+                Ok(response) => {
+                    let stats = response.files.get(&self.info_hash).copied().unwrap_or_default();
+                    entry.seeders = Some(stats.complete);
+                    entry.leechers = Some(stats.incomplete);
+                    entry.completed = Some(stats.downloaded);
+                    entry.last_scrape_error = None;
+                },
+                Err(err) => entry.last_scrape_error = Some(err.to_string()),
+            }
+        }
+        self.stats()
+    }
+
+    pub fn has_tracker(&self, url: &str) -> bool {
+        self.tiers.iter().flatten().any(|entry| entry.url == url)
+    }
+
+    /// Sends a test announce to a tracker learned from a peer and, if it answers, adds it to
+    /// a last tier of its own, after every tracker of the metainfo.
+    pub async fn add_exchanged_tracker(&mut self, url: String, connector: C) -> Result<(), TrackerError> {
+        if self.stopped {
+            return Err(TrackerError::Stopped);
+        }
+        if self.has_tracker(&url) {
+            return Ok(());
+        }
+        let tracker = TestedTracker::test(url, connector, &self.test_announce_request()).await?;
+        self.add_tested_tracker(tracker)
+    }
+
+    /// The request test announces are sent with, the url aside. Testing trackers apart from the
+    /// manager, e.g. concurrently, keeps it free for the torrent's own announces meanwhile.
+    pub fn test_announce_request(&self) -> TrackerAnnounceRequest {
+        self.announce_request(TrackerEvent::None)
+    }
+
+    /// Adds a tracker that answered its test announce to a last tier of its own, after every
+    /// tracker of the metainfo, unless the manager already has it.
+    pub fn add_tested_tracker(&mut self, tracker: TestedTracker<C>) -> Result<(), TrackerError> {
+        if self.stopped {
+            return Err(TrackerError::Stopped);
+        }
+        if self.has_tracker(&tracker.url) {
+            return Ok(());
+        }
+        let mut entry = TrackerEntry::new(tracker.url, tracker.connector);
+        entry.record_success(tracker.announced_at, &tracker.info);
+        entry.latency = Some(tracker.latency);
+        let tier = *self.exchanged_tier.get_or_insert_with(|| {
+            self.tiers.push(vec![]);
+            self.tiers.len() - 1
+        });
+        self.tiers[tier].push(entry);
+        Ok(())
+    }
+
+    fn current_event(&self) -> TrackerEvent {
+        if !self.started {
+            TrackerEvent::Started
+        } else if self.completed_pending {
+            TrackerEvent::Completed
+        } else {
+            TrackerEvent::None
+        }
+    }
+
+    /// Announces to the first tracker that responds and returns the peers that weren't seen before.
+    pub async fn announce(&mut self) -> Result<Vec<PeerInfo>, TrackerError> {
+        if self.stopped {
+            return Err(TrackerError::Stopped);
+        }
+        let event = self.current_event();
+        let mut info = match self.announce_round(event).await {
+            Ok(info) => info,
+            Err(err) => {
+                self.next_announce = self.earliest_retry();
+                return Err(err);
+            }
+        };
+        match event {
+            TrackerEvent::Started => self.started = true,
+            TrackerEvent::Completed => {
+                self.completed_pending = false;
+                self.completed_sent = true;
+            },
+            _ => {},
+        }
+        if info.external_ip.is_some() {
+            self.external_ip = info.external_ip;
+        }
+        self.next_announce = if self.completed_pending {
+            Instant::now()
+        } else {
+            Instant::now() + announce_interval(&info)
+        };
+        // Past the bound, peers are forgotten and streamed again when returned, which the
+        // caller's own deduplication absorbs.
+        info.peers.truncate(MAX_REMEMBERED_PEERS);
+        if self.known_peers.len() + info.peers.len() > MAX_REMEMBERED_PEERS {
+            self.known_peers.clear();
+        }
+        Ok(info.peers.into_iter()
+            .filter(|peer| self.known_peers.insert(peer.socket_addr))
+            .collect())
+    }
+
+    /// Sends the `stopped` event, unless the trackers were never told the torrent started.
+    pub async fn stop(&mut self) -> Result<(), TrackerError> {
+        if self.stopped {
+            return Ok(());
+        }
+        self.stopped = true;
+        if !self.started {
+            return Ok(());
+        }
+        self.announce_round(TrackerEvent::Stopped).await.map(|_| ())
+    }
+
+    /// Announces on schedule and forwards newly discovered peers until a `Stop` command is received,
+    /// the command channel is closed or the peers receiver is dropped.
+    pub async fn run(mut self, peers: mpsc::Sender<PeerInfo>, mut commands: mpsc::Receiver<TrackerCommand>) {
+        loop {
+            tokio::select! {
+                _ = sleep_until(self.next_announce) => {
+                    let Ok(new_peers) = self.announce().await else {
+                        continue;
+                    };
+                    for peer in new_peers {
+                        if peers.send(peer).await.is_err() {
+                            let _ = self.stop().await;
+                            return;
+                        }
+                    }
+                }
+                command = commands.recv() => match command {
+                    Some(TrackerCommand::Progress(progress)) => self.set_progress(progress),
+                    Some(TrackerCommand::Completed) => self.completed(),
+                    Some(TrackerCommand::Stats(reply)) => {
+                        let _ = reply.send(self.stats());
+                    },
+                    Some(TrackerCommand::Stop) | None => {
+                        let _ = self.stop().await;
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    fn announce_request(&self, event: TrackerEvent) -> TrackerAnnounceRequest {
+        TrackerAnnounceRequest {
+            url: String::new(),
+            peer_id: self.peer_id,
+            info_hash: self.info_hash,
+            downloaded: self.progress.downloaded,
+            left: self.progress.left,
+            uploaded: self.progress.uploaded,
+            event,
+            ip: 0,
+            port: self.port,
+            compact: true,
+            num_want: None,
+            tracker_id: None,
+        }
+    }
+
+    async fn announce_round(&mut self, event: TrackerEvent) -> Result<TrackerNetworkInfo, TrackerError> {
+        let mut request = self.announce_request(event);
+        let mut last_error = None;
+        for tier in self.tiers.iter_mut() {
+            for index in 0..tier.len() {
+                let entry = &mut tier[index];
+                if !entry.is_available(Instant::now()) {
+                    continue;
+                }
+                request.url.clone_from(&entry.url);
+                request.tracker_id.clone_from(&entry.tracker_id);
+                let now = Instant::now();
+                match entry.connector.announce(&request).await {
+                    Ok(info) => {
+                        entry.record_success(now, &info);
+                        tier[..=index].rotate_right(1);
+                        return Ok(info);
+                    },
+                    Err(err) => {
+                        entry.record_failure(now, &err);
+                        last_error = Some(err);
+                    },
+                }
+            }
+        }
+        Err(last_error.unwrap_or(TrackerError::NoTrackerAvailable))
+    }
+
+    fn earliest_retry(&self) -> Instant {
+        self.tiers.iter()
+            .flatten()
+            .filter(|entry| !entry.disabled)
+            .filter_map(|entry| entry.retry_at)
+            .min()
+            .unwrap_or_else(|| Instant::now() + RETRY_BASE_DELAY)
+    }
+}
+
+/// The tracker's `interval`, never shorter than its `min interval`.
+fn announce_interval(info: &TrackerNetworkInfo) -> Duration {
+    let interval = if info.interval == 0 {
+        DEFAULT_ANNOUNCE_INTERVAL
+    } else {
+        Duration::from_secs(info.interval as u64)
+    };
+    let min_interval = Duration::from_secs(info.min_interval.unwrap_or(0) as u64);
+    interval.max(min_interval)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use super::*;
+
+    type AnnounceLog = Arc<Mutex<Vec<TrackerAnnounceRequest>>>;
+
+    struct FakeTracker {
+        log: AnnounceLog,
+        failing: Arc<AtomicBool>,
+        retry_in: Option<RetryIn>,
+        peers: Vec<PeerInfo>,
+        min_interval: Option<u32>,
+        tracker_id: Option<String>,
+    }
+
+    impl TrackerConnector for FakeTracker {
+        async fn announce(&mut self, request: &TrackerAnnounceRequest) -> Result<TrackerNetworkInfo, TrackerError> {
+            self.log.lock().unwrap().push(request.clone());
+            if self.failing.load(Ordering::SeqCst) {
+                return Err(TrackerError::Failure { reason: String::from("tracker is down"), retry_in: self.retry_in });
+            }
+            Ok(TrackerNetworkInfo {
+                interval: 60,
+                min_interval: self.min_interval,
+                tracker_id: self.tracker_id.clone(),
+                external_ip: Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))),
+                peers: self.peers.clone(),
+                ..Default::default()
+            })
+        }
+    }
+
+    fn peer(port: u16) -> PeerInfo {
+        PeerInfo { socket_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port) }
+    }
+
+    fn tracker(log: &AnnounceLog, url: &str, failing: bool, peers: Vec<PeerInfo>) -> ((String, FakeTracker), Arc<AtomicBool>) {
+        let failing = Arc::new(AtomicBool::new(failing));
+        let connector = FakeTracker {
+            log: log.clone(),
+            failing: failing.clone(),
+            retry_in: None,
+            peers,
+            min_interval: None,
+            tracker_id: None,
+        };
+        ((url.to_string(), connector), failing)
+    }
+
+    fn urls(log: &AnnounceLog) -> Vec<String> {
+        log.lock().unwrap().iter().map(|request| request.url.clone()).collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failover_within_tier_promotes_responding_tracker() {
+        let log = AnnounceLog::default();
+        let (a, a_failing) = tracker(&log, "a", true, vec![]);
+        let (b, _) = tracker(&log, "b", false, vec![peer(1)]);
+        let mut manager = TrackerManager::new([0; 20], [1; 20], 6881, vec![vec![a, b]]);
+
+        assert_eq!(manager.announce().await.unwrap(), vec![peer(1)]);
+        assert_eq!(manager.tracker_urls(), vec![vec!["b", "a"]]);
+
+        a_failing.store(false, Ordering::SeqCst);
+        tokio::time::advance(Duration::from_secs(60)).await;
+        manager.announce().await.unwrap();
+        assert_eq!(urls(&log), vec!["a", "b", "b"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failover_across_tiers_with_backoff() {
+        let log = AnnounceLog::default();
+        let (a, _) = tracker(&log, "a", true, vec![]);
+        let (b, _) = tracker(&log, "b", false, vec![]);
+        let mut manager = TrackerManager::new([0; 20], [1; 20], 6881, vec![vec![a], vec![], vec![b]]);
+
+        manager.announce().await.unwrap();
+        tokio::time::advance(Duration::from_secs(5)).await;
+        manager.announce().await.unwrap();
+        tokio::time::advance(Duration::from_secs(15)).await;
+        manager.announce().await.unwrap();
+        assert_eq!(urls(&log), vec!["a", "b", "b", "a", "b"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_all_trackers_failing_schedules_retry() {
+        let log = AnnounceLog::default();
+        let (a, _) = tracker(&log, "a", true, vec![]);
+        let mut manager = TrackerManager::new([0; 20], [1; 20], 6881, vec![vec![a]]);
+
+        assert!(manager.announce().await.is_err());
+        assert_eq!(manager.next_announce(), Instant::now() + RETRY_BASE_DELAY);
+        tokio::time::advance(RETRY_BASE_DELAY).await;
+        assert!(manager.announce().await.is_err());
+        assert_eq!(manager.next_announce(), Instant::now() + RETRY_BASE_DELAY * 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lifecycle_events_are_sent_once() {
+        let log = AnnounceLog::default();
+        let (a, _) = tracker(&log, "a", false, vec![]);
+        let mut manager = TrackerManager::new([0; 20], [1; 20], 6881, vec![vec![a]]);
+
+        manager.announce().await.unwrap();
+        manager.announce().await.unwrap();
+        manager.completed();
+        assert_eq!(manager.next_announce(), Instant::now());
+        manager.announce().await.unwrap();
+        manager.completed();
+        manager.announce().await.unwrap();
+        manager.stop().await.unwrap();
+        manager.stop().await.unwrap();
+        assert!(manager.announce().await.is_err());
+
+        let events: Vec<TrackerEvent> = log.lock().unwrap().iter().map(|request| request.event).collect();
+        assert_eq!(events, vec![
+            TrackerEvent::Started,
+            TrackerEvent::None,
+            TrackerEvent::Completed,
+            TrackerEvent::None,
+            TrackerEvent::Stopped,
+        ]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stop_without_start_sends_nothing() {
+        let log = AnnounceLog::default();
+        let (a, _) = tracker(&log, "a", false, vec![]);
+        let mut manager = TrackerManager::new([0; 20], [1; 20], 6881, vec![vec![a]]);
+
+        manager.stop().await.unwrap();
+        assert!(log.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_run_streams_deduplicated_peers() {
+        let log = AnnounceLog::default();
+        let (a, a_failing) = tracker(&log, "a", false, vec![peer(1), peer(2)]);
+        let (b, _) = tracker(&log, "b", false, vec![peer(2), peer(3)]);
+        let manager = TrackerManager::new([0; 20], [1; 20], 6881, vec![vec![a], vec![b]]);
+        let (peers_tx, mut peers_rx) = mpsc::channel(8);
+        let (commands_tx, commands_rx) = mpsc::channel(8);
+        let handle = tokio::spawn(manager.run(peers_tx, commands_rx));
+
+        assert_eq!(peers_rx.recv().await, Some(peer(1)));
+        assert_eq!(peers_rx.recv().await, Some(peer(2)));
+        a_failing.store(true, Ordering::SeqCst);
+        assert_eq!(peers_rx.recv().await, Some(peer(3)));
+
+        commands_tx.send(TrackerCommand::Stop).await.unwrap();
+        handle.await.unwrap();
+        let last = log.lock().unwrap().last().cloned().unwrap();
+        assert_eq!((last.url.as_str(), last.event), ("b", TrackerEvent::Stopped));
+        assert_eq!(peers_rx.recv().await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_remembered_peers_are_bounded() {
+        let log = AnnounceLog::default();
+        let peers: Vec<PeerInfo> = (1..=MAX_REMEMBERED_PEERS as u16 + 1).map(peer).collect();
+        let (a, _) = tracker(&log, "a", false, peers);
+        let mut manager = TrackerManager::new([0; 20], [1; 20], 6881, vec![vec![a]]);
+
+        assert_eq!(manager.announce().await.unwrap().len(), MAX_REMEMBERED_PEERS);
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(manager.announce().await.unwrap().len(), MAX_REMEMBERED_PEERS);
+        assert_eq!(manager.known_peers.len(), MAX_REMEMBERED_PEERS);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tracker_id_is_echoed_and_min_interval_respected() {
+        let log = AnnounceLog::default();
+        let ((url, mut connector), _) = tracker(&log, "a", false, vec![]);
+        connector.tracker_id = Some(String::from("abc"));
+        connector.min_interval = Some(120);
+        let mut manager = TrackerManager::new([0; 20], [1; 20], 6881, vec![vec![(url, connector)]]);
+
+        manager.announce().await.unwrap();
+        assert_eq!(manager.next_announce(), Instant::now() + Duration::from_secs(120));
+        assert_eq!(manager.external_ip(), Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))));
+        manager.announce().await.unwrap();
+
+        let tracker_ids: Vec<Option<String>> = log.lock().unwrap().iter().map(|request| request.tracker_id.clone()).collect();
+        assert_eq!(tracker_ids, vec![None, Some(String::from("abc"))]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retry_in_overrides_backoff() {
+        let log = AnnounceLog::default();
+        let ((a_url, mut a), _) = tracker(&log, "a", true, vec![]);
+        a.retry_in = Some(RetryIn::Minutes(2));
+        let ((b_url, mut b), _) = tracker(&log, "b", true, vec![]);
+        b.retry_in = Some(RetryIn::Never);
+        let mut manager = TrackerManager::new([0; 20], [1; 20], 6881, vec![vec![(a_url, a), (b_url, b)]]);
+
+        assert!(matches!(manager.announce().await, Err(TrackerError::Failure { .. })));
+        assert_eq!(manager.next_announce(), Instant::now() + Duration::from_secs(120));
+        tokio::time::advance(Duration::from_secs(120)).await;
+        assert!(manager.announce().await.is_err());
+        assert_eq!(urls(&log), vec!["a", "b", "a"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stats_track_each_tracker() {
+        let log = AnnounceLog::default();
+        let (a, _) = tracker(&log, "a", true, vec![]);
+        let (b, _) = tracker(&log, "b", false, vec![peer(1), peer(2)]);
+        let mut manager = TrackerManager::new([0; 20], [1; 20], 6881, vec![vec![a], vec![b]]);
+        let started = Instant::now();
+        manager.announce().await.unwrap();
+
+        let stats = manager.stats();
+        assert_eq!(stats.iter().map(|stats| (stats.url.as_str(), stats.tier)).collect::<Vec<_>>(), vec![("a", 0), ("b", 1)]);
+        assert_eq!(stats[0].last_error.as_deref(), Some("Tracker failure: tracker is down"));
+        assert_eq!(stats[0].consecutive_failures, 1);
+        assert_eq!(stats[0].peers_returned, None);
+        assert_eq!(stats[0].next_announce, Some(started + Duration::from_secs(60)));
+        assert_eq!(stats[1].last_announce, Some(started));
+        assert_eq!(stats[1].latency, Some(Duration::ZERO));
+        assert_eq!((stats[1].last_error.clone(), stats[1].peers_returned), (None, Some(2)));
+        assert_eq!(stats[1].next_announce, Some(started + Duration::from_secs(60)));
+
+        manager.stop().await.unwrap();
+        assert!(manager.stats().iter().all(|stats| stats.next_announce.is_none()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_probe_reaches_every_tracker() {
+        let log = AnnounceLog::default();
+        let (a, _) = tracker(&log, "a", false, vec![peer(1)]);
+        let (mut b, _) = tracker(&log, "b", true, vec![]);
+        b.1.retry_in = Some(RetryIn::Never);
+        let mut manager = TrackerManager::new([0; 20], [1; 20], 6881, vec![vec![a], vec![b]]);
+        let before = manager.stats();
+
+        let stats = manager.probe().await;
+        assert_eq!(urls(&log), vec!["a", "b"]);
+        assert!(log.lock().unwrap().iter().all(|request| request.event == TrackerEvent::None));
+        assert_eq!(stats[0].peers_returned, Some(1));
+        assert_eq!(stats[1].last_error.as_deref(), Some("Tracker failure: tracker is down (retry never)"));
+        assert_eq!((stats[1].consecutive_failures, stats[1].disabled), (0, false));
+        assert_eq!(stats[1].next_announce, before[1].next_announce);
+        assert_eq!(stats[0].last_scrape_error.as_deref(), Some("Unsupported tracker url 'a'"));
+
+        assert_eq!(manager.announce().await.unwrap(), vec![peer(1)]);
+        assert_eq!(log.lock().unwrap().last().unwrap().event, TrackerEvent::Started);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_run_answers_stats_requests() {
+        let log = AnnounceLog::default();
+        let (a, _) = tracker(&log, "a", false, vec![]);
+        let manager = TrackerManager::new([0; 20], [1; 20], 6881, vec![vec![a]]);
+        let (peers_tx, _peers_rx) = mpsc::channel(8);
+        let (commands_tx, commands_rx) = mpsc::channel(8);
+        let handle = tokio::spawn(manager.run(peers_tx, commands_rx));
+
+        let (reply_tx, reply_rx) = oneshot::channel();
+        commands_tx.send(TrackerCommand::Stats(reply_tx)).await.unwrap();
+        let stats = reply_rx.await.unwrap();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].url, "a");
+        commands_tx.send(TrackerCommand::Stop).await.unwrap();
+        handle.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_exchanged_trackers_are_validated_into_last_tier() {
+        let log = AnnounceLog::default();
+        let (a, a_failing) = tracker(&log, "a", false, vec![]);
+        let (b, _) = tracker(&log, "b", false, vec![]);
+        let ((c_url, c), _) = tracker(&log, "c", true, vec![]);
+        let mut manager = TrackerManager::new([0; 20], [1; 20], 6881, vec![vec![a]]);
+
+        manager.add_exchanged_tracker(b.0, b.1).await.unwrap();
+        assert!(manager.add_exchanged_tracker(c_url, c).await.is_err());
+        assert_eq!(manager.tracker_urls(), vec![vec!["a"], vec!["b"]]);
+        assert_eq!(manager.stats()[1].tier, 1);
+
+        a_failing.store(true, Ordering::SeqCst);
+        manager.announce().await.unwrap();
+        assert_eq!(urls(&log), vec!["b", "c", "a", "b"]);
+        assert_eq!(log.lock().unwrap()[0].event, TrackerEvent::None);
+    }
+}
@@ -0,0 +1,199 @@
+//! Scriptable in-process tracker for deterministic connector and manager tests.
+//!
+//! A [`MockTracker`] listens for HTTP and UDP announces on loopback, records every announce it
+//! receives and answers with the next scripted [`MockResponse`], or with an empty peer list once
+//! the script runs out.
+
+use std::collections::VecDeque;
+use std::future::pending;
+use std::io::{self, Cursor};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use byteorder::{BigEndian, ReadBytesExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::task::JoinHandle;
+
+use crate::model::{failure_to_bencode, PeerInfo, TrackerNetworkInfo};
+use crate::tracker::server::{http, udp};
+use crate::tracker::udp_connector::{Action, CONNECT_REQUEST_PROTOCOL_ID};
+use crate::tracker::TrackerAnnounceRequest;
+
+const DEFAULT_INTERVAL: u32 = 30 * 60;
+const CONNECTION_ID: u64 = 0x1122_3344_5566_7788;
+const MAX_PACKET_SIZE: usize = 2048;
+
+/// How the mock answers a single announce.
+#[derive(Debug)]
+pub enum MockResponse {
+    /// A successful response with the default interval and these peers.
+    Peers(Vec<PeerInfo>),
+    Response(TrackerNetworkInfo),
+    Failure(String),
+    /// Never answers; HTTP connections are held open until the client gives up.
+    Timeout,
+    /// Answers with a transaction id the client didn't send. HTTP ignores it and answers normally.
+    WrongTransactionId,
+    /// Cuts a successful response short.
+    Truncated,
+    /// Sends these bytes as the response body or datagram as-is.
+    Raw(Vec<u8>),
+}
+
+#[derive(Default)]
+struct MockState {
+    script: Mutex<VecDeque<MockResponse>>,
+    announces: Mutex<Vec<TrackerAnnounceRequest>>,
+    connects: AtomicUsize,
+}
+
+impl MockState {
+    fn record(&self, request: TrackerAnnounceRequest) -> MockResponse {
+        self.announces.lock().unwrap().push(request);
+        self.script.lock().unwrap().pop_front().unwrap_or(MockResponse::Peers(vec![]))
+    }
+}
+
+pub struct MockTracker {
+    http_addr: SocketAddr,
+    udp_addr: SocketAddr,
+    state: Arc<MockState>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl MockTracker {
+    /// Binds the HTTP and UDP endpoints on ephemeral loopback ports and starts serving.
+    pub async fn start() -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        let http_addr = listener.local_addr()?;
+        let udp_addr = socket.local_addr()?;
+        let state = Arc::new(MockState::default());
+        let tasks = vec![
+            tokio::spawn(serve_http(listener, state.clone(), format!("http://{http_addr}/announce"))),
+            tokio::spawn(serve_udp(socket, state.clone(), format!("udp://{udp_addr}"))),
+        ];
+        Ok(Self { http_addr, udp_addr, state, tasks })
+    }
+
+    pub fn http_url(&self) -> String {
+        format!("http://{}/announce", self.http_addr)
+    }
+
+    pub fn udp_url(&self) -> String {
+        format!("udp://{}", self.udp_addr)
+    }
+
+    /// Queues the answer to the next announce, over either protocol.
+    pub fn push(&self, response: MockResponse) {
+        self.state.script.lock().unwrap().push_back(response);
+    }
+
+    /// Every announce received so far, with `url` set to the endpoint it arrived on. UDP
+    /// announces append the BEP 41 URL data they carried.
+    pub fn announces(&self) -> Vec<TrackerAnnounceRequest> {
+        self.state.announces.lock().unwrap().clone()
+    }
+
+    /// Number of BEP 15 connect requests answered.
+    pub fn connects(&self) -> usize {
+        self.state.connects.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for MockTracker {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+fn default_info(peers: Vec<PeerInfo>) -> TrackerNetworkInfo {
+    TrackerNetworkInfo { interval: DEFAULT_INTERVAL, peers, ..Default::default() }
+}
+
+async fn serve_http(listener: TcpListener, state: Arc<MockState>, url: String) {
+    while let Ok((stream, _)) = listener.accept().await {
+        let state = state.clone();
+        let url = url.clone();
+        tokio::spawn(async move {
+            let _ = handle_http(stream, &state, url).await;
+        });
+    }
+}
+
+async fn handle_http(mut stream: TcpStream, state: &MockState, url: String) -> io::Result<()> {
+    let Some(target) = http::read_request_target(&mut stream).await? else {
+        return http::write_response(&mut stream, "400 Bad Request", b"").await;
+    };
+    let (_, query) = target.split_once('?').unwrap_or((&target, ""));
+    let params = http::parse_query(query);
+    let request = match http::parse_announce(&params) {
+        Ok(request) => request,
+        Err(reason) => return http::write_response(&mut stream, "200 OK", &failure_to_bencode(&reason, None)).await,
+    };
+    let tracker_id = params.get("trackerid")
+        .and_then(|values| values.first())
+        .map(|value| String::from_utf8_lossy(value).into_owned());
+    let compact = request.compact;
+    let body = match state.record(TrackerAnnounceRequest { url, tracker_id, ..request }) {
+        MockResponse::Peers(peers) => default_info(peers).to_bencode(compact),
+        MockResponse::Response(info) => info.to_bencode(compact),
+        MockResponse::Failure(reason) => failure_to_bencode(&reason, None),
+        MockResponse::Timeout => pending().await,
+        MockResponse::WrongTransactionId => default_info(vec![]).to_bencode(compact),
+        MockResponse::Truncated => {
+            let mut body = default_info(vec![]).to_bencode(compact);
+            body.truncate(body.len() / 2);
+            body
+        },
+        MockResponse::Raw(body) => body,
+    };
+    http::write_response(&mut stream, "200 OK", &body).await
+}
+
+async fn serve_udp(socket: UdpSocket, state: Arc<MockState>, url: String) {
+    let mut buf = [0u8; MAX_PACKET_SIZE];
+    while let Ok((n, remote_addr)) = socket.recv_from(&mut buf).await {
+        if let Some(response) = handle_udp(&buf[..n], remote_addr, &state, &url) {
+            let _ = socket.send_to(&response, remote_addr).await;
+        }
+    }
+}
+
+fn handle_udp(packet: &[u8], remote_addr: SocketAddr, state: &MockState, url: &str) -> Option<Vec<u8>> {
+    let mut cursor = Cursor::new(packet);
+    let connection_id = cursor.read_u64::<BigEndian>().ok()?;
+    let action = Action::from_u32(cursor.read_u32::<BigEndian>().ok()?);
+    let transaction_id = cursor.read_u32::<BigEndian>().ok()?;
+    match action {
+        Some(Action::Connect) if connection_id == CONNECT_REQUEST_PROTOCOL_ID => {
+            state.connects.fetch_add(1, Ordering::SeqCst);
+            Some(udp::connect_response(transaction_id, CONNECTION_ID))
+        },
+        Some(Action::Announce) if connection_id == CONNECTION_ID => {
+            let Ok(request) = udp::parse_announce(&mut cursor) else {
+                return Some(udp::error_response(transaction_id, "Malformed announce request"));
+            };
+            let url = format!("{url}{}", request.url);
+            let ipv4 = remote_addr.is_ipv4();
+            match state.record(TrackerAnnounceRequest { url, ..request }) {
+                MockResponse::Peers(peers) => Some(udp::announce_response(transaction_id, &default_info(peers), ipv4)),
+                MockResponse::Response(info) => Some(udp::announce_response(transaction_id, &info, ipv4)),
+                MockResponse::Failure(reason) => Some(udp::error_response(transaction_id, &reason)),
+                MockResponse::Timeout => None,
+                MockResponse::WrongTransactionId => {
+                    Some(udp::announce_response(transaction_id.wrapping_add(1), &default_info(vec![]), ipv4))
+                },
+                MockResponse::Truncated => {
+                    let mut response = udp::announce_response(transaction_id, &default_info(vec![]), ipv4);
+                    response.truncate(12);
+                    Some(response)
+                },
+                MockResponse::Raw(response) => Some(response),
+            }
+        },
+        _ => Some(udp::error_response(transaction_id, "Invalid connection id")),
+    }
+}
@@ -1,44 +1,120 @@
+mod error;
 mod http_connector;
 mod udp_connector;
+mod ws_connector;
+mod manager;
+mod scrape;
+mod tracker_url;
+mod tex;
+pub mod server;
+#[cfg(any(test, feature = "test-support"))]
+pub mod mock;
 
 use std::future::Future;
+pub use error::*;
 pub use http_connector::*;
 pub use udp_connector::*;
+pub use ws_connector::*;
+pub use manager::*;
+pub use scrape::*;
+pub use tracker_url::*;
+pub use tex::*;
 
 use crate::{ model::{ Sha1Hash, TrackerNetworkInfo }, peer::peer::PeerId };
 
 pub trait TrackerConnector {
-    fn announce(&mut self, request: &TrackerAnnounceRequest) -> impl Future<Output = Result<TrackerNetworkInfo, String>> + Send;
-    async fn scrape(&mut self, request: &TrackerScrapeRequest) -> Result<TrackerScrapeResponse, String> {
-        unimplemented!("Not implemented")
+    fn announce(&mut self, request: &TrackerAnnounceRequest) -> impl Future<Output = Result<TrackerNetworkInfo, TrackerError>> + Send;
+    /// Asks for the swarm sizes of the requested torrents. Trackers that can't be scraped
+    /// answer [`TrackerError::UnsupportedUrl`].
+    fn scrape(&mut self, request: &TrackerScrapeRequest) -> impl Future<Output = Result<TrackerScrapeResponse, TrackerError>> + Send {
+        let url = request.url().to_string();
+        async { Err(TrackerError::UnsupportedUrl(url)) }
     }
 }
 
-pub struct TrackerAnnounceRequest {
-    url: String,
-    peer_id: PeerId,
-    info_hash: Sha1Hash,
-    downloaded: i32,
-    left: u32,
-    uploaded: u32,
-    event: TrackerEvent,
-    ip: u32,
-    port: u16,
-    compact: bool,
+/// Dispatches to the connector matching the scheme of the announce url.
+pub enum AnyTrackerConnector {
+    Http(HttpTrackerConnector),
+    Udp(UdpTrackerConnector),
+    Ws(WsTrackerConnector),
 }
 
-pub struct TrackerScrapeRequest {
+impl AnyTrackerConnector {
+    pub fn for_url(url: &str) -> Result<Self, TrackerError> {
+        TrackerUrl::parse(url).map(|url| url.connector())
+    }
+}
 
+impl TrackerConnector for AnyTrackerConnector {
+    async fn announce(&mut self, request: &TrackerAnnounceRequest) -> Result<TrackerNetworkInfo, TrackerError> {
+        match self {
+            Self::Http(connector) => connector.announce(request).await,
+            Self::Udp(connector) => connector.announce(request).await,
+            Self::Ws(connector) => connector.announce(request).await,
+        }
+    }
+
+    async fn scrape(&mut self, request: &TrackerScrapeRequest) -> Result<TrackerScrapeResponse, TrackerError> {
+        match self {
+            Self::Http(connector) => connector.scrape(request).await,
+            Self::Udp(connector) => connector.scrape(request).await,
+            Self::Ws(connector) => connector.scrape(request).await,
+        }
+    }
 }
 
-pub struct TrackerScrapeResponse {
+#[derive(Debug, Clone)]
+pub struct TrackerAnnounceRequest {
+    pub url: String,
+    pub peer_id: PeerId,
+    pub info_hash: Sha1Hash,
+    pub downloaded: u64,
+    pub left: u64,
+    pub uploaded: u64,
+    pub event: TrackerEvent,
+    pub ip: u32,
+    pub port: u16,
+    pub compact: bool,
+    pub num_want: Option<u32>,
+    pub tracker_id: Option<String>,
+}
 
+impl TrackerAnnounceRequest {
+    /// A compact announce with no event and no transfer progress.
+    pub fn new(url: impl Into<String>, info_hash: Sha1Hash, peer_id: PeerId, port: u16) -> Self {
+        Self {
+            url: url.into(),
+            peer_id,
+            info_hash,
+            downloaded: 0,
+            left: 0,
+            uploaded: 0,
+            event: TrackerEvent::None,
+            ip: 0,
+            port,
+            compact: true,
+            num_want: None,
+            tracker_id: None,
+        }
+    }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum TrackerEvent {
     None = 0,
     Completed = 1,
     Started = 2,
     Stopped = 3,
-}
\ No newline at end of file
+}
+
+impl TrackerEvent {
+    pub fn from_u32(event: u32) -> Option<Self> {
+        match event {
+            0 => Some(Self::None),
+            1 => Some(Self::Completed),
+            2 => Some(Self::Started),
+            3 => Some(Self::Stopped),
+            _ => None,
+        }
+    }
+}
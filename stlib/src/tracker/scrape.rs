@@ -0,0 +1,102 @@
+use std::collections::{BTreeMap, HashMap};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::model::{Sha1Hash, SHA1_HASH_LEN};
+use crate::util::bencode;
+
+use super::TrackerError;
+
+#[derive(Debug, Clone)]
+pub struct TrackerScrapeRequest {
+    url: String,
+    info_hashes: Vec<Sha1Hash>,
+}
+
+impl TrackerScrapeRequest {
+    pub fn new(url: impl Into<String>, info_hashes: Vec<Sha1Hash>) -> Self {
+        Self { url: url.into(), info_hashes }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn info_hashes(&self) -> &[Sha1Hash] {
+        &self.info_hashes
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct TrackerScrapeResponse {
+    pub files: HashMap<Sha1Hash, ScrapeStats>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScrapeStats {
+    /// Number of seeders.
+    pub complete: u32,
+    /// Number of times the torrent was completed.
+    #[serde(default)]
+    pub downloaded: u32,
+    /// Number of leechers.
+    pub incomplete: u32,
+}
+
+impl TrackerScrapeResponse {
+    pub fn from_bencode(bytes: &[u8]) -> Result<Self, TrackerError> {
+        let response: ScrapeResponseBencode = bencode::from_bytes(bytes)?;
+        if let Some(failure_reason) = response.failure_reason {
+            return Err(TrackerError::Failure {
+                reason: String::from_utf8_lossy(&failure_reason).into_owned(),
+                retry_in: None,
+            });
+        }
+        let mut files = HashMap::new();
+        for (info_hash, stats) in response.files {
+            let info_hash: Sha1Hash = info_hash.as_slice().try_into().map_err(|_| TrackerError::InvalidResponse(
+                format!("Scraped info hash is {} bytes long instead of {SHA1_HASH_LEN}", info_hash.len())
+            ))?;
+            files.insert(info_hash, stats);
+        }
+        Ok(Self { files })
+    }
+
+    pub fn to_bencode(&self) -> Vec<u8> {
+        let response = ScrapeResponseBencode {
+            failure_reason: None,
+            files: self.files.iter()
+                .map(|(info_hash, stats)| (ByteBuf::from(info_hash.to_vec()), *stats))
+                .collect(),
+        };
+        serde_bencode::to_bytes(&response).expect("Scrape response is always serializable")
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ScrapeResponseBencode {
+    #[serde(rename = "failure reason", skip_serializing_if = "Option::is_none")]
+    failure_reason: Option<ByteBuf>,
+    #[serde(default)]
+    files: BTreeMap<ByteBuf, ScrapeStats>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scrape_response_round_trip() {
+        let stats = ScrapeStats { complete: 4, downloaded: 10, incomplete: 2 };
+        let response = TrackerScrapeResponse { files: HashMap::from([([7; 20], stats)]) };
+        let bytes = response.to_bencode();
+        assert!(bytes.starts_with(b"d5:filesd20:"));
+        assert_eq!(TrackerScrapeResponse::from_bencode(&bytes).unwrap(), response);
+    }
+
+    #[test]
+    fn test_scrape_response_with_bad_info_hash() {
+        let bytes = b"d5:filesd3:abcd8:completei1e10:incompletei0eeee";
+        assert!(matches!(TrackerScrapeResponse::from_bencode(bytes), Err(TrackerError::InvalidResponse(_))));
+    }
+}
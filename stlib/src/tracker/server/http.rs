@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+use crate::model::{failure_to_bencode, Sha1Hash, SHA1_HASH_LEN};
+use crate::tracker::{TrackerAnnounceRequest, TrackerEvent, TrackerScrapeResponse};
+
+use super::ServerState;
+
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(super) async fn serve(listener: TcpListener, state: Arc<ServerState>) -> io::Result<()> {
+    loop {
+        let (stream, remote_addr) = listener.accept().await?;
+        let state = state.clone();
+        tokio::spawn(async move {
+            let _ = timeout(REQUEST_TIMEOUT, handle_connection(stream, remote_addr, &state)).await;
+        });
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, remote_addr: SocketAddr, state: &ServerState) -> io::Result<()> {
+    let Some(target) = read_request_target(&mut stream).await? else {
+        return write_response(&mut stream, "400 Bad Request", b"").await;
+    };
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let params = parse_query(query);
+    let body = if path.ends_with("/announce") {
+        handle_announce(&params, remote_addr, state)
+    } else if path.ends_with("/scrape") {
+        handle_scrape(&params, state)
+    } else {
+        return write_response(&mut stream, "404 Not Found", b"").await;
+    };
+    write_response(&mut stream, "200 OK", &body).await
+}
+
+/// Reads the request head and returns the target of a `GET` request line.
+pub(in crate::tracker) async fn read_request_target(stream: &mut TcpStream) -> io::Result<Option<String>> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|window| window == b"\r\n\r\n") {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 || buf.len() + n > MAX_REQUEST_SIZE {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    let head = String::from_utf8_lossy(&buf);
+    let mut request_line = head.lines().next().unwrap_or_default().split(' ');
+    match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some(target)) => Ok(Some(target.to_string())),
+        _ => Ok(None),
+    }
+}
+
+pub(in crate::tracker) async fn write_response(stream: &mut TcpStream, status: &str, body: &[u8]) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.shutdown().await
+}
+
+pub(in crate::tracker) fn parse_query(query: &str) -> HashMap<String, Vec<Vec<u8>>> {
+    let mut params: HashMap<String, Vec<Vec<u8>>> = HashMap::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        params.entry(key.to_string())
+            .or_default()
+            .push(urlencoding::decode_binary(value.as_bytes()).into_owned());
+    }
+    params
+}
+
+fn param<'a>(params: &'a HashMap<String, Vec<Vec<u8>>>, key: &str) -> Option<&'a [u8]> {
+    params.get(key).and_then(|values| values.first()).map(Vec::as_slice)
+}
+
+fn numeric_param<T: std::str::FromStr>(params: &HashMap<String, Vec<Vec<u8>>>, key: &str) -> Result<Option<T>, String> {
+    match param(params, key) {
+        None => Ok(None),
+        Some(value) => std::str::from_utf8(value).ok()
+            .and_then(|value| value.parse().ok())
+            .map(Some)
+            .ok_or_else(|| format!("Invalid {key}")),
+    }
+}
+
+fn hash_param(value: &[u8], key: &str) -> Result<Sha1Hash, String> {
+    value.try_into().map_err(|_| format!("{key} must be {SHA1_HASH_LEN} bytes long"))
+}
+
+pub(in crate::tracker) fn parse_announce(params: &HashMap<String, Vec<Vec<u8>>>) -> Result<TrackerAnnounceRequest, String> {
+    let info_hash = hash_param(param(params, "info_hash").ok_or("Missing info_hash")?, "info_hash")?;
+    let peer_id = hash_param(param(params, "peer_id").ok_or("Missing peer_id")?, "peer_id")?;
+    let port = numeric_param(params, "port")?.ok_or("Missing port")?;
+    let event = match param(params, "event") {
+        None | Some(b"") => TrackerEvent::None,
+        Some(b"started") => TrackerEvent::Started,
+        Some(b"completed") => TrackerEvent::Completed,
+        Some(b"stopped") => TrackerEvent::Stopped,
+        Some(_) => return Err(String::from("Invalid event")),
+    };
+    Ok(TrackerAnnounceRequest {
+        url: String::new(),
+        peer_id,
+        info_hash,
+        downloaded: numeric_param(params, "downloaded")?.unwrap_or(0),
+        left: numeric_param(params, "left")?.unwrap_or(0),
+        uploaded: numeric_param(params, "uploaded")?.unwrap_or(0),
+        event,
+        ip: 0,
+        port,
+        compact: param(params, "compact") != Some(b"0"),
+        num_want: numeric_param(params, "numwant")?,
+        tracker_id: None,
+    })
+}
+
+fn handle_announce(params: &HashMap<String, Vec<Vec<u8>>>, remote_addr: SocketAddr, state: &ServerState) -> Vec<u8> {
+    parse_announce(params)
+        .and_then(|request| state.announce(&request, remote_addr).map(|info| info.to_bencode(request.compact)))
+        .unwrap_or_else(|reason| failure_to_bencode(&reason, None))
+}
+
+fn handle_scrape(params: &HashMap<String, Vec<Vec<u8>>>, state: &ServerState) -> Vec<u8> {
+    let info_hashes = match params.get("info_hash") {
+        Some(values) => values.iter().map(|value| hash_param(value, "info_hash")).collect(),
+        None => Ok(state.tracked_info_hashes()),
+    };
+    info_hashes
+        .and_then(|info_hashes| info_hashes.into_iter()
+            .map(|info_hash| state.scrape(&info_hash).map(|stats| (info_hash, stats)))
+            .collect())
+        .map(|files| TrackerScrapeResponse { files }.to_bencode())
+        .unwrap_or_else(|reason| failure_to_bencode(&reason, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_announce_query() {
+        let query = "info_hash=%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01\
+            &peer_id=-ST0001-aaaaaaaaaaaa&port=6881&left=10&event=started&compact=0&numwant=5";
+        let request = parse_announce(&parse_query(query)).unwrap();
+        assert_eq!(request.info_hash, [1; 20]);
+        assert_eq!(&request.peer_id, b"-ST0001-aaaaaaaaaaaa");
+        assert_eq!((request.port, request.left, request.num_want), (6881, 10, Some(5)));
+        assert_eq!(request.event, TrackerEvent::Started);
+        assert!(!request.compact);
+    }
+
+    #[test]
+    fn test_parse_invalid_announce_query() {
+        assert_eq!(parse_announce(&parse_query("port=1")).unwrap_err(), "Missing info_hash");
+        let query = "info_hash=abc&peer_id=-ST0001-aaaaaaaaaaaa&port=1";
+        assert_eq!(parse_announce(&parse_query(query)).unwrap_err(), "info_hash must be 20 bytes long");
+    }
+}
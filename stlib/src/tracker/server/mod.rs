@@ -0,0 +1,247 @@
+//! Embeddable BitTorrent tracker serving HTTP announce/scrape and BEP 15 UDP from in-memory swarms.
+
+pub(super) mod http;
+mod swarm;
+pub(super) mod udp;
+
+use std::collections::HashSet;
+use std::future::pending;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::time::{interval, Instant};
+
+pub use swarm::*;
+
+use crate::model::{PeerInfo, Sha1Hash, TrackerNetworkInfo};
+use crate::tracker::{ScrapeStats, TrackerAnnounceRequest};
+
+const DEFAULT_NUM_WANT: usize = 50;
+
+#[derive(Debug, Clone)]
+pub struct TrackerServerConfig {
+    pub http_addr: Option<SocketAddr>,
+    pub udp_addr: Option<SocketAddr>,
+    pub announce_interval: Duration,
+    /// Peers that haven't announced for this long are dropped from their swarm.
+    pub peer_ttl: Duration,
+    /// Upper bound on the peers returned by a single announce.
+    pub max_peers: usize,
+    /// When set, only these torrents are tracked.
+    pub whitelist: Option<HashSet<Sha1Hash>>,
+    /// Upper bound on the torrents tracked at once, announces of further ones being refused.
+    pub max_torrents: usize,
+    /// Upper bound on the peers of a swarm, announces of further ones being refused.
+    pub max_swarm_peers: usize,
+}
+
+impl Default for TrackerServerConfig {
+    fn default() -> Self {
+        Self {
+            http_addr: Some(SocketAddr::from(([0, 0, 0, 0], 6969))),
+            udp_addr: Some(SocketAddr::from(([0, 0, 0, 0], 6969))),
+            announce_interval: Duration::from_secs(30 * 60),
+            peer_ttl: Duration::from_secs(2 * 30 * 60),
+            max_peers: DEFAULT_NUM_WANT,
+            whitelist: None,
+            max_torrents: 100_000,
+            max_swarm_peers: 10_000,
+        }
+    }
+}
+
+pub struct TrackerServer {
+    http: Option<TcpListener>,
+    udp: Option<UdpSocket>,
+    state: Arc<ServerState>,
+}
+
+struct ServerState {
+    config: TrackerServerConfig,
+    swarms: Mutex<SwarmTable>,
+}
+
+impl TrackerServer {
+    pub async fn bind(config: TrackerServerConfig) -> io::Result<Self> {
+        let http = match config.http_addr {
+            Some(addr) => Some(TcpListener::bind(addr).await?),
+            None => None,
+        };
+        let udp = match config.udp_addr {
+            Some(addr) => Some(UdpSocket::bind(addr).await?),
+            None => None,
+        };
+        let state = Arc::new(ServerState {
+            swarms: Mutex::new(SwarmTable::new(config.peer_ttl, config.max_torrents, config.max_swarm_peers)),
+            config,
+        });
+        Ok(Self { http, udp, state })
+    }
+
+    pub fn http_addr(&self) -> Option<SocketAddr> {
+        self.http.as_ref().and_then(|listener| listener.local_addr().ok())
+    }
+
+    pub fn udp_addr(&self) -> Option<SocketAddr> {
+        self.udp.as_ref().and_then(|socket| socket.local_addr().ok())
+    }
+
+    /// Serves requests until an I/O error occurs on one of the listening sockets.
+    pub async fn run(self) -> io::Result<()> {
+        let http = async {
+            match self.http {
+                Some(listener) => http::serve(listener, self.state.clone()).await,
+                None => pending().await,
+            }
+        };
+        let udp = async {
+            match self.udp {
+                Some(socket) => udp::serve(socket, self.state.clone()).await,
+                None => pending().await,
+            }
+        };
+        tokio::try_join!(http, udp, expire_peers(self.state.clone())).map(|_| ())
+    }
+}
+
+async fn expire_peers(state: Arc<ServerState>) -> io::Result<()> {
+    let mut ticker = interval((state.config.peer_ttl / 4).max(Duration::from_secs(1)));
+    loop {
+        ticker.tick().await;
+        state.swarms.lock().unwrap().expire(Instant::now());
+    }
+}
+
+impl ServerState {
+    fn check_whitelist(&self, info_hash: &Sha1Hash) -> Result<(), String> {
+        match &self.config.whitelist {
+            Some(whitelist) if !whitelist.contains(info_hash) => Err(String::from("Torrent isn't tracked")),
+            _ => Ok(()),
+        }
+    }
+
+    /// Handles an announce arriving from `remote_addr`; the peer is registered with the
+    /// address the request came from and the port it asked for.
+    fn announce(&self, request: &TrackerAnnounceRequest, remote_addr: SocketAddr) -> Result<TrackerNetworkInfo, String> {
+        self.check_whitelist(&request.info_hash)?;
+        let num_want = request.num_want
+            .map_or(DEFAULT_NUM_WANT, |num_want| num_want as usize)
+            .min(self.config.max_peers);
+        let announce = SwarmAnnounce {
+            info_hash: request.info_hash,
+            peer_id: request.peer_id,
+            socket_addr: SocketAddr::new(remote_addr.ip(), request.port),
+            left: request.left,
+            event: request.event,
+            num_want,
+        };
+        let (peers, stats) = self.swarms.lock().unwrap().announce(&announce, Instant::now())
+            .ok_or_else(|| String::from("Tracker is full"))?;
+        Ok(TrackerNetworkInfo {
+            interval: self.config.announce_interval.as_secs() as u32,
+            complete: Some(stats.complete),
+            incomplete: Some(stats.incomplete),
+            peers,
+            external_ip: Some(remote_addr.ip()),
+            ..Default::default()
+        })
+    }
+
+    fn scrape(&self, info_hash: &Sha1Hash) -> Result<ScrapeStats, String> {
+        self.check_whitelist(info_hash)?;
+        Ok(self.swarms.lock().unwrap().scrape(info_hash))
+    }
+
+    fn tracked_info_hashes(&self) -> Vec<Sha1Hash> {
+        self.swarms.lock().unwrap().info_hashes()
+    }
+}
+
+fn peers_of_family(peers: &[PeerInfo], ipv4: bool) -> impl Iterator<Item = &PeerInfo> {
+    peers.iter().filter(move |peer| peer.socket_addr.is_ipv4() == ipv4)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracker::{
+        HttpTrackerConnector, TrackerConnector, TrackerEvent, TrackerError, TrackerScrapeRequest, UdpTrackerConnector,
+    };
+
+    fn request(url: String, peer: u8, left: u64) -> TrackerAnnounceRequest {
+        TrackerAnnounceRequest {
+            left,
+            event: TrackerEvent::Started,
+            ..TrackerAnnounceRequest::new(url, [9; 20], [peer; 20], 6000 + peer as u16)
+        }
+    }
+
+    async fn start(config: TrackerServerConfig) -> (SocketAddr, SocketAddr) {
+        let server = TrackerServer::bind(TrackerServerConfig {
+            http_addr: Some(SocketAddr::from(([127, 0, 0, 1], 0))),
+            udp_addr: Some(SocketAddr::from(([127, 0, 0, 1], 0))),
+            ..config
+        }).await.unwrap();
+        let addrs = (server.http_addr().unwrap(), server.udp_addr().unwrap());
+        tokio::spawn(server.run());
+        addrs
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_http_and_udp_announces_share_swarm() {
+        let (http_addr, udp_addr) = start(TrackerServerConfig::default()).await;
+        let http_url = format!("http://{http_addr}/announce");
+        let udp_url = format!("udp://{udp_addr}");
+
+        let mut http = HttpTrackerConnector::new();
+        let info = http.announce(&request(http_url.clone(), 1, 100)).await.unwrap();
+        assert!(info.peers.is_empty());
+        assert_eq!(info.interval, 30 * 60);
+        assert_eq!(info.external_ip, Some(http_addr.ip()));
+
+        let mut udp = UdpTrackerConnector::new();
+        let info = udp.announce(&request(udp_url, 2, 0)).await.unwrap();
+        assert_eq!(info.peers, vec![PeerInfo { socket_addr: SocketAddr::from(([127, 0, 0, 1], 6001)) }]);
+        assert_eq!((info.complete, info.incomplete), (Some(1), Some(1)));
+
+        let mut non_compact = request(http_url, 1, 100);
+        non_compact.compact = false;
+        let info = http.announce(&non_compact).await.unwrap();
+        assert_eq!(info.peers, vec![PeerInfo { socket_addr: SocketAddr::from(([127, 0, 0, 1], 6002)) }]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_http_and_udp_scrapes() {
+        let (http_addr, udp_addr) = start(TrackerServerConfig::default()).await;
+        let http_url = format!("http://{http_addr}/announce");
+        let udp_url = format!("udp://{udp_addr}");
+        let mut http = HttpTrackerConnector::new();
+        http.announce(&request(http_url.clone(), 1, 100)).await.unwrap();
+        http.announce(&request(http_url.clone(), 2, 0)).await.unwrap();
+
+        let expected = ScrapeStats { complete: 1, downloaded: 0, incomplete: 1 };
+        let response = http.scrape(&TrackerScrapeRequest::new(http_url, vec![[9; 20], [8; 20]])).await.unwrap();
+        assert_eq!(response.files.get(&[9; 20]), Some(&expected));
+        assert_eq!(response.files.get(&[8; 20]), Some(&ScrapeStats::default()));
+        let response = UdpTrackerConnector::new().scrape(&TrackerScrapeRequest::new(udp_url, vec![[9; 20]])).await.unwrap();
+        assert_eq!(response.files.get(&[9; 20]), Some(&expected));
+
+        let err = http.scrape(&TrackerScrapeRequest::new(format!("http://{http_addr}/a"), vec![[9; 20]])).await.unwrap_err();
+        assert!(matches!(err, TrackerError::UnsupportedUrl(_)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_whitelist_rejects_unknown_torrents() {
+        let (http_addr, udp_addr) = start(TrackerServerConfig {
+            whitelist: Some(HashSet::from([[1; 20]])),
+            ..Default::default()
+        }).await;
+
+        let err = HttpTrackerConnector::new().announce(&request(format!("http://{http_addr}/announce"), 1, 0)).await.unwrap_err();
+        assert!(matches!(err, TrackerError::Failure { .. }));
+        let err = UdpTrackerConnector::new().announce(&request(format!("udp://{udp_addr}"), 1, 0)).await.unwrap_err();
+        assert!(matches!(err, TrackerError::Failure { .. }));
+    }
+}
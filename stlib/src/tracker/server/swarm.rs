@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+use rand::seq::IteratorRandom;
+use tokio::time::Instant;
+
+use crate::model::{PeerInfo, Sha1Hash};
+use crate::peer::peer::PeerId;
+use crate::tracker::{ScrapeStats, TrackerEvent};
+
+/// In-memory peers of every torrent the tracker has seen, keyed by info hash.
+pub struct SwarmTable {
+    swarms: HashMap<Sha1Hash, Swarm>,
+    peer_ttl: Duration,
+    max_torrents: usize,
+    max_swarm_peers: usize,
+}
+
+#[derive(Default)]
+struct Swarm {
+    peers: HashMap<PeerId, SwarmPeer>,
+    downloaded: u32,
+}
+
+struct SwarmPeer {
+    socket_addr: SocketAddr,
+    left: u64,
+    last_seen: Instant,
+}
+
+pub struct SwarmAnnounce {
+    pub info_hash: Sha1Hash,
+    pub peer_id: PeerId,
+    pub socket_addr: SocketAddr,
+    pub left: u64,
+    pub event: TrackerEvent,
+    pub num_want: usize,
+}
+
+impl Swarm {
+    fn stats(&self) -> ScrapeStats {
+        let complete = self.peers.values().filter(|peer| peer.left == 0).count() as u32;
+        ScrapeStats {
+            complete,
+            downloaded: self.downloaded,
+            incomplete: self.peers.len() as u32 - complete,
+        }
+    }
+}
+
+impl SwarmTable {
+    /// A table of at most `max_torrents` swarms of at most `max_swarm_peers` peers each.
+    pub fn new(peer_ttl: Duration, max_torrents: usize, max_swarm_peers: usize) -> Self {
+        Self { swarms: HashMap::new(), peer_ttl, max_torrents, max_swarm_peers }
+    }
+
+    /// Records the announcing peer and returns up to `num_want` random other peers along with
+    /// the swarm statistics. Seeders are only given leechers, and `stopped` removes the peer.
+    /// `None` when the peer is new and its swarm, or the table for a new torrent, is full.
+    pub fn announce(&mut self, announce: &SwarmAnnounce, now: Instant) -> Option<(Vec<PeerInfo>, ScrapeStats)> {
+        if !self.swarms.contains_key(&announce.info_hash) && self.swarms.len() >= self.max_torrents {
+            return None;
+        }
+        let swarm = self.swarms.entry(announce.info_hash).or_default();
+        if announce.event == TrackerEvent::Stopped {
+            swarm.peers.remove(&announce.peer_id);
+            let stats = swarm.stats();
+            if swarm.peers.is_empty() && swarm.downloaded == 0 {
+                self.swarms.remove(&announce.info_hash);
+            }
+            return Some((vec![], stats));
+        }
+        if !swarm.peers.contains_key(&announce.peer_id) && swarm.peers.len() >= self.max_swarm_peers {
+            return None;
+        }
+        if announce.event == TrackerEvent::Completed {
+            swarm.downloaded += 1;
+        }
+        swarm.peers.insert(announce.peer_id, SwarmPeer {
+            socket_addr: announce.socket_addr,
+            left: announce.left,
+            last_seen: now,
+        });
+        let peers = swarm.peers.iter()
+            .filter(|(peer_id, _)| **peer_id != announce.peer_id)
+            .filter(|(_, peer)| announce.left > 0 || peer.left > 0)
+            .map(|(_, peer)| PeerInfo { socket_addr: peer.socket_addr })
+            .choose_multiple(&mut rand::thread_rng(), announce.num_want);
+        Some((peers, swarm.stats()))
+    }
+
+    pub fn scrape(&self, info_hash: &Sha1Hash) -> ScrapeStats {
+        self.swarms.get(info_hash).map(Swarm::stats).unwrap_or_default()
+    }
+
+    pub fn info_hashes(&self) -> Vec<Sha1Hash> {
+        self.swarms.keys().copied().collect()
+    }
+
+    /// Drops peers that haven't announced for longer than the peer TTL and returns how many were dropped.
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let peer_ttl = self.peer_ttl;
+        let mut expired = 0;
+        self.swarms.retain(|_, swarm| {
+            let before = swarm.peers.len();
+            swarm.peers.retain(|_, peer| now.duration_since(peer.last_seen) < peer_ttl);
+            expired += before - swarm.peers.len();
+            !swarm.peers.is_empty() || swarm.downloaded > 0
+        });
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn announce(peer: u8, left: u64, event: TrackerEvent) -> SwarmAnnounce {
+        SwarmAnnounce {
+            info_hash: [1; 20],
+            peer_id: [peer; 20],
+            socket_addr: SocketAddr::from(([10, 0, 0, peer], 6881)),
+            left,
+            event,
+            num_want: 50,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_announce_and_scrape() {
+        let mut table = SwarmTable::new(Duration::from_secs(60), 10, 10);
+        let (peers, _) = table.announce(&announce(1, 0, TrackerEvent::Started), Instant::now()).unwrap();
+        assert!(peers.is_empty());
+        table.announce(&announce(2, 0, TrackerEvent::Started), Instant::now());
+        let (peers, stats) = table.announce(&announce(3, 100, TrackerEvent::Started), Instant::now()).unwrap();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(stats, ScrapeStats { complete: 2, downloaded: 0, incomplete: 1 });
+
+        let (peers, _) = table.announce(&announce(1, 0, TrackerEvent::None), Instant::now()).unwrap();
+        assert_eq!(peers, vec![PeerInfo { socket_addr: SocketAddr::from(([10, 0, 0, 3], 6881)) }]);
+
+        table.announce(&announce(3, 0, TrackerEvent::Completed), Instant::now());
+        table.announce(&announce(2, 0, TrackerEvent::Stopped), Instant::now());
+        assert_eq!(table.scrape(&[1; 20]), ScrapeStats { complete: 2, downloaded: 1, incomplete: 0 });
+        assert_eq!(table.scrape(&[2; 20]), ScrapeStats::default());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_table_and_swarms_are_bounded() {
+        let mut table = SwarmTable::new(Duration::from_secs(60), 1, 2);
+        assert!(table.announce(&announce(1, 10, TrackerEvent::Started), Instant::now()).is_some());
+        assert!(table.announce(&announce(2, 10, TrackerEvent::Started), Instant::now()).is_some());
+        assert!(table.announce(&announce(3, 10, TrackerEvent::Started), Instant::now()).is_none());
+        assert!(table.announce(&announce(2, 0, TrackerEvent::Completed), Instant::now()).is_some());
+        let other = SwarmAnnounce { info_hash: [2; 20], ..announce(1, 10, TrackerEvent::Started) };
+        assert!(table.announce(&other, Instant::now()).is_none());
+        assert_eq!(table.info_hashes(), vec![[1; 20]]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expire_idle_peers() {
+        let mut table = SwarmTable::new(Duration::from_secs(60), 10, 10);
+        table.announce(&announce(1, 10, TrackerEvent::Started), Instant::now());
+        tokio::time::advance(Duration::from_secs(45)).await;
+        table.announce(&announce(2, 10, TrackerEvent::Started), Instant::now());
+        tokio::time::advance(Duration::from_secs(30)).await;
+
+        assert_eq!(table.expire(Instant::now()), 1);
+        assert_eq!(table.scrape(&[1; 20]).incomplete, 1);
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert_eq!(table.expire(Instant::now()), 1);
+        assert!(table.info_hashes().is_empty());
+    }
+}
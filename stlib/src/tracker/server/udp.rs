@@ -0,0 +1,232 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Cursor, Read};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use rand::random;
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+
+use crate::model::{compact_peer, Sha1Hash, TrackerNetworkInfo, SHA1_HASH_LEN};
+use crate::tracker::udp_connector::{
+    Action, ANNOUNCE_REQUEST_SIZE, CONNECT_REQUEST_PROTOCOL_ID, MAX_SCRAPE_INFO_HASHES, OPTION_END_OF_OPTIONS, OPTION_NOP,
+    OPTION_URL_DATA,
+};
+use crate::tracker::{TrackerAnnounceRequest, TrackerEvent};
+
+use super::{peers_of_family, ServerState};
+
+const CONNECTION_ID_TTL: Duration = Duration::from_secs(2 * 60);
+/// Upper bound on the connection ids held at once, the oldest ones being evicted first.
+const MAX_CONNECTION_IDS: usize = 1 << 16;
+const REQUEST_HEADER_SIZE: usize = 16;
+const MAX_PACKET_SIZE: usize = 2048;
+
+/// Connection ids handed out by `connect`, only valid for the address that asked for them.
+#[derive(Default)]
+struct Connections {
+    ids: HashMap<u64, (SocketAddr, Instant)>,
+    /// The ids in the order they were issued.
+    issued: VecDeque<u64>,
+}
+
+impl Connections {
+    fn issue(&mut self, remote_addr: SocketAddr, now: Instant) -> u64 {
+        while let Some(oldest) = self.issued.front() {
+            let expired = self.ids.get(oldest).is_none_or(|(_, issued_at)| now.duration_since(*issued_at) >= CONNECTION_ID_TTL);
+            if !expired && self.ids.len() < MAX_CONNECTION_IDS {
+                break;
+            }
+            self.ids.remove(oldest);
+            self.issued.pop_front();
+        }
+        let connection_id = random();
+        if self.ids.insert(connection_id, (remote_addr, now)).is_none() {
+            self.issued.push_back(connection_id);
+        }
+        connection_id
+    }
+
+    fn is_valid(&self, connection_id: u64, remote_addr: SocketAddr, now: Instant) -> bool {
+        matches!(self.ids.get(&connection_id), Some((addr, issued_at))
+            if *addr == remote_addr && now.duration_since(*issued_at) < CONNECTION_ID_TTL)
+    }
+}
+
+pub(super) async fn serve(socket: UdpSocket, state: Arc<ServerState>) -> io::Result<()> {
+    let mut connections = Connections::default();
+    let mut buf = [0u8; MAX_PACKET_SIZE];
+    loop {
+        let (n, remote_addr) = socket.recv_from(&mut buf).await?;
+        if let Some(response) = handle_packet(&buf[..n], remote_addr, &state, &mut connections) {
+            // A peer that went away shouldn't take the tracker down with it.
+            let _ = socket.send_to(&response, remote_addr).await;
+        }
+    }
+}
+
+fn handle_packet(packet: &[u8], remote_addr: SocketAddr, state: &ServerState, connections: &mut Connections) -> Option<Vec<u8>> {
+    if packet.len() < REQUEST_HEADER_SIZE {
+        return None;
+    }
+    let mut cursor = Cursor::new(packet);
+    let connection_id = cursor.read_u64::<BigEndian>().ok()?;
+    let action = cursor.read_u32::<BigEndian>().ok()?;
+    let transaction_id = cursor.read_u32::<BigEndian>().ok()?;
+    let now = Instant::now();
+
+    let action = Action::from_u32(action);
+    if action == Some(Action::Connect) {
+        if connection_id != CONNECT_REQUEST_PROTOCOL_ID {
+            return None;
+        }
+        return Some(connect_response(transaction_id, connections.issue(remote_addr, now)));
+    }
+    if !connections.is_valid(connection_id, remote_addr, now) {
+        return Some(error_response(transaction_id, "Invalid connection id"));
+    }
+    let result = match action {
+        Some(Action::Announce) => handle_announce(&mut cursor, remote_addr, transaction_id, state),
+        Some(Action::Scrape) => handle_scrape(&mut cursor, transaction_id, state),
+        _ => Err(String::from("Unknown action")),
+    };
+    Some(result.unwrap_or_else(|reason| error_response(transaction_id, &reason)))
+}
+
+fn handle_announce(cursor: &mut Cursor<&[u8]>, remote_addr: SocketAddr, transaction_id: u32, state: &ServerState) -> Result<Vec<u8>, String> {
+    let request = parse_announce(cursor).map_err(|_| String::from("Malformed announce request"))?;
+    let info = state.announce(&request, remote_addr)?;
+    Ok(announce_response(transaction_id, &info, remote_addr.is_ipv4()))
+}
+
+pub(in crate::tracker) fn connect_response(transaction_id: u32, connection_id: u64) -> Vec<u8> {
+    let mut response = header(Action::Connect, transaction_id);
+    response.extend_from_slice(&connection_id.to_be_bytes());
+    response
+}
+
+/// IPv4 announces get 6 byte peers and IPv6 announces 18 byte peers, as BEP 15 requires.
+pub(in crate::tracker) fn announce_response(transaction_id: u32, info: &TrackerNetworkInfo, ipv4: bool) -> Vec<u8> {
+    let mut response = header(Action::Announce, transaction_id);
+    response.extend_from_slice(&info.interval.to_be_bytes());
+    response.extend_from_slice(&info.incomplete.unwrap_or(0).to_be_bytes());
+    response.extend_from_slice(&info.complete.unwrap_or(0).to_be_bytes());
+    for peer in peers_of_family(&info.peers, ipv4) {
+        if response.len() + 18 > MAX_PACKET_SIZE {
+            break;
+        }
+        response.extend_from_slice(&compact_peer(&peer.socket_addr));
+    }
+    response
+}
+
+/// Parses an announce request, the cursor being positioned right after the request header.
+/// The request's `url` holds the path and query sent as BEP 41 URL data.
+pub(in crate::tracker) fn parse_announce(cursor: &mut Cursor<&[u8]>) -> io::Result<TrackerAnnounceRequest> {
+    if cursor.get_ref().len() < ANNOUNCE_REQUEST_SIZE {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    let mut info_hash = [0u8; SHA1_HASH_LEN];
+    cursor.read_exact(&mut info_hash)?;
+    let mut peer_id = [0u8; 20];
+    cursor.read_exact(&mut peer_id)?;
+    let downloaded = cursor.read_u64::<BigEndian>()?;
+    let left = cursor.read_u64::<BigEndian>()?;
+    let uploaded = cursor.read_u64::<BigEndian>()?;
+    let event = TrackerEvent::from_u32(cursor.read_u32::<BigEndian>()?)
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+    let ip = cursor.read_u32::<BigEndian>()?;
+    let _key = cursor.read_u32::<BigEndian>()?;
+    let num_want = cursor.read_i32::<BigEndian>()?;
+    let port = cursor.read_u16::<BigEndian>()?;
+    Ok(TrackerAnnounceRequest {
+        url: String::from_utf8_lossy(&parse_url_data(cursor)).into_owned(),
+        peer_id,
+        info_hash,
+        downloaded,
+        left,
+        uploaded,
+        event,
+        ip,
+        port,
+        compact: true,
+        num_want: u32::try_from(num_want).ok(),
+        tracker_id: None,
+    })
+}
+
+/// Concatenates the BEP 41 URL data options following an announce request, the cursor being
+/// positioned right after the fixed size request. Malformed trailing options are ignored.
+fn parse_url_data(cursor: &mut Cursor<&[u8]>) -> Vec<u8> {
+    let mut url_data = vec![];
+    while let Ok(option) = cursor.read_u8() {
+        match option {
+            OPTION_END_OF_OPTIONS => break,
+            OPTION_NOP => continue,
+            _ => {
+                let Ok(len) = cursor.read_u8() else { break };
+                let mut data = vec![0u8; len as usize];
+                if cursor.read_exact(&mut data).is_err() {
+                    break;
+                }
+                if option == OPTION_URL_DATA {
+                    url_data.extend_from_slice(&data);
+                }
+            },
+        }
+    }
+    url_data
+}
+
+fn handle_scrape(cursor: &mut Cursor<&[u8]>, transaction_id: u32, state: &ServerState) -> Result<Vec<u8>, String> {
+    let hashes = &cursor.get_ref()[cursor.position() as usize..];
+    if hashes.is_empty() || !hashes.len().is_multiple_of(SHA1_HASH_LEN) {
+        return Err(String::from("Malformed scrape request"));
+    }
+    let mut response = header(Action::Scrape, transaction_id);
+    for info_hash in hashes.chunks_exact(SHA1_HASH_LEN).take(MAX_SCRAPE_INFO_HASHES) {
+        let info_hash: Sha1Hash = info_hash.try_into().expect("chunk is 20 bytes long");
+        let stats = state.scrape(&info_hash)?;
+        response.write_u32::<BigEndian>(stats.complete).map_err(|err| err.to_string())?;
+        response.write_u32::<BigEndian>(stats.downloaded).map_err(|err| err.to_string())?;
+        response.write_u32::<BigEndian>(stats.incomplete).map_err(|err| err.to_string())?;
+    }
+    Ok(response)
+}
+
+fn header(action: Action, transaction_id: u32) -> Vec<u8> {
+    let mut response = Vec::with_capacity(MAX_PACKET_SIZE);
+    response.extend_from_slice(&(action as u32).to_be_bytes());
+    response.extend_from_slice(&transaction_id.to_be_bytes());
+    response
+}
+
+pub(in crate::tracker) fn error_response(transaction_id: u32, message: &str) -> Vec<u8> {
+    let mut response = header(Action::Error, transaction_id);
+    response.extend_from_slice(message.as_bytes());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_ids_are_bounded() {
+        let mut connections = Connections::default();
+        let remote_addr = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let now = Instant::now();
+        let first = connections.issue(remote_addr, now);
+        for _ in 0..MAX_CONNECTION_IDS {
+            connections.issue(remote_addr, now);
+        }
+        assert_eq!(connections.ids.len(), MAX_CONNECTION_IDS);
+        assert!(!connections.is_valid(first, remote_addr, now));
+
+        let later = now + CONNECTION_ID_TTL;
+        let last = connections.issue(remote_addr, later);
+        assert_eq!(connections.ids.len(), 1);
+        assert!(connections.is_valid(last, remote_addr, later));
+    }
+}
@@ -0,0 +1,458 @@
+//! Tracker exchange (`lt_tex`): peers tell each other which trackers of a torrent work, so a
+//! torrent whose trackers died can still find its swarm.
+//!
+//! The extension payload is a bencoded dictionary whose `added` list holds tracker urls.
+//! Received urls are only used once a test announce succeeded, urls of hosts that are or
+//! resolve to local addresses aren't tried at all, and nothing is exchanged for private
+//! torrents (BEP 27).
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use serde_bencode::value::Value;
+use serde_bytes::ByteBuf;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{timeout, Instant};
+
+use crate::model::{Sha1Hash, Torrent};
+use crate::peer::extension::{ExtendedHandshake, Extension, ExtensionError};
+use crate::util::bencode;
+use crate::util::common::{is_local_ip, sha1_hash};
+use crate::util::proxy::ProxyConfig;
+
+use super::{
+    AnyTrackerConnector, TestedTracker, TrackerAnnounceRequest, TrackerError, TrackerStats, TrackerUrl, TEST_ANNOUNCE_TIMEOUT,
+};
+
+/// Name of the extension in the extension handshake.
+pub const LT_TEX: &str = "lt_tex";
+/// Minimum delay between two messages sent to the same peer.
+pub const TEX_INTERVAL: Duration = Duration::from_secs(60);
+/// Upper bound on the urls sent in, or taken from, a single message.
+const MAX_ADDED_TRACKERS: usize = 50;
+/// Upper bound on the urls a torrent remembers being offered, the oldest being forgotten first.
+const MAX_OFFERED_TRACKERS: usize = 1000;
+/// How long an offered url isn't tested again.
+const OFFERED_TRACKER_TTL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TexMessage {
+    pub added: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct TexMessageBencode {
+    #[serde(default)]
+    added: Vec<ByteBuf>,
+}
+
+impl TexMessage {
+    /// Urls that aren't valid UTF-8 are dropped.
+    pub fn from_bencode(bytes: &[u8]) -> Result<Self, TrackerError> {
+        let message: TexMessageBencode = bencode::from_bytes(bytes)?;
+        Ok(Self {
+            added: message.added.into_iter()
+                .filter_map(|url| String::from_utf8(url.into_vec()).ok())
+                .collect(),
+        })
+    }
+
+    pub fn to_bencode(&self) -> Vec<u8> {
+        let message = TexMessageBencode {
+            added: self.added.iter().map(|url| ByteBuf::from(url.as_bytes())).collect(),
+        };
+        serde_bencode::to_bytes(&message).expect("Tex message is always serializable")
+    }
+}
+
+/// Hash of a tracker list, advertised as `tr` in the extension handshake so that peers
+/// already sharing our trackers needn't exchange them.
+pub fn tracker_list_hash<S: AsRef<str>>(urls: &[S]) -> Sha1Hash {
+    let mut urls: Vec<&str> = urls.iter().map(AsRef::as_ref).collect();
+    urls.sort_unstable();
+    sha1_hash(urls.concat())
+}
+
+/// Tracker exchange state of a torrent, shared by all its peer connections.
+pub struct TrackerExchange {
+    private: bool,
+    proxy: Option<ProxyConfig>,
+    /// Urls of the metainfo, which need no test.
+    own: HashSet<String>,
+    /// Urls received recently and when, so that each one gets a single test announce.
+    offered: HashMap<String, Instant>,
+    allow_local: bool,
+}
+
+impl TrackerExchange {
+    /// Trackers learned from peers are reached through `proxy` when set, like the torrent's own.
+    pub fn new(torrent: &Torrent, proxy: Option<&ProxyConfig>) -> Self {
+        Self {
+            private: torrent.private,
+            proxy: proxy.cloned(),
+            own: torrent.announce_tiers.iter().flatten().cloned().collect(),
+            offered: HashMap::new(),
+            allow_local: false,
+        }
+    }
+
+    /// Lets peers offer trackers on loopback, link-local and private addresses, refused by
+    /// default so that peers can't make us announce to hosts of our own network.
+    pub fn allow_local_trackers(&mut self, allow: bool) {
+        self.allow_local = allow;
+    }
+
+    /// Whether `lt_tex` should be offered in the extension handshake at all.
+    pub fn is_enabled(&self) -> bool {
+        !self.private
+    }
+
+    /// The trackers we announced to successfully that this peer wasn't told about yet,
+    /// recording them in `sent`, the peer's own record.
+    pub fn outgoing(&self, stats: &[TrackerStats], sent: &mut HashSet<String>) -> Option<TexMessage> {
+        if self.private {
+            return None;
+        }
+        outgoing_message(stats, sent)
+    }
+
+    /// The `lt_tex` extension of a new connection, `None` for private torrents. `trackers`
+    /// follows the torrent's tracker stats, and received messages are forwarded to `received`
+    /// for [`Self::receive`], dropped when it's full.
+    pub fn extension(&self, trackers: watch::Receiver<Vec<TrackerStats>>, received: mpsc::Sender<TexMessage>) -> Option<TexExtension> {
+        if self.private {
+            return None;
+        }
+        Some(TexExtension {
+            trackers,
+            received,
+            sent: HashSet::new(),
+            next_message: None,
+            last_received: None,
+        })
+    }
+
+    /// The urls of a received message worth a test announce: supported, not local and not seen
+    /// recently.
+    pub fn incoming(&mut self, message: &TexMessage) -> Vec<TrackerUrl> {
+        if self.private {
+            return vec![];
+        }
+        let now = Instant::now();
+        self.offered.retain(|_, offered_at| now.duration_since(*offered_at) < OFFERED_TRACKER_TTL);
+        let allow_local = self.allow_local;
+        message.added.iter()
+            .take(MAX_ADDED_TRACKERS)
+            .filter(|url| !self.own.contains(*url) && offer(&mut self.offered, url, now))
+            .filter_map(|url| TrackerUrl::parse(url).ok())
+            .filter(|url| allow_local || !url.is_local())
+            .collect()
+    }
+
+    /// Sends test announces to the new trackers of a received message, all at once, and returns
+    /// the ones answering for [`super::TrackerManager::add_tested_tracker`]. `request` is the
+    /// manager's [`super::TrackerManager::test_announce_request`].
+    pub async fn receive(&mut self, request: &TrackerAnnounceRequest, message: &TexMessage) -> Vec<TestedTracker<AnyTrackerConnector>> {
+        let urls = self.incoming(message);
+        let tests = urls.into_iter()
+            .map(|url| test_tracker(url, self.proxy.as_ref(), self.allow_local, request));
+        join_all(tests).await.into_iter().flatten().collect()
+    }
+}
+
+/// Records `url` as offered at `now`, forgetting the oldest url when there are too many.
+/// Returns whether it's new.
+fn offer(offered: &mut HashMap<String, Instant>, url: &str, now: Instant) -> bool {
+    if offered.contains_key(url) {
+        return false;
+    }
+    if offered.len() >= MAX_OFFERED_TRACKERS {
+        let oldest = offered.iter().min_by_key(|(_, offered_at)| **offered_at).map(|(url, _)| url.clone());
+        if let Some(oldest) = oldest {
+            offered.remove(&oldest);
+        }
+    }
+    offered.insert(url.to_string(), now);
+    true
+}
+
+/// Test announce to a tracker whose url isn't local, unless `allow_local`, provided its host
+/// doesn't resolve to a local address either.
+async fn test_tracker(
+    url: TrackerUrl,
+    proxy: Option<&ProxyConfig>,
+    allow_local: bool,
+    request: &TrackerAnnounceRequest,
+) -> Option<TestedTracker<AnyTrackerConnector>> {
+    let connector = match proxy {
+        Some(proxy) => url.proxied_connector(proxy).ok()?,
+        None => url.connector(),
+    };
+    if !allow_local {
+        let addrs = timeout(TEST_ANNOUNCE_TIMEOUT, url.resolve()).await.ok()?.ok()?;
+        if addrs.iter().any(|addr| is_local_ip(addr.ip())) {
+            return None;
+        }
+    }
+    TestedTracker::test(url.to_string(), connector, request).await.ok()
+}
+
+fn working_trackers(stats: &[TrackerStats]) -> impl Iterator<Item = &str> {
+    stats.iter()
+        .filter(|stats| stats.last_announce.is_some() && stats.last_error.is_none())
+        .map(|stats| stats.url.as_str())
+}
+
+fn outgoing_message(stats: &[TrackerStats], sent: &mut HashSet<String>) -> Option<TexMessage> {
+    let added: Vec<String> = working_trackers(stats)
+        .filter(|url| !sent.contains(*url))
+        .take(MAX_ADDED_TRACKERS)
+        .map(str::to_string)
+        .collect();
+    if added.is_empty() {
+        return None;
+    }
+    sent.extend(added.iter().cloned());
+    Some(TexMessage { added })
+}
+
+/// `lt_tex` on a single connection: sends our working trackers at most every [`TEX_INTERVAL`]
+/// and hands the peer's messages over to the torrent's [`TrackerExchange`].
+pub struct TexExtension {
+    trackers: watch::Receiver<Vec<TrackerStats>>,
+    received: mpsc::Sender<TexMessage>,
+    /// Trackers this peer was told about.
+    sent: HashSet<String>,
+    next_message: Option<Instant>,
+    last_received: Option<Instant>,
+}
+
+impl Extension for TexExtension {
+    fn name(&self) -> &str {
+        LT_TEX
+    }
+
+    fn extend_handshake(&self, handshake: &mut ExtendedHandshake) {
+        let trackers = self.trackers.borrow();
+        let hash = tracker_list_hash(&working_trackers(&trackers).collect::<Vec<_>>());
+        handshake.extra.insert(String::from("tr"), Value::Bytes(hash.to_vec()));
+    }
+
+    fn on_handshake(&mut self, handshake: &ExtendedHandshake) -> Vec<Vec<u8>> {
+        // A peer announcing the same tracker list has nothing to learn from us.
+        let trackers = self.trackers.borrow();
+        let working: Vec<&str> = working_trackers(&trackers).collect();
+        if handshake.extra.get("tr") == Some(&Value::Bytes(tracker_list_hash(&working).to_vec())) {
+            self.sent.extend(working.into_iter().map(str::to_string));
+        }
+        vec![]
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, ExtensionError> {
+        let message = TexMessage::from_bencode(payload).map_err(|err| ExtensionError::InvalidMessage(err.to_string()))?;
+        // Flooding peers are ignored, as are messages arriving while the exchange is busy.
+        let now = Instant::now();
+        if self.last_received.is_some_and(|last| now < last + TEX_INTERVAL) {
+            return Ok(vec![]);
+        }
+        self.last_received = Some(now);
+        let _ = self.received.try_send(message);
+        Ok(vec![])
+    }
+
+    fn tick(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        if self.next_message.is_some_and(|next_message| now < next_message) {
+            return vec![];
+        }
+        let stats = self.trackers.borrow().clone();
+        match outgoing_message(&stats, &mut self.sent) {
+            Some(message) => {
+                self.next_message = Some(now + TEX_INTERVAL);
+                vec![message.to_bencode()]
+            },
+            None => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use super::*;
+    use crate::model::PeerMessage;
+    use crate::peer::extension::ExtensionRegistry;
+    use crate::tracker::mock::MockTracker;
+    use crate::tracker::TrackerManager;
+
+    fn stats(url: &str, error: Option<&str>) -> TrackerStats {
+        TrackerStats {
+            url: url.to_string(),
+            tier: 0,
+            last_announce: Some(Instant::now()),
+            last_error: error.map(str::to_string),
+            consecutive_failures: 0,
+            latency: None,
+            peers_returned: None,
+            seeders: None,
+            leechers: None,
+            completed: None,
+            last_scrape_error: None,
+            next_announce: None,
+            disabled: false,
+        }
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let message = TexMessage { added: vec![String::from("udp://tracker.example:6969")] };
+        let bytes = message.to_bencode();
+        assert_eq!(bytes, b"d5:addedl26:udp://tracker.example:6969ee");
+        assert_eq!(TexMessage::from_bencode(&bytes).unwrap(), message);
+        assert_eq!(TexMessage::from_bencode(b"de").unwrap(), TexMessage::default());
+        assert!(TexMessage::from_bencode(b"d5:addedi1ee").is_err());
+    }
+
+    #[test]
+    fn test_tracker_list_hash_ignores_order() {
+        assert_eq!(tracker_list_hash(&["b", "a"]), tracker_list_hash(&["a", "b"]));
+        assert_ne!(tracker_list_hash(&["a"]), tracker_list_hash(&["a", "b"]));
+    }
+
+    #[test]
+    fn test_outgoing_sends_working_trackers_once() {
+        let exchange = TrackerExchange::new(&Torrent::fixture(false, &["http://a.example/announce"]), None);
+        let stats = [stats("http://a.example/announce", None), stats("http://b.example/announce", Some("down"))];
+        let mut sent = HashSet::new();
+        let message = exchange.outgoing(&stats, &mut sent).unwrap();
+        assert_eq!(message.added, vec!["http://a.example/announce"]);
+        assert_eq!(exchange.outgoing(&stats, &mut sent), None);
+    }
+
+    #[test]
+    fn test_incoming_skips_known_and_unsupported_urls() {
+        let mut exchange = TrackerExchange::new(&Torrent::fixture(false, &["http://a.example/announce"]), None);
+        let message = TexMessage {
+            added: vec![
+                String::from("http://a.example/announce"),
+                String::from("dht://abc"),
+                String::from("udp://b.example:6969"),
+            ],
+        };
+        let urls: Vec<String> = exchange.incoming(&message).iter().map(ToString::to_string).collect();
+        assert_eq!(urls, vec!["udp://b.example:6969"]);
+        assert!(exchange.incoming(&message).is_empty());
+
+        let local = TexMessage { added: vec![String::from("http://127.0.0.1:8080/announce"), String::from("udp://10.0.0.1:6969")] };
+        assert!(exchange.incoming(&local).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_offered_urls_expire() {
+        let mut exchange = TrackerExchange::new(&Torrent::fixture(false, &[]), None);
+        let first = TexMessage { added: vec![String::from("udp://first.example:6969")] };
+        assert_eq!(exchange.incoming(&first).len(), 1);
+        tokio::time::advance(Duration::from_secs(1)).await;
+        let many = TexMessage { added: (0..MAX_OFFERED_TRACKERS).map(|i| format!("udp://t{i}.example:6969")).collect() };
+        for urls in many.added.chunks(MAX_ADDED_TRACKERS) {
+            assert_eq!(exchange.incoming(&TexMessage { added: urls.to_vec() }).len(), urls.len());
+        }
+        assert_eq!(exchange.offered.len(), MAX_OFFERED_TRACKERS);
+        // The oldest url made room, and is tested again.
+        assert_eq!(exchange.incoming(&first).len(), 1);
+        assert!(exchange.incoming(&TexMessage { added: vec![many.added[1].clone()] }).is_empty());
+        tokio::time::advance(OFFERED_TRACKER_TTL).await;
+        assert_eq!(exchange.incoming(&TexMessage { added: vec![many.added[1].clone()] }).len(), 1);
+        assert_eq!(exchange.offered.len(), 1);
+    }
+
+    #[test]
+    fn test_private_torrents_exchange_nothing() {
+        let mut exchange = TrackerExchange::new(&Torrent::fixture(true, &["http://a.example/announce"]), None);
+        assert!(!exchange.is_enabled());
+        let mut sent = HashSet::new();
+        assert_eq!(exchange.outgoing(&[stats("http://a.example/announce", None)], &mut sent), None);
+        assert!(exchange.incoming(&TexMessage { added: vec![String::from("udp://b.example:6969")] }).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_extension_sends_on_schedule_and_forwards_messages() {
+        let exchange = TrackerExchange::new(&Torrent::fixture(false, &[]), None);
+        let (stats_tx, stats_rx) = watch::channel(vec![stats("http://a.example/announce", None)]);
+        let (received_tx, mut received_rx) = mpsc::channel(1);
+        let mut registry = ExtensionRegistry::new();
+        registry.register(Box::new(exchange.extension(stats_rx, received_tx).unwrap())).unwrap();
+        let theirs = ExtendedHandshake { m: BTreeMap::from([(String::from(LT_TEX), 3)]), ..Default::default() };
+        registry.handle(0, &theirs.to_bencode()).unwrap();
+
+        let message = |urls: &[&str]| PeerMessage::Extended {
+            id: 3,
+            payload: TexMessage { added: urls.iter().map(|url| url.to_string()).collect() }.to_bencode(),
+        };
+        let now = Instant::now();
+        assert_eq!(registry.tick(now), vec![message(&["http://a.example/announce"])]);
+        stats_tx.send_modify(|stats_list| stats_list.push(stats("http://b.example/announce", None)));
+        assert_eq!(registry.tick(now + Duration::from_secs(30)), vec![]);
+        assert_eq!(registry.tick(now + TEX_INTERVAL), vec![message(&["http://b.example/announce"])]);
+
+        let incoming = TexMessage { added: vec![String::from("udp://c.example:6969")] };
+        registry.handle(1, &incoming.to_bencode()).unwrap();
+        assert_eq!(received_rx.recv().await, Some(incoming.clone()));
+        // Until TEX_INTERVAL passed, further messages are dropped.
+        tokio::time::advance(Duration::from_secs(30)).await;
+        registry.handle(1, &incoming.to_bencode()).unwrap();
+        assert!(received_rx.try_recv().is_err());
+        tokio::time::advance(TEX_INTERVAL).await;
+        registry.handle(1, &incoming.to_bencode()).unwrap();
+        assert_eq!(received_rx.recv().await, Some(incoming));
+        assert!(TrackerExchange::new(&Torrent::fixture(true, &[]), None).extension(watch::channel(vec![]).1, mpsc::channel(1).0).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_extension_skips_peers_with_same_trackers() {
+        let exchange = TrackerExchange::new(&Torrent::fixture(false, &[]), None);
+        let (_stats_tx, stats_rx) = watch::channel(vec![stats("http://a.example/announce", None)]);
+        let mut registry = ExtensionRegistry::new();
+        registry.register(Box::new(exchange.extension(stats_rx, mpsc::channel(1).0).unwrap())).unwrap();
+        let PeerMessage::Extended { payload, .. } = registry.handshake(ExtendedHandshake::default()) else {
+            unreachable!();
+        };
+        let ours = ExtendedHandshake::from_bencode(&payload).unwrap();
+        let theirs = ExtendedHandshake { m: BTreeMap::from([(String::from(LT_TEX), 3)]), extra: ours.extra, ..Default::default() };
+        registry.handle(0, &theirs.to_bencode()).unwrap();
+        assert_eq!(registry.tick(Instant::now()), vec![]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_receive_adds_trackers_answering_test_announce() {
+        let tracker = MockTracker::start().await.unwrap();
+        let dead = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let torrent = Torrent::fixture(false, &["http://a.example/announce"]);
+        let mut exchange = TrackerExchange::new(&torrent, None);
+        exchange.allow_local_trackers(true);
+        let mut manager = TrackerManager::from_torrent(&torrent, [1; 20], 6881);
+        let message = TexMessage { added: vec![format!("http://{dead}/announce"), tracker.http_url()] };
+
+        let tested = exchange.receive(&manager.test_announce_request(), &message).await;
+        assert_eq!(tested.iter().map(|tracker| tracker.url.as_str()).collect::<Vec<_>>(), vec![tracker.http_url()]);
+        for tracker in tested {
+            manager.add_tested_tracker(tracker).unwrap();
+        }
+        assert_eq!(manager.tracker_urls(), vec![vec!["http://a.example/announce".to_string()], vec![tracker.http_url()]]);
+        assert_eq!(tracker.announces()[0].info_hash, torrent.info_hash);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_receive_tests_trackers_concurrently_with_a_timeout() {
+        let silent = [tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap(), tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap()];
+        let torrent = Torrent::fixture(false, &[]);
+        let mut exchange = TrackerExchange::new(&torrent, None);
+        exchange.allow_local_trackers(true);
+        let manager = TrackerManager::from_torrent(&torrent, [1; 20], 6881);
+        let message = TexMessage { added: silent.iter().map(|socket| format!("udp://{}", socket.local_addr().unwrap())).collect() };
+
+        let start = Instant::now();
+        assert!(exchange.receive(&manager.test_announce_request(), &message).await.is_empty());
+        assert!(start.elapsed() < TEST_ANNOUNCE_TIMEOUT * 2);
+    }
+}
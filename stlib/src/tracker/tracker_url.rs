@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::net::lookup_host;
+use tokio::time::Instant;
+use url::{Host, Url};
+
+use crate::util::common::is_local_ip;
+use crate::util::proxy::ProxyConfig;
+
+use super::{AnyTrackerConnector, HttpTrackerConnector, TrackerError, UdpTrackerConnector, WsTrackerConnector};
+
+const DNS_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackerScheme {
+    Http,
+    Https,
+    Udp,
+    Ws,
+    Wss,
+}
+
+/// An announce url of one of the tracker protocols we speak, e.g.
+/// `https://tracker.example/announce.php?passkey=abc` or `udp://[::1]:6969`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackerUrl {
+    url: Url,
+    scheme: TrackerScheme,
+    port: u16,
+}
+
+impl TrackerUrl {
+    pub fn parse(url: &str) -> Result<Self, TrackerError> {
+        let unsupported = || TrackerError::UnsupportedUrl(url.to_string());
+        let parsed = Url::parse(url.trim()).map_err(|_| unsupported())?;
+        let scheme = match parsed.scheme() {
+            "http" => TrackerScheme::Http,
+            "https" => TrackerScheme::Https,
+            "udp" => TrackerScheme::Udp,
+            "ws" => TrackerScheme::Ws,
+            "wss" => TrackerScheme::Wss,
+            _ => return Err(unsupported()),
+        };
+        if parsed.host().is_none() {
+            return Err(unsupported());
+        }
+        // UDP has no well-known port, so its urls must name one.
+        let port = parsed.port_or_known_default().ok_or_else(unsupported)?;
+        Ok(Self { url: parsed, scheme, port })
+    }
+
+    pub fn scheme(&self) -> TrackerScheme {
+        self.scheme
+    }
+
+    /// The host as written in the url, with IPv6 literals in brackets.
+    pub fn host(&self) -> String {
+        self.url.host().map(|host| host.to_string()).unwrap_or_default()
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Whether the host is written as a loopback, link-local or private address, or is
+    /// `localhost`. Where other names lead is only known once [`Self::resolve`]d.
+    pub fn is_local(&self) -> bool {
+        match self.url.host() {
+            Some(Host::Ipv4(ip)) => is_local_ip(IpAddr::V4(ip)),
+            Some(Host::Ipv6(ip)) => is_local_ip(IpAddr::V6(ip)),
+            Some(Host::Domain(domain)) => {
+                // Hosts of non-special schemes like `udp` are opaque, IPv4 literals included.
+                if let Ok(ip) = domain.parse() {
+                    return is_local_ip(ip);
+                }
+                let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+                domain == "localhost" || domain.ends_with(".localhost")
+            }
+            None => true,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.url.as_str()
+    }
+
+    /// The path and query of the url, e.g. `/announce?passkey=abc`, empty when there are none.
+    pub fn path_and_query(&self) -> String {
+        match self.url.query() {
+            Some(query) => format!("{}?{query}", self.url.path()),
+            None => self.url.path().to_string(),
+        }
+    }
+
+    /// Appends already encoded `key=value` pairs to the url, after any query it already has,
+    /// so private tracker passkeys survive.
+    pub fn with_query(&self, params: &str) -> String {
+        let mut url = self.url.clone();
+        url.set_fragment(None);
+        let separator = match url.query() {
+            Some("") | None => "",
+            Some(_) => "&",
+        };
+        let query = format!("{}{separator}{params}", url.query().unwrap_or_default());
+        url.set_query(Some(&query));
+        url.into()
+    }
+
+    /// The url scrapes are sent to, if the tracker supports them. HTTP trackers follow the
+    /// convention of replacing a last path segment starting with `announce` by `scrape`,
+    /// UDP trackers scrape on the announce url itself.
+    pub fn scrape_url(&self) -> Option<TrackerUrl> {
+        match self.scheme {
+            TrackerScheme::Udp => Some(self.clone()),
+            TrackerScheme::Http | TrackerScheme::Https => {
+                let path = self.url.path();
+                let (dir, last) = path.rsplit_once('/').unwrap_or(("", path));
+                let rest = last.strip_prefix("announce")?;
+                let mut url = self.url.clone();
+                url.set_path(&format!("{dir}/scrape{rest}"));
+                Some(Self { url, ..self.clone() })
+            },
+            TrackerScheme::Ws | TrackerScheme::Wss => None,
+        }
+    }
+
+    /// Picks the connector speaking this url's protocol.
+    pub fn connector(&self) -> AnyTrackerConnector {
+        match self.scheme {
+            TrackerScheme::Http | TrackerScheme::Https => AnyTrackerConnector::Http(HttpTrackerConnector::new()),
+            TrackerScheme::Udp => AnyTrackerConnector::Udp(UdpTrackerConnector::new()),
+            TrackerScheme::Ws | TrackerScheme::Wss => AnyTrackerConnector::Ws(WsTrackerConnector::new()),
+        }
+    }
+
+    /// Picks the connector speaking this url's protocol, sending its traffic through `proxy`.
+    pub fn proxied_connector(&self, proxy: &ProxyConfig) -> Result<AnyTrackerConnector, TrackerError> {
+        Ok(match self.scheme {
+            TrackerScheme::Http | TrackerScheme::Https => AnyTrackerConnector::Http(HttpTrackerConnector::with_proxy(proxy)?),
+            TrackerScheme::Udp => AnyTrackerConnector::Udp(UdpTrackerConnector::with_proxy(proxy.clone())),
+            TrackerScheme::Ws | TrackerScheme::Wss => AnyTrackerConnector::Ws(WsTrackerConnector::with_proxy(proxy.clone())),
+        })
+    }
+
+    /// Resolves the host, answering from a process-wide cache when the name was looked up
+    /// recently. IP literals are returned as is.
+    pub async fn resolve(&self) -> Result<Vec<SocketAddr>, TrackerError> {
+        match self.url.host() {
+            Some(Host::Ipv4(ip)) => Ok(vec![SocketAddr::from((ip, self.port))]),
+            Some(Host::Ipv6(ip)) => Ok(vec![SocketAddr::from((ip, self.port))]),
+            Some(Host::Domain(domain)) => dns_cache().resolve(domain, self.port, Instant::now()).await,
+            None => Err(TrackerError::UnsupportedUrl(self.url.to_string())),
+        }
+    }
+}
+
+impl FromStr for TrackerUrl {
+    type Err = TrackerError;
+
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        Self::parse(url)
+    }
+}
+
+impl fmt::Display for TrackerUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.url.as_str())
+    }
+}
+
+fn dns_cache() -> &'static DnsCache {
+    static CACHE: OnceLock<DnsCache> = OnceLock::new();
+    CACHE.get_or_init(|| DnsCache::new(DNS_CACHE_TTL))
+}
+
+/// Addresses of a host and when they were looked up.
+type DnsEntry = (Vec<SocketAddr>, Instant);
+
+struct DnsCache {
+    entries: Mutex<HashMap<(String, u16), DnsEntry>>,
+    ttl: Duration,
+}
+
+impl DnsCache {
+    fn new(ttl: Duration) -> Self {
+        Self { entries: Mutex::new(HashMap::new()), ttl }
+    }
+
+    fn cached(&self, host: &str, port: u16, now: Instant) -> Option<Vec<SocketAddr>> {
+        match self.entries.lock().unwrap().get(&(host.to_string(), port)) {
+            Some((addrs, resolved_at)) if now.duration_since(*resolved_at) < self.ttl => Some(addrs.clone()),
+            _ => None,
+        }
+    }
+
+    async fn resolve(&self, host: &str, port: u16, now: Instant) -> Result<Vec<SocketAddr>, TrackerError> {
+        if let Some(addrs) = self.cached(host, port, now) {
+            return Ok(addrs);
+        }
+        let addrs: Vec<SocketAddr> = lookup_host((host, port)).await?.collect();
+        if addrs.is_empty() {
+            return Err(TrackerError::InvalidResponse(format!("'{host}' has no address")));
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (_, resolved_at)| now.duration_since(*resolved_at) < self.ttl);
+        entries.insert((host.to_string(), port), (addrs.clone(), now));
+        Ok(addrs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_announce_urls() {
+        let url = TrackerUrl::parse("https://tracker.example/announce.php?passkey=abc").unwrap();
+        assert_eq!((url.scheme(), url.host().as_str(), url.port()), (TrackerScheme::Https, "tracker.example", 443));
+        let url = TrackerUrl::parse("http://tracker.example/announce").unwrap();
+        assert_eq!((url.scheme(), url.port()), (TrackerScheme::Http, 80));
+        let url = TrackerUrl::parse("udp://[2001:db8::1]:6969/announce").unwrap();
+        assert_eq!((url.scheme(), url.host().as_str(), url.port()), (TrackerScheme::Udp, "[2001:db8::1]", 6969));
+        let url = TrackerUrl::parse("wss://tracker.openwebtorrent.com").unwrap();
+        assert_eq!((url.scheme(), url.port()), (TrackerScheme::Wss, 443));
+        assert_eq!("ws://127.0.0.1:8000".parse::<TrackerUrl>().unwrap().scheme(), TrackerScheme::Ws);
+    }
+
+    #[test]
+    fn test_parse_rejects_unsupported_urls() {
+        for url in ["udp://tracker.example/announce", "dht://abc", "udp:tracker.example:80", "random text"] {
+            assert!(matches!(TrackerUrl::parse(url), Err(TrackerError::UnsupportedUrl(_))), "{url}");
+        }
+    }
+
+    #[test]
+    fn test_local_hosts() {
+        for url in ["http://127.0.0.1:8080/announce", "udp://[::1]:6969", "udp://192.168.1.2:6969", "http://169.254.0.1/a", "http://LocalHost./a", "udp://[fe80::1]:1"] {
+            assert!(TrackerUrl::parse(url).unwrap().is_local(), "{url}");
+        }
+        for url in ["http://tracker.example/announce", "udp://8.8.8.8:6969", "udp://[2001:db8::1]:6969"] {
+            assert!(!TrackerUrl::parse(url).unwrap().is_local(), "{url}");
+        }
+    }
+
+    #[test]
+    fn test_with_query_keeps_passkey() {
+        let url = TrackerUrl::parse("https://tracker.example/a/announce?passkey=abc").unwrap();
+        assert_eq!(url.with_query("info_hash=%01&port=1"), "https://tracker.example/a/announce?passkey=abc&info_hash=%01&port=1");
+        let url = TrackerUrl::parse("http://tracker.example:8080/announce").unwrap();
+        assert_eq!(url.with_query("port=1"), "http://tracker.example:8080/announce?port=1");
+    }
+
+    #[test]
+    fn test_path_and_query() {
+        let url = TrackerUrl::parse("udp://tracker.example:6969/announce?passkey=abc").unwrap();
+        assert_eq!(url.path_and_query(), "/announce?passkey=abc");
+        assert_eq!(TrackerUrl::parse("udp://tracker.example:6969").unwrap().path_and_query(), "");
+    }
+
+    #[test]
+    fn test_scrape_url() {
+        let scrape_url = |url| TrackerUrl::parse(url).unwrap().scrape_url().map(|url| url.to_string());
+        assert_eq!(scrape_url("http://tracker.example/x/announce.php?passkey=abc").as_deref(), Some("http://tracker.example/x/scrape.php?passkey=abc"));
+        assert_eq!(scrape_url("udp://tracker.example:6969/announce").as_deref(), Some("udp://tracker.example:6969/announce"));
+        assert_eq!(scrape_url("http://tracker.example/a"), None);
+        assert_eq!(scrape_url("http://tracker.example/announce/x"), None);
+        assert_eq!(scrape_url("wss://tracker.example"), None);
+    }
+
+    #[test]
+    fn test_connector_matches_scheme() {
+        let connector = |url| TrackerUrl::parse(url).unwrap().connector();
+        assert!(matches!(connector("https://tracker.example/announce"), AnyTrackerConnector::Http(_)));
+        assert!(matches!(connector("udp://tracker.example:80"), AnyTrackerConnector::Udp(_)));
+        assert!(matches!(connector("wss://tracker.example"), AnyTrackerConnector::Ws(_)));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_ip_literals_and_localhost() {
+        let addrs = TrackerUrl::parse("udp://[::1]:6969").unwrap().resolve().await.unwrap();
+        assert_eq!(addrs, vec![SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], 6969))]);
+        let addrs = TrackerUrl::parse("http://localhost/announce").unwrap().resolve().await.unwrap();
+        assert!(addrs.iter().all(|addr| addr.ip().is_loopback() && addr.port() == 80));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_dns_cache_expires() {
+        let cache = DnsCache::new(Duration::from_secs(60));
+        let now = Instant::now();
+        let addrs = cache.resolve("localhost", 80, now).await.unwrap();
+        assert!(addrs.iter().all(|addr| addr.ip().is_loopback()));
+        assert_eq!(cache.cached("localhost", 80, now + Duration::from_secs(59)), Some(addrs));
+        assert_eq!(cache.cached("localhost", 80, now + Duration::from_secs(60)), None);
+        assert_eq!(cache.cached("localhost", 81, now), None);
+    }
+}
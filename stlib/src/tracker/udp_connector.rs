@@ -1,144 +1,293 @@
+use std::collections::HashMap;
 use std::io;
 use std::io::{Cursor, ErrorKind, Write};
-use std::net::{IpAddr, SocketAddr, UdpSocket};
 use std::time::{Duration, Instant};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use rand::random;
-use crate::model::{PeerInfo, TrackerNetworkInfo};
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+use crate::model::{parse_compact_peers, parse_compact_peers6, Sha1Hash, TrackerNetworkInfo, SHA1_HASH_LEN};
+use crate::util::proxy::{ProxyConfig, Socks5UdpSocket};
 
-use super::{TrackerAnnounceRequest, TrackerConnector};
+use super::{ScrapeStats, TrackerAnnounceRequest, TrackerConnector, TrackerError, TrackerScrapeRequest, TrackerScrapeResponse, TrackerUrl};
 
 const MINUTE_SECONDS: u64 = 60;
-const CONNECT_REQUEST_PROTOCOL_ID: u64 = 0x41727101980;
-const CONNECT_REQUEST_MIN_PACKET_SIZE: usize = 16;
-const ANNOUNCE_REQUEST_MIN_PACKET_SIZE: usize = 20;
-const IPV4_PORT_SIZE: usize = 4 + 2;
+pub(super) const CONNECT_REQUEST_PROTOCOL_ID: u64 = 0x41727101980;
+const RESPONSE_HEADER_SIZE: usize = 8;
+const CONNECT_RESPONSE_MIN_PACKET_SIZE: usize = 16;
+const ANNOUNCE_RESPONSE_MIN_PACKET_SIZE: usize = 20;
+pub(super) const ANNOUNCE_REQUEST_SIZE: usize = 98;
+const SCRAPE_STATS_SIZE: usize = 12;
+/// BEP 15 caps a scrape to the info hashes fitting in a single packet.
+pub(super) const MAX_SCRAPE_INFO_HASHES: usize = 74;
+/// BEP 41 options trailing an announce request.
+pub(super) const OPTION_END_OF_OPTIONS: u8 = 0;
+pub(super) const OPTION_NOP: u8 = 1;
+pub(super) const OPTION_URL_DATA: u8 = 2;
+/// BEP 15 waits `15 * 2 ^ n` seconds for the n-th retransmission, giving up after n = 8.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(15);
+const DEFAULT_MAX_RETRIES: u32 = 8;
 
 
-struct UdpTrackerConnector {
+pub struct UdpTrackerConnector {
     key: u32,
     connection_id: Option<u64>,
     last_connect_timestamp: Instant,
+    timeout: Duration,
+    max_retries: u32,
+    proxy: Option<ProxyConfig>,
+}
+
+/// Where announce packets go: straight to the tracker or through a SOCKS5 relay.
+enum Transport {
+    Direct(UdpSocket),
+    Socks5(Socks5UdpSocket),
+}
+
+impl Transport {
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Direct(socket) => socket.send(buf).await,
+            Self::Socks5(socket) => socket.send(buf).await,
+        }
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Direct(socket) => socket.recv(buf).await,
+            Self::Socks5(socket) => socket.recv(buf).await,
+        }
+    }
+
+    /// Whether the tracker is reached over IPv6, and so answers with IPv6 peers (BEP 15). Names
+    /// are resolved by the proxy, so only IPv6 literals are known to be.
+    fn is_ipv6(&self, url: &TrackerUrl) -> bool {
+        match self {
+            Self::Direct(socket) => socket.peer_addr().is_ok_and(|addr| addr.is_ipv6()),
+            Self::Socks5(_) => url.host().starts_with('['),
+        }
+    }
+}
+
+impl Default for UdpTrackerConnector {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl UdpTrackerConnector {
-    fn new() -> Self {
+    pub fn new() -> Self {
+        Self::with_timeout(DEFAULT_TIMEOUT, DEFAULT_MAX_RETRIES)
+    }
+
+    /// Waits `timeout * 2 ^ n` for the n-th retransmission and gives up after `max_retries` of them.
+    pub fn with_timeout(timeout: Duration, max_retries: u32) -> Self {
         Self {
             key: random(),
             connection_id: None,
             last_connect_timestamp: Instant::now(),
+            timeout,
+            max_retries,
+            proxy: None,
+        }
+    }
+
+    /// Relays announces through a SOCKS5 proxy with `UDP ASSOCIATE`.
+    pub fn with_proxy(proxy: ProxyConfig) -> Self {
+        Self { proxy: Some(proxy), ..Self::new() }
+    }
+
+    async fn open_transport(&self, url: &TrackerUrl) -> Result<Transport, TrackerError> {
+        if let Some(proxy) = &self.proxy {
+            return Ok(Transport::Socks5(Socks5UdpSocket::associate(proxy, &url.host(), url.port()).await?));
         }
+        let addrs = url.resolve().await?;
+        let tracker_addr = addrs.iter().find(|addr| addr.is_ipv4()).unwrap_or(&addrs[0]);
+        let bind_addr = if tracker_addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(bind_addr).await?;
+        socket.connect(tracker_addr).await?;
+        Ok(Transport::Direct(socket))
     }
 }
 
 impl TrackerConnector for UdpTrackerConnector {
-    async fn announce(&mut self, request: &super::TrackerAnnounceRequest) -> Result<TrackerNetworkInfo, String> {
-        let mut socket = UdpSocket::bind("127.0.0.1:0").expect("Couldn't bind UDP socket");
-        socket.connect(SocketAddr::new(IpAddr::from(request.ip.to_be_bytes()), request.port)).expect("Couldn't connect to tracker");
+    async fn announce(&mut self, request: &super::TrackerAnnounceRequest) -> Result<TrackerNetworkInfo, TrackerError> {
+        let url = TrackerUrl::parse(&request.url)?;
+        let url_data = url.path_and_query();
+        let key = self.key;
+        let socket = self.open_transport(&url).await?;
+        let (packet, transaction_id) = self.transact(&socket, |connection_id, transaction_id| {
+            make_announce_request(connection_id, transaction_id, key, request, url_data.as_bytes())
+        }).await?;
+        parse_announce_response(&packet, transaction_id, socket.is_ipv6(&url))
+    }
+
+    async fn scrape(&mut self, request: &TrackerScrapeRequest) -> Result<TrackerScrapeResponse, TrackerError> {
+        let url = TrackerUrl::parse(request.url())?;
+        let info_hashes = &request.info_hashes()[..request.info_hashes().len().min(MAX_SCRAPE_INFO_HASHES)];
+        let socket = self.open_transport(&url).await?;
+        let (packet, transaction_id) = self.transact(&socket, |connection_id, transaction_id| {
+            make_scrape_request(connection_id, transaction_id, info_hashes)
+        }).await?;
+        parse_scrape_response(&packet, transaction_id, info_hashes)
+    }
+}
+
+
+impl UdpTrackerConnector {
+    /// Sends the request built for the current connection id and a fresh transaction id,
+    /// connecting first when needed and retransmitting on timeouts, and returns the response
+    /// with the transaction id it should carry.
+    async fn transact<F>(&mut self, socket: &Transport, make_request: F) -> Result<(Vec<u8>, u32), TrackerError>
+    where
+        F: Fn(u64, u32) -> Vec<u8>,
+    {
         let mut buf = [0u8; 2048];
         let mut timeout_multiplier = 0;
         loop {
             if self.connection_id.is_none() || self.last_connect_timestamp.elapsed().as_secs() >= MINUTE_SECONDS {
-                match self.connect(&mut socket, &mut timeout_multiplier)
-                    .await {
-                    Ok(_) => {},
-                    Err(err) => return Err(err.to_string()),
-                }
+                self.connect(socket, &mut timeout_multiplier).await?;
             }
-            if timeout_multiplier > 8 {
-                return Err(String::from( "Announce request timed out"));
+            if timeout_multiplier > self.max_retries {
+                return Err(TrackerError::Timeout);
             }
             let transaction_id: u32 = random();
-            let message = make_announce_request(self.connection_id.unwrap(), transaction_id, self.key, request);
-            match send_recv_timeout(&mut socket, &mut buf, &message, timeout_multiplier) {
-                Ok(n) if n >= ANNOUNCE_REQUEST_MIN_PACKET_SIZE => {
-                    let mut cursor = Cursor::new(&mut buf[..]);
-                    let action = cursor.read_u32::<BigEndian>().unwrap();
-                    let tracker_transaction_id = cursor.read_u32::<BigEndian>().unwrap();
-                    let interval = cursor.read_u32::<BigEndian>().unwrap();
-                    let leechers = cursor.read_u32::<BigEndian>().unwrap();
-                    let seeders = cursor.read_u32::<BigEndian>().unwrap();
-
-                    if action != Action::Announce as u32 {
-                        return Err(String::from("Response action isn't announce"));
-                    }
-                    if transaction_id != tracker_transaction_id {
-                        return Err(String::from("Response transaction id doesn't equal generated transaction id"));
-                    }
-
-                    let peers_len = (n - ANNOUNCE_REQUEST_MIN_PACKET_SIZE) / IPV4_PORT_SIZE;
-                    let peers: Vec<PeerInfo> = (0..peers_len)
-                        .map(|_| {
-                            let ip = cursor.read_u32::<BigEndian>().unwrap();
-                            let port = cursor.read_u16::<BigEndian>().unwrap();
-                            SocketAddr::new(IpAddr::from(ip.to_be_bytes()), port)
-                        })
-                        .map(|socket_addr| PeerInfo { socket_addr })
-                        .collect();
-
-                    return Ok(TrackerNetworkInfo {
-                        interval,
-                        leechers: Some(leechers),
-                        seeders: Some(seeders),
-                        peers,
-                    });
-                },
+            let Some(connection_id) = self.connection_id else {
+                continue;
+            };
+            let message = make_request(connection_id, transaction_id);
+            match send_recv_timeout(socket, &mut buf, &message, self.read_timeout(timeout_multiplier)).await {
+                Ok(n) => return Ok((buf[..n].to_vec(), transaction_id)),
                 Err(err) if err.kind() == ErrorKind::TimedOut => { timeout_multiplier += 1; }
-                Ok(_) => return Err(String::from("Announce response too short")),
-                Err(err) => return Err(err.to_string()),
+                Err(err) => return Err(err.into()),
             }
         }
-
     }
-}
 
+    fn read_timeout(&self, timeout_multiplier: u32) -> Duration {
+        self.timeout.saturating_mul(2u32.saturating_pow(timeout_multiplier))
+    }
 
-impl UdpTrackerConnector {
-    
-    async fn connect(&mut self, socket: &mut UdpSocket, timeout_multiplier: &mut u32) -> Result<(), io::Error> {
+    async fn connect(&mut self, socket: &Transport, timeout_multiplier: &mut u32) -> Result<(), TrackerError> {
         let transaction_id: u32 = random();
         let message = make_connection_request(transaction_id);
 
         let mut buf = [0u8; 256];
         loop {
-            if *timeout_multiplier > 8 {
-                return Err(io::Error::new(io::ErrorKind::TimedOut, "Connect request timed out"));
+            if *timeout_multiplier > self.max_retries {
+                return Err(TrackerError::Timeout);
             }
-            match send_recv_timeout(socket, &mut buf, &message, *timeout_multiplier) {
-                Ok(n) if n >= CONNECT_REQUEST_MIN_PACKET_SIZE => {
-                    let mut cursor = Cursor::new(&mut buf[..]);
-                    let action = cursor.read_u32::<BigEndian>().unwrap();
-                    let tracker_transaction_id = cursor.read_u32::<BigEndian>().unwrap();
-                    let connection_id = cursor.read_u64::<BigEndian>().unwrap();
-                    if action != Action::Connect as u32 {
-                        return Err(io::Error::new(io::ErrorKind::InvalidData, "Response action isn't connect"));
-                    }
-                    if transaction_id != tracker_transaction_id {
-                        return Err(io::Error::new(io::ErrorKind::InvalidData, "Response transaction id doesn't equal generated transaction id"));
-                    }
-                    self.connection_id = Some(connection_id);
+            match send_recv_timeout(socket, &mut buf, &message, self.read_timeout(*timeout_multiplier)).await {
+                Ok(n) => {
+                    self.connection_id = Some(parse_connect_response(&buf[..n], transaction_id)?);
                     self.last_connect_timestamp = Instant::now();
+                    return Ok(());
                 },
                 Err(err) if err.kind() == ErrorKind::TimedOut => { *timeout_multiplier += 1; }
-                Ok(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "Connect response too short")),
-                Err(err) => return Err(err),
+                Err(err) => return Err(err.into()),
             }
         }
     }
 }
 
-enum Action {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Action {
     Connect = 0,
     Announce = 1,
+    Scrape = 2,
+    Error = 3,
+}
+
+impl Action {
+    pub(super) fn from_u32(action: u32) -> Option<Self> {
+        match action {
+            0 => Some(Self::Connect),
+            1 => Some(Self::Announce),
+            2 => Some(Self::Scrape),
+            3 => Some(Self::Error),
+            _ => None,
+        }
+    }
+}
+
+/// Reads the action and transaction id every response starts with, turning
+/// error responses and mismatching transactions into errors.
+fn parse_response_header(cursor: &mut Cursor<&[u8]>, expected_action: Action, transaction_id: u32) -> Result<(), TrackerError> {
+    let packet_len = cursor.get_ref().len();
+    if packet_len < RESPONSE_HEADER_SIZE {
+        return Err(TrackerError::InvalidResponse(String::from("Response too short")));
+    }
+    let action = cursor.read_u32::<BigEndian>()?;
+    let tracker_transaction_id = cursor.read_u32::<BigEndian>()?;
+    if transaction_id != tracker_transaction_id {
+        return Err(TrackerError::InvalidResponse(String::from("Response transaction id doesn't equal generated transaction id")));
+    }
+    if action == Action::Error as u32 {
+        return Err(TrackerError::Failure {
+            reason: String::from_utf8_lossy(&cursor.get_ref()[RESPONSE_HEADER_SIZE..]).into_owned(),
+            retry_in: None,
+        });
+    }
+    if action != expected_action as u32 {
+        return Err(TrackerError::InvalidResponse(format!("Response action {action} isn't {}", expected_action as u32)));
+    }
+    Ok(())
+}
+
+fn parse_connect_response(packet: &[u8], transaction_id: u32) -> Result<u64, TrackerError> {
+    let mut cursor = Cursor::new(packet);
+    parse_response_header(&mut cursor, Action::Connect, transaction_id)?;
+    if packet.len() < CONNECT_RESPONSE_MIN_PACKET_SIZE {
+        return Err(TrackerError::InvalidResponse(String::from("Connect response too short")));
+    }
+    Ok(cursor.read_u64::<BigEndian>()?)
+}
+
+/// Peers are in the compact format of the address family the tracker was reached over.
+fn parse_announce_response(packet: &[u8], transaction_id: u32, ipv6: bool) -> Result<TrackerNetworkInfo, TrackerError> {
+    let mut cursor = Cursor::new(packet);
+    parse_response_header(&mut cursor, Action::Announce, transaction_id)?;
+    if packet.len() < ANNOUNCE_RESPONSE_MIN_PACKET_SIZE {
+        return Err(TrackerError::InvalidResponse(String::from("Announce response too short")));
+    }
+    let interval = cursor.read_u32::<BigEndian>()?;
+    let leechers = cursor.read_u32::<BigEndian>()?;
+    let seeders = cursor.read_u32::<BigEndian>()?;
+    let peers = &packet[ANNOUNCE_RESPONSE_MIN_PACKET_SIZE..];
+    Ok(TrackerNetworkInfo {
+        interval,
+        complete: Some(seeders),
+        incomplete: Some(leechers),
+        peers: if ipv6 { parse_compact_peers6(peers)? } else { parse_compact_peers(peers)? },
+        ..Default::default()
+    })
 }
 
-fn send_recv_timeout(socket: &mut UdpSocket, buf: &mut [u8], message: &[u8], timeout_multiplier: u32) -> Result<usize, io::Error> {
-    let read_timeout = Duration::from_secs(15u64 * 2u64.pow(timeout_multiplier));
-    if socket.send(message)? != message.len() {
-        return Err(io::Error::new(io::ErrorKind::Other, "Couldn't write full message to socket"));
+/// Reads the seeders, completed and leechers counts, one triple per requested info hash.
+fn parse_scrape_response(packet: &[u8], transaction_id: u32, info_hashes: &[Sha1Hash]) -> Result<TrackerScrapeResponse, TrackerError> {
+    let mut cursor = Cursor::new(packet);
+    parse_response_header(&mut cursor, Action::Scrape, transaction_id)?;
+    if packet.len() != RESPONSE_HEADER_SIZE + SCRAPE_STATS_SIZE * info_hashes.len() {
+        return Err(TrackerError::InvalidResponse(String::from("Scrape response doesn't match the requested torrents")));
+    }
+    let mut files = HashMap::new();
+    for info_hash in info_hashes {
+        let complete = cursor.read_u32::<BigEndian>()?;
+        let downloaded = cursor.read_u32::<BigEndian>()?;
+        let incomplete = cursor.read_u32::<BigEndian>()?;
+        files.insert(*info_hash, ScrapeStats { complete, downloaded, incomplete });
+    }
+    Ok(TrackerScrapeResponse { files })
+}
+
+async fn send_recv_timeout(socket: &Transport, buf: &mut [u8], message: &[u8], read_timeout: Duration) -> Result<usize, io::Error> {
+    if socket.send(message).await? != message.len() {
+        return Err(io::Error::other("Couldn't write full message to socket"));
+    }
+    match timeout(read_timeout, socket.recv(buf)).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::from(io::ErrorKind::TimedOut)),
     }
-    socket.set_read_timeout(Some(read_timeout)).unwrap();
-    socket.recv(buf)
 }
 
 
@@ -153,24 +302,199 @@ fn make_connection_request(translation_id: u32) -> [u8; 16] {
     buf
 }
 
-fn make_announce_request(connection_id: u64, transaction_id: u32, key: u32, request: &TrackerAnnounceRequest) -> [u8; 98] {
+fn make_scrape_request(connection_id: u64, transaction_id: u32, info_hashes: &[Sha1Hash]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(16 + info_hashes.len() * SHA1_HASH_LEN);
+    buf.write_u64::<BigEndian>(connection_id).unwrap();
+    buf.write_u32::<BigEndian>(Action::Scrape as u32).unwrap();
+    buf.write_u32::<BigEndian>(transaction_id).unwrap();
+    for info_hash in info_hashes {
+        buf.extend_from_slice(info_hash);
+    }
+    buf
+}
+
+fn make_announce_request(connection_id: u64, transaction_id: u32, key: u32, request: &TrackerAnnounceRequest, url_data: &[u8]) -> Vec<u8> {
     use byteorder::BigEndian;
 
-    let mut buf = [0u8; 98];
-    let mut cursor = Cursor::new(&mut buf[..]);
+    let mut buf = Vec::with_capacity(ANNOUNCE_REQUEST_SIZE + url_data.len() + url_data.len() / 255 * 2 + 3);
+    let mut cursor = Cursor::new(&mut buf);
     cursor.write_u64::<BigEndian>(connection_id).unwrap();
     cursor.write_u32::<BigEndian>(Action::Announce as u32).unwrap();
     cursor.write_u32::<BigEndian>(transaction_id).unwrap();
     cursor.write_all(&request.info_hash).unwrap();
     cursor.write_all(&request.peer_id).unwrap();
-    cursor.write_u64::<BigEndian>(request.downloaded as u64).unwrap();
-    cursor.write_u64::<BigEndian>(request.left as u64).unwrap();
-    cursor.write_u64::<BigEndian>(request.uploaded as u64).unwrap();
+    cursor.write_u64::<BigEndian>(request.downloaded).unwrap();
+    cursor.write_u64::<BigEndian>(request.left).unwrap();
+    cursor.write_u64::<BigEndian>(request.uploaded).unwrap();
     cursor.write_u32::<BigEndian>(request.event as u32).unwrap();
     cursor.write_u32::<BigEndian>(request.ip).unwrap();
     cursor.write_u32::<BigEndian>(key).unwrap();
-    cursor.write_i32::<BigEndian>(-1).unwrap(); // num_want
+    cursor.write_i32::<BigEndian>(request.num_want.map_or(-1, |num_want| num_want.min(i32::MAX as u32) as i32)).unwrap();
     cursor.write_u16::<BigEndian>(request.port).unwrap();
-
+    // BEP 41 splits the path and query of the announce url into options of at most 255 bytes.
+    if !url_data.is_empty() {
+        for chunk in url_data.chunks(u8::MAX as usize) {
+            buf.push(OPTION_URL_DATA);
+            buf.push(chunk.len() as u8);
+            buf.extend_from_slice(chunk);
+        }
+        buf.push(OPTION_END_OF_OPTIONS);
+    }
     buf
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn announce_response(transaction_id: u32, peers: &[u8]) -> Vec<u8> {
+        let mut packet = vec![];
+        packet.write_u32::<BigEndian>(Action::Announce as u32).unwrap();
+        packet.write_u32::<BigEndian>(transaction_id).unwrap();
+        packet.write_u32::<BigEndian>(1800).unwrap();
+        packet.write_u32::<BigEndian>(3).unwrap();
+        packet.write_u32::<BigEndian>(5).unwrap();
+        packet.extend_from_slice(peers);
+        packet
+    }
+
+    #[test]
+    fn test_parse_announce_response() {
+        let packet = announce_response(7, &[127, 0, 0, 1, 0x1a, 0xe1]);
+        let info = parse_announce_response(&packet, 7, false).unwrap();
+        assert_eq!(info.interval, 1800);
+        assert_eq!((info.complete, info.incomplete), (Some(5), Some(3)));
+        assert_eq!(info.peers[0].socket_addr, "127.0.0.1:6881".parse().unwrap());
+
+        let mut peer6 = [0; 18];
+        peer6[15] = 1;
+        peer6[16..].copy_from_slice(&[0x1a, 0xe1]);
+        let info = parse_announce_response(&announce_response(7, &peer6), 7, true).unwrap();
+        assert_eq!(info.peers.len(), 1);
+        assert_eq!(info.peers[0].socket_addr, "[::1]:6881".parse().unwrap());
+        assert!(parse_announce_response(&packet, 7, true).is_err());
+    }
+
+    #[test]
+    fn test_parse_malformed_announce_responses() {
+        let packet = announce_response(7, &[127, 0, 0, 1, 0x1a, 0xe1]);
+        for len in (0..packet.len()).filter(|len| *len != ANNOUNCE_RESPONSE_MIN_PACKET_SIZE) {
+            assert!(matches!(parse_announce_response(&packet[..len], 7, false), Err(TrackerError::InvalidResponse(_))));
+        }
+        assert!(matches!(parse_announce_response(&packet, 8, false), Err(TrackerError::InvalidResponse(_))));
+        assert!(matches!(parse_connect_response(&packet, 7), Err(TrackerError::InvalidResponse(_))));
+    }
+
+    #[test]
+    fn test_parse_error_response() {
+        let mut packet = vec![];
+        packet.write_u32::<BigEndian>(Action::Error as u32).unwrap();
+        packet.write_u32::<BigEndian>(7).unwrap();
+        packet.extend_from_slice(b"unregistered torrent");
+        let err = parse_announce_response(&packet, 7, false).unwrap_err();
+        assert!(matches!(err, TrackerError::Failure { ref reason, .. } if reason == "unregistered torrent"));
+    }
+
+    #[test]
+    fn test_announce_request_carries_url_data() {
+        let request = TrackerAnnounceRequest::new("udp://tracker.example:6969", [1; 20], [2; 20], 6881);
+        assert_eq!(make_announce_request(1, 2, 3, &request, b"").len(), ANNOUNCE_REQUEST_SIZE);
+
+        let url_data = format!("/announce?passkey={}", "a".repeat(300));
+        let packet = make_announce_request(1, 2, 3, &request, url_data.as_bytes());
+        assert_eq!(packet.len(), ANNOUNCE_REQUEST_SIZE + url_data.len() + 2 * 2 + 1);
+        assert_eq!(&packet[ANNOUNCE_REQUEST_SIZE..ANNOUNCE_REQUEST_SIZE + 2], &[OPTION_URL_DATA, 255]);
+        let mut cursor = Cursor::new(&packet[..]);
+        cursor.set_position(16);
+        assert_eq!(crate::tracker::server::udp::parse_announce(&mut cursor).unwrap().url, url_data);
+    }
+
+    mod against_mock {
+        use super::super::*;
+        use crate::model::PeerInfo;
+        use crate::tracker::mock::{MockResponse, MockTracker};
+        use crate::util::mock_proxy::MockProxy;
+
+        fn connector() -> UdpTrackerConnector {
+            UdpTrackerConnector::with_timeout(Duration::from_millis(50), 1)
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn test_announce_reuses_connection_id() {
+            let tracker = MockTracker::start().await.unwrap();
+            let peer = PeerInfo { socket_addr: "10.0.0.1:6881".parse().unwrap() };
+            tracker.push(MockResponse::Peers(vec![peer.clone()]));
+            let mut request = TrackerAnnounceRequest::new(tracker.udp_url(), [1; 20], [2; 20], 6881);
+            request.left = 42;
+            request.num_want = Some(10);
+
+            let mut connector = connector();
+            let info = connector.announce(&request).await.unwrap();
+            assert_eq!(info.peers, vec![peer]);
+            assert_eq!(info.interval, 30 * 60);
+            connector.announce(&request).await.unwrap();
+
+            assert_eq!(tracker.connects(), 1);
+            let announces = tracker.announces();
+            assert_eq!(announces.len(), 2);
+            assert_eq!((announces[0].left, announces[0].port, announces[0].num_want), (42, 6881, Some(10)));
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn test_announce_sends_passkey_as_url_data() {
+            let tracker = MockTracker::start().await.unwrap();
+            let url = format!("{}/announce?passkey=secret", tracker.udp_url());
+            connector().announce(&TrackerAnnounceRequest::new(url.clone(), [1; 20], [2; 20], 6881)).await.unwrap();
+            assert_eq!(tracker.announces()[0].url, url);
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn test_announce_through_socks5() {
+            let tracker = MockTracker::start().await.unwrap();
+            let proxy = MockProxy::start(ProxyConfig::socks5("")).await.unwrap();
+            let peer = PeerInfo { socket_addr: "10.0.0.1:6881".parse().unwrap() };
+            tracker.push(MockResponse::Peers(vec![peer.clone()]));
+            let request = TrackerAnnounceRequest::new(tracker.udp_url(), [1; 20], [2; 20], 6881);
+
+            let info = UdpTrackerConnector::with_proxy(proxy.config()).announce(&request).await.unwrap();
+            assert_eq!(info.peers, vec![peer]);
+            assert_eq!(proxy.relayed(), 2);
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn test_announce_failure() {
+            let tracker = MockTracker::start().await.unwrap();
+            tracker.push(MockResponse::Failure(String::from("unregistered torrent")));
+            let request = TrackerAnnounceRequest::new(tracker.udp_url(), [1; 20], [2; 20], 6881);
+            let err = connector().announce(&request).await.unwrap_err();
+            assert!(matches!(err, TrackerError::Failure { ref reason, .. } if reason == "unregistered torrent"));
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn test_announce_retransmits_then_times_out() {
+            let tracker = MockTracker::start().await.unwrap();
+            let request = TrackerAnnounceRequest::new(tracker.udp_url(), [1; 20], [2; 20], 6881);
+            tracker.push(MockResponse::Timeout);
+            connector().announce(&request).await.unwrap();
+            assert_eq!(tracker.announces().len(), 2);
+
+            tracker.push(MockResponse::Timeout);
+            tracker.push(MockResponse::Timeout);
+            let err = connector().announce(&request).await.unwrap_err();
+            assert!(matches!(err, TrackerError::Timeout));
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn test_announce_rejects_bad_responses() {
+            let tracker = MockTracker::start().await.unwrap();
+            let request = TrackerAnnounceRequest::new(tracker.udp_url(), [1; 20], [2; 20], 6881);
+            tracker.push(MockResponse::WrongTransactionId);
+            tracker.push(MockResponse::Truncated);
+            tracker.push(MockResponse::Raw(vec![0xff; 3]));
+            for _ in 0..3 {
+                let err = connector().announce(&request).await.unwrap_err();
+                assert!(matches!(err, TrackerError::InvalidResponse(_)), "{err:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,391 @@
+use std::io;
+use std::time::Duration;
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::net::TcpStream;
+use tokio::time::{timeout_at, Instant};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{client_async_tls, MaybeTlsStream, WebSocketStream};
+
+use crate::model::{Sha1Hash, TrackerNetworkInfo};
+use crate::peer::peer::PeerId;
+use crate::util::proxy::{connect_tcp, ProxyConfig};
+
+use super::http_connector::event_to_string;
+use super::{TrackerAnnounceRequest, TrackerConnector, TrackerError, TrackerEvent, TrackerUrl};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub type OfferId = [u8; 20];
+
+/// A WebRTC offer to be relayed by the tracker to another swarm member. The tracker never
+/// looks inside `offer`, so it is kept as the JSON value produced by the WebRTC stack.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WsOffer {
+    pub offer_id: OfferId,
+    pub offer: Value,
+}
+
+/// Signalling messages relayed to us by a WebSocket tracker.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WsSignal {
+    /// Another peer wants to connect and awaits an answer carrying the same offer id.
+    Offer { info_hash: Sha1Hash, peer_id: PeerId, offer_id: OfferId, offer: Value },
+    /// Another peer answered one of our offers.
+    Answer { info_hash: Sha1Hash, peer_id: PeerId, offer_id: OfferId, answer: Value },
+}
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Announces to WebTorrent trackers over `ws://` and `wss://`.
+///
+/// Members of such swarms are only reachable through WebRTC, so announces never yield socket
+/// addresses: the response counts the swarm, and offers and answers exchanged with other
+/// members are collected as [`WsSignal`]s for a WebRTC stack to act on.
+pub struct WsTrackerConnector {
+    socket: Option<(String, Box<WsStream>)>,
+    offers: Vec<WsOffer>,
+    signals: Vec<WsSignal>,
+    timeout: Duration,
+    proxy: Option<ProxyConfig>,
+}
+
+#[derive(Serialize)]
+struct AnnounceMessage {
+    action: &'static str,
+    info_hash: String,
+    peer_id: String,
+    uploaded: u64,
+    downloaded: u64,
+    left: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event: Option<&'static str>,
+    numwant: usize,
+    offers: Vec<OfferMessage>,
+}
+
+#[derive(Serialize)]
+struct OfferMessage {
+    offer: Value,
+    offer_id: String,
+}
+
+#[derive(Serialize)]
+struct AnswerMessage {
+    action: &'static str,
+    info_hash: String,
+    peer_id: String,
+    to_peer_id: String,
+    offer_id: String,
+    answer: Value,
+}
+
+#[derive(Deserialize)]
+struct TrackerMessage {
+    action: Option<String>,
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+    #[serde(rename = "warning message")]
+    warning_message: Option<String>,
+    interval: Option<u32>,
+    #[serde(rename = "min interval")]
+    min_interval: Option<u32>,
+    info_hash: Option<String>,
+    complete: Option<u32>,
+    incomplete: Option<u32>,
+    peer_id: Option<String>,
+    offer_id: Option<String>,
+    offer: Option<Value>,
+    answer: Option<Value>,
+}
+
+impl Default for WsTrackerConnector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WsTrackerConnector {
+    pub fn new() -> Self {
+        Self::with_timeout(DEFAULT_TIMEOUT)
+    }
+
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self { socket: None, offers: vec![], signals: vec![], timeout, proxy: None }
+    }
+
+    /// Opens tracker connections through `proxy`.
+    pub fn with_proxy(proxy: ProxyConfig) -> Self {
+        Self { proxy: Some(proxy), ..Self::new() }
+    }
+
+    /// Offers to hand to the tracker with the next announce.
+    pub fn set_offers(&mut self, offers: Vec<WsOffer>) {
+        self.offers = offers;
+    }
+
+    /// Signalling messages received while waiting for announce responses.
+    pub fn take_signals(&mut self) -> Vec<WsSignal> {
+        std::mem::take(&mut self.signals)
+    }
+
+    /// Waits for the tracker to relay the next offer or answer.
+    pub async fn next_signal(&mut self) -> Result<WsSignal, TrackerError> {
+        if !self.signals.is_empty() {
+            return Ok(self.signals.remove(0));
+        }
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            if let Message::Text(text) = self.recv(deadline).await? {
+                let message = parse_message(&text)?;
+                if let Some(signal) = to_signal(&message) {
+                    return Ok(signal);
+                }
+            }
+        }
+    }
+
+    /// Relays `answer` to the peer that sent the offer `offer_id`.
+    pub async fn send_answer(&mut self, info_hash: &Sha1Hash, peer_id: &PeerId, to_peer_id: &PeerId, offer_id: &OfferId, answer: Value) -> Result<(), TrackerError> {
+        let message = AnswerMessage {
+            action: "announce",
+            info_hash: to_binary_string(info_hash),
+            peer_id: to_binary_string(peer_id),
+            to_peer_id: to_binary_string(to_peer_id),
+            offer_id: to_binary_string(offer_id),
+            answer,
+        };
+        self.send(&serde_json::to_string(&message).expect("answer serializes to JSON")).await
+    }
+
+    async fn connect(&mut self, url: &str) -> Result<(), TrackerError> {
+        if !matches!(&self.socket, Some((connected_url, _)) if connected_url == url) {
+            let tracker_url = TrackerUrl::parse(url)?;
+            let connect = async {
+                let stream = connect_tcp(self.proxy.as_ref(), &tracker_url.host(), tracker_url.port()).await?;
+                client_async_tls(url, stream).await.map_err(TrackerError::from)
+            };
+            let (socket, _) = tokio::time::timeout(self.timeout, connect).await
+                .map_err(|_| TrackerError::Timeout)??;
+            self.socket = Some((url.to_string(), Box::new(socket)));
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, text: &str) -> Result<(), TrackerError> {
+        let (_, socket) = self.socket.as_mut().ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+        let result = socket.send(Message::text(text)).await;
+        if result.is_err() {
+            self.socket = None;
+        }
+        Ok(result?)
+    }
+
+    /// Reads the next message. Errors and timeouts drop the connection, so that a late response
+    /// can't be mistaken for the answer to a later announce; the next announce reconnects.
+    async fn recv(&mut self, deadline: Instant) -> Result<Message, TrackerError> {
+        let (_, socket) = self.socket.as_mut().ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+        let result = match timeout_at(deadline, socket.next()).await {
+            Err(_) => Err(TrackerError::Timeout),
+            Ok(None) | Ok(Some(Ok(Message::Close(_)))) => Err(io::Error::from(io::ErrorKind::ConnectionAborted).into()),
+            Ok(Some(result)) => result.map_err(TrackerError::from),
+        };
+        if result.is_err() {
+            self.socket = None;
+        }
+        result
+    }
+}
+
+impl TrackerConnector for WsTrackerConnector {
+    async fn announce(&mut self, request: &TrackerAnnounceRequest) -> Result<TrackerNetworkInfo, TrackerError> {
+        self.connect(&request.url).await?;
+        let offers = std::mem::take(&mut self.offers);
+        let message = AnnounceMessage {
+            action: "announce",
+            info_hash: to_binary_string(&request.info_hash),
+            peer_id: to_binary_string(&request.peer_id),
+            uploaded: request.uploaded,
+            downloaded: request.downloaded,
+            left: request.left,
+            event: (request.event != TrackerEvent::None).then(|| event_to_string(&request.event)),
+            // Trackers relay at most one offer per wanted peer.
+            numwant: offers.len(),
+            offers: offers.into_iter()
+                .map(|offer| OfferMessage { offer: offer.offer, offer_id: to_binary_string(&offer.offer_id) })
+                .collect(),
+        };
+        self.send(&serde_json::to_string(&message).expect("announce serializes to JSON")).await?;
+
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let Message::Text(text) = self.recv(deadline).await? else {
+                continue;
+            };
+            let message = parse_message(&text)?;
+            if let Some(reason) = message.failure_reason {
+                return Err(TrackerError::Failure { reason, retry_in: None });
+            }
+            if let Some(signal) = to_signal(&message) {
+                self.signals.push(signal);
+                continue;
+            }
+            let for_request = message.info_hash.as_deref().and_then(from_binary_string) == Some(request.info_hash);
+            if message.action.as_deref() == Some("announce") && for_request {
+                if let Some(interval) = message.interval {
+                    return Ok(TrackerNetworkInfo {
+                        interval,
+                        min_interval: message.min_interval,
+                        complete: message.complete,
+                        incomplete: message.incomplete,
+                        warning_message: message.warning_message,
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+    }
+}
+
+fn parse_message(text: &str) -> Result<TrackerMessage, TrackerError> {
+    serde_json::from_str(text).map_err(|err| TrackerError::InvalidResponse(err.to_string()))
+}
+
+fn to_signal(message: &TrackerMessage) -> Option<WsSignal> {
+    let info_hash = from_binary_string(message.info_hash.as_deref()?)?;
+    let peer_id = from_binary_string(message.peer_id.as_deref()?)?;
+    let offer_id = from_binary_string(message.offer_id.as_deref()?)?;
+    match (&message.offer, &message.answer) {
+        (Some(offer), _) => Some(WsSignal::Offer { info_hash, peer_id, offer_id, offer: offer.clone() }),
+        (None, Some(answer)) => Some(WsSignal::Answer { info_hash, peer_id, offer_id, answer: answer.clone() }),
+        (None, None) => None,
+    }
+}
+
+/// WebTorrent sends binary ids as strings with one code point per byte.
+fn to_binary_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| char::from(*byte)).collect()
+}
+
+fn from_binary_string<const N: usize>(text: &str) -> Option<[u8; N]> {
+    let bytes = text.chars()
+        .map(|c| u8::try_from(c).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    bytes.try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::accept_async;
+
+    /// Serves a single WebSocket connection with `handler` and returns the url to reach it.
+    async fn serve<F, Fut>(handler: F) -> String
+    where
+        F: FnOnce(WebSocketStream<TcpStream>) -> Fut + Send + 'static,
+        Fut: std::future::Future<Output = ()> + Send,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            handler(accept_async(stream).await.unwrap()).await;
+        });
+        url
+    }
+
+    async fn recv_json(socket: &mut WebSocketStream<TcpStream>) -> Value {
+        let message = socket.next().await.unwrap().unwrap();
+        serde_json::from_str(message.to_text().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_binary_string_round_trip() {
+        let bytes: Sha1Hash = std::array::from_fn(|i| (i * 13) as u8);
+        assert_eq!(from_binary_string(&to_binary_string(&bytes)), Some(bytes));
+        assert_eq!(from_binary_string::<20>("\u{100}"), None);
+        assert_eq!(from_binary_string::<20>("short"), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_announce_counts_swarm_and_collects_offers() {
+        let info_hash = [0xab; 20];
+        let url = serve(move |mut socket| async move {
+            let announce = recv_json(&mut socket).await;
+            assert_eq!(announce["info_hash"], to_binary_string(&info_hash));
+            assert_eq!((announce["event"].as_str(), announce["left"].as_u64()), (Some("started"), Some(7)));
+            assert_eq!(announce["numwant"], 1);
+            assert_eq!(announce["offers"][0]["offer_id"], to_binary_string(&[3; 20]));
+            assert_eq!(announce["offers"][0]["offer"]["sdp"], "ours");
+            let relayed = json!({
+                "action": "announce",
+                "info_hash": to_binary_string(&info_hash),
+                "peer_id": to_binary_string(&[5; 20]),
+                "offer_id": to_binary_string(&[6; 20]),
+                "offer": { "type": "offer", "sdp": "theirs" },
+            });
+            socket.send(Message::text(relayed.to_string())).await.unwrap();
+            let response = json!({
+                "action": "announce",
+                "info_hash": to_binary_string(&info_hash),
+                "interval": 120,
+                "complete": 4,
+                "incomplete": 9,
+            });
+            socket.send(Message::text(response.to_string())).await.unwrap();
+            let answer = recv_json(&mut socket).await;
+            assert_eq!(answer["to_peer_id"], to_binary_string(&[5; 20]));
+            assert_eq!(answer["answer"]["sdp"], "answer");
+        }).await;
+
+        let mut connector = WsTrackerConnector::new();
+        connector.set_offers(vec![WsOffer { offer_id: [3; 20], offer: json!({ "type": "offer", "sdp": "ours" }) }]);
+        let mut request = TrackerAnnounceRequest::new(url, info_hash, [1; 20], 0);
+        request.event = TrackerEvent::Started;
+        request.left = 7;
+        let info = connector.announce(&request).await.unwrap();
+        assert_eq!((info.interval, info.complete, info.incomplete), (120, Some(4), Some(9)));
+        assert!(info.peers.is_empty());
+
+        let signals = connector.take_signals();
+        assert_eq!(signals, vec![WsSignal::Offer {
+            info_hash,
+            peer_id: [5; 20],
+            offer_id: [6; 20],
+            offer: json!({ "type": "offer", "sdp": "theirs" }),
+        }]);
+        connector.send_answer(&info_hash, &[1; 20], &[5; 20], &[6; 20], json!({ "type": "answer", "sdp": "answer" })).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_announce_failure() {
+        let url = serve(|mut socket| async move {
+            recv_json(&mut socket).await;
+            socket.send(Message::text(r#"{"failure reason":"info_hash is not tracked"}"#)).await.unwrap();
+        }).await;
+        let err = WsTrackerConnector::new().announce(&TrackerAnnounceRequest::new(url, [1; 20], [2; 20], 0)).await.unwrap_err();
+        assert!(matches!(err, TrackerError::Failure { ref reason, .. } if reason == "info_hash is not tracked"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_announce_times_out_and_rejects_garbage() {
+        let url = serve(|mut socket| async move {
+            recv_json(&mut socket).await;
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        }).await;
+        let request = TrackerAnnounceRequest::new(url, [1; 20], [2; 20], 0);
+        let err = WsTrackerConnector::with_timeout(Duration::from_millis(100)).announce(&request).await.unwrap_err();
+        assert!(matches!(err, TrackerError::Timeout), "{err:?}");
+
+        let url = serve(|mut socket| async move {
+            recv_json(&mut socket).await;
+            socket.send(Message::text("not json")).await.unwrap();
+        }).await;
+        let request = TrackerAnnounceRequest::new(url, [1; 20], [2; 20], 0);
+        let err = WsTrackerConnector::new().announce(&request).await.unwrap_err();
+        assert!(matches!(err, TrackerError::InvalidResponse(_)), "{err:?}");
+    }
+}
@@ -0,0 +1,95 @@
+//! Raw access to bencoded data, for values whose exact bytes matter, like the info dictionary
+//! the info hash is computed from, and decoding of data received from the network.
+
+use serde::Deserialize;
+
+/// Upper bound on the nesting of lists and dictionaries.
+pub const MAX_DEPTH: usize = 512;
+
+/// Decodes `bytes` as [`serde_bencode::from_bytes`] does, after making sure they aren't nested
+/// deeper than [`MAX_DEPTH`]: the decoder recurses once per level, so a few kilobytes of `l`
+/// would otherwise overflow the stack.
+pub fn from_bytes<'de, T: Deserialize<'de>>(bytes: &'de [u8]) -> Result<T, serde_bencode::Error> {
+    if value_end(bytes, 0).is_none() {
+        return Err(serde_bencode::Error::Custom(format!("Malformed or nested deeper than {MAX_DEPTH} levels")));
+    }
+    serde_bencode::from_bytes(bytes)
+}
+
+/// The bytes of the value of `key` in a bencoded dictionary, exactly as they appear.
+pub fn dict_value<'a>(bytes: &'a [u8], key: &[u8]) -> Option<&'a [u8]> {
+    if bytes.first() != Some(&b'd') {
+        return None;
+    }
+    let mut pos = 1;
+    while *bytes.get(pos)? != b'e' {
+        let (name, value_start) = string(bytes, pos)?;
+        let value_end = value_end(bytes, value_start)?;
+        if name == key {
+            return Some(&bytes[value_start..value_end]);
+        }
+        pos = value_end;
+    }
+    None
+}
+
+/// The string at `pos` and the position right after it.
+fn string(bytes: &[u8], pos: usize) -> Option<(&[u8], usize)> {
+    let colon = pos + bytes.get(pos..)?.iter().position(|byte| *byte == b':')?;
+    let length: usize = std::str::from_utf8(&bytes[pos..colon]).ok()?.parse().ok()?;
+    let end = colon.checked_add(1)?.checked_add(length)?;
+    Some((bytes.get(colon + 1..end)?, end))
+}
+
+/// The position right after the value starting at `pos`, `None` when it's malformed or nested
+/// deeper than [`MAX_DEPTH`].
+pub fn value_end(bytes: &[u8], mut pos: usize) -> Option<usize> {
+    let mut depth = 0;
+    loop {
+        match *bytes.get(pos)? {
+            b'i' => pos += bytes[pos..].iter().position(|byte| *byte == b'e')? + 1,
+            b'l' | b'd' if depth < MAX_DEPTH => {
+                depth += 1;
+                pos += 1;
+                continue;
+            }
+            b'e' if depth > 0 => {
+                depth -= 1;
+                pos += 1;
+            }
+            b'0'..=b'9' => pos = string(bytes, pos)?.1,
+            _ => return None,
+        }
+        if depth == 0 {
+            return Some(pos);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dict_value() {
+        let bytes = b"d1:ai-1e4:infod4:listl1:xi2ee3:subd0:0:ee1:z3:endee";
+        assert_eq!(dict_value(bytes, b"a"), Some(&b"i-1e"[..]));
+        assert_eq!(dict_value(bytes, b"info"), Some(&b"d4:listl1:xi2ee3:subd0:0:ee"[..]));
+        assert_eq!(dict_value(bytes, b"missing"), None);
+        assert_eq!(dict_value(b"d4:infod1:xi1e", b"info"), None);
+        assert_eq!(dict_value(b"d4:info5:abce", b"info"), None);
+        assert_eq!(dict_value(b"l4:infoe", b"info"), None);
+        let deep = [&b"d4:info"[..], &[b'l'; MAX_DEPTH + 1], &[b'e'; MAX_DEPTH + 2]].concat();
+        assert_eq!(dict_value(&deep, b"info"), None);
+    }
+
+    #[test]
+    fn test_from_bytes_limits_depth() {
+        let nested = |depth: usize| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+        let value: serde_bencode::value::Value = from_bytes(&nested(MAX_DEPTH)).unwrap();
+        assert!(matches!(value, serde_bencode::value::Value::List(_)));
+        assert!(from_bytes::<serde_bencode::value::Value>(&nested(MAX_DEPTH + 1)).is_err());
+        let deep = [&b"d5:peers"[..], &[b'l'; 200_000]].concat();
+        assert!(from_bytes::<serde_bencode::value::Value>(&deep).is_err());
+    }
+}
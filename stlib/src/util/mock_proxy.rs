@@ -0,0 +1,217 @@
+//! Loopback SOCKS5 and HTTP proxy stand-in for tests of proxied connections.
+//!
+//! The SOCKS5 side supports `CONNECT` and `UDP ASSOCIATE`; the HTTP side tunnels `CONNECT`
+//! requests and forwards absolute-form requests such as `GET http://host/path`.
+
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use tokio::io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpListener, TcpStream, UdpSocket};
+use tokio::task::JoinHandle;
+
+use super::proxy::{encode_address, udp_header_len, ProxyConfig, ProxyCredentials, ProxyKind};
+
+pub struct MockProxy {
+    config: ProxyConfig,
+    relayed: Arc<AtomicUsize>,
+    task: JoinHandle<()>,
+}
+
+impl MockProxy {
+    /// Starts a proxy of `template`'s kind on an ephemeral loopback port, requiring its
+    /// credentials if it has any. The template's address is ignored.
+    pub async fn start(template: ProxyConfig) -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let config = ProxyConfig { addr: listener.local_addr()?.to_string(), ..template };
+        let relayed = Arc::new(AtomicUsize::new(0));
+        let task = tokio::spawn(serve(listener, config.clone(), relayed.clone()));
+        Ok(Self { config, relayed, task })
+    }
+
+    /// The configuration clients should use to go through this proxy.
+    pub fn config(&self) -> ProxyConfig {
+        self.config.clone()
+    }
+
+    /// Number of TCP connections, HTTP requests and UDP datagrams relayed on behalf of clients.
+    pub fn relayed(&self) -> usize {
+        self.relayed.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for MockProxy {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn serve(listener: TcpListener, config: ProxyConfig, relayed: Arc<AtomicUsize>) {
+    while let Ok((stream, _)) = listener.accept().await {
+        let credentials = config.credentials.clone();
+        let relayed = relayed.clone();
+        let kind = config.kind;
+        tokio::spawn(async move {
+            let _ = match kind {
+                ProxyKind::Socks5 => serve_socks5(stream, credentials, &relayed).await,
+                ProxyKind::Http => serve_http(stream, credentials, &relayed).await,
+            };
+        });
+    }
+}
+
+async fn read_address(stream: &mut TcpStream) -> io::Result<(String, u16)> {
+    let mut address = vec![stream.read_u8().await?];
+    let len = match address[0] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            address.push(stream.read_u8().await?);
+            address[1] as usize
+        },
+        _ => return Err(io::Error::from(ErrorKind::InvalidData)),
+    };
+    let start = address.len();
+    address.resize(start + len + 2, 0);
+    stream.read_exact(&mut address[start..]).await?;
+    decode_address(&address)
+}
+
+async fn resolve(host: &str, port: u16) -> io::Result<SocketAddr> {
+    lookup_host((host, port)).await?.next().ok_or_else(|| io::Error::from(ErrorKind::NotFound))
+}
+
+async fn serve_socks5(mut stream: TcpStream, credentials: Option<ProxyCredentials>, relayed: &AtomicUsize) -> io::Result<()> {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+    let mut methods = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    let method = if credentials.is_some() { 2 } else { 0 };
+    if !methods.contains(&method) {
+        return stream.write_all(&[5, 0xff]).await;
+    }
+    stream.write_all(&[5, method]).await?;
+    if let Some(credentials) = credentials {
+        let mut fields = vec![];
+        stream.read_u8().await?;
+        for _ in 0..2 {
+            let mut field = vec![0u8; stream.read_u8().await? as usize];
+            stream.read_exact(&mut field).await?;
+            fields.push(String::from_utf8_lossy(&field).into_owned());
+        }
+        if fields != [credentials.username, credentials.password] {
+            return stream.write_all(&[1, 1]).await;
+        }
+        stream.write_all(&[1, 0]).await?;
+    }
+
+    let mut request = [0u8; 3];
+    stream.read_exact(&mut request).await?;
+    let (host, port) = read_address(&mut stream).await?;
+    match request[1] {
+        1 => {
+            let Ok(mut target) = TcpStream::connect(resolve(&host, port).await?).await else {
+                return stream.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]).await;
+            };
+            relayed.fetch_add(1, Ordering::SeqCst);
+            stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+            copy_bidirectional(&mut stream, &mut target).await.map(|_| ())
+        },
+        3 => {
+            let relay = UdpSocket::bind("127.0.0.1:0").await?;
+            let mut reply = vec![5, 0, 0];
+            let relay_addr = relay.local_addr()?;
+            reply.extend_from_slice(&encode_address(&relay_addr.ip().to_string(), relay_addr.port())?);
+            stream.write_all(&reply).await?;
+            tokio::select! {
+                result = relay_datagrams(&relay, relayed) => result,
+                // The association ends with the control connection.
+                _ = stream.read_u8() => Ok(()),
+            }
+        },
+        _ => stream.write_all(&[5, 7, 0, 1, 0, 0, 0, 0, 0, 0]).await,
+    }
+}
+
+/// Forwards datagrams from the client to the target in their header, and wraps whatever
+/// comes back from elsewhere in a header naming its sender.
+async fn relay_datagrams(relay: &UdpSocket, relayed: &AtomicUsize) -> io::Result<()> {
+    let mut client = None;
+    let mut buf = [0u8; 4096];
+    loop {
+        let (n, from) = relay.recv_from(&mut buf).await?;
+        let datagram = &buf[..n];
+        if client.is_none() || client == Some(from) {
+            let Some(header_len) = udp_header_len(datagram) else {
+                continue;
+            };
+            client = Some(from);
+            let (host, port) = decode_address(&datagram[3..header_len])?;
+            relayed.fetch_add(1, Ordering::SeqCst);
+            relay.send_to(&datagram[header_len..], resolve(&host, port).await?).await?;
+        } else if let Some(client) = client {
+            let mut response = vec![0, 0, 0];
+            response.extend_from_slice(&encode_address(&from.ip().to_string(), from.port())?);
+            response.extend_from_slice(datagram);
+            relay.send_to(&response, client).await?;
+        }
+    }
+}
+
+/// Decodes a SOCKS address: its type, the address itself and the port.
+fn decode_address(address: &[u8]) -> io::Result<(String, u16)> {
+    let invalid = || io::Error::from(ErrorKind::InvalidData);
+    let (host, rest) = match address.first() {
+        Some(1) if address.len() >= 5 => {
+            let octets: [u8; 4] = address[1..5].try_into().unwrap();
+            (std::net::Ipv4Addr::from(octets).to_string(), &address[5..])
+        },
+        Some(4) if address.len() >= 17 => {
+            let octets: [u8; 16] = address[1..17].try_into().unwrap();
+            (std::net::Ipv6Addr::from(octets).to_string(), &address[17..])
+        },
+        Some(3) => {
+            let len = *address.get(1).ok_or_else(invalid)? as usize;
+            let name = address.get(2..2 + len).ok_or_else(invalid)?;
+            (String::from_utf8_lossy(name).into_owned(), &address[2 + len..])
+        },
+        _ => return Err(invalid()),
+    };
+    let port = rest.get(..2).ok_or_else(invalid)?;
+    Ok((host, u16::from_be_bytes([port[0], port[1]])))
+}
+
+async fn serve_http(mut stream: TcpStream, credentials: Option<ProxyCredentials>, relayed: &AtomicUsize) -> io::Result<()> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(stream.read_u8().await?);
+    }
+    let text = String::from_utf8_lossy(&head).into_owned();
+    if let Some(credentials) = credentials {
+        let token = BASE64.encode(format!("{}:{}", credentials.username, credentials.password));
+        let authorized = text.lines()
+            .any(|line| line.eq_ignore_ascii_case(&format!("proxy-authorization: basic {token}")));
+        if !authorized {
+            return stream.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\nContent-Length: 0\r\n\r\n").await;
+        }
+    }
+    let mut request_line = text.lines().next().unwrap_or_default().split(' ');
+    let (method, target) = (request_line.next().unwrap_or_default(), request_line.next().unwrap_or_default());
+    let authority = match method {
+        "CONNECT" => target,
+        _ => target.strip_prefix("http://").and_then(|rest| rest.split('/').next()).unwrap_or_default(),
+    };
+    let Ok(mut upstream) = TcpStream::connect(authority).await else {
+        return stream.write_all(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n").await;
+    };
+    relayed.fetch_add(1, Ordering::SeqCst);
+    if method == "CONNECT" {
+        stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await?;
+    } else {
+        upstream.write_all(&head).await?;
+    }
+    copy_bidirectional(&mut stream, &mut upstream).await.map(|_| ())
+}
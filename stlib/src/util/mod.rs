@@ -1 +1,5 @@
-pub mod common;
\ No newline at end of file
+pub mod common;
+pub mod bencode;
+pub mod proxy;
+#[cfg(any(test, feature = "test-support"))]
+pub mod mock_proxy;
@@ -0,0 +1,383 @@
+//! Outbound connections through SOCKS5 (RFC 1928) and HTTP `CONNECT` proxies.
+
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpStream, UdpSocket};
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_AUTH_NONE: u8 = 0;
+const SOCKS_AUTH_PASSWORD: u8 = 2;
+const SOCKS_AUTH_UNACCEPTABLE: u8 = 0xff;
+const SOCKS_CMD_CONNECT: u8 = 1;
+const SOCKS_CMD_UDP_ASSOCIATE: u8 = 3;
+const SOCKS_ATYP_IPV4: u8 = 1;
+const SOCKS_ATYP_DOMAIN: u8 = 3;
+const SOCKS_ATYP_IPV6: u8 = 4;
+const MAX_HTTP_RESPONSE_HEAD: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyKind {
+    Socks5,
+    /// An HTTP proxy, tunnelling TCP with `CONNECT`. It can't carry UDP.
+    Http,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyConfig {
+    pub kind: ProxyKind,
+    /// `host:port` of the proxy.
+    pub addr: String,
+    pub credentials: Option<ProxyCredentials>,
+}
+
+impl ProxyConfig {
+    pub fn socks5(addr: impl Into<String>) -> Self {
+        Self { kind: ProxyKind::Socks5, addr: addr.into(), credentials: None }
+    }
+
+    pub fn http(addr: impl Into<String>) -> Self {
+        Self { kind: ProxyKind::Http, addr: addr.into(), credentials: None }
+    }
+
+    pub fn with_credentials(self, username: impl Into<String>, password: impl Into<String>) -> Self {
+        Self {
+            credentials: Some(ProxyCredentials { username: username.into(), password: password.into() }),
+            ..self
+        }
+    }
+
+    /// The proxy as a `reqwest` proxy for every scheme.
+    pub fn to_reqwest(&self) -> reqwest::Result<reqwest::Proxy> {
+        let scheme = match self.kind {
+            // `socks5h` leaves name resolution to the proxy.
+            ProxyKind::Socks5 => "socks5h",
+            ProxyKind::Http => "http",
+        };
+        let proxy = reqwest::Proxy::all(format!("{scheme}://{}", self.addr))?;
+        Ok(match &self.credentials {
+            Some(credentials) => proxy.basic_auth(&credentials.username, &credentials.password),
+            None => proxy,
+        })
+    }
+}
+
+/// Opens a TCP connection to `host:port`, through `proxy` when there is one. Names are
+/// resolved by the proxy, so they don't leak to the local resolver.
+pub async fn connect_tcp(proxy: Option<&ProxyConfig>, host: &str, port: u16) -> io::Result<TcpStream> {
+    let Some(proxy) = proxy else {
+        return TcpStream::connect((unbracket(host), port)).await;
+    };
+    let mut stream = TcpStream::connect(proxy.addr.as_str()).await?;
+    match proxy.kind {
+        ProxyKind::Socks5 => {
+            socks5_handshake(&mut stream, proxy.credentials.as_ref()).await?;
+            socks5_command(&mut stream, SOCKS_CMD_CONNECT, host, port).await?;
+        },
+        ProxyKind::Http => http_connect(&mut stream, proxy.credentials.as_ref(), host, port).await?,
+    }
+    Ok(stream)
+}
+
+/// A UDP socket whose datagrams to a single target are relayed by a SOCKS5 proxy through
+/// `UDP ASSOCIATE`. The association lasts as long as this value.
+pub struct Socks5UdpSocket {
+    // The proxy drops the association once this connection closes.
+    _control: TcpStream,
+    socket: UdpSocket,
+    header: Vec<u8>,
+}
+
+impl Socks5UdpSocket {
+    pub async fn associate(proxy: &ProxyConfig, host: &str, port: u16) -> io::Result<Self> {
+        if proxy.kind != ProxyKind::Socks5 {
+            return Err(io::Error::new(ErrorKind::Unsupported, "UDP can only be relayed by SOCKS5 proxies"));
+        }
+        let mut control = TcpStream::connect(proxy.addr.as_str()).await?;
+        socks5_handshake(&mut control, proxy.credentials.as_ref()).await?;
+        let mut relay = socks5_command(&mut control, SOCKS_CMD_UDP_ASSOCIATE, "0.0.0.0", 0).await?;
+        // Proxies answering with an unspecified address relay on the address we reached them at.
+        if relay.ip().is_unspecified() {
+            relay.set_ip(control.peer_addr()?.ip());
+        }
+        let socket = UdpSocket::bind(if relay.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await?;
+        socket.connect(relay).await?;
+        let mut header = vec![0, 0, 0];
+        header.extend_from_slice(&encode_address(host, port)?);
+        Ok(Self { _control: control, socket, header })
+    }
+
+    pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let mut datagram = Vec::with_capacity(self.header.len() + buf.len());
+        datagram.extend_from_slice(&self.header);
+        datagram.extend_from_slice(buf);
+        self.socket.send(&datagram).await?;
+        Ok(buf.len())
+    }
+
+    /// Receives the next unfragmented datagram relayed back, without its SOCKS header.
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut datagram = vec![0u8; buf.len() + 262];
+        loop {
+            let n = self.socket.recv(&mut datagram).await?;
+            let Some(header_len) = udp_header_len(&datagram[..n]) else {
+                continue;
+            };
+            let payload = &datagram[header_len..n];
+            let len = payload.len().min(buf.len());
+            buf[..len].copy_from_slice(&payload[..len]);
+            return Ok(len);
+        }
+    }
+}
+
+/// Length of the SOCKS header of a relayed datagram, `None` for fragments and malformed ones.
+pub(crate) fn udp_header_len(datagram: &[u8]) -> Option<usize> {
+    if datagram.len() < 4 || datagram[2] != 0 {
+        return None;
+    }
+    let len = match datagram[3] {
+        SOCKS_ATYP_IPV4 => 4 + 4 + 2,
+        SOCKS_ATYP_IPV6 => 4 + 16 + 2,
+        SOCKS_ATYP_DOMAIN => 4 + 1 + *datagram.get(4)? as usize + 2,
+        _ => return None,
+    };
+    (datagram.len() >= len).then_some(len)
+}
+
+fn unbracket(host: &str) -> &str {
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// Encodes `host:port` as a SOCKS address, keeping names as names.
+pub(crate) fn encode_address(host: &str, port: u16) -> io::Result<Vec<u8>> {
+    let host = unbracket(host);
+    let mut buf = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => [&[SOCKS_ATYP_IPV4][..], &ip.octets()].concat(),
+        Ok(IpAddr::V6(ip)) => [&[SOCKS_ATYP_IPV6][..], &ip.octets()].concat(),
+        Err(_) => {
+            let len = u8::try_from(host.len())
+                .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "Host name too long for SOCKS5"))?;
+            [&[SOCKS_ATYP_DOMAIN, len][..], host.as_bytes()].concat()
+        },
+    };
+    buf.extend_from_slice(&port.to_be_bytes());
+    Ok(buf)
+}
+
+async fn socks5_handshake(stream: &mut TcpStream, credentials: Option<&ProxyCredentials>) -> io::Result<()> {
+    let greeting: &[u8] = match credentials {
+        Some(_) => &[SOCKS_VERSION, 2, SOCKS_AUTH_NONE, SOCKS_AUTH_PASSWORD],
+        None => &[SOCKS_VERSION, 1, SOCKS_AUTH_NONE],
+    };
+    stream.write_all(greeting).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    match (reply, credentials) {
+        ([SOCKS_VERSION, SOCKS_AUTH_NONE], _) => Ok(()),
+        ([SOCKS_VERSION, SOCKS_AUTH_PASSWORD], Some(credentials)) => {
+            let mut request = vec![1];
+            for field in [&credentials.username, &credentials.password] {
+                let len = u8::try_from(field.len())
+                    .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "SOCKS5 credentials too long"))?;
+                request.push(len);
+                request.extend_from_slice(field.as_bytes());
+            }
+            stream.write_all(&request).await?;
+            stream.read_exact(&mut reply).await?;
+            match reply[1] {
+                0 => Ok(()),
+                _ => Err(io::Error::new(ErrorKind::PermissionDenied, "SOCKS5 proxy rejected the credentials")),
+            }
+        },
+        ([SOCKS_VERSION, SOCKS_AUTH_UNACCEPTABLE], _) => {
+            Err(io::Error::new(ErrorKind::PermissionDenied, "SOCKS5 proxy accepts none of our authentication methods"))
+        },
+        _ => Err(io::Error::new(ErrorKind::InvalidData, "Invalid SOCKS5 method selection")),
+    }
+}
+
+/// Sends a request and returns the address the proxy bound for it.
+async fn socks5_command(stream: &mut TcpStream, command: u8, host: &str, port: u16) -> io::Result<SocketAddr> {
+    let mut request = vec![SOCKS_VERSION, command, 0];
+    request.extend_from_slice(&encode_address(host, port)?);
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(io::Error::new(ErrorKind::InvalidData, "Invalid SOCKS5 reply"));
+    }
+    if reply[1] != 0 {
+        return Err(io::Error::new(ErrorKind::ConnectionRefused, format!("SOCKS5 proxy refused the request: {}", socks5_reply_message(reply[1]))));
+    }
+    let ip = match reply[3] {
+        SOCKS_ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            stream.read_exact(&mut octets).await?;
+            IpAddr::from(octets)
+        },
+        SOCKS_ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            stream.read_exact(&mut octets).await?;
+            IpAddr::from(octets)
+        },
+        SOCKS_ATYP_DOMAIN => {
+            let len = stream.read_u8().await?;
+            let mut name = vec![0u8; len as usize];
+            stream.read_exact(&mut name).await?;
+            let port = stream.read_u16().await?;
+            let name = String::from_utf8_lossy(&name).into_owned();
+            return lookup_host((name, port)).await?
+                .next()
+                .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "SOCKS5 proxy bound an unknown host"));
+        },
+        _ => return Err(io::Error::new(ErrorKind::InvalidData, "Invalid SOCKS5 address type")),
+    };
+    let port = stream.read_u16().await?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+fn socks5_reply_message(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+async fn http_connect(stream: &mut TcpStream, credentials: Option<&ProxyCredentials>, host: &str, port: u16) -> io::Result<()> {
+    let authority = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(_)) => format!("[{host}]:{port}"),
+        _ => format!("{host}:{port}"),
+    };
+    let mut request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+    if let Some(credentials) = credentials {
+        let token = BASE64.encode(format!("{}:{}", credentials.username, credentials.password));
+        request.push_str(&format!("Proxy-Authorization: Basic {token}\r\n"));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read byte by byte so that nothing the tunnelled peer sends right away is swallowed.
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HTTP_RESPONSE_HEAD {
+            return Err(io::Error::new(ErrorKind::InvalidData, "HTTP proxy response too long"));
+        }
+        head.push(stream.read_u8().await?);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let status = head.split(' ').nth(1).unwrap_or_default();
+    if status != "200" {
+        let status_line = head.lines().next().unwrap_or_default();
+        return Err(io::Error::new(ErrorKind::ConnectionRefused, format!("HTTP proxy refused the tunnel: {status_line}")));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::mock_proxy::MockProxy;
+    use tokio::net::TcpListener;
+
+    async fn echo_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+        addr
+    }
+
+    async fn assert_echoes(mut stream: TcpStream) {
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[test]
+    fn test_encode_address() {
+        assert_eq!(encode_address("10.0.0.1", 80).unwrap(), [1, 10, 0, 0, 1, 0, 80]);
+        assert_eq!(encode_address("[::1]", 1).unwrap()[..2], [4, 0]);
+        assert_eq!(encode_address("a.io", 1).unwrap(), [3, 4, b'a', b'.', b'i', b'o', 0, 1]);
+        assert_eq!(udp_header_len(&[0, 0, 0, 3, 4, b'a', b'.', b'i', b'o', 0, 1, 9]), Some(11));
+        assert_eq!(udp_header_len(&[0, 0, 1, 1, 10, 0, 0, 1, 0, 80]), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_connect_through_socks5() {
+        let target = echo_server().await;
+        let proxy = MockProxy::start(ProxyConfig::socks5("")).await.unwrap();
+        assert_echoes(connect_tcp(Some(&proxy.config()), "127.0.0.1", target.port()).await.unwrap()).await;
+        assert_echoes(connect_tcp(Some(&proxy.config()), "localhost", target.port()).await.unwrap()).await;
+        assert_eq!(proxy.relayed(), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_connect_through_socks5_with_credentials() {
+        let target = echo_server().await;
+        let proxy = MockProxy::start(ProxyConfig::socks5("").with_credentials("user", "secret")).await.unwrap();
+        assert_echoes(connect_tcp(Some(&proxy.config()), "127.0.0.1", target.port()).await.unwrap()).await;
+
+        let wrong = proxy.config().with_credentials("user", "wrong");
+        let err = connect_tcp(Some(&wrong), "127.0.0.1", target.port()).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        let anonymous = ProxyConfig { credentials: None, ..proxy.config() };
+        assert!(connect_tcp(Some(&anonymous), "127.0.0.1", target.port()).await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_connect_through_http_proxy() {
+        let target = echo_server().await;
+        let proxy = MockProxy::start(ProxyConfig::http("").with_credentials("user", "secret")).await.unwrap();
+        assert_echoes(connect_tcp(Some(&proxy.config()), "127.0.0.1", target.port()).await.unwrap()).await;
+        assert_eq!(proxy.relayed(), 1);
+
+        let anonymous = ProxyConfig { credentials: None, ..proxy.config() };
+        let err = connect_tcp(Some(&anonymous), "127.0.0.1", target.port()).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_udp_associate() {
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            let (n, from) = target.recv_from(&mut buf).await.unwrap();
+            target.send_to(&buf[..n], from).await.unwrap();
+        });
+        let proxy = MockProxy::start(ProxyConfig::socks5("")).await.unwrap();
+        let socket = Socks5UdpSocket::associate(&proxy.config(), "127.0.0.1", target_addr.port()).await.unwrap();
+        socket.send(b"ping").await.unwrap();
+        let mut buf = [0u8; 64];
+        let n = socket.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"ping");
+        assert_eq!(proxy.relayed(), 1);
+
+        let err = Socks5UdpSocket::associate(&ProxyConfig::http(proxy.config().addr), "127.0.0.1", 1).await.err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+    }
+}